@@ -0,0 +1,371 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Daemon-side parser for Qubes OS GUI Protocol
+//!
+//! This parses the messages an agent sends to the GUI daemon.  Everything the
+//! agent sends is untrusted, so every field that has restricted values is
+//! checked, and strings are only exposed in sanitized form.
+
+use super::Error;
+use core::convert::TryInto as _;
+use qubes_castable::Castable;
+use qubes_gui::{SanitizedStr, WindowLimits};
+
+/// A GUI protocol event sent by an agent
+#[non_exhaustive]
+pub enum AgentEvent<'a> {
+    /// Create a window.  The size is permitted by the [`WindowLimits`] passed
+    /// to [`AgentEvent::parse`].
+    Create(qubes_gui::Create),
+    /// Destroy a window
+    Destroy,
+    /// Map a window
+    Map(qubes_gui::MapInfo),
+    /// Unmap a window
+    Unmap,
+    /// Move and/or resize a window.  The size is permitted by the
+    /// [`WindowLimits`] passed to [`AgentEvent::parse`].
+    Configure(qubes_gui::Configure),
+    /// Redraw a region of a window from shared memory
+    ShmImage(qubes_gui::ShmImage),
+    /// Set the title of a window.  Called MSG_WMNAME in C.
+    SetTitle(SanitizedStr<'a>),
+    /// Dock a window
+    Dock,
+    /// Set window manager hints.  Only known flags are set, and the minimum
+    /// size is no larger than the maximum size.
+    WindowHints(qubes_gui::WindowHints),
+    /// Set window manager flags.  Only known flags are set or unset.
+    WindowFlags(qubes_gui::WindowFlags),
+    /// Set the window class
+    WindowClass {
+        /// The window class
+        res_class: SanitizedStr<'a>,
+        /// The window name
+        res_name: SanitizedStr<'a>,
+    },
+    /// Send a shared memory dump
+    WindowDump(qubes_gui::WindowDumpBody<'a>),
+    /// Resize a shared memory dump (version 1.15+ only)
+    #[cfg(feature = "extensions")]
+    WindowDumpDelta {
+        /// The header of the delta
+        header: qubes_gui::WindowDumpDeltaHeader,
+        /// Grant refs to add, as native-endian [`u32`]s.  There are exactly
+        /// `header.add` of them.
+        grant_refs: &'a [u8],
+    },
+    /// Set the cursor type.  The cursor is valid; see
+    /// [`qubes_gui::Cursor::is_valid`].
+    Cursor(qubes_gui::Cursor),
+    /// Set the cursor image (version 1.8+ only)
+    #[cfg(feature = "extensions")]
+    CursorImage {
+        /// The header of the image
+        header: qubes_gui::CursorImageHeader,
+        /// UNTRUSTED pixel data, of the length the header requires
+        pixels: &'a [u8],
+    },
+    /// Set the icon of a window (version 1.10+ only)
+    #[cfg(feature = "extensions")]
+    WindowIcon {
+        /// The header of the icon
+        header: qubes_gui::WindowIconHeader,
+        /// UNTRUSTED pixel data, of the length the header requires
+        pixels: &'a [u8],
+    },
+    /// Redraw several regions of a window (version 1.14+ only)
+    #[cfg(feature = "extensions")]
+    Damage(qubes_gui::DamageList<'a>),
+    /// Set the contents of the clipboard.  The contents are not trusted.
+    ClipboardData {
+        /// UNTRUSTED clipboard data!
+        untrusted_data: &'a [u8],
+    },
+    /// Set the contents of the clipboard, along with a MIME type (version
+    /// 1.9+ only).  The contents are not trusted.
+    #[cfg(feature = "extensions")]
+    ClipboardMimeData {
+        /// The MIME type of the data
+        mime_type: &'a str,
+        /// UNTRUSTED clipboard data!
+        untrusted_data: &'a [u8],
+    },
+}
+
+impl AgentEvent<'_> {
+    /// Returns the kind of message this event was parsed from.
+    pub fn kind(&self) -> qubes_gui::Msg {
+        use qubes_gui::Msg;
+        match self {
+            AgentEvent::Create(_) => Msg::Create,
+            AgentEvent::Destroy => Msg::Destroy,
+            AgentEvent::Map(_) => Msg::Map,
+            AgentEvent::Unmap => Msg::Unmap,
+            AgentEvent::Configure(_) => Msg::Configure,
+            AgentEvent::ShmImage(_) => Msg::ShmImage,
+            AgentEvent::SetTitle(_) => Msg::SetTitle,
+            AgentEvent::Dock => Msg::Dock,
+            AgentEvent::WindowHints(_) => Msg::WindowHints,
+            AgentEvent::WindowFlags(_) => Msg::WindowFlags,
+            AgentEvent::WindowClass { .. } => Msg::WindowClass,
+            AgentEvent::WindowDump(_) => Msg::WindowDump,
+            #[cfg(feature = "extensions")]
+            AgentEvent::WindowDumpDelta { .. } => Msg::WindowDumpDelta,
+            AgentEvent::Cursor(_) => Msg::Cursor,
+            #[cfg(feature = "extensions")]
+            AgentEvent::CursorImage { .. } => Msg::CursorImage,
+            #[cfg(feature = "extensions")]
+            AgentEvent::WindowIcon { .. } => Msg::WindowIcon,
+            #[cfg(feature = "extensions")]
+            AgentEvent::Damage(_) => Msg::Damage,
+            AgentEvent::ClipboardData { .. } => Msg::ClipboardData,
+            #[cfg(feature = "extensions")]
+            AgentEvent::ClipboardMimeData { .. } => Msg::ClipboardMimeData,
+        }
+    }
+}
+
+/// Displays the symbolic name of the message the event was parsed from.  The
+/// contents of the event are not displayed, so this is safe to log.
+impl core::fmt::Display for AgentEvent<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.kind().name())
+    }
+}
+
+/// Checks an `override_redirect` field, which must be 0 or 1.
+fn override_redirect(value: u32) -> Result<(), Error> {
+    match value {
+        0 | 1 => Ok(()),
+        value => Err(Error::BadOverrideRedirect { value }),
+    }
+}
+
+/// Checks the rectangle of a window, which must be permitted by `limits`
+/// and must not extend past the coordinate space.
+fn window_rectangle(rectangle: &qubes_gui::Rectangle, limits: &WindowLimits) -> Result<(), Error> {
+    if limits.allows(rectangle.size) && rectangle.is_valid() {
+        Ok(())
+    } else {
+        Err(Error::BadRectangle)
+    }
+}
+
+/// Splits the body of a message into a header of type `T` and the pixel data
+/// that follows it, which must be `data_len(header)` bytes long.
+#[cfg(feature = "extensions")]
+fn split_image<T: Castable + Default>(
+    body: &[u8],
+    data_len: fn(&T) -> Option<usize>,
+) -> Option<(T, &[u8])> {
+    let header_len = core::mem::size_of::<T>();
+    if body.len() < header_len {
+        return None;
+    }
+    let (header, pixels) = body.split_at(header_len);
+    let header = T::from_bytes(header);
+    if data_len(&header)? == pixels.len() {
+        Some((header, pixels))
+    } else {
+        None
+    }
+}
+
+impl<'a> AgentEvent<'a> {
+    /// Returns true if [`AgentEvent::parse`] parses messages of type `ty`,
+    /// rather than ignoring them.
+    pub fn handles(ty: qubes_gui::Msg) -> bool {
+        use qubes_gui::Msg;
+        matches!(
+            ty,
+            Msg::Create
+                | Msg::Destroy
+                | Msg::Map
+                | Msg::Unmap
+                | Msg::Configure
+                | Msg::ShmImage
+                | Msg::SetTitle
+                | Msg::Dock
+                | Msg::WindowHints
+                | Msg::WindowFlags
+                | Msg::WindowClass
+                | Msg::WindowDump
+                | Msg::Cursor
+                | Msg::ClipboardData
+        ) || Self::handles_extension(ty)
+    }
+
+    #[cfg(feature = "extensions")]
+    fn handles_extension(ty: qubes_gui::Msg) -> bool {
+        use qubes_gui::Msg;
+        matches!(
+            ty,
+            Msg::WindowDumpDelta
+                | Msg::CursorImage
+                | Msg::WindowIcon
+                | Msg::Damage
+                | Msg::ClipboardMimeData
+        )
+    }
+
+    #[cfg(not(feature = "extensions"))]
+    fn handles_extension(_: qubes_gui::Msg) -> bool {
+        false
+    }
+
+    /// Returns true if [`AgentEvent::parse`] rejects messages of type `ty`,
+    /// because only a daemon may send them.
+    pub fn rejects(ty: qubes_gui::Msg) -> bool {
+        ty.direction() == qubes_gui::Direction::DaemonToAgent
+    }
+
+    /// Parse a Qubes OS GUI message from an agent.  Windows may not be larger
+    /// than `limits`.
+    ///
+    /// # Return
+    ///
+    /// Returns `Ok(Some(window, event))` on success.  Returns `Ok(None)` if
+    /// the message is deprecated.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the given GUI message cannot be parsed, or if any of its
+    /// fields are invalid.  Messages that only a daemon may send fail with
+    /// [`Error::WrongDirection`].  Length errors are reported as by
+    /// [`super::Event::parse`].
+    pub fn parse(
+        header: qubes_gui::Header,
+        body: &'a [u8],
+        limits: &WindowLimits,
+    ) -> Result<Option<(qubes_gui::WindowID, Self)>, Error> {
+        use qubes_gui::Msg;
+        super::check_length(header, body)?;
+        let window = header.untrusted_window();
+        let ty = match header.ty().try_into() {
+            Ok(ty) if Self::handles(ty) => ty,
+            Ok(ty) if Self::rejects(ty) => return Err(Error::WrongDirection { ty: ty as u32 }),
+            _ => return Ok(None),
+        };
+        let res = match ty {
+            Msg::Create => {
+                let create: qubes_gui::Create = Castable::from_bytes(body);
+                override_redirect(create.override_redirect)?;
+                window_rectangle(&create.rectangle, limits)?;
+                AgentEvent::Create(create)
+            }
+            Msg::Destroy => AgentEvent::Destroy,
+            Msg::Map => {
+                let map_info: qubes_gui::MapInfo = Castable::from_bytes(body);
+                override_redirect(map_info.override_redirect)?;
+                AgentEvent::Map(map_info)
+            }
+            Msg::Unmap => AgentEvent::Unmap,
+            Msg::Configure => {
+                let configure: qubes_gui::Configure = Castable::from_bytes(body);
+                override_redirect(configure.override_redirect)?;
+                window_rectangle(&configure.rectangle, limits)?;
+                AgentEvent::Configure(configure)
+            }
+            Msg::ShmImage => {
+                let shm_image: qubes_gui::ShmImage = Castable::from_bytes(body);
+                if !shm_image.rectangle.is_valid() {
+                    return Err(Error::BadRectangle);
+                }
+                AgentEvent::ShmImage(shm_image)
+            }
+            Msg::SetTitle => AgentEvent::SetTitle(SanitizedStr::new(body)),
+            Msg::Dock => AgentEvent::Dock,
+            Msg::WindowHints => {
+                let hints: qubes_gui::WindowHints = Castable::from_bytes(body);
+                if !hints.is_valid() {
+                    return Err(Error::BadWindowHints);
+                }
+                AgentEvent::WindowHints(hints)
+            }
+            Msg::WindowFlags => {
+                let flags: qubes_gui::WindowFlags = Castable::from_bytes(body);
+                if !flags.is_valid() {
+                    return Err(Error::BadWindowFlags);
+                }
+                AgentEvent::WindowFlags(flags)
+            }
+            Msg::WindowClass => {
+                let (res_class, res_name) = body.split_at(body.len() / 2);
+                AgentEvent::WindowClass {
+                    res_class: SanitizedStr::new(res_class),
+                    res_name: SanitizedStr::new(res_name),
+                }
+            }
+            Msg::WindowDump => AgentEvent::WindowDump(
+                qubes_gui::WindowDumpBody::parse(body, limits).ok_or(Error::BadWindowDump)?,
+            ),
+            #[cfg(feature = "extensions")]
+            Msg::WindowDumpDelta => {
+                let (header, grant_refs) =
+                    qubes_gui::WindowDumpDeltaHeader::split_body(body, limits)
+                        .ok_or(Error::BadWindowDump)?;
+                AgentEvent::WindowDumpDelta { header, grant_refs }
+            }
+            Msg::Cursor => {
+                let cursor: qubes_gui::Cursor = Castable::from_bytes(body);
+                if !cursor.is_valid() {
+                    return Err(Error::BadCursor {
+                        cursor: cursor.cursor,
+                    });
+                }
+                AgentEvent::Cursor(cursor)
+            }
+            #[cfg(feature = "extensions")]
+            Msg::CursorImage => {
+                let (header, pixels) = split_image(body, qubes_gui::CursorImageHeader::data_len)
+                    .ok_or(Error::BadImage)?;
+                AgentEvent::CursorImage { header, pixels }
+            }
+            #[cfg(feature = "extensions")]
+            Msg::WindowIcon => {
+                let (header, pixels) = split_image(body, qubes_gui::WindowIconHeader::data_len)
+                    .ok_or(Error::BadImage)?;
+                AgentEvent::WindowIcon { header, pixels }
+            }
+            #[cfg(feature = "extensions")]
+            Msg::Damage => {
+                AgentEvent::Damage(qubes_gui::DamageList::parse(body).ok_or(Error::BadDamage)?)
+            }
+            Msg::ClipboardData => AgentEvent::ClipboardData {
+                untrusted_data: body,
+            },
+            #[cfg(feature = "extensions")]
+            Msg::ClipboardMimeData => {
+                let (mime_type, untrusted_data) =
+                    qubes_gui::ClipboardMimeHeader::split_body(body).ok_or(Error::BadMimeType)?;
+                AgentEvent::ClipboardMimeData {
+                    mime_type,
+                    untrusted_data,
+                }
+            }
+            // Rejected by handles() above
+            _ => return Ok(None),
+        };
+        Ok(Some((window, res)))
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Debug formatting that does not leak message contents
+//!
+//! Clipboard contents, window titles, window classes, and keystrokes are user
+//! data, which must not end up in logs.  Unless the `debug-plaintext` feature
+//! is enabled, data is formatted as its length only, and key codes and the
+//! keymap are left out.  Even a hash would not do: short secrets, such as
+//! passwords, could be recovered from it by brute force.
+
+use super::{GenericEvent, Payload};
+use core::fmt;
+
+/// Formats data that must not be logged, as its length
+pub(crate) struct Redacted<'a, T: ?Sized>(pub(crate) &'a T);
+
+impl<T: ?Sized + AsRef<[u8]> + fmt::Debug> fmt::Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if cfg!(feature = "debug-plaintext") {
+            return self.0.fmt(f);
+        }
+        write!(f, "<{} bytes>", self.0.as_ref().len())
+    }
+}
+
+/// Formats a [`qubes_gui::ValidatedKeypress`] without its key code or
+/// modifiers, which would reveal what was typed.
+pub(crate) struct RedactedKeypress<'a>(pub(crate) &'a qubes_gui::ValidatedKeypress);
+
+impl fmt::Debug for RedactedKeypress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if cfg!(feature = "debug-plaintext") {
+            return self.0.fmt(f);
+        }
+        f.debug_struct("ValidatedKeypress")
+            .field("ty", &self.0.ty)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Formats a [`qubes_gui::KeymapNotify`] without the keys that are pressed.
+pub(crate) struct RedactedKeymap<'a>(pub(crate) &'a qubes_gui::KeymapNotify);
+
+impl fmt::Debug for RedactedKeymap<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if cfg!(feature = "debug-plaintext") {
+            return self.0.fmt(f);
+        }
+        f.write_str("KeymapNotify { .. }")
+    }
+}
+
+/// Formats a [`qubes_gui::WMClass`], whose strings must not be logged.
+pub(crate) struct RedactedClass<'a>(pub(crate) &'a qubes_gui::WMClass);
+
+impl fmt::Debug for RedactedClass<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WMClass")
+            .field("res_class", &Redacted(&self.0.res_class))
+            .field("res_name", &Redacted(&self.0.res_name))
+            .finish()
+    }
+}
+
+/// Formats the event without its clipboard data, window title, window class,
+/// or keystrokes, unless the `debug-plaintext` feature is enabled.
+impl<P: Payload> fmt::Debug for GenericEvent<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenericEvent::Keypress(e) => f
+                .debug_tuple("Keypress")
+                .field(&RedactedKeypress(e))
+                .finish(),
+            GenericEvent::Button(e) => f.debug_tuple("Button").field(e).finish(),
+            GenericEvent::Motion(e) => f.debug_tuple("Motion").field(e).finish(),
+            GenericEvent::Crossing(e) => f.debug_tuple("Crossing").field(e).finish(),
+            GenericEvent::Focus(e) => f.debug_tuple("Focus").field(e).finish(),
+            #[cfg(feature = "legacy-messages")]
+            GenericEvent::Resize(e) => f.debug_tuple("Resize").field(e).finish(),
+            GenericEvent::Create(e) => f.debug_tuple("Create").field(e).finish(),
+            GenericEvent::Destroy => f.write_str("Destroy"),
+            GenericEvent::Redraw(e) => f.debug_tuple("Redraw").field(e).finish(),
+            GenericEvent::Unmap => f.write_str("Unmap"),
+            GenericEvent::Configure(e) => f.debug_tuple("Configure").field(e).finish(),
+            #[cfg(feature = "legacy-messages")]
+            GenericEvent::MfnDump(e) => f.debug_tuple("MfnDump").field(e).finish(),
+            GenericEvent::ShmImage(e) => f.debug_tuple("ShmImage").field(e).finish(),
+            GenericEvent::Close => f.write_str("Close"),
+            GenericEvent::ClipboardReq => f.write_str("ClipboardReq"),
+            GenericEvent::ClipboardData { untrusted_data } => f
+                .debug_struct("ClipboardData")
+                .field("untrusted_data", &Redacted(&**untrusted_data))
+                .finish(),
+            #[cfg(feature = "extensions")]
+            GenericEvent::ClipboardMimeData {
+                mime_type,
+                untrusted_data,
+            } => f
+                .debug_struct("ClipboardMimeData")
+                .field("mime_type", mime_type)
+                .field("untrusted_data", &Redacted(&**untrusted_data))
+                .finish(),
+            #[cfg(feature = "extensions")]
+            GenericEvent::Outputs(e) => f.debug_tuple("Outputs").field(e).finish(),
+            #[cfg(feature = "extensions")]
+            GenericEvent::WindowScale(e) => f.debug_tuple("WindowScale").field(e).finish(),
+            GenericEvent::SetTitle(title) => f
+                .debug_tuple("SetTitle")
+                .field(&Redacted(&**title))
+                .finish(),
+            GenericEvent::Keymap(e) => f.debug_tuple("Keymap").field(&RedactedKeymap(e)).finish(),
+            GenericEvent::Dock => f.write_str("Dock"),
+            GenericEvent::WindowHints(e) => f.debug_tuple("WindowHints").field(e).finish(),
+            GenericEvent::WindowFlags(e) => f.debug_tuple("WindowFlags").field(e).finish(),
+            GenericEvent::WindowClass(e) => f
+                .debug_tuple("WindowClass")
+                .field(&RedactedClass(e))
+                .finish(),
+            GenericEvent::WindowDump(e) => f.debug_tuple("WindowDump").field(e).finish(),
+            GenericEvent::Cursor(e) => f.debug_tuple("Cursor").field(e).finish(),
+            GenericEvent::Unknown { ty, len } => f
+                .debug_struct("Unknown")
+                .field("ty", ty)
+                .field("len", len)
+                .finish(),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Turning events back into messages
+
+use super::Event;
+use qubes_castable::{static_assert, Castable};
+
+/// Large enough for any fixed-size part of a message
+const MAX_HEAD_LEN: usize = 128;
+static_assert!(core::mem::size_of::<qubes_gui::WMClass>() <= MAX_HEAD_LEN);
+static_assert!(core::mem::size_of::<qubes_gui::WMName>() <= MAX_HEAD_LEN);
+static_assert!(core::mem::size_of::<qubes_gui::ClipboardMimeHeader>() <= MAX_HEAD_LEN);
+
+/// The body of an encoded event: a fixed-size part, stored inline, followed
+/// by borrowed data.  See [`Event::encode`].
+#[derive(Copy, Clone)]
+pub struct Encoded<'a> {
+    head: [u8; MAX_HEAD_LEN],
+    head_len: usize,
+    tail: &'a [u8],
+}
+
+impl<'a> Encoded<'a> {
+    fn new(head: &[u8], tail: &'a [u8]) -> Self {
+        let mut buf = [0; MAX_HEAD_LEN];
+        buf[..head.len()].copy_from_slice(head);
+        Self {
+            head: buf,
+            head_len: head.len(),
+            tail,
+        }
+    }
+
+    fn fixed<T: Castable>(msg: &T) -> Self {
+        Self::new(msg.as_bytes(), &[])
+    }
+
+    /// The body, in two parts that must be sent one after the other
+    pub fn parts(&self) -> [&[u8]; 2] {
+        [&self.head[..self.head_len], self.tail]
+    }
+
+    /// The length of the body
+    pub fn len(&self) -> usize {
+        self.head_len + self.tail.len()
+    }
+
+    /// Returns `true` if the body is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> Event<'a> {
+    /// Encodes the event as a message directed to `window`.  This is the
+    /// inverse of [`Event::parse`].  It does not allocate.
+    ///
+    /// Returns [`None`] for [`Event::Unknown`], whose body is not known, for
+    /// [`Event::WindowDump`], which does not include the grant references, and
+    /// for events that cannot be sent as they are, such as a title that
+    /// contains a control character or is too long.
+    pub fn encode(&self, window: qubes_gui::WindowID) -> Option<(qubes_gui::Header, Encoded<'a>)> {
+        let body = match *self {
+            Event::Keypress(e) => Encoded::fixed(&qubes_gui::Keypress::from(e)),
+            Event::Button(e) => Encoded::fixed(&qubes_gui::Button::from(e)),
+            Event::Motion(e) => Encoded::fixed(&e),
+            Event::Crossing(e) => Encoded::fixed(&qubes_gui::Crossing::from(e)),
+            Event::Focus(e) => Encoded::fixed(&qubes_gui::Focus::from(e)),
+            #[cfg(feature = "legacy-messages")]
+            Event::Resize(e) => Encoded::fixed(&e),
+            Event::Create(e) => Encoded::fixed(&e),
+            Event::Destroy | Event::Unmap | Event::Close | Event::ClipboardReq | Event::Dock => {
+                Encoded::new(&[], &[])
+            }
+            Event::Redraw(e) => Encoded::fixed(&e),
+            Event::Configure(e) => Encoded::fixed(&e),
+            #[cfg(feature = "legacy-messages")]
+            Event::MfnDump(e) => Encoded::fixed(&e),
+            Event::ShmImage(e) => Encoded::fixed(&e),
+            Event::ClipboardData { untrusted_data } => Encoded::new(&[], untrusted_data.as_bytes()),
+            #[cfg(feature = "extensions")]
+            Event::ClipboardMimeData {
+                mime_type,
+                untrusted_data,
+            } => Encoded::new(
+                qubes_gui::ClipboardMimeHeader::new(mime_type)?.as_bytes(),
+                untrusted_data,
+            ),
+            #[cfg(feature = "extensions")]
+            Event::Outputs(outputs) => Encoded::new(&[], outputs.as_bytes()),
+            #[cfg(feature = "extensions")]
+            Event::WindowScale(e) => Encoded::fixed(&e),
+            Event::SetTitle(title) => {
+                let name = qubes_gui::WMName::new(title).ok()?;
+                // Truncated titles are not sent
+                if name.as_str() != Ok(title) {
+                    return None;
+                }
+                Encoded::fixed(&name)
+            }
+            Event::Keymap(e) => Encoded::fixed(&e),
+            Event::WindowHints(e) => Encoded::fixed(&e),
+            Event::WindowFlags(e) => Encoded::fixed(&e),
+            Event::WindowClass(e) => Encoded::fixed(&e),
+            Event::Cursor(e) => Encoded::fixed(&e),
+            Event::WindowDump(_) | Event::Unknown { .. } => return None,
+        };
+        let header = qubes_gui::UntrustedHeader {
+            ty: self.kind()? as u32,
+            window,
+            untrusted_len: body.len() as u32,
+        }
+        .validate_length()
+        .ok()??;
+        Some((header, body))
+    }
+}
@@ -24,11 +24,31 @@
 //! Agent-side parser for Qubes OS GUI Protocol
 //!
 //! This implements agent-side parsing for Qubes OS GUI messages.  It performs
-//! no I/O.
+//! no I/O.  The [`daemon`] module parses messages in the other direction.
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
 use core::convert::TryInto as _;
 use qubes_castable::Castable;
 
+pub mod daemon;
+mod debug;
+mod encode;
+#[cfg(feature = "alloc")]
+mod owned;
+#[cfg(feature = "alloc")]
+mod parser;
+#[cfg(feature = "window-event")]
+pub mod window_event;
+pub use encode::Encoded;
+#[cfg(feature = "alloc")]
+pub use owned::{Owned, OwnedEvent};
+#[cfg(feature = "alloc")]
+pub use parser::Parser;
+#[cfg(test)]
+mod tests;
+
 /// Errors when parsing an agent-side Qubes OS GUI Protocol message.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Error {
@@ -49,22 +69,150 @@ pub enum Error {
         /// The type provided by the GUI daemon
         ty: u32,
     },
+    /// Invalid crossing event type
+    BadCrossing {
+        /// The type provided by the GUI daemon
+        ty: u32,
+    },
+    /// Some other field of an event has an invalid value
+    BadField(qubes_gui::BadFieldError),
+    /// Invalid or missing MIME type in a clipboard message
+    BadMimeType,
+    /// Invalid output configuration
+    BadOutputs,
+    /// Zero scale factor
+    BadScale,
+    /// The length of the body does not match the length in the header
+    LengthMismatch {
+        /// The length in the header
+        header_len: usize,
+        /// The length of the body provided
+        body_len: usize,
+    },
+    /// The length of the body is not valid for the message type
+    BadLength {
+        /// The type of the message
+        ty: u32,
+        /// The length of the body
+        len: usize,
+    },
+    /// Invalid `override_redirect` flag
+    BadOverrideRedirect {
+        /// The value provided by the GUI agent
+        value: u32,
+    },
+    /// Window rectangle is empty, too large, or out of bounds
+    BadRectangle,
+    /// Invalid window dump
+    BadWindowDump,
+    /// Cursor image or window icon with a bad size or hotspot
+    BadImage,
+    /// Invalid damage rectangle list
+    BadDamage,
+    /// Cursor that is neither the default nor an X11 cursor
+    BadCursor {
+        /// The value provided by the GUI agent
+        cursor: u32,
+    },
+    /// Window hints with unknown flags, or a minimum size larger than the
+    /// maximum size
+    BadWindowHints,
+    /// Unknown window flags
+    BadWindowFlags,
+    /// A message that only the receiving side may send
+    WrongDirection {
+        /// The type of the message
+        ty: u32,
+    },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::BadUTF8(e) => write!(f, "Invalid UTF-8 in clipboard data: {}", e),
+            Error::BadKeypress { ty } => write!(f, "Bad type {} for MSG_KEYPRESS", ty),
+            Error::BadButton { ty } => write!(f, "Bad type {} for MSG_BUTTON", ty),
+            Error::BadFocus { ty } => write!(f, "Bad type {} for MSG_FOCUS", ty),
+            Error::BadCrossing { ty } => write!(f, "Bad type {} for MSG_CROSSING", ty),
+            Error::BadField(e) => e.fmt(f),
+            Error::BadMimeType => write!(f, "Bad MIME type in MSG_CLIPBOARD_MIME_DATA"),
+            Error::BadOutputs => write!(f, "Bad output configuration in MSG_OUTPUTS"),
+            Error::BadScale => write!(f, "Zero scale factor in MSG_WINDOW_SCALE"),
+            Error::LengthMismatch {
+                header_len,
+                body_len,
+            } => write!(
+                f,
+                "Body length {} does not match header length {}",
+                body_len, header_len
+            ),
+            Error::BadLength { ty, len } => {
+                write!(f, "Bad length {} for message of type {}", len, ty)
+            }
+            Error::BadOverrideRedirect { value } => {
+                write!(f, "Bad override_redirect value {}", value)
+            }
+            Error::BadRectangle => write!(f, "Bad window rectangle"),
+            Error::BadWindowDump => write!(f, "Bad window dump"),
+            Error::BadImage => write!(f, "Bad cursor image or window icon"),
+            Error::BadDamage => write!(f, "Bad rectangle list in MSG_DAMAGE"),
+            Error::BadCursor { cursor } => write!(f, "Bad cursor {:#x} in MSG_CURSOR", cursor),
+            Error::BadWindowHints => write!(f, "Bad flags or sizes in MSG_WINDOW_HINTS"),
+            Error::BadWindowFlags => write!(f, "Unknown flags in MSG_WINDOW_FLAGS"),
+            Error::WrongDirection { ty } => {
+                write!(
+                    f,
+                    "Message {} sent in the wrong direction",
+                    qubes_gui::MsgType(*ty)
+                )
+            }
+        }
+    }
 }
 
-/// A GUI protocol event
+/// The types of the data in a [`GenericEvent`] that may borrow the message it
+/// was parsed from.  This is [`Borrowed`] for an [`Event`], and `Owned` for
+/// an `OwnedEvent` (with the `alloc` feature).
+pub trait Payload {
+    /// A string, such as clipboard data or a window title
+    type Str: core::ops::Deref<Target = str> + Clone + core::fmt::Debug;
+    /// Raw bytes, such as clipboard data with a MIME type
+    type Bytes: core::ops::Deref<Target = [u8]> + Clone + core::fmt::Debug;
+    /// A list of outputs
+    type Outputs: Clone + core::fmt::Debug;
+}
+
+/// The [`Payload`] of an [`Event`], which borrows the message it was parsed
+/// from
+#[derive(Debug, Copy, Clone)]
+pub struct Borrowed<'a>(core::marker::PhantomData<&'a [u8]>);
+
+impl<'a> Payload for Borrowed<'a> {
+    type Str = &'a str;
+    type Bytes = &'a [u8];
+    type Outputs = qubes_gui::OutputList<'a>;
+}
+
+/// An event that borrows the message it was parsed from.  See
+/// [`Event::parse`].
+pub type Event<'a> = GenericEvent<Borrowed<'a>>;
+
+/// A GUI protocol event, generic over whether its data is borrowed
 #[non_exhaustive]
-pub enum Event<'a> {
+#[derive(Clone)]
+pub enum GenericEvent<P: Payload> {
     /// Daemon ⇒ agent: A key has been pressed or released
-    Keypress(qubes_gui::Keypress),
+    Keypress(qubes_gui::ValidatedKeypress),
     /// Daemon ⇒ agent: A button has been pressed or released
-    Button(qubes_gui::Button),
+    Button(qubes_gui::ValidatedButton),
     /// Daemon ⇒ agent: The pointer has moved
     Motion(qubes_gui::Motion),
     /// Daemon ⇒ agent: The pointer has entered or left a window.
-    Crossing(qubes_gui::Crossing),
+    Crossing(qubes_gui::ValidatedCrossing),
     /// Daemon ⇒ agent: A window has just acquired focus.
-    Focus(qubes_gui::Focus),
+    Focus(qubes_gui::ValidatedFocus),
     /// Daemon ⇒ agent, obsolete.
+    #[cfg(feature = "legacy-messages")]
     Resize(qubes_gui::Rectangle),
     /// Agent ⇒ daemon: Create a window
     Create(qubes_gui::Create),
@@ -80,6 +228,7 @@ pub enum Event<'a> {
     Configure(qubes_gui::Configure),
     /// Ask dom0 (qubes_gui::only!) to map the given amount of memory into composition
     /// buffer.  Deprecated.
+    #[cfg(feature = "legacy-messages")]
     MfnDump(qubes_gui::ShmCmd),
     /// Agent ⇒ daemon: Redraw given area of screen.
     ShmImage(qubes_gui::ShmImage),
@@ -92,10 +241,28 @@ pub enum Event<'a> {
     /// clipboard are not trusted.
     ClipboardData {
         /// UNTRUSTED (though valid UTF-8) clipboard data!
-        untrusted_data: &'a str,
+        untrusted_data: P::Str,
+    },
+    /// Bidirectional: Set the contents of the clipboard, along with a MIME
+    /// type (version 1.9+ only).  The contents of the clipboard are not
+    /// trusted.
+    #[cfg(feature = "extensions")]
+    ClipboardMimeData {
+        /// The MIME type of the data
+        mime_type: P::Str,
+        /// UNTRUSTED clipboard data!
+        untrusted_data: P::Bytes,
     },
+    /// Daemon ⇒ agent: The output (monitor) configuration has changed
+    /// (version 1.11+ only).
+    #[cfg(feature = "extensions")]
+    Outputs(P::Outputs),
+    /// Daemon ⇒ agent: The scale factor of a window, or the default scale
+    /// factor if the window is 0, has changed (version 1.13+ only).
+    #[cfg(feature = "extensions")]
+    WindowScale(qubes_gui::WindowScale),
     /// Agent ⇒ daemon: Set the title of a window.  Called MSG_WMNAME in C.
-    SetTitle(&'a str),
+    SetTitle(P::Str),
     /// Daemon ⇒ agent: Update the keymap.
     Keymap(qubes_gui::KeymapNotify),
     /// Agent ⇒ daemon: Dock a window
@@ -110,16 +277,147 @@ pub enum Event<'a> {
     WindowDump(qubes_gui::WindowDumpHeader),
     /// Agent ⇒ daemon: Set cursor type.
     Cursor(qubes_gui::Cursor),
+    /// A message of a type this library does not know.  The protocol requires
+    /// these to be ignored, but they may be logged and counted.  The body is
+    /// not included, as it must not be logged.  See [`Event::parse_untrusted`].
+    Unknown {
+        /// The type of the message
+        ty: u32,
+        /// The UNTRUSTED length of the message body
+        len: u32,
+    },
+}
+
+impl<P: Payload> GenericEvent<P> {
+    /// Returns the kind of message this event was parsed from, or [`None`]
+    /// for [`GenericEvent::Unknown`].
+    pub fn kind(&self) -> Option<qubes_gui::Msg> {
+        use qubes_gui::Msg;
+        Some(match self {
+            GenericEvent::Keypress(_) => Msg::Keypress,
+            GenericEvent::Button(_) => Msg::Button,
+            GenericEvent::Motion(_) => Msg::Motion,
+            GenericEvent::Crossing(_) => Msg::Crossing,
+            GenericEvent::Focus(_) => Msg::Focus,
+            #[cfg(feature = "legacy-messages")]
+            GenericEvent::Resize(_) => Msg::Resize,
+            GenericEvent::Create(_) => Msg::Create,
+            GenericEvent::Destroy => Msg::Destroy,
+            GenericEvent::Redraw(_) => Msg::Map,
+            GenericEvent::Unmap => Msg::Unmap,
+            GenericEvent::Configure(_) => Msg::Configure,
+            #[cfg(feature = "legacy-messages")]
+            GenericEvent::MfnDump(_) => Msg::MfnDump,
+            GenericEvent::ShmImage(_) => Msg::ShmImage,
+            GenericEvent::Close => Msg::Close,
+            GenericEvent::ClipboardReq => Msg::ClipboardReq,
+            GenericEvent::ClipboardData { .. } => Msg::ClipboardData,
+            #[cfg(feature = "extensions")]
+            GenericEvent::ClipboardMimeData { .. } => Msg::ClipboardMimeData,
+            #[cfg(feature = "extensions")]
+            GenericEvent::Outputs(_) => Msg::Outputs,
+            #[cfg(feature = "extensions")]
+            GenericEvent::WindowScale(_) => Msg::WindowScale,
+            GenericEvent::SetTitle(_) => Msg::SetTitle,
+            GenericEvent::Keymap(_) => Msg::KeymapNotify,
+            GenericEvent::Dock => Msg::Dock,
+            GenericEvent::WindowHints(_) => Msg::WindowHints,
+            GenericEvent::WindowFlags(_) => Msg::WindowFlags,
+            GenericEvent::WindowClass(_) => Msg::WindowClass,
+            GenericEvent::WindowDump(_) => Msg::WindowDump,
+            GenericEvent::Cursor(_) => Msg::Cursor,
+            GenericEvent::Unknown { .. } => return None,
+        })
+    }
+
+    /// Returns the type of message this event was parsed from.
+    pub fn ty(&self) -> qubes_gui::MsgType {
+        match *self {
+            GenericEvent::Unknown { ty, .. } => qubes_gui::MsgType(ty),
+            _ => qubes_gui::MsgType(self.kind().expect("only Unknown has no kind") as u32),
+        }
+    }
+}
+
+/// Displays the symbolic name of the message the event was parsed from, or
+/// its number if it is unknown.  The contents of the event are not displayed,
+/// so this is safe to log.
+impl<P: Payload> core::fmt::Display for GenericEvent<P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.ty().fmt(f)
+    }
+}
+
+/// Checks that the length of `body` matches `header`, and is permitted by
+/// [`qubes_gui::msg_length_limits`].
+fn check_length(header: qubes_gui::Header, body: &[u8]) -> Result<(), Error> {
+    if header.len() != body.len() {
+        return Err(Error::LengthMismatch {
+            header_len: header.len(),
+            body_len: body.len(),
+        });
+    }
+    let ty = header.ty();
+    match qubes_gui::msg_length_limits(ty) {
+        Some(limits) if limits.contains(&body.len()) => Ok(()),
+        _ => Err(Error::BadLength {
+            ty,
+            len: body.len(),
+        }),
+    }
+}
+
+/// Parses a message of type `T` and converts it to its validated form `V`.
+/// A bad `ty` field is reported with `bad_ty`, and any other bad field as
+/// [`Error::BadField`].
+fn validate<T, V>(body: &[u8], bad_ty: fn(u32) -> Error) -> Result<V, Error>
+where
+    T: Castable + Default,
+    V: core::convert::TryFrom<T, Error = qubes_gui::BadFieldError>,
+{
+    V::try_from(T::from_bytes(body)).map_err(|e| match e.field {
+        "ty" => bad_ty(e.value),
+        _ => Error::BadField(e),
+    })
 }
 
 impl<'a> Event<'a> {
+    /// Returns true if [`Event::parse`] parses messages of type `ty`, rather
+    /// than ignoring them.
+    pub fn handles(ty: qubes_gui::Msg) -> bool {
+        use qubes_gui::Msg;
+        matches!(
+            ty,
+            Msg::Motion
+                | Msg::Crossing
+                | Msg::Close
+                | Msg::Keypress
+                | Msg::Button
+                | Msg::ClipboardReq
+                | Msg::ClipboardData
+                | Msg::KeymapNotify
+                | Msg::Map
+                | Msg::Unmap
+                | Msg::Configure
+                | Msg::Focus
+                | Msg::WindowFlags
+                | Msg::Destroy
+        ) || Self::handles_extension(ty)
+    }
+
+    #[cfg(feature = "extensions")]
+    fn handles_extension(ty: qubes_gui::Msg) -> bool {
+        use qubes_gui::Msg;
+        matches!(ty, Msg::ClipboardMimeData | Msg::Outputs | Msg::WindowScale)
+    }
+
+    #[cfg(not(feature = "extensions"))]
+    fn handles_extension(_: qubes_gui::Msg) -> bool {
+        false
+    }
+
     /// Parse a Qubes OS GUI message from the GUI daemon
     ///
-    /// # Panics
-    ///
-    /// Will panic if the length of the message does not match the length in the
-    /// header.
-    ///
     /// # Return
     ///
     /// Returns `Ok(Some(window, event))` on success.  Returns `Ok(None)` if
@@ -127,71 +425,100 @@ impl<'a> Event<'a> {
     ///
     /// # Errors
     ///
-    /// Fails if the given GUI message cannot be parsed.
+    /// Fails if the given GUI message cannot be parsed.  In particular, fails
+    /// with [`Error::LengthMismatch`] if the length of `body` does not match
+    /// the length in the header, and with [`Error::BadLength`] if it is not
+    /// permitted by [`qubes_gui::msg_length_limits`].
     pub fn parse(
         header: qubes_gui::Header,
         body: &'a [u8],
     ) -> Result<Option<(qubes_gui::WindowID, Self)>, Error> {
         use qubes_gui::Msg;
-        assert_eq!(header.len(), body.len(), "Wrong body length provided!");
+        check_length(header, body)?;
         let window = header.untrusted_window();
-        let ty = header
-            .ty()
-            .try_into()
-            .expect("validated by Header::validate_length()");
+        let ty = match header.ty().try_into() {
+            Ok(ty) if Self::handles(ty) => ty,
+            _ => return Ok(None),
+        };
         let res = match ty {
             Msg::Motion => Event::Motion(Castable::from_bytes(body)),
-            Msg::Crossing => Event::Crossing(Castable::from_bytes(body)),
+            Msg::Crossing => Event::Crossing(validate::<qubes_gui::Crossing, _>(body, |ty| {
+                Error::BadCrossing { ty }
+            })?),
             Msg::Close => Event::Close,
-            Msg::Keypress => {
-                let keypress: qubes_gui::Keypress = Castable::from_bytes(body);
-                match keypress.ty {
-                    qubes_gui::EV_KEY_PRESS | qubes_gui::EV_KEY_RELEASE => {}
-                    ty => return Err(Error::BadKeypress { ty }),
-                }
-                Event::Keypress(keypress)
-            }
-            Msg::Button => {
-                let button: qubes_gui::Button = Castable::from_bytes(body);
-                match button.ty {
-                    qubes_gui::EV_BUTTON_PRESS | qubes_gui::EV_BUTTON_RELEASE => {}
-                    ty => return Err(Error::BadButton { ty }),
-                }
-                Event::Button(button)
-            }
+            Msg::Keypress => Event::Keypress(validate::<qubes_gui::Keypress, _>(body, |ty| {
+                Error::BadKeypress { ty }
+            })?),
+            Msg::Button => Event::Button(validate::<qubes_gui::Button, _>(body, |ty| {
+                Error::BadButton { ty }
+            })?),
             Msg::ClipboardReq => Event::ClipboardReq,
             Msg::ClipboardData => {
                 let untrusted_data = core::str::from_utf8(body).map_err(Error::BadUTF8)?;
                 Event::ClipboardData { untrusted_data }
             }
-            Msg::KeymapNotify => Event::Keymap(Castable::from_bytes(body)),
-            Msg::Map => Event::Redraw(Castable::from_bytes(body)),
-            Msg::Unmap => Event::Configure(Castable::from_bytes(body)),
-            Msg::Focus => {
-                let focus: qubes_gui::Focus = Castable::from_bytes(body);
-                match focus.ty {
-                    qubes_gui::EV_FOCUS_IN | qubes_gui::EV_FOCUS_OUT => {}
-                    ty => return Err(Error::BadFocus { ty }),
+            #[cfg(feature = "extensions")]
+            Msg::ClipboardMimeData => {
+                let (mime_type, untrusted_data) =
+                    qubes_gui::ClipboardMimeHeader::split_body(body).ok_or(Error::BadMimeType)?;
+                Event::ClipboardMimeData {
+                    mime_type,
+                    untrusted_data,
+                }
+            }
+            #[cfg(feature = "extensions")]
+            Msg::Outputs => {
+                Event::Outputs(qubes_gui::OutputList::parse(body).ok_or(Error::BadOutputs)?)
+            }
+            #[cfg(feature = "extensions")]
+            Msg::WindowScale => {
+                let scale: qubes_gui::WindowScale = Castable::from_bytes(body);
+                if !scale.is_valid() {
+                    return Err(Error::BadScale);
                 }
-                Event::Focus(focus)
+                Event::WindowScale(scale)
             }
+            Msg::KeymapNotify => Event::Keymap(Castable::from_bytes(body)),
+            Msg::Map => Event::Redraw(Castable::from_bytes(body)),
+            Msg::Unmap => Event::Unmap,
+            Msg::Configure => Event::Configure(Castable::from_bytes(body)),
+            Msg::Focus => Event::Focus(validate::<qubes_gui::Focus, _>(body, |ty| {
+                Error::BadFocus { ty }
+            })?),
             Msg::WindowFlags => Event::WindowFlags(Castable::from_bytes(body)),
             Msg::Destroy => Event::Destroy,
-            // Agent ⇒ daemon messages
-            Msg::Resize
-            | Msg::Create
-            | Msg::Configure
-            | Msg::MfnDump
-            | Msg::ShmImage
-            | Msg::Execute
-            | Msg::SetTitle
-            | Msg::Dock
-            | Msg::WindowHints
-            | Msg::WindowClass
-            | Msg::WindowDump
-            | Msg::Cursor => return Ok(None),
+            // Rejected by Self::handles() above
             _ => return Ok(None),
         };
         Ok(Some((window, res)))
     }
+
+    /// Parse a Qubes OS GUI message from the GUI daemon, with a header that
+    /// has not been validated yet.  Messages of unknown type are returned as
+    /// [`Event::Unknown`], and their body is ignored; the caller must still
+    /// skip `len` bytes.  Otherwise this is the same as [`Event::parse`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::BadLength`] if the length in the header is not
+    /// valid for its type, and otherwise as [`Event::parse`] does.
+    pub fn parse_untrusted(
+        header: qubes_gui::UntrustedHeader,
+        body: &'a [u8],
+    ) -> Result<Option<(qubes_gui::WindowID, Self)>, Error> {
+        match header.validate_length() {
+            Ok(Some(header)) => Self::parse(header, body),
+            Ok(None) => Ok(Some((
+                header.window,
+                Event::Unknown {
+                    ty: header.ty,
+                    len: header.untrusted_len,
+                },
+            ))),
+            Err(e) => Err(Error::BadLength {
+                ty: e.ty,
+                len: e.untrusted_len as usize,
+            }),
+        }
+    }
 }
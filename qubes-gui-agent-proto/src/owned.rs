@@ -0,0 +1,90 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Events that do not borrow the receive buffer
+
+use super::{Event, GenericEvent, Payload};
+use alloc::string::{String, ToString as _};
+use alloc::vec::Vec;
+
+/// The [`Payload`] of an [`OwnedEvent`], which owns its data
+#[derive(Debug, Copy, Clone)]
+pub enum Owned {}
+
+impl Payload for Owned {
+    type Str = String;
+    type Bytes = Vec<u8>;
+    type Outputs = Vec<qubes_gui::Output>;
+}
+
+/// An [`Event`] that owns its data, so that it can be queued or sent to
+/// another thread.  See [`Event::into_owned`].  There is always at least one
+/// output in [`GenericEvent::Outputs`].
+pub type OwnedEvent = GenericEvent<Owned>;
+
+impl Event<'_> {
+    /// Copies any borrowed data into owned storage.
+    pub fn into_owned(self) -> OwnedEvent {
+        match self {
+            Event::Keypress(e) => OwnedEvent::Keypress(e),
+            Event::Button(e) => OwnedEvent::Button(e),
+            Event::Motion(e) => OwnedEvent::Motion(e),
+            Event::Crossing(e) => OwnedEvent::Crossing(e),
+            Event::Focus(e) => OwnedEvent::Focus(e),
+            #[cfg(feature = "legacy-messages")]
+            Event::Resize(e) => OwnedEvent::Resize(e),
+            Event::Create(e) => OwnedEvent::Create(e),
+            Event::Destroy => OwnedEvent::Destroy,
+            Event::Redraw(e) => OwnedEvent::Redraw(e),
+            Event::Unmap => OwnedEvent::Unmap,
+            Event::Configure(e) => OwnedEvent::Configure(e),
+            #[cfg(feature = "legacy-messages")]
+            Event::MfnDump(e) => OwnedEvent::MfnDump(e),
+            Event::ShmImage(e) => OwnedEvent::ShmImage(e),
+            Event::Close => OwnedEvent::Close,
+            Event::ClipboardReq => OwnedEvent::ClipboardReq,
+            Event::ClipboardData { untrusted_data } => OwnedEvent::ClipboardData {
+                untrusted_data: untrusted_data.to_string(),
+            },
+            #[cfg(feature = "extensions")]
+            Event::ClipboardMimeData {
+                mime_type,
+                untrusted_data,
+            } => OwnedEvent::ClipboardMimeData {
+                mime_type: mime_type.to_string(),
+                untrusted_data: untrusted_data.to_vec(),
+            },
+            #[cfg(feature = "extensions")]
+            Event::Outputs(outputs) => OwnedEvent::Outputs(outputs.iter().collect()),
+            #[cfg(feature = "extensions")]
+            Event::WindowScale(e) => OwnedEvent::WindowScale(e),
+            Event::SetTitle(title) => OwnedEvent::SetTitle(title.to_string()),
+            Event::Keymap(e) => OwnedEvent::Keymap(e),
+            Event::Dock => OwnedEvent::Dock,
+            Event::WindowHints(e) => OwnedEvent::WindowHints(e),
+            Event::WindowFlags(e) => OwnedEvent::WindowFlags(e),
+            Event::WindowClass(e) => OwnedEvent::WindowClass(e),
+            Event::WindowDump(e) => OwnedEvent::WindowDump(e),
+            Event::Cursor(e) => OwnedEvent::Cursor(e),
+            Event::Unknown { ty, len } => OwnedEvent::Unknown { ty, len },
+        }
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Incremental parsing of a stream of messages
+
+use super::{Error, Event};
+use alloc::vec::Vec;
+use core::convert::TryFrom as _;
+use qubes_castable::Castable as _;
+
+const HEADER_LEN: usize = core::mem::size_of::<qubes_gui::UntrustedHeader>();
+
+/// A push-based parser for a stream of messages from the GUI daemon.  Feed it
+/// bytes in chunks of any size with [`Parser::push`], and take events out
+/// with [`Parser::next_event`].  It performs no I/O.
+///
+/// ```
+/// use qubes_castable::Castable as _;
+/// use qubes_gui_agent_proto::{Event, Parser};
+/// let header = qubes_gui::UntrustedHeader {
+///     ty: qubes_gui::MSG_CLOSE,
+///     window: 1.into(),
+///     untrusted_len: 0,
+/// };
+/// let mut parser = Parser::new();
+/// let (first, rest) = header.as_bytes().split_at(5);
+/// parser.push(first);
+/// assert!(parser.next_event().unwrap().is_none());
+/// parser.push(rest);
+/// let (window, event) = parser.next_event().unwrap().unwrap();
+/// assert_eq!(window, 1.into());
+/// assert!(matches!(event, Event::Close));
+/// ```
+#[derive(Default)]
+pub struct Parser {
+    /// Bytes received but not yet consumed
+    buf: Vec<u8>,
+    /// Length of the message returned by the last call to
+    /// [`Parser::next_event`], which is discarded by the next call
+    consumed: usize,
+    /// Number of bytes of a skipped message that have not been received yet
+    skip: usize,
+    /// The [`Error::BadLength`] that desynchronized the stream.  Terminal
+    /// state.
+    failed: Option<Error>,
+}
+
+/// Shows how much is buffered, but not the buffered bytes themselves, which
+/// may contain clipboard data.
+impl core::fmt::Debug for Parser {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Parser")
+            .field("buffered", &self.buffered())
+            .field("skip", &self.skip)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+impl Parser {
+    /// Creates a parser with nothing buffered.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `data` to the stream.  Nothing is buffered once the parser
+    /// has failed with [`Error::BadLength`].
+    pub fn push(&mut self, data: &[u8]) {
+        if self.failed.is_some() {
+            return;
+        }
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        self.buf.extend_from_slice(&data[skipped..])
+    }
+
+    /// Returns the number of bytes buffered but not yet parsed.
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.consumed
+    }
+
+    /// Consumes the header of the next message, and skips its body of `len`
+    /// bytes, including any part that has not been received yet.
+    fn skip_message(&mut self, len: usize) {
+        let available = (self.buf.len() - HEADER_LEN).min(len);
+        self.skip = len - available;
+        self.consumed = HEADER_LEN + available;
+    }
+
+    /// Parses the next event, if a complete message has been received.
+    /// Messages that [`Event::parse`] ignores, such as those that should only
+    /// be sent by an agent, are skipped without being buffered.  Messages
+    /// of unknown type are returned as [`Event::Unknown`] as soon as their
+    /// header has been received, and their body is skipped without being
+    /// buffered.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the next message cannot be parsed, as [`Event::parse`] does.
+    /// The bad message is skipped, but as this is a protocol violation by
+    /// the daemon, the caller should usually disconnect.  After an
+    /// [`Error::BadLength`] the stream cannot be resynchronized, so
+    /// everything buffered is discarded, and every later call fails with the
+    /// same error.
+    pub fn next_event(&mut self) -> Result<Option<(qubes_gui::WindowID, Event<'_>)>, Error> {
+        if let Some(e) = self.failed {
+            return Err(e);
+        }
+        loop {
+            self.buf.drain(..self.consumed);
+            self.consumed = 0;
+            if self.buf.len() < HEADER_LEN {
+                return Ok(None);
+            }
+            let untrusted_header = qubes_gui::UntrustedHeader::from_bytes(&self.buf[..HEADER_LEN]);
+            let header = match untrusted_header.validate_length() {
+                Ok(Some(header)) => header,
+                Ok(None) => {
+                    self.skip_message(untrusted_header.untrusted_len as usize);
+                    return Ok(Some((
+                        untrusted_header.window,
+                        Event::Unknown {
+                            ty: untrusted_header.ty,
+                            len: untrusted_header.untrusted_len,
+                        },
+                    )));
+                }
+                Err(e) => {
+                    // The length cannot be trusted, so nothing after the
+                    // header can be parsed.
+                    let e = Error::BadLength {
+                        ty: e.ty,
+                        len: e.untrusted_len as usize,
+                    };
+                    self.buf = Vec::new();
+                    self.failed = Some(e);
+                    return Err(e);
+                }
+            };
+            let len = header.len();
+            let ty = qubes_gui::Msg::try_from(header.ty()).expect("validated above");
+            if !super::Event::handles(ty) {
+                self.skip_message(len);
+                continue;
+            }
+            if self.buf.len() < HEADER_LEN + len {
+                return Ok(None);
+            }
+            self.consumed = HEADER_LEN + len;
+            return Event::parse(header, &self.buf[HEADER_LEN..HEADER_LEN + len]);
+        }
+    }
+}
@@ -0,0 +1,692 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ */
+
+extern crate std;
+
+use super::*;
+use qubes_gui::Header;
+use std::vec::Vec;
+
+fn header<T: qubes_gui::Message>(len: usize) -> Header {
+    Header::for_message::<T>(1.into(), len).unwrap()
+}
+
+#[test]
+fn length_mismatch() {
+    let motion = header::<qubes_gui::Motion>(core::mem::size_of::<qubes_gui::Motion>());
+    let body = [0; 3];
+    match Event::parse(motion, &body) {
+        Err(Error::LengthMismatch {
+            header_len,
+            body_len: 3,
+        }) => assert_eq!(header_len, motion.len()),
+        _ => panic!("wrong result"),
+    }
+}
+
+#[test]
+fn unmap() {
+    let unmap = header::<qubes_gui::Unmap>(0);
+    let (window, event) = Event::parse(unmap, &[]).unwrap().unwrap();
+    assert_eq!(window, 1.into());
+    assert!(matches!(event, Event::Unmap));
+}
+
+#[test]
+fn configure() {
+    let mut configure = qubes_gui::Configure::default();
+    configure.rectangle.top_left.x = 10;
+    configure.rectangle.size.width = 640;
+    configure.rectangle.size.height = 480;
+    let header = header::<qubes_gui::Configure>(core::mem::size_of::<qubes_gui::Configure>());
+    let (window, event) = Event::parse(header, configure.as_bytes()).unwrap().unwrap();
+    assert_eq!(window, 1.into());
+    assert!(matches!(event, Event::Configure(c) if c == configure));
+    assert!(Event::handles(qubes_gui::Msg::Configure));
+}
+
+#[test]
+fn daemon_create() {
+    use daemon::AgentEvent;
+    let limits = qubes_gui::WindowLimits::MAX;
+    let mut create = qubes_gui::Create::default();
+    create.rectangle.size.width = 100;
+    create.rectangle.size.height = 100;
+    let header = header::<qubes_gui::Create>(core::mem::size_of::<qubes_gui::Create>());
+    let (_, event) = AgentEvent::parse(header, create.as_bytes(), &limits)
+        .unwrap()
+        .unwrap();
+    assert!(matches!(event, AgentEvent::Create(c) if c == create));
+    create.override_redirect = 2;
+    assert!(matches!(
+        AgentEvent::parse(header, create.as_bytes(), &limits),
+        Err(Error::BadOverrideRedirect { value: 2 })
+    ));
+    create.override_redirect = 0;
+    create.rectangle.size.width = 0;
+    assert!(matches!(
+        AgentEvent::parse(header, create.as_bytes(), &limits),
+        Err(Error::BadRectangle)
+    ));
+}
+
+#[test]
+fn daemon_daemon_only() {
+    let motion = header::<qubes_gui::Motion>(core::mem::size_of::<qubes_gui::Motion>());
+    let body = [0; core::mem::size_of::<qubes_gui::Motion>()];
+    let limits = qubes_gui::WindowLimits::MAX;
+    assert!(matches!(
+        daemon::AgentEvent::parse(motion, &body, &limits),
+        Err(Error::WrongDirection {
+            ty: qubes_gui::MSG_MOTION
+        })
+    ));
+}
+
+#[test]
+fn daemon_cursor() {
+    let limits = qubes_gui::WindowLimits::MAX;
+    let header = header::<qubes_gui::Cursor>(core::mem::size_of::<qubes_gui::Cursor>());
+    for &cursor in &[
+        qubes_gui::CURSOR_DEFAULT,
+        qubes_gui::CURSOR_X11,
+        qubes_gui::CURSOR_X11_MAX,
+    ] {
+        let cursor = qubes_gui::Cursor { cursor };
+        assert!(matches!(
+            daemon::AgentEvent::parse(header, cursor.as_bytes(), &limits),
+            Ok(Some((_, daemon::AgentEvent::Cursor(_))))
+        ));
+    }
+    for &cursor in &[
+        1,
+        qubes_gui::CURSOR_X11 - 1,
+        qubes_gui::CURSOR_X11_MAX + 1,
+        u32::MAX,
+    ] {
+        let bad = qubes_gui::Cursor { cursor };
+        assert!(matches!(
+            daemon::AgentEvent::parse(header, bad.as_bytes(), &limits),
+            Err(Error::BadCursor { cursor: c }) if c == cursor
+        ));
+    }
+}
+
+#[test]
+fn daemon_window_hints() {
+    use qubes_gui::WindowHintsFlags;
+    let limits = qubes_gui::WindowLimits::MAX;
+    let header = header::<qubes_gui::WindowHints>(core::mem::size_of::<qubes_gui::WindowHints>());
+    let mut hints = qubes_gui::WindowHints {
+        flags: WindowHintsFlags::PMinSize as u32 | WindowHintsFlags::PMaxSize as u32,
+        ..Default::default()
+    };
+    hints.min_size.width = 100;
+    hints.max_size.width = 200;
+    assert!(matches!(
+        daemon::AgentEvent::parse(header, hints.as_bytes(), &limits),
+        Ok(Some((_, daemon::AgentEvent::WindowHints(_))))
+    ));
+    hints.min_size.height = 1;
+    assert!(matches!(
+        daemon::AgentEvent::parse(header, hints.as_bytes(), &limits),
+        Err(Error::BadWindowHints)
+    ));
+    // Sizes that are not marked as valid are not checked
+    hints.flags = WindowHintsFlags::PMinSize as u32;
+    assert!(daemon::AgentEvent::parse(header, hints.as_bytes(), &limits).is_ok());
+    hints.flags |= 1 << 1;
+    assert!(matches!(
+        daemon::AgentEvent::parse(header, hints.as_bytes(), &limits),
+        Err(Error::BadWindowHints)
+    ));
+}
+
+#[test]
+fn daemon_window_flags() {
+    let limits = qubes_gui::WindowLimits::MAX;
+    let header = header::<qubes_gui::WindowFlags>(core::mem::size_of::<qubes_gui::WindowFlags>());
+    let mut flags = qubes_gui::WindowFlags {
+        set: qubes_gui::WindowFlag::Fullscreen as u32,
+        unset: qubes_gui::WindowFlag::Minimize as u32,
+    };
+    assert!(matches!(
+        daemon::AgentEvent::parse(header, flags.as_bytes(), &limits),
+        Ok(Some((_, daemon::AgentEvent::WindowFlags(_))))
+    ));
+    flags.unset |= 1 << 3;
+    assert!(matches!(
+        daemon::AgentEvent::parse(header, flags.as_bytes(), &limits),
+        Err(Error::BadWindowFlags)
+    ));
+    flags.unset = 0;
+    flags.set = 1 << 31;
+    assert!(matches!(
+        daemon::AgentEvent::parse(header, flags.as_bytes(), &limits),
+        Err(Error::BadWindowFlags)
+    ));
+}
+
+#[test]
+fn daemon_window_dump() {
+    let dump = qubes_gui::WindowDumpHeader {
+        ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+        width: 1024,
+        height: 2,
+        bpp: 24,
+    };
+    let body = [dump.as_bytes(), &[0; 8]].concat();
+    let dump_header = header::<qubes_gui::WindowDumpHeader>(body.len());
+    let limits = qubes_gui::WindowLimits::MAX;
+    assert!(matches!(
+        daemon::AgentEvent::parse(dump_header, &body, &limits),
+        Ok(Some((_, daemon::AgentEvent::WindowDump(_))))
+    ));
+    let small = qubes_gui::WindowLimits::for_root_size(qubes_gui::WindowSize {
+        width: 800,
+        height: 600,
+    });
+    assert!(matches!(
+        daemon::AgentEvent::parse(dump_header, &body, &small),
+        Err(Error::BadWindowDump)
+    ));
+    #[cfg(feature = "extensions")]
+    {
+        let delta = qubes_gui::WindowDumpDeltaHeader {
+            width: 1024,
+            height: 2,
+            bpp: 24,
+            keep: 1,
+            add: 1,
+        };
+        let body = [delta.as_bytes(), &[0; 4]].concat();
+        let header = header::<qubes_gui::WindowDumpDeltaHeader>(body.len());
+        assert!(matches!(
+            daemon::AgentEvent::parse(header, &body, &limits),
+            Ok(Some((_, daemon::AgentEvent::WindowDumpDelta { .. })))
+        ));
+        assert!(matches!(
+            daemon::AgentEvent::parse(header, &body, &small),
+            Err(Error::BadWindowDump)
+        ));
+    }
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn into_owned() {
+    let body = b"some text";
+    let clipboard = qubes_gui::UntrustedHeader {
+        ty: qubes_gui::MSG_CLIPBOARD_DATA,
+        window: 0.into(),
+        untrusted_len: body.len() as u32,
+    }
+    .validate_length()
+    .unwrap()
+    .unwrap();
+    let (_, event) = Event::parse(clipboard, body).unwrap().unwrap();
+    let owned = event.clone().into_owned();
+    // Both share the same formatting, so the data is redacted from both
+    assert_eq!(std::format!("{:?}", owned), std::format!("{:?}", event));
+    assert_eq!(owned.kind(), Some(qubes_gui::Msg::ClipboardData));
+    match owned {
+        OwnedEvent::ClipboardData { untrusted_data } => assert_eq!(untrusted_data, "some text"),
+        _ => panic!("wrong event"),
+    }
+}
+
+#[test]
+fn validated_fields() {
+    let mut focus = qubes_gui::Focus {
+        ty: qubes_gui::EV_FOCUS_IN,
+        mode: 0,
+        detail: 8,
+    };
+    let focus_header = header::<qubes_gui::Focus>(core::mem::size_of::<qubes_gui::Focus>());
+    assert!(matches!(
+        Event::parse(focus_header, focus.as_bytes()),
+        Err(Error::BadField(qubes_gui::BadFieldError {
+            field: "detail",
+            value: 8,
+            ..
+        }))
+    ));
+    focus.ty = 0;
+    focus.detail = 0;
+    assert!(matches!(
+        Event::parse(focus_header, focus.as_bytes()),
+        Err(Error::BadFocus { ty: 0 })
+    ));
+    let crossing = qubes_gui::Crossing::default();
+    let crossing_header =
+        header::<qubes_gui::Crossing>(core::mem::size_of::<qubes_gui::Crossing>());
+    assert!(matches!(
+        Event::parse(crossing_header, crossing.as_bytes()),
+        Err(Error::BadCrossing { ty: 0 })
+    ));
+}
+
+#[test]
+fn unknown() {
+    let header = qubes_gui::UntrustedHeader {
+        ty: 0xDEAD,
+        window: 5.into(),
+        untrusted_len: 1000,
+    };
+    let (window, event) = Event::parse_untrusted(header, &[]).unwrap().unwrap();
+    assert_eq!(window, 5.into());
+    assert!(matches!(
+        event,
+        Event::Unknown {
+            ty: 0xDEAD,
+            len: 1000
+        }
+    ));
+    assert!(event.kind().is_none());
+}
+
+/// A small deterministic pseudo-random number generator, so that the
+/// round-trip test needs no extra dependencies
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 32) as u32
+    }
+
+    /// A word that is small half of the time, so that enum-like fields are
+    /// often valid
+    fn word(&mut self) -> u32 {
+        match self.next() {
+            r if r & 1 == 0 => r >> 1,
+            r => (r >> 1) % 12,
+        }
+    }
+}
+
+#[test]
+fn encode_round_trip() {
+    let mut rng = Lcg(1);
+    let mut parsed = 0;
+    let types = [
+        qubes_gui::MSG_KEYPRESS,
+        qubes_gui::MSG_BUTTON,
+        qubes_gui::MSG_MOTION,
+        qubes_gui::MSG_CROSSING,
+        qubes_gui::MSG_FOCUS,
+        qubes_gui::MSG_MAP,
+        qubes_gui::MSG_UNMAP,
+        qubes_gui::MSG_CLOSE,
+        qubes_gui::MSG_DESTROY,
+        qubes_gui::MSG_CLIPBOARD_REQ,
+        qubes_gui::MSG_KEYMAP_NOTIFY,
+        qubes_gui::MSG_WINDOW_FLAGS,
+    ];
+    #[cfg(feature = "extensions")]
+    let types = [
+        &types[..],
+        &[qubes_gui::MSG_WINDOW_SCALE, qubes_gui::MSG_OUTPUTS],
+    ]
+    .concat();
+    for &ty in &types {
+        let len = *qubes_gui::msg_length_limits(ty).unwrap().start();
+        for _ in 0..200 {
+            let body: Vec<u8> = (0..len / 4)
+                .flat_map(|_| rng.word().to_ne_bytes())
+                .collect();
+            let header = qubes_gui::UntrustedHeader {
+                ty,
+                window: rng.next().into(),
+                untrusted_len: len as u32,
+            };
+            let (window, event) = match Event::parse_untrusted(header, &body) {
+                Ok(Some(parsed)) => parsed,
+                _ => continue,
+            };
+            parsed += 1;
+            let (encoded_header, encoded) = event.encode(window).unwrap();
+            assert_eq!(encoded_header.inner(), header);
+            assert_eq!(encoded.parts().concat(), body);
+        }
+    }
+    assert!(parsed > 500, "only {} messages parsed", parsed);
+    assert!(Event::Unknown { ty: 1, len: 0 }.encode(0.into()).is_none());
+}
+
+#[cfg(feature = "extensions")]
+#[test]
+fn encode_clipboard() {
+    let event = Event::ClipboardMimeData {
+        mime_type: "text/plain",
+        untrusted_data: b"data",
+    };
+    let (header, encoded) = event.encode(0.into()).unwrap();
+    let body = encoded.parts().concat();
+    match Event::parse(header, &body).unwrap().unwrap().1 {
+        Event::ClipboardMimeData {
+            mime_type: "text/plain",
+            untrusted_data: b"data",
+        } => {}
+        _ => panic!("wrong event"),
+    }
+}
+
+/// Encodes `event`, parses it back, and checks that it is unchanged.  Events
+/// that only agents send are parsed by the daemon-side parser instead, and
+/// only their kind is compared.
+fn round_trip(event: Event<'_>) {
+    let (header, encoded) = event.encode(5.into()).unwrap();
+    assert_eq!(Some(header.ty()), event.kind().map(|k| k as u32));
+    let body = encoded.parts().concat();
+    match Event::parse(header, &body).unwrap() {
+        Some((window, parsed)) => {
+            assert_eq!(window, 5.into());
+            let (parsed_header, parsed) = parsed.encode(window).unwrap();
+            assert_eq!(parsed_header.inner(), header.inner());
+            assert_eq!(parsed.parts().concat(), body);
+        }
+        None => {
+            let limits = qubes_gui::WindowLimits::MAX;
+            let (window, parsed) = daemon::AgentEvent::parse(header, &body, &limits)
+                .unwrap()
+                .unwrap();
+            assert_eq!(window, 5.into());
+            assert_eq!(Some(parsed.kind()), event.kind());
+        }
+    }
+}
+
+#[test]
+fn encode_every_variant() {
+    use qubes_gui::{Coordinates, Rectangle, WindowSize};
+    let rectangle = Rectangle {
+        top_left: Coordinates { x: -3, y: 4 },
+        size: WindowSize {
+            width: 640,
+            height: 480,
+        },
+    };
+    let coordinates = Coordinates { x: 1, y: 2 };
+    round_trip(Event::Keypress(qubes_gui::ValidatedKeypress {
+        ty: qubes_gui::KeyEvent::Release,
+        coordinates,
+        state: 5.into(),
+        keycode: 38,
+    }));
+    round_trip(Event::Button(qubes_gui::ValidatedButton {
+        ty: qubes_gui::ButtonEvent::Press,
+        coordinates,
+        state: 0.into(),
+        button: 3,
+    }));
+    round_trip(Event::Motion(qubes_gui::Motion {
+        coordinates,
+        state: 1,
+        is_hint: 0,
+    }));
+    round_trip(Event::Crossing(qubes_gui::ValidatedCrossing {
+        ty: qubes_gui::CrossingEvent::Leave,
+        coordinates,
+        state: 0.into(),
+        mode: qubes_gui::NotifyMode::Grab,
+        detail: qubes_gui::NotifyDetail::Inferior,
+        focus: 1,
+    }));
+    round_trip(Event::Focus(qubes_gui::ValidatedFocus {
+        ty: qubes_gui::FocusEvent::In,
+        mode: 0,
+        detail: qubes_gui::NotifyDetail::Virtual,
+    }));
+    round_trip(Event::Create(qubes_gui::Create {
+        rectangle,
+        parent: None,
+        override_redirect: 1,
+    }));
+    round_trip(Event::Destroy);
+    round_trip(Event::Redraw(qubes_gui::MapInfo {
+        transient_for: 7,
+        override_redirect: 0,
+    }));
+    round_trip(Event::Unmap);
+    round_trip(Event::Configure(qubes_gui::Configure {
+        rectangle,
+        override_redirect: 0,
+    }));
+    round_trip(Event::ShmImage(qubes_gui::ShmImage { rectangle }));
+    round_trip(Event::Close);
+    round_trip(Event::ClipboardReq);
+    round_trip(Event::ClipboardData {
+        untrusted_data: "clipboard",
+    });
+    round_trip(Event::SetTitle("Terminal"));
+    round_trip(Event::Keymap(qubes_gui::KeymapNotify { keys: [0xA5; 32] }));
+    round_trip(Event::Dock);
+    round_trip(Event::WindowHints(qubes_gui::WindowHints {
+        flags: qubes_gui::WindowHintsFlags::PMinSize as u32,
+        min_size: rectangle.size,
+        ..Default::default()
+    }));
+    round_trip(Event::WindowFlags(qubes_gui::WindowFlags {
+        set: 1,
+        unset: 2,
+    }));
+    round_trip(Event::WindowClass(
+        qubes_gui::WMClass::new("xterm", "XTerm").unwrap(),
+    ));
+    round_trip(Event::Cursor(qubes_gui::Cursor {
+        cursor: qubes_gui::CURSOR_DEFAULT,
+    }));
+    #[cfg(feature = "extensions")]
+    {
+        round_trip(Event::ClipboardMimeData {
+            mime_type: "text/html",
+            untrusted_data: b"<p>",
+        });
+        let output = qubes_gui::Output {
+            rectangle,
+            scale: qubes_gui::SCALE_DENOMINATOR,
+            flags: 0,
+        };
+        let outputs = [output.as_bytes(), output.as_bytes()].concat();
+        round_trip(Event::Outputs(
+            qubes_gui::OutputList::parse(&outputs).unwrap(),
+        ));
+        round_trip(Event::WindowScale(qubes_gui::WindowScale { scale: 2 }));
+    }
+    #[cfg(feature = "legacy-messages")]
+    {
+        // Neither side parses this any more
+        let event = Event::MfnDump(qubes_gui::ShmCmd {
+            width: 1,
+            ..Default::default()
+        });
+        let (header, encoded) = event.encode(5.into()).unwrap();
+        assert_eq!(header.ty(), qubes_gui::MSG_MFNDUMP);
+        assert_eq!(encoded.len(), header.len());
+    }
+}
+
+#[test]
+fn encode_unrepresentable() {
+    let long = "a".repeat(200);
+    assert!(Event::SetTitle(&long).encode(1.into()).is_none());
+    assert!(Event::SetTitle("tab\t").encode(1.into()).is_none());
+    let dump = qubes_gui::WindowDumpHeader {
+        ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+        width: 1,
+        height: 1,
+        bpp: 24,
+    };
+    assert!(Event::WindowDump(dump).encode(1.into()).is_none());
+    // No length is valid for this message
+    #[cfg(feature = "legacy-messages")]
+    assert!(Event::Resize(Default::default()).encode(1.into()).is_none());
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn parser() {
+    fn message(ty: u32, body: &[u8]) -> Vec<u8> {
+        let header = qubes_gui::UntrustedHeader {
+            ty,
+            window: 7.into(),
+            untrusted_len: body.len() as u32,
+        };
+        [header.as_bytes(), body].concat()
+    }
+    let stream = [
+        message(qubes_gui::MSG_CLIPBOARD_DATA, b"hello"),
+        // Only sent by agents, so skipped
+        message(qubes_gui::MSG_SET_TITLE, &[b'a'; 128]),
+        message(0xDEAD, &[0; 1000]),
+        message(qubes_gui::MSG_CLOSE, &[]),
+    ]
+    .concat();
+    let mut parser = Parser::new();
+    let mut events = Vec::new();
+    for chunk in stream.chunks(7) {
+        parser.push(chunk);
+        while let Some((window, event)) = parser.next_event().unwrap() {
+            assert_eq!(window, 7.into());
+            events.push(event.into_owned());
+        }
+    }
+    assert_eq!(parser.buffered(), 0);
+    assert!(matches!(
+        &events[..],
+        [
+            OwnedEvent::ClipboardData { untrusted_data },
+            OwnedEvent::Unknown {
+                ty: 0xDEAD,
+                len: 1000
+            },
+            OwnedEvent::Close,
+        ] if untrusted_data == "hello"
+    ));
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn parser_bad_length() {
+    let bad = qubes_gui::UntrustedHeader {
+        ty: qubes_gui::MSG_MOTION,
+        window: 1.into(),
+        untrusted_len: 1,
+    };
+    let close = qubes_gui::UntrustedHeader {
+        ty: qubes_gui::MSG_CLOSE,
+        window: 1.into(),
+        untrusted_len: 0,
+    };
+    let expected = Error::BadLength {
+        ty: qubes_gui::MSG_MOTION,
+        len: 1,
+    };
+    let mut parser = Parser::new();
+    parser.push(&[bad.as_bytes(), &[0], close.as_bytes()].concat());
+    assert_eq!(parser.next_event().err(), Some(expected));
+    assert_eq!(parser.buffered(), 0);
+    // Nothing can be parsed after the bad message, not even a good one
+    parser.push(close.as_bytes());
+    assert_eq!(parser.buffered(), 0);
+    assert_eq!(parser.next_event().err(), Some(expected));
+}
+
+#[test]
+fn debug_redacts_contents() {
+    let event = Event::ClipboardData {
+        untrusted_data: "secret",
+    };
+    let debug = std::format!("{:?}", event);
+    assert_eq!(debug.contains("secret"), cfg!(feature = "debug-plaintext"));
+    if !cfg!(feature = "debug-plaintext") {
+        assert!(debug.contains("<6 bytes>"), "{}", debug);
+        // Nothing but the length is shown
+        let other = Event::ClipboardData {
+            untrusted_data: "secreT",
+        };
+        assert_eq!(debug, std::format!("{:?}", other));
+    }
+    let title = std::format!("{:?}", Event::SetTitle("hunter2"));
+    assert_eq!(title.contains("hunter2"), cfg!(feature = "debug-plaintext"));
+    let keypress = Event::Keypress(qubes_gui::ValidatedKeypress {
+        ty: qubes_gui::KeyEvent::Press,
+        coordinates: Default::default(),
+        state: 0.into(),
+        keycode: 12345,
+    });
+    let debug = std::format!("{:?}", keypress);
+    assert_eq!(debug.contains("12345"), cfg!(feature = "debug-plaintext"));
+    assert!(debug.contains("Press"), "{}", debug);
+    let mut keymap = qubes_gui::KeymapNotify::default();
+    keymap.keys[3] = 0x5a;
+    let debug = std::format!("{:?}", Event::Keymap(keymap));
+    assert_eq!(debug.contains("90"), cfg!(feature = "debug-plaintext"));
+}
+
+#[cfg(feature = "window-event")]
+#[test]
+fn window_events() {
+    use window_event::{ElementState, MouseButton, MouseScrollDelta, PhysicalSize, WindowEvent};
+    let button = |ty, button| {
+        Event::Button(qubes_gui::ValidatedButton {
+            ty,
+            coordinates: qubes_gui::Coordinates { x: 1, y: 2 },
+            state: 0.into(),
+            button,
+        })
+        .to_window_event()
+    };
+    assert_eq!(
+        button(qubes_gui::ButtonEvent::Press, 3),
+        Some(WindowEvent::MouseInput {
+            state: ElementState::Pressed,
+            button: MouseButton::Right,
+        })
+    );
+    assert_eq!(
+        button(qubes_gui::ButtonEvent::Press, 5),
+        Some(WindowEvent::MouseWheel {
+            delta: MouseScrollDelta::LineDelta(0.0, -1.0),
+        })
+    );
+    assert_eq!(button(qubes_gui::ButtonEvent::Release, 5), None);
+    assert_eq!(
+        Event::Close.to_window_event(),
+        Some(WindowEvent::CloseRequested)
+    );
+    assert_eq!(Event::Dock.to_window_event(), None);
+    let mut configure = qubes_gui::Configure::default();
+    configure.rectangle.size.width = 800;
+    configure.rectangle.size.height = 600;
+    let header = header::<qubes_gui::Configure>(core::mem::size_of::<qubes_gui::Configure>());
+    let (_, event) = Event::parse(header, configure.as_bytes()).unwrap().unwrap();
+    assert_eq!(
+        event.to_window_event(),
+        Some(WindowEvent::Resized(PhysicalSize {
+            width: 800,
+            height: 600,
+        }))
+    );
+}
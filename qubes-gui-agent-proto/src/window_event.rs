@@ -0,0 +1,185 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Conversion of events into window events shaped like those of `winit`
+//!
+//! These types mirror `winit::event::WindowEvent` and friends, but do not
+//! depend on `winit`, so that a toolkit backend only needs a trivial mapping
+//! from one to the other.  Only the input and window management events a
+//! toolkit acts on are converted.
+
+use super::{GenericEvent, Payload};
+use qubes_gui::{ButtonEvent, CrossingEvent, FocusEvent, KeyEvent, ModifierState};
+
+/// A position in physical pixels, relative to the top left corner of the
+/// window
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct PhysicalPosition {
+    /// X coordinate
+    pub x: i32,
+    /// Y coordinate
+    pub y: i32,
+}
+
+/// A size in physical pixels
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct PhysicalSize {
+    /// Width
+    pub width: u32,
+    /// Height
+    pub height: u32,
+}
+
+/// Whether a key or button is pressed or released
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ElementState {
+    /// The key or button was pressed
+    Pressed,
+    /// The key or button was released
+    Released,
+}
+
+/// A mouse button
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MouseButton {
+    /// The left button (X11 button 1)
+    Left,
+    /// The middle button (X11 button 2)
+    Middle,
+    /// The right button (X11 button 3)
+    Right,
+    /// Any other X11 button, except the scroll buttons 4 through 7
+    Other(u32),
+}
+
+/// A scroll amount
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MouseScrollDelta {
+    /// Scrolling by lines.  Positive values scroll up and to the right.
+    LineDelta(f32, f32),
+}
+
+/// An event directed to a window, as `winit::event::WindowEvent`
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WindowEvent {
+    /// The window has been resized
+    Resized(PhysicalSize),
+    /// The user wishes to close the window
+    CloseRequested,
+    /// The window gained (`true`) or lost (`false`) focus
+    Focused(bool),
+    /// A key was pressed or released
+    KeyboardInput {
+        /// The X11 key code.  Use the daemon’s keymap to turn it into a
+        /// key symbol.
+        keycode: u32,
+        /// Whether the key was pressed or released
+        state: ElementState,
+        /// The modifiers active when the key was pressed or released
+        modifiers: ModifierState,
+    },
+    /// The pointer moved
+    CursorMoved {
+        /// The new position of the pointer
+        position: PhysicalPosition,
+    },
+    /// The pointer entered the window
+    CursorEntered,
+    /// The pointer left the window
+    CursorLeft,
+    /// A mouse button was pressed or released
+    MouseInput {
+        /// Whether the button was pressed or released
+        state: ElementState,
+        /// The button
+        button: MouseButton,
+    },
+    /// The mouse wheel was scrolled
+    MouseWheel {
+        /// How far it was scrolled
+        delta: MouseScrollDelta,
+    },
+}
+
+impl<P: Payload> GenericEvent<P> {
+    /// Converts the event into a [`WindowEvent`], if there is one that
+    /// corresponds to it.
+    ///
+    /// X11 reports scrolling as presses and releases of buttons 4 through 7.
+    /// Presses become [`WindowEvent::MouseWheel`], and releases are ignored.
+    /// A [`GenericEvent::Configure`] becomes [`WindowEvent::Resized`] whether or not
+    /// the size changed; use [`qubes_gui::ConfigureTracker`] to tell.
+    pub fn to_window_event(&self) -> Option<WindowEvent> {
+        Some(match *self {
+            GenericEvent::Configure(e) => WindowEvent::Resized(PhysicalSize {
+                width: e.rectangle.size.width,
+                height: e.rectangle.size.height,
+            }),
+            GenericEvent::Close => WindowEvent::CloseRequested,
+            GenericEvent::Focus(e) => WindowEvent::Focused(e.ty == FocusEvent::In),
+            GenericEvent::Keypress(e) => WindowEvent::KeyboardInput {
+                keycode: e.keycode,
+                state: match e.ty {
+                    KeyEvent::Press => ElementState::Pressed,
+                    KeyEvent::Release => ElementState::Released,
+                },
+                modifiers: e.state,
+            },
+            GenericEvent::Motion(e) => WindowEvent::CursorMoved {
+                position: PhysicalPosition {
+                    x: e.coordinates.x,
+                    y: e.coordinates.y,
+                },
+            },
+            GenericEvent::Crossing(e) => match e.ty {
+                CrossingEvent::Enter => WindowEvent::CursorEntered,
+                CrossingEvent::Leave => WindowEvent::CursorLeft,
+            },
+            GenericEvent::Button(e) => {
+                let state = match e.ty {
+                    ButtonEvent::Press => ElementState::Pressed,
+                    ButtonEvent::Release => ElementState::Released,
+                };
+                let button = match e.button {
+                    1 => MouseButton::Left,
+                    2 => MouseButton::Middle,
+                    3 => MouseButton::Right,
+                    4..=7 if state == ElementState::Released => return None,
+                    4..=7 => {
+                        let (x, y) = match e.button {
+                            4 => (0.0, 1.0),
+                            5 => (0.0, -1.0),
+                            6 => (-1.0, 0.0),
+                            _ => (1.0, 0.0),
+                        };
+                        return Some(WindowEvent::MouseWheel {
+                            delta: MouseScrollDelta::LineDelta(x, y),
+                        });
+                    }
+                    other => MouseButton::Other(other),
+                };
+                WindowEvent::MouseInput { state, button }
+            }
+            _ => return None,
+        })
+    }
+}
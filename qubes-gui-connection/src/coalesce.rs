@@ -0,0 +1,269 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Coalescing of queued messages.  See [`crate::Connection::set_coalescing`].
+
+use qubes_castable::Castable as _;
+use qubes_gui::{Header, Rectangle, UntrustedHeader};
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+/// A message in the write queue, none of which has been sent
+#[derive(Debug)]
+struct Queued {
+    /// Offset of the header in the queue
+    start: usize,
+    header: Header,
+}
+
+impl Queued {
+    fn len(&self) -> usize {
+        size_of::<UntrustedHeader>() + self.header.len()
+    }
+}
+
+/// What [`Coalescer::coalesce`] removed from the queue
+#[derive(Debug, Default)]
+pub(crate) struct Removed {
+    pub(crate) messages: u64,
+    pub(crate) bytes: usize,
+}
+
+/// Tracks the messages in the write queue, so that stale ones can be
+/// replaced, and frame updates dropped when the queue is full
+#[derive(Debug, Default)]
+pub(crate) struct Coalescer {
+    /// Messages that are still wholly queued, in queue order
+    queued: Vec<Queued>,
+    /// Whether [`Coalescer::coalesce`] removes anything
+    pub(crate) merge: bool,
+}
+
+/// Returns true if a message of type `ty` only asks for regions of a window
+/// to be redrawn.  The daemon does not redraw a region on its own, so such a
+/// message can only be dropped if a later one redraws its regions instead.
+pub(crate) fn is_frame_update(ty: u32) -> bool {
+    match ty {
+        qubes_gui::MSG_SHMIMAGE => true,
+        #[cfg(feature = "extensions")]
+        qubes_gui::MSG_DAMAGE => true,
+        _ => false,
+    }
+}
+
+impl Coalescer {
+    pub(crate) fn new(merge: bool) -> Self {
+        Self {
+            queued: Vec::new(),
+            merge,
+        }
+    }
+
+    /// Called when `bytes` bytes have been removed from the front of the
+    /// queue.  Messages that have been partly sent can no longer be touched.
+    pub(crate) fn drained(&mut self, bytes: usize) {
+        self.queued.retain_mut(|queued| {
+            let keep = queued.start >= bytes;
+            if keep {
+                queued.start -= bytes
+            }
+            keep
+        })
+    }
+
+    /// Forget about every queued message.  Called when data that is not a
+    /// message is queued, or the queue is cleared.
+    pub(crate) fn clear(&mut self) {
+        self.queued.clear()
+    }
+
+    /// Record that a message with `header` was queued at offset `start`
+    pub(crate) fn push(&mut self, start: usize, header: Header) {
+        self.queued.push(Queued { start, header })
+    }
+
+    /// Remove the queued messages that a message with `header` makes
+    /// redundant.  Only messages for the same window that no other message
+    /// for that window follows are removed, so the order of the messages for
+    /// each window is kept:
+    ///
+    /// - A [`qubes_gui::Configure`] replaces a queued one.
+    /// - A [`qubes_gui::ShmImage`] is merged with a queued one that overlaps
+    ///   it.  `image` is the rectangle of the new message, and is grown to
+    ///   cover the rectangles of the removed messages.
+    pub(crate) fn coalesce(
+        &mut self,
+        queue: &mut VecDeque<u8>,
+        header: Header,
+        mut image: Option<&mut Rectangle>,
+    ) -> Removed {
+        let mut removed = Removed::default();
+        if !self.merge {
+            return removed;
+        }
+        while let Some(index) = self
+            .queued
+            .iter()
+            .rposition(|queued| queued.header.untrusted_window() == header.untrusted_window())
+        {
+            let queued = &self.queued[index];
+            match (queued.header.ty(), header.ty(), &mut image) {
+                (qubes_gui::MSG_CONFIGURE, qubes_gui::MSG_CONFIGURE, _) => {}
+                (qubes_gui::MSG_SHMIMAGE, qubes_gui::MSG_SHMIMAGE, Some(image)) => {
+                    let mut old = Rectangle::default();
+                    read(
+                        queue,
+                        queued.start + size_of::<UntrustedHeader>(),
+                        old.as_mut_bytes(),
+                    );
+                    if old.intersect(image).is_none() {
+                        break;
+                    }
+                    // A union that does not fit in the coordinate space
+                    // cannot be sent, so the images are left alone.
+                    match old.union(image) {
+                        Some(union) => **image = union,
+                        None => break,
+                    }
+                }
+                _ => break,
+            }
+            self.remove(queue, index, &mut removed)
+        }
+        removed
+    }
+
+    /// Returns the index of the first frame update for the same window that
+    /// is queued after the frame update at `index`, and redraws at least one
+    /// region, if there is one
+    fn later_frame_update(&self, queue: &VecDeque<u8>, index: usize) -> Option<usize> {
+        let queued = &self.queued[index];
+        if !is_frame_update(queued.header.ty()) {
+            return None;
+        }
+        let window = queued.header.untrusted_window();
+        (index + 1..self.queued.len()).find(|&later| {
+            let header = self.queued[later].header;
+            header.untrusted_window() == window
+                && is_frame_update(header.ty())
+                && self.rectangles(queue, later).1 != 0
+        })
+    }
+
+    /// If the frame update at `index` can be dropped, returns the index of
+    /// the later frame update that must redraw its regions instead, and the
+    /// rectangle that the first rectangle of that update must grow to.
+    /// Returns [`None`] if there is no such update, or if the grown
+    /// rectangle would not fit in the coordinate space.
+    fn fold(&self, queue: &VecDeque<u8>, index: usize) -> Option<(usize, Rectangle)> {
+        let later = self.later_frame_update(queue, index)?;
+        let (start, _) = self.rectangles(queue, later);
+        let mut first = Rectangle::default();
+        read(queue, start, first.as_mut_bytes());
+        Some((later, first.union(&self.bounds(queue, index)?)?))
+    }
+
+    /// The number of bytes of queued frame updates that can be dropped
+    pub(crate) fn frame_update_bytes(&self, queue: &VecDeque<u8>) -> usize {
+        (0..self.queued.len())
+            .filter(|&index| self.fold(queue, index).is_some())
+            .map(|index| self.queued[index].len())
+            .sum()
+    }
+
+    /// Remove the oldest queued frame updates (see [`is_frame_update`])
+    /// until at least `bytes` bytes have been removed, or none are left.  A
+    /// frame update is only removed if a later one for the same window is
+    /// queued, which is grown to cover the regions of the removed one, so
+    /// that no region is left without a redraw.
+    pub(crate) fn drop_frame_updates(&mut self, queue: &mut VecDeque<u8>, bytes: usize) -> Removed {
+        let mut removed = Removed::default();
+        let mut index = 0;
+        while removed.bytes < bytes && index < self.queued.len() {
+            match self.fold(queue, index) {
+                Some((later, grown)) => {
+                    let (start, _) = self.rectangles(queue, later);
+                    for (dst, src) in queue.range_mut(start..).zip(grown.as_bytes()) {
+                        *dst = *src
+                    }
+                    self.remove(queue, index, &mut removed)
+                }
+                None => index += 1,
+            }
+        }
+        removed
+    }
+
+    /// The offset in `queue` of the first rectangle of the frame update at
+    /// `index`, and the number of rectangles it has
+    #[cfg_attr(not(feature = "extensions"), allow(unused_variables))]
+    fn rectangles(&self, queue: &VecDeque<u8>, index: usize) -> (usize, usize) {
+        let queued = &self.queued[index];
+        let body = queued.start + size_of::<UntrustedHeader>();
+        match queued.header.ty() {
+            #[cfg(feature = "extensions")]
+            qubes_gui::MSG_DAMAGE => {
+                let mut header = qubes_gui::DamageHeader::default();
+                read(queue, body, header.as_mut_bytes());
+                (
+                    body + size_of::<qubes_gui::DamageHeader>(),
+                    header.count as usize,
+                )
+            }
+            _ => (body, 1),
+        }
+    }
+
+    /// The smallest rectangle containing every region that the frame update
+    /// at `index` redraws, which is empty if it redraws nothing, or [`None`]
+    /// if that rectangle would not fit in the coordinate space
+    fn bounds(&self, queue: &VecDeque<u8>, index: usize) -> Option<Rectangle> {
+        let (start, count) = self.rectangles(queue, index);
+        (0..count).try_fold(Rectangle::default(), |bounds, i| {
+            let mut rectangle = Rectangle::default();
+            read(
+                queue,
+                start + i * size_of::<Rectangle>(),
+                rectangle.as_mut_bytes(),
+            );
+            bounds.union(&rectangle)
+        })
+    }
+
+    /// Remove the message at `index` from the queue
+    fn remove(&mut self, queue: &mut VecDeque<u8>, index: usize, removed: &mut Removed) {
+        let queued = self.queued.remove(index);
+        let (start, len) = (queued.start, queued.len());
+        queue.drain(start..start + len);
+        for later in &mut self.queued[index..] {
+            later.start -= len
+        }
+        removed.messages += 1;
+        removed.bytes += len;
+    }
+}
+
+/// Copy `dst.len()` bytes from `queue`, starting at `start`
+fn read(queue: &VecDeque<u8>, start: usize, dst: &mut [u8]) {
+    for (dst, src) in dst.iter_mut().zip(queue.range(start..)) {
+        *dst = *src
+    }
+}
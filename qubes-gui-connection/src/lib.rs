@@ -25,18 +25,30 @@
 #![forbid(clippy::all)]
 
 pub use qubes_gui;
-use std::convert::TryInto;
+pub use qubes_gui_agent_proto;
+use std::convert::{TryFrom, TryInto};
 use std::task::Poll;
+pub use vchan;
 
 use qubes_castable::{static_assert, Castable};
 use qubes_gui::{Header, UntrustedHeader};
 use std::collections::VecDeque;
+use std::io::BufRead;
 use std::io::{self, Error, ErrorKind};
 use std::mem::size_of;
-use vchan::{Status, Vchan};
+use vchan::{BufVchan, ServerVchan, Status, Transport, Vchan};
 
+mod coalesce;
+mod metrics;
+mod split;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 #[cfg(test)]
 mod tests;
+mod window_ids;
+pub use metrics::Metrics;
+pub use split::{Reader, Writer};
+pub use window_ids::WindowIdAllocator;
 
 /// Protocol state
 #[derive(Debug)]
@@ -46,62 +58,216 @@ enum ReadState {
     Connecting,
     /// Negotiating protocol version
     Negotiating,
+    /// Exchanging capabilities (version 1.12+ only)
+    NegotiatingCapabilities,
     /// Reading a message header
     ReadingHeader,
     /// Reading a message body
     ReadingBody { header: Header },
     /// Discarding data from an unknown message
     Discard(usize),
-    /// Something went wrong.  Terminal state.
-    Error,
+    /// Returning a message body as it arrives.  The field is the number of
+    /// bytes still to come.
+    StreamingBody(usize),
+    /// Something went wrong.  Terminal for [`ReadErrorKind::Protocol`];
+    /// [`ReadErrorKind::Transport`] is left by reconnecting.
+    Error(ReadErrorKind),
+}
+
+/// Why reading from a connection failed.  See [`Connection::error_kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadErrorKind {
+    /// The peer sent invalid data, version negotiation failed, or there was
+    /// not enough memory for a message.  This is terminal: the connection
+    /// cannot be reconnected.
+    Protocol,
+    /// The transport failed, for instance because the peer disconnected.
+    /// An agent can recover with [`Connection::reconnect`].
+    Transport,
 }
 
-// Trait for a vchan, for unit-testing
-trait VchanMock
-where
-    Self: Sized,
-{
-    fn buffer_space(&self) -> usize;
-    fn recv_into(&self, buf: &mut Vec<u8>, bytes: usize) -> Result<(), vchan::Error>;
-    fn recv_struct<T: Castable + Default>(&self) -> Result<T, vchan::Error>;
-    fn send(&self, buf: &[u8]) -> Result<(), vchan::Error>;
-    fn wait(&self);
-    fn data_ready(&self) -> usize;
-    fn status(&self) -> Status;
-    fn discard(&self, bytes: usize) -> Result<(), vchan::Error>;
+/// The vchan of an agent, which listens again when the daemon disconnects,
+/// or of a daemon.  This is the default transport of a [`Connection`].
+#[derive(Debug)]
+pub enum Endpoint {
+    /// The vchan of an agent
+    Server(ServerVchan),
+    /// The vchan of a daemon
+    Client(Vchan),
 }
 
-impl VchanMock for Option<Vchan> {
+impl Endpoint {
+    fn get(&self) -> &dyn Transport {
+        match self {
+            Endpoint::Server(server) => server,
+            Endpoint::Client(client) => client,
+        }
+    }
+
+    /// See [`Vchan::wait_timeout`].
+    fn wait_timeout(&self, timeout: std::time::Duration) -> Result<(), vchan::Error> {
+        let vchan = match self {
+            Endpoint::Server(server) => server.vchan().ok_or_else(|| {
+                vchan::Error::Wait(Error::new(ErrorKind::NotConnected, "not listening"))
+            })?,
+            Endpoint::Client(client) => client,
+        };
+        vchan.wait_timeout(timeout)
+    }
+}
+
+impl Transport for Endpoint {
     fn discard(&self, bytes: usize) -> Result<(), vchan::Error> {
-        Vchan::discard(self.as_ref().unwrap(), bytes)
+        self.get().discard(bytes)
     }
     fn buffer_space(&self) -> usize {
-        Vchan::buffer_space(self.as_ref().unwrap())
+        self.get().buffer_space()
     }
-    fn recv_into(&self, buf: &mut Vec<u8>, bytes: usize) -> Result<(), vchan::Error> {
-        Vchan::recv_into(self.as_ref().unwrap(), buf, bytes)
+    fn recv(&self, buf: &mut [u8]) -> Result<(), vchan::Error> {
+        self.get().recv(buf)
     }
-    fn recv_struct<T: Castable>(&self) -> Result<T, vchan::Error> {
-        Vchan::recv_struct(self.as_ref().unwrap())
+    fn recv_into(&self, buf: &mut Vec<u8>, bytes: usize) -> Result<(), vchan::Error> {
+        self.get().recv_into(buf, bytes)
     }
     fn send(&self, buf: &[u8]) -> Result<(), vchan::Error> {
-        Vchan::send(self.as_ref().unwrap(), buf)
+        self.get().send(buf)
     }
     fn wait(&self) {
-        Vchan::wait(self.as_ref().unwrap())
+        self.get().wait()
     }
     fn data_ready(&self) -> usize {
-        Vchan::data_ready(self.as_ref().unwrap())
+        self.get().data_ready()
     }
     fn status(&self) -> Status {
-        self.as_ref()
-            .map(Vchan::status)
-            .unwrap_or(Status::Disconnected)
+        self.get().status()
+    }
+    fn fd(&self) -> std::os::unix::prelude::RawFd {
+        self.get().fd()
+    }
+}
+
+/// The peer’s protocol version is not supported.  This is the inner error of
+/// the [`io::Error`] returned when version negotiation fails, and can be
+/// retrieved with [`io::Error::get_ref`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionMismatch {
+    /// The version sent by the peer
+    pub peer: u32,
+    /// The oldest version accepted.  See [`Connection::set_min_version`].
+    pub min: u32,
+    /// The version advertised to the peer.  See [`Connection::set_version`].
+    pub ours: u32,
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Version negotiation failed: their version is {}.{} but we support {}.{} to {}.{}",
+            self.peer >> 16,
+            self.peer & 0xFFFF,
+            self.min >> 16,
+            self.min & 0xFFFF,
+            self.ours >> 16,
+            self.ours & 0xFFFF,
+        )
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+/// A message was not queued, as the write queue would have grown past the
+/// limit set with [`Connection::set_queue_limit`].  This is the inner error
+/// of the [`io::Error`], of kind [`ErrorKind::WouldBlock`], returned when
+/// sending fails for this reason.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull {
+    /// The number of bytes that were queued
+    pub queued: usize,
+    /// The length of the message, including its header
+    pub len: usize,
+    /// The limit on the number of queued bytes
+    pub limit: usize,
+}
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Write queue full: {} bytes queued, and a {} byte message would exceed the limit of {}",
+            self.queued, self.len, self.limit,
+        )
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+impl From<QueueFull> for io::Error {
+    fn from(e: QueueFull) -> Self {
+        Error::new(ErrorKind::WouldBlock, e)
     }
 }
 
+/// What to do when sending a message would make the write queue longer than
+/// its limit.  See [`Connection::set_queue_limit`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Fail with [`QueueFull`], without queueing the message
+    Error,
+    /// Drop the oldest queued frame updates ([`qubes_gui::ShmImage`] and
+    /// [`qubes_gui::DamageHeader`] messages) to make room, and fail with
+    /// [`QueueFull`], dropping nothing, if that would not free enough.  The
+    /// daemon only redraws regions it is told about, so a frame update is
+    /// only dropped if a later one for the same window is queued, which is
+    /// grown to cover its regions.  Only messages that are still wholly
+    /// queued, and were queued after the limit was set, can be dropped.
+    DropFrameUpdates,
+    /// Block until the peer has read enough.  This must not be used if the
+    /// peer is untrusted, as it can then stall the caller forever.  After
+    /// [`Connection::split`], this fails with [`QueueFull`] instead, as
+    /// blocking would also block the [`Reader`], and so deadlock with a peer
+    /// that is waiting for us to read.
+    Block,
+}
+
+/// See [`Connection::set_queue_limit`]
+#[derive(Debug, Copy, Clone)]
+struct QueueLimit {
+    bytes: usize,
+    policy: OverflowPolicy,
+}
+
+/// Progress of the handshake.  See [`Connection::handshake_state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    /// Waiting for the peer to connect
+    Connecting,
+    /// Exchanging protocol versions
+    Negotiating,
+    /// Exchanging capabilities (version 1.12+ only)
+    NegotiatingCapabilities,
+    /// Messages can be sent and received
+    Done,
+    /// The connection failed, and must be reconnected
+    Failed,
+}
+
+/// A message from the peer, parsed according to [`Connection::kind`].  See
+/// [`Connection::read_event`].
+pub enum PeerEvent<'a> {
+    /// An agent received an event from the daemon
+    FromDaemon(qubes_gui_agent_proto::Event<'a>),
+    /// A daemon received an event from the agent
+    FromAgent(qubes_gui_agent_proto::daemon::AgentEvent<'a>),
+}
+
+/// The oldest protocol version agents support.  Older daemons send an
+/// [`qubes_gui::XConf`] without a version, which an agent cannot tell apart
+/// from an [`qubes_gui::XConfVersion`].
+const MIN_AGENT_VERSION: u32 = qubes_gui::PROTOCOL_VERSION_MAJOR << 16 | 4;
+
 /// The kind of a state machine
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Kind {
     /// An agent instance
     Agent,
@@ -110,29 +276,116 @@ pub enum Kind {
 }
 
 #[derive(Debug)]
-struct RawMessageStream<T: VchanMock> {
+struct RawMessageStream<T: Transport> {
     /// Vchan
-    vchan: T,
+    vchan: BufVchan<T>,
     /// Write buffer
     queue: VecDeque<u8>,
     /// State of the read state machine
     state: ReadState,
     /// Read buffer
     buffer: Vec<u8>,
+    /// Empty buffer that replaces the read buffer when the caller takes it
+    spare: Vec<u8>,
     /// Was reconnect successful?
     did_reconnect: bool,
     /// Configuration from the daemon
     xconf: qubes_gui::XConfVersion,
-    /// Peer domain ID
-    domid: u16,
     /// Agent or daemon?
     kind: Kind,
+    /// Protocol version advertised to the peer
+    version: u32,
+    /// Oldest protocol version accepted from the peer
+    min_version: u32,
+    /// Capabilities advertised to the peer
+    capabilities: qubes_gui::Capabilities,
+    /// Capabilities advertised by, or implied by the version of, the peer
+    peer_capabilities: qubes_gui::Capabilities,
+    /// Called with the header of every message sent or received
+    hook: Option<MessageHook>,
+    /// Tracks queued messages, if coalescing is enabled or queued frame
+    /// updates may be dropped
+    coalescer: Option<coalesce::Coalescer>,
+    /// Counters
+    metrics: Metrics,
+}
+
+/// Whether a message was sent or received.  See
+/// [`Connection::set_message_hook`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MessageDirection {
+    /// The message was sent to the peer
+    Sent,
+    /// The message was received from the peer
+    Received,
+}
+
+/// A function called with the header of every message
+struct MessageHook(Box<dyn FnMut(MessageDirection, Header) + Send>);
+
+impl std::fmt::Debug for MessageHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MessageHook")
+    }
+}
+
+/// Read buffers with more capacity than this are shrunk once the message in
+/// them has been handled, so that a rare large message, such as a clipboard
+/// transfer, does not keep its memory allocated.
+const MAX_RETAINED_CAPACITY: usize = 1 << 16;
+
+/// What [`RawMessageStream::read_message_internal`] found
+enum Read {
+    /// A complete message, whose body is in the buffer
+    Message(Header),
+    /// The header of a message whose body will be streamed
+    Header(Header),
+    /// Part of a streamed body, which is in the buffer
+    Body { remaining: usize },
+}
+
+/// What [`RawMessageStream::read_message_internal`] reads
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ReadMode {
+    /// Whole messages
+    Whole,
+    /// Whole messages, except that variable-length bodies are streamed
+    Chunked,
+    /// Only the handshake, stopping before the first message
+    Handshake,
+}
+
+/// Returns true if messages of type `ty` do not have a fixed size.  Only
+/// the bodies of these messages are streamed by
+/// [`Connection::read_body_chunked`].
+fn has_variable_length(ty: u32) -> bool {
+    matches!(qubes_gui::msg_length_limits(ty), Some(limits) if limits.start() != limits.end())
+}
+
+/// Part of a message, as returned by [`Connection::read_body_chunked`]
+#[derive(Debug)]
+pub enum Chunk<'a> {
+    /// A complete message.  Used for messages with a fixed size, and for
+    /// empty messages.
+    Message(Buffer<'a>),
+    /// The header of a message with a variable-length body.  The body
+    /// follows in one or more [`Chunk::Body`]s.
+    Header(Header),
+    /// Part of the body of the message whose header was returned last
+    Body {
+        /// The data that has arrived
+        data: &'a [u8],
+        /// The number of bytes of the body still to come.  Zero for the last
+        /// chunk.
+        remaining: usize,
+    },
 }
 
 /// A buffer
 #[derive(Debug)]
 pub struct Buffer<'a> {
     inner: &'a mut Vec<u8>,
+    spare: &'a mut Vec<u8>,
     hdr: Header,
 }
 
@@ -145,20 +398,21 @@ impl<'a> Buffer<'a> {
     pub fn body(&self) -> &[u8] {
         &self.inner[..]
     }
-    /// Takes ownership of the body
-    pub fn take(mut self) -> Vec<u8> {
-        std::mem::replace(&mut self.inner, vec![])
+    /// Takes ownership of the body.  Pass it to [`Connection::recycle`] once
+    /// done with it, so that its memory is used for a later message.
+    pub fn take(self) -> Vec<u8> {
+        std::mem::replace(self.inner, std::mem::take(self.spare))
     }
 }
 
-impl<T: VchanMock + 'static> RawMessageStream<T> {
+impl<T: Transport + 'static> RawMessageStream<T> {
     /// Attempts to write as much of `slice` as possible to the `vchan`.  Never
     /// blocks.  Returns the number of bytes written.
     ///
     /// # Errors
     ///
     /// Fails if writing to the vchan fails.
-    fn write_slice(vchan: &mut T, slice: &[u8]) -> Result<usize, vchan::Error> {
+    fn write_slice(vchan: &mut BufVchan<T>, slice: &[u8]) -> Result<usize, vchan::Error> {
         let space = vchan.buffer_space();
         if space == 0 {
             Ok(0)
@@ -169,6 +423,17 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
         }
     }
 
+    /// Receives a `S` if all of it has arrived, without blocking.
+    fn try_recv_struct<S: Castable + Default>(&mut self) -> Result<Option<S>, vchan::Error> {
+        let mut datum = S::default();
+        match self.vchan.peek(size_of::<S>())? {
+            Some(bytes) => datum.as_mut_bytes().copy_from_slice(bytes),
+            None => return Ok(None),
+        }
+        self.vchan.consume(size_of::<S>());
+        Ok(Some(datum))
+    }
+
     /// Write as much of the buffered data as possible without blocking.
     /// Returns the number of bytes successfully written.
     fn flush_pending_writes(&mut self) -> Result<usize, vchan::Error> {
@@ -188,8 +453,9 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
                 break Ok(written);
             }
             written += written_this_time;
-            for _ in 0..written_this_time {
-                let _ = self.queue.pop_front();
+            self.queue.drain(..written_this_time);
+            if let Some(coalescer) = &mut self.coalescer {
+                coalescer.drained(written_this_time)
             }
         }
     }
@@ -201,24 +467,89 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
     ///
     /// Fails if there is an I/O error on the vchan.
     pub fn write(&mut self, buf: &[u8]) -> Result<(), vchan::Error> {
-        #[cfg(not(test))]
-        match self.state {
-            ReadState::Error | ReadState::Connecting | ReadState::Negotiating => return Ok(()),
-            _ => {}
+        if self.discards_writes() {
+            return Ok(());
         }
         self.flush_pending_writes()?;
-        if !self.queue.is_empty() {
+        if let Some(coalescer) = &mut self.coalescer {
+            coalescer.clear()
+        }
+        self.metrics.bytes_sent += buf.len() as u64;
+        if self.queue.is_empty() {
+            let written = Self::write_slice(&mut self.vchan, buf)?;
+            if written != buf.len() {
+                assert!(written < buf.len());
+                self.queue.extend(&buf[written..]);
+            }
+        } else {
             self.queue.extend(buf);
+        }
+        self.metrics.max_queued_bytes = self.metrics.max_queued_bytes.max(self.queue.len());
+        Ok(())
+    }
+
+    /// Write a message consisting of `header` followed by `body`, as
+    /// [`RawMessageStream::write`] does.  Unless older data is still queued,
+    /// the message is handed to the vchan in a single send, so the peer never
+    /// sees a partial message unless the vchan is full.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there is an I/O error on the vchan.
+    pub fn write_message(&mut self, header: Header, body: &[&[u8]]) -> Result<(), vchan::Error> {
+        if self.discards_writes() {
             return Ok(());
         }
-        let written = Self::write_slice(&mut self.vchan, buf)?;
-        if written != buf.len() {
-            assert!(written < buf.len());
-            self.queue.extend(&buf[written..]);
+        if let Some(MessageHook(hook)) = &mut self.hook {
+            hook(MessageDirection::Sent, header)
+        }
+        self.metrics.count_sent(header);
+        self.flush_pending_writes()?;
+        let mut image = None;
+        if let Some(coalescer) = &mut self.coalescer {
+            if header.ty() == qubes_gui::MSG_SHMIMAGE {
+                image = Some(qubes_gui::ShmImage::from_bytes(&body.concat()).rectangle)
+            }
+            let removed = coalescer.coalesce(&mut self.queue, header, image.as_mut());
+            self.metrics.coalesced += removed.messages;
+            self.metrics.bytes_sent -= removed.bytes as u64;
+            coalescer.push(self.queue.len(), header);
+        }
+        let merged;
+        let body = match &image {
+            Some(image) => {
+                merged = [image.as_bytes()];
+                &merged[..]
+            }
+            None => body,
+        };
+        let was_empty = self.queue.is_empty();
+        self.queue.extend(header.inner().as_bytes());
+        for part in body {
+            self.queue.extend(*part);
+        }
+        self.metrics.bytes_sent += (size_of::<UntrustedHeader>() + header.len()) as u64;
+        self.metrics.max_queued_bytes = self.metrics.max_queued_bytes.max(self.queue.len());
+        if was_empty {
+            self.queue.make_contiguous();
+            self.flush_pending_writes()?;
         }
         Ok(())
     }
 
+    /// Returns true if writes are silently dropped, because the connection
+    /// has not been established or has failed.
+    fn discards_writes(&self) -> bool {
+        !cfg!(test)
+            && matches!(
+                self.state,
+                ReadState::Error(_)
+                    | ReadState::Connecting
+                    | ReadState::Negotiating
+                    | ReadState::NegotiatingCapabilities
+            )
+    }
+
     /// Acknowledge an event on the vchan.
     pub fn wait(&mut self) {
         self.vchan.wait()
@@ -229,7 +560,7 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
         std::mem::replace(&mut self.did_reconnect, false)
     }
 
-    fn read_message_internal(&mut self) -> io::Result<Option<Header>> {
+    fn read_message_internal(&mut self, mode: ReadMode) -> io::Result<Option<Read>> {
         const SIZE_OF_XCONF: usize = size_of::<qubes_gui::XConfVersion>();
         self.flush_pending_writes()?;
         static_assert!(
@@ -237,6 +568,9 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
             "<32-bit systems not supported"
         );
         loop {
+            if mode == ReadMode::Handshake && self.negotiated_version().is_some() {
+                break Ok(None);
+            }
             let ready = self.vchan.data_ready();
             match &mut self.state {
                 ReadState::Connecting => match self.vchan.status() {
@@ -245,87 +579,151 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
                         Kind::Daemon => self.state = ReadState::Negotiating,
                         Kind::Agent => {
                             assert!(self.vchan.buffer_space() >= 4, "vchans have larger buffers");
-                            match self.vchan.send(qubes_gui::PROTOCOL_VERSION.as_bytes()) {
+                            match self.vchan.send(self.version.as_bytes()) {
                                 Ok(()) => self.state = ReadState::Negotiating,
                                 Err(e) => break Err(e.into()),
                             }
                         }
                     },
-                    Status::Disconnected => {
+                    Status::Disconnected | Status::HalfClosed => {
                         break Err(Error::new(ErrorKind::Other, "vchan connection refused"));
                     }
                 },
-                ReadState::Error => {
-                    break Err(Error::new(ErrorKind::Other, "Already in error state"))
+                ReadState::Error(ReadErrorKind::Protocol) => {
+                    break Err(Error::other(
+                        "Already in error state after a protocol violation",
+                    ))
+                }
+                ReadState::Error(ReadErrorKind::Transport) => {
+                    break Err(Error::new(
+                        ErrorKind::NotConnected,
+                        "Connection interrupted, and must be reconnected",
+                    ))
                 }
                 ReadState::Negotiating => match self.kind {
                     Kind::Agent if ready >= SIZE_OF_XCONF => {
                         let new_xconf: qubes_gui::XConfVersion = self.vchan.recv_struct()?;
                         let (daemon_major, daemon_minor) =
                             (new_xconf.version >> 16, new_xconf.version & 0xFFFF);
-                        if qubes_gui::PROTOCOL_VERSION_MAJOR == daemon_major
-                            && qubes_gui::PROTOCOL_VERSION_MINOR >= daemon_minor
-                            && daemon_minor >= 4
+                        if self.version >> 16 == daemon_major
+                            && self.version & 0xFFFF >= daemon_minor
+                            && new_xconf.version >= self.min_version
                         {
                             self.xconf = new_xconf;
-                            self.state = ReadState::ReadingHeader;
-                            self.did_reconnect = true;
+                            self.peer_capabilities =
+                                qubes_gui::Capabilities::implied_by_version(new_xconf.version);
+                            if new_xconf.version >= qubes_gui::Capabilities::MIN_VERSION {
+                                self.state = ReadState::NegotiatingCapabilities;
+                            } else {
+                                self.state = ReadState::ReadingHeader;
+                                self.did_reconnect = true;
+                            }
                         } else {
-                            break Err(Error::new(ErrorKind::InvalidData,
-                                            format!(
-                                                "Version negotiation failed: their version is {}.{} but ours is {}.{}",
-                                                daemon_major, daemon_minor,
-                                                qubes_gui::PROTOCOL_VERSION_MAJOR,
-                                                qubes_gui::PROTOCOL_VERSION_MINOR,
-                                                )));
+                            let e = self.version_mismatch(new_xconf.version);
+                            break Err(self.protocol_error(e));
                         }
                     }
                     Kind::Daemon if ready >= 4 => {
                         let version: u32 = self.vchan.recv_struct()?;
                         let (major, minor) = (version >> 16, version & 0xFFFF);
-                        if major == qubes_gui::PROTOCOL_VERSION_MAJOR {
-                            let version = version.min(qubes_gui::PROTOCOL_VERSION_MINOR);
-                            self.xconf.version = version;
-                            self.vchan.send(if version >= 4 {
+                        if major == self.version >> 16 && version >= self.min_version {
+                            let minor = minor.min(self.version & 0xFFFF);
+                            self.xconf.version = major << 16 | minor;
+                            self.peer_capabilities =
+                                qubes_gui::Capabilities::implied_by_version(self.xconf.version);
+                            self.vchan.send(if minor >= 4 {
                                 self.xconf.as_bytes()
                             } else {
                                 self.xconf.xconf.as_bytes()
                             })?;
-                            self.state = ReadState::ReadingHeader
+                            if self.xconf.version >= qubes_gui::Capabilities::MIN_VERSION {
+                                self.vchan.send(self.capabilities.as_bytes())?;
+                                self.state = ReadState::NegotiatingCapabilities
+                            } else {
+                                self.state = ReadState::ReadingHeader
+                            }
                         } else {
-                            break Err(Error::new(
-                                    ErrorKind::InvalidData,
-                                    format!(
-                                        "Unsupported version from agent: daemon supports {}.{} but agent sent {}.{}",
-                                        qubes_gui::PROTOCOL_VERSION_MAJOR,
-                                        qubes_gui::PROTOCOL_VERSION_MINOR,
-                                        major,
-                                        minor,
-                                    )));
+                            let e = self.version_mismatch(version);
+                            break Err(self.protocol_error(e));
                         }
                     }
                     Kind::Agent | Kind::Daemon => break Ok(None),
                 },
-                ReadState::ReadingHeader if ready < size_of::<Header>() => break Ok(None),
+                ReadState::NegotiatingCapabilities => {
+                    self.peer_capabilities = match self.try_recv_struct()? {
+                        Some(capabilities) => capabilities,
+                        None => break Ok(None),
+                    };
+                    if let Kind::Agent = self.kind {
+                        self.vchan.send(self.capabilities.as_bytes())?;
+                        self.did_reconnect = true;
+                    }
+                    self.state = ReadState::ReadingHeader
+                }
                 ReadState::ReadingHeader => {
-                    // Reset buffer to 0 bytes
+                    let header: UntrustedHeader = match self.try_recv_struct()? {
+                        Some(header) => header,
+                        None => break Ok(None),
+                    };
+                    // Reset buffer to 0 bytes, and give back the memory of
+                    // unusually large messages
                     self.buffer.clear();
-                    let header: UntrustedHeader = self.vchan.recv_struct()?;
+                    self.buffer.shrink_to(MAX_RETAINED_CAPACITY);
                     match header.validate_length() {
                         Err(e) => {
-                            break Err(Error::new(ErrorKind::InvalidData, format!("{}", e)));
+                            break Err(self.protocol_error(Error::new(
+                                ErrorKind::InvalidData,
+                                format!("{}", e),
+                            )));
                         }
+                        Ok(Some(header)) if !self.peer_supports(header.ty()) => match self.kind {
+                            Kind::Daemon => {
+                                let e = Error::new(
+                                    ErrorKind::InvalidData,
+                                    format!(
+                                        "Message of type {} not supported by negotiated version {}.{}",
+                                        qubes_gui::MsgType(header.ty()),
+                                        self.xconf.version >> 16,
+                                        self.xconf.version & 0xFFFF,
+                                    ),
+                                );
+                                break Err(self.protocol_error(e));
+                            }
+                            Kind::Agent if header.len() == 0 => {}
+                            Kind::Agent => self.state = ReadState::Discard(header.len()),
+                        },
                         Ok(Some(header)) if header.len() == 0 => {
                             self.state = ReadState::ReadingHeader;
-                            break Ok(Some(header));
+                            break Ok(Some(Read::Message(header)));
+                        }
+                        Ok(Some(header))
+                            if mode == ReadMode::Chunked && has_variable_length(header.ty()) =>
+                        {
+                            self.state = ReadState::StreamingBody(header.len());
+                            break Ok(Some(Read::Header(header)));
+                        }
+                        Ok(Some(header)) => {
+                            // Allocate once, rather than as the body arrives,
+                            // preferring memory given back by the caller
+                            if self.spare.capacity() > self.buffer.capacity() {
+                                std::mem::swap(&mut self.buffer, &mut self.spare)
+                            }
+                            // The peer chose the length, so running out of
+                            // memory is not recoverable by reconnecting.
+                            if let Err(e) = self.buffer.try_reserve_exact(header.len()) {
+                                break Err(self.protocol_error(vchan::Error::OutOfMemory(e).into()));
+                            }
+                            self.state = ReadState::ReadingBody { header }
                         }
-                        Ok(Some(header)) => self.state = ReadState::ReadingBody { header },
                         Ok(None) if header.untrusted_len == 0 => {
                             self.state = ReadState::ReadingHeader
                         }
                         Ok(None) => self.state = ReadState::Discard(header.untrusted_len as _),
                     }
                 }
+                ReadState::Discard(_) | ReadState::StreamingBody(_) if ready == 0 => {
+                    break Ok(None)
+                }
                 ReadState::Discard(untrusted_len) => {
                     match self.vchan.discard(ready.min(*untrusted_len)) {
                         Err(e) => break Err(e.into()),
@@ -333,12 +731,28 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
                         Ok(()) => *untrusted_len -= ready,
                     }
                 }
+                ReadState::StreamingBody(remaining) if mode != ReadMode::Chunked => {
+                    // The caller stopped reading the body
+                    self.state = ReadState::Discard(*remaining)
+                }
+                ReadState::StreamingBody(remaining) => {
+                    let to_read = ready.min(*remaining);
+                    self.buffer.clear();
+                    self.vchan.recv_into(&mut self.buffer, to_read)?;
+                    *remaining -= to_read;
+                    let remaining = *remaining;
+                    if remaining == 0 {
+                        self.state = ReadState::ReadingHeader
+                    }
+                    break Ok(Some(Read::Body { remaining }));
+                }
                 &mut ReadState::ReadingBody { header } => {
                     let to_read = header.len() - self.buffer.len();
                     self.vchan.recv_into(&mut self.buffer, to_read.min(ready))?;
                     break if ready >= to_read {
                         self.state = ReadState::ReadingHeader;
-                        Ok(Some(header))
+                        self.check_window_size(header)?;
+                        Ok(Some(Read::Message(header)))
                     } else {
                         Ok(None)
                     };
@@ -352,88 +766,646 @@ impl<T: VchanMock + 'static> RawMessageStream<T> {
     /// `Err` is returned, and the stream is placed in an error state.  If the
     /// stream is in an error state, all further functions will fail.
     pub fn read_message<'a>(&'a mut self) -> io::Result<Option<Buffer<'a>>> {
-        match self.read_message_internal() {
-            Ok(Some(header)) => Ok(Some(Buffer {
-                hdr: header,
-                inner: &mut self.buffer,
-            })),
+        match self.read_body_chunked_internal(ReadMode::Whole)? {
+            Some(Chunk::Message(buffer)) => Ok(Some(buffer)),
+            Some(Chunk::Header(_) | Chunk::Body { .. }) => {
+                unreachable!("bodies are only streamed in chunked mode")
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// See [`Connection::read_body_chunked`].
+    pub fn read_body_chunked<'a>(&'a mut self) -> io::Result<Option<Chunk<'a>>> {
+        self.read_body_chunked_internal(ReadMode::Chunked)
+    }
+
+    /// See [`Connection::poll_handshake`].  Returns true once the handshake
+    /// has completed.
+    fn poll_handshake(&mut self) -> io::Result<bool> {
+        match self.read_body_chunked_internal(ReadMode::Handshake)? {
+            Some(_) => unreachable!("no messages are read during the handshake"),
+            None => Ok(self.negotiated_version().is_some()),
+        }
+    }
+
+    fn read_body_chunked_internal<'a>(
+        &'a mut self,
+        mode: ReadMode,
+    ) -> io::Result<Option<Chunk<'a>>> {
+        match self.read_message_internal(mode) {
+            Ok(Some(read)) => {
+                if let Read::Message(header) | Read::Header(header) = read {
+                    if let Some(MessageHook(hook)) = &mut self.hook {
+                        hook(MessageDirection::Received, header)
+                    }
+                    self.metrics.count_received(header);
+                }
+                Ok(Some(match read {
+                    Read::Message(header) => Chunk::Message(Buffer {
+                        hdr: header,
+                        inner: &mut self.buffer,
+                        spare: &mut self.spare,
+                    }),
+                    Read::Header(header) => Chunk::Header(header),
+                    Read::Body { remaining } => Chunk::Body {
+                        data: &self.buffer,
+                        remaining,
+                    },
+                }))
+            }
             Ok(None) => Ok(None),
+            // Protocol violations were classified where they were found, and
+            // an earlier failure is not reclassified.
+            Err(e) if self.error_kind().is_some() => Err(e),
             Err(e) => {
-                self.state = ReadState::Error;
+                self.state = ReadState::Error(ReadErrorKind::Transport);
                 Err(e)
             }
         }
     }
 
+    /// See [`Connection::recycle`].
+    fn recycle(&mut self, mut buffer: Vec<u8>) {
+        if buffer.capacity() <= MAX_RETAINED_CAPACITY && buffer.capacity() > self.spare.capacity() {
+            buffer.clear();
+            self.spare = buffer
+        }
+    }
+
+    /// See [`Connection::next_agent_event`].
+    fn next_agent_event(
+        &mut self,
+    ) -> io::Result<
+        Option<(
+            qubes_gui::WindowID,
+            qubes_gui_agent_proto::daemon::AgentEvent<'_>,
+        )>,
+    > {
+        use qubes_gui_agent_proto::daemon::AgentEvent;
+        if let Kind::Agent = self.kind {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "only daemons receive agent events",
+            ));
+        }
+        let limits = self.xconf.xconf.window_limits();
+        // Messages only a daemon may send are passed on, so that parsing
+        // them fails instead of skipping them.
+        let header =
+            match self.next_handled(|ty| AgentEvent::handles(ty) || AgentEvent::rejects(ty))? {
+                None => return Ok(None),
+                Some(header) => header,
+            };
+        match AgentEvent::parse(header, &self.buffer, &limits) {
+            Ok(Some(event)) => Ok(Some(event)),
+            Ok(None) => unreachable!("AgentEvent::handles() and rejects() checked above"),
+            Err(e) => Err(parse_error(&mut self.metrics, &mut self.state, e)),
+        }
+    }
+
+    /// See [`Connection::read_event`].
+    fn next_daemon_event(
+        &mut self,
+    ) -> io::Result<Option<(qubes_gui::WindowID, qubes_gui_agent_proto::Event<'_>)>> {
+        use qubes_gui_agent_proto::Event;
+        let header = match self.next_handled(Event::handles)? {
+            None => return Ok(None),
+            Some(header) => header,
+        };
+        match Event::parse(header, &self.buffer) {
+            Ok(Some(event)) => Ok(Some(event)),
+            Ok(None) => unreachable!("Event::handles() checked above"),
+            Err(e) => Err(parse_error(&mut self.metrics, &mut self.state, e)),
+        }
+    }
+
+    /// Reads messages until one whose type `handles` accepts has been
+    /// buffered, and returns its header.  Other messages are skipped.
+    fn next_handled(&mut self, handles: fn(qubes_gui::Msg) -> bool) -> io::Result<Option<Header>> {
+        loop {
+            let header = match self.read_message()? {
+                None => return Ok(None),
+                Some(buffer) => buffer.hdr(),
+            };
+            if matches!(header.ty().try_into(), Ok(ty) if handles(ty)) {
+                return Ok(Some(header));
+            }
+        }
+    }
+
     pub fn needs_reconnect(&self) -> bool {
         self.vchan.status() == Status::Disconnected
+            || self.error_kind() == Some(ReadErrorKind::Transport)
+    }
+
+    /// See [`Connection::error_kind`].
+    fn error_kind(&self) -> Option<ReadErrorKind> {
+        match self.state {
+            ReadState::Error(kind) => Some(kind),
+            _ => None,
+        }
+    }
+
+    /// Returns true if messages of type `ty` are allowed by the negotiated
+    /// protocol version and capabilities.  Unknown message types are never
+    /// allowed.
+    fn peer_supports(&self, ty: u32) -> bool {
+        match qubes_gui::Msg::try_from(ty) {
+            Ok(msg) => {
+                msg.min_version() <= self.xconf.version
+                    && match msg.capability() {
+                        Some(capability) => self.negotiated_capabilities().contains(capability),
+                        None => true,
+                    }
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Reject windows created or configured by the agent with a size outside
+    /// of the negotiated [`qubes_gui::WindowLimits`].
+    fn check_window_size(&mut self, header: Header) -> io::Result<()> {
+        let size = match (self.kind, header.ty()) {
+            (Kind::Daemon, qubes_gui::MSG_CREATE) => {
+                qubes_gui::Create::from_bytes(&self.buffer).rectangle.size
+            }
+            (Kind::Daemon, qubes_gui::MSG_CONFIGURE) => {
+                qubes_gui::Configure::from_bytes(&self.buffer)
+                    .rectangle
+                    .size
+            }
+            _ => return Ok(()),
+        };
+        if self.xconf.xconf.window_limits().allows(size) {
+            Ok(())
+        } else {
+            let e = Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Window size {}x{} in {} exceeds limits",
+                    size.width,
+                    size.height,
+                    qubes_gui::MsgType(header.ty()),
+                ),
+            );
+            Err(self.protocol_error(e))
+        }
+    }
+
+    /// See [`protocol_error`].
+    fn protocol_error(&mut self, e: Error) -> Error {
+        protocol_error(&mut self.metrics, &mut self.state, e)
+    }
+
+    /// The error returned when the peer sends version `peer`, which is not
+    /// supported
+    fn version_mismatch(&self, peer: u32) -> Error {
+        Error::new(
+            ErrorKind::InvalidData,
+            VersionMismatch {
+                peer,
+                min: self.min_version,
+                ours: self.version,
+            },
+        )
+    }
+
+    /// See [`Connection::handshake_state`].
+    fn handshake_state(&self) -> HandshakeState {
+        match self.state {
+            ReadState::Connecting => HandshakeState::Connecting,
+            ReadState::Negotiating => HandshakeState::Negotiating,
+            ReadState::NegotiatingCapabilities => HandshakeState::NegotiatingCapabilities,
+            ReadState::Error(_) => HandshakeState::Failed,
+            ReadState::ReadingHeader
+            | ReadState::ReadingBody { .. }
+            | ReadState::Discard(_)
+            | ReadState::StreamingBody(_) => HandshakeState::Done,
+        }
+    }
+
+    /// Returns the negotiated protocol version, if version negotiation has
+    /// completed
+    fn negotiated_version(&self) -> Option<u32> {
+        match self.handshake_state() {
+            HandshakeState::Done => Some(self.xconf.version),
+            _ => None,
+        }
+    }
+
+    /// Capabilities supported by both sides
+    fn negotiated_capabilities(&self) -> qubes_gui::Capabilities {
+        self.capabilities.intersection(self.peer_capabilities)
     }
 }
 
-impl RawMessageStream<Option<Vchan>> {
-    pub fn agent(domain: u16) -> io::Result<Self> {
-        let vchan = Vchan::server(domain, qubes_gui::LISTENING_PORT.into(), 4096, 4096)?;
-        Ok(Self {
-            vchan: Some(vchan),
+/// Records a protocol violation by the peer in `metrics`, and makes it
+/// terminal by setting `state`.  Returns `e`.
+fn protocol_error(metrics: &mut Metrics, state: &mut ReadState, e: Error) -> Error {
+    metrics.protocol_errors += 1;
+    *state = ReadState::Error(ReadErrorKind::Protocol);
+    e
+}
+
+/// Records a message that could not be parsed as a protocol violation, and
+/// converts the error
+fn parse_error(
+    metrics: &mut Metrics,
+    state: &mut ReadState,
+    e: qubes_gui_agent_proto::Error,
+) -> Error {
+    protocol_error(
+        metrics,
+        state,
+        Error::new(ErrorKind::InvalidData, e.to_string()),
+    )
+}
+
+/// The configuration of the vchan an agent listens on
+fn agent_vchan_config(domain: u16) -> vchan::VchanBuilder {
+    *Vchan::builder()
+        .domain(domain)
+        .port(qubes_gui::LISTENING_PORT.into())
+        .read_min(4096)
+        .write_min(4096)
+}
+
+impl<T: Transport + 'static> RawMessageStream<T> {
+    fn agent_with_transport(transport: T) -> Self {
+        Self {
+            vchan: BufVchan::new(transport),
             queue: Default::default(),
             state: ReadState::Connecting,
             buffer: vec![],
+            spare: vec![],
             did_reconnect: false,
-            domid: domain,
             kind: Kind::Agent,
             xconf: Default::default(),
-        })
+            version: qubes_gui::PROTOCOL_VERSION,
+            min_version: MIN_AGENT_VERSION,
+            capabilities: qubes_gui::Capabilities::ALL,
+            peer_capabilities: qubes_gui::Capabilities::EMPTY,
+            hook: None,
+            coalescer: None,
+            metrics: Default::default(),
+        }
     }
 
-    pub fn daemon(domain: u16, xconf: qubes_gui::XConf) -> io::Result<Self> {
-        Ok(Self {
-            vchan: Some(Vchan::client(domain, qubes_gui::LISTENING_PORT.into())?),
+    fn daemon_with_transport(transport: T, xconf: qubes_gui::XConf) -> Self {
+        Self {
+            vchan: BufVchan::new(transport),
             queue: Default::default(),
-            state: ReadState::ReadingHeader,
+            state: ReadState::Negotiating,
             buffer: vec![],
+            spare: vec![],
             did_reconnect: false,
-            domid: domain,
             kind: Kind::Daemon,
             xconf: qubes_gui::XConfVersion {
                 version: qubes_gui::PROTOCOL_VERSION,
                 xconf,
             },
-        })
+            version: qubes_gui::PROTOCOL_VERSION,
+            min_version: qubes_gui::PROTOCOL_VERSION_MAJOR << 16,
+            capabilities: qubes_gui::Capabilities::ALL,
+            peer_capabilities: qubes_gui::Capabilities::EMPTY,
+            hook: None,
+            coalescer: None,
+            metrics: Default::default(),
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> std::os::raw::c_int {
+        self.vchan.fd()
+    }
+}
+
+impl RawMessageStream<Endpoint> {
+    pub fn agent(domain: u16) -> io::Result<Self> {
+        let vchan = ServerVchan::new(agent_vchan_config(domain))?;
+        Ok(Self::agent_with_transport(Endpoint::Server(vchan)))
+    }
+
+    pub fn daemon(domain: u16, xconf: qubes_gui::XConf) -> io::Result<Self> {
+        let vchan = Vchan::client(domain, qubes_gui::LISTENING_PORT.into())?;
+        Ok(Self::daemon_with_transport(Endpoint::Client(vchan), xconf))
     }
 
     pub fn reconnect(&mut self) -> Result<(), vchan::Error> {
-        self.vchan = None;
-        self.vchan = Some(Vchan::server(
-            self.domid,
-            qubes_gui::LISTENING_PORT.into(),
-            4096,
-            4096,
-        )?);
+        match self.vchan.get_mut() {
+            Endpoint::Server(server) => server.relisten()?,
+            Endpoint::Client(_) => {
+                return Err(vchan::Error::CannotListen(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only agents can listen for a new connection",
+                )))
+            }
+        }
+        self.vchan.clear();
         self.queue.clear();
+        if let Some(coalescer) = &mut self.coalescer {
+            coalescer.clear()
+        }
         self.buffer.clear();
+        self.peer_capabilities = qubes_gui::Capabilities::EMPTY;
         self.state = ReadState::Connecting;
         Ok(())
     }
-
-    pub fn as_raw_fd(&self) -> std::os::raw::c_int {
-        self.vchan.as_ref().unwrap().fd()
-    }
 }
 /// The entry-point to the library.
+///
+/// The transport defaults to a vchan.  Tests can use any other
+/// [`Transport`], such as one end of a [`vchan::SocketTransport::pair`] or a
+/// [`vchan::ReplayVchan`] that replays a recorded session, to run an agent or
+/// daemon against a scripted peer without Xen.
 #[derive(Debug)]
-pub struct Connection {
-    raw: RawMessageStream<Option<vchan::Vchan>>,
+pub struct Connection<V: Transport = Endpoint> {
+    raw: RawMessageStream<V>,
+    window_ids: WindowIdAllocator,
+    high_water_mark: Option<HighWaterMark>,
+    queue_limit: Option<QueueLimit>,
+    /// Whether the connection is shared by a [`Reader`] and a [`Writer`]
+    split: bool,
+    close_behavior: CloseBehavior,
+    /// Domain ID of the peer, if known
+    peer_domid: Option<u16>,
 }
 
-impl Connection {
+/// Blocking iterator over the messages received on a [`Connection`].  See
+/// [`Connection::incoming`].
+#[derive(Debug)]
+pub struct Incoming<'a, V: Transport = Endpoint> {
+    /// [`None`] once the iterator has ended
+    connection: Option<&'a mut Connection<V>>,
+}
+
+impl<V: Transport + 'static> Iterator for Incoming<'_, V> {
+    type Item = io::Result<(Header, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.connection.as_mut()?.recv_message();
+        if res.is_err() {
+            self.connection = None
+        }
+        match res {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
+            res => Some(res),
+        }
+    }
+}
+
+impl<V: Transport + 'static> std::iter::FusedIterator for Incoming<'_, V> {}
+
+/// What [`Connection::handle_close`] does when the daemon asks for a window
+/// to be closed
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CloseBehavior {
+    /// Destroy the window and release its ID
+    Destroy,
+    /// Unmap the window, so that it can be mapped again later
+    Unmap,
+    /// Do nothing, leaving the caller to ask the application.  This is the
+    /// default.
+    #[default]
+    Notify,
+}
+
+/// See [`Connection::set_high_water_mark`]
+struct HighWaterMark {
+    /// Number of queued bytes above which the callback is called
+    bytes: usize,
+    callback: Box<dyn FnMut(usize) + Send>,
+    /// Whether the queue was above the mark when last checked
+    above: bool,
+}
+
+impl std::fmt::Debug for HighWaterMark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HighWaterMark")
+            .field("bytes", &self.bytes)
+            .field("above", &self.above)
+            .finish()
+    }
+}
+
+impl<V: Transport + 'static> Connection<V> {
+    /// Creates an agent instance that uses `transport`, which must already be
+    /// connected
+    pub fn agent_with_transport(transport: V) -> Self {
+        Self::from_raw(RawMessageStream::agent_with_transport(transport))
+    }
+
+    /// Creates a daemon instance that uses `transport`, which must already
+    /// be connected
+    pub fn daemon_with_transport(transport: V, xconf: qubes_gui::XConf) -> Self {
+        Self::from_raw(RawMessageStream::daemon_with_transport(transport, xconf))
+    }
+
+    fn from_raw(raw: RawMessageStream<V>) -> Self {
+        Self {
+            raw,
+            window_ids: Default::default(),
+            high_water_mark: None,
+            queue_limit: None,
+            split: false,
+            close_behavior: Default::default(),
+            peer_domid: None,
+        }
+    }
+
     /// Send a GUI message.  This never blocks; outgoing messages are queued
-    /// until there is space in the vchan.
+    /// until there is space in the vchan.  Fails with an error of kind
+    /// [`ErrorKind::NotConnected`] until the handshake has completed.
     pub fn send<T: qubes_gui::Message>(
         &mut self,
         message: &T,
         window: qubes_gui::WindowID,
     ) -> io::Result<()> {
-        self.send_raw(message.as_bytes(), window, T::KIND as _)
+        self.send_framed(&qubes_gui::FramedMessage::new(*message, window))
+    }
+
+    /// Send a message that has already been framed.  This never blocks;
+    /// outgoing messages are queued until there is space in the vchan.
+    pub fn send_framed<T: qubes_gui::Message>(
+        &mut self,
+        message: &qubes_gui::FramedMessage<T>,
+    ) -> io::Result<()> {
+        self.send_parts(message.header(), &message.parts()[1..])
+    }
+
+    /// Agent only: create a window with an ID from
+    /// [`Connection::window_ids`], and return the ID.
+    ///
+    /// # Errors
+    ///
+    /// Fails if every ID is in use, or if sending fails.
+    pub fn create_window(&mut self, create: &qubes_gui::Create) -> io::Result<qubes_gui::WindowID> {
+        let window = self
+            .window_ids
+            .allocate()
+            .ok_or_else(|| Error::other("no window IDs left"))?;
+        let res = self.send(create, window);
+        if res.is_err() {
+            self.window_ids.release(window);
+        }
+        res.map(|()| window)
+    }
+
+    /// Agent only: destroy a window created by [`Connection::create_window`],
+    /// and release its ID.  The ID is not reused for some time, so that late
+    /// messages from the daemon about this window are not taken to refer to
+    /// its successor.
+    pub fn destroy_window(&mut self, window: qubes_gui::WindowID) -> io::Result<()> {
+        self.window_ids.release(window);
+        self.send(&qubes_gui::Destroy {}, window)
+    }
+
+    /// Agent only: set what [`Connection::handle_close`] does.
+    pub fn set_close_behavior(&mut self, behavior: CloseBehavior) {
+        self.close_behavior = behavior
+    }
+
+    /// Get what [`Connection::handle_close`] does.
+    pub fn close_behavior(&self) -> CloseBehavior {
+        self.close_behavior
+    }
+
+    /// Agent only: respond to a [`qubes_gui::MSG_CLOSE`] for `window` as
+    /// [`Connection::set_close_behavior`] selected.  Returns the response, or
+    /// `None` if `window` was not created by [`Connection::create_window`] or
+    /// has since been destroyed, in which case the request is ignored.
+    ///
+    /// # Errors
+    ///
+    /// Fails if sending fails.
+    pub fn handle_close(
+        &mut self,
+        window: qubes_gui::WindowID,
+    ) -> io::Result<Option<CloseBehavior>> {
+        if !self.window_ids.is_allocated(window) {
+            return Ok(None);
+        }
+        match self.close_behavior {
+            CloseBehavior::Destroy => self.destroy_window(window)?,
+            CloseBehavior::Unmap => self.send(&qubes_gui::Unmap {}, window)?,
+            CloseBehavior::Notify => {}
+        }
+        Ok(Some(self.close_behavior))
+    }
+
+    /// Call `hook` with the header of every message sent or received, for
+    /// tracing or debugging.  Message bodies are never passed to it, as they
+    /// must not be logged.  Bytes sent with [`Connection::send_raw_bytes`]
+    /// are not reported, as they are not parsed.
+    pub fn set_message_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(MessageDirection, Header) + Send + 'static,
+    {
+        self.raw.hook = Some(MessageHook(Box::new(hook)))
+    }
+
+    /// Remove the function set by [`Connection::set_message_hook`].
+    pub fn clear_message_hook(&mut self) {
+        self.raw.hook = None
+    }
+
+    /// The allocator used by [`Connection::create_window`].  Agents that
+    /// choose some window IDs themselves can use this to avoid collisions.
+    pub fn window_ids(&mut self) -> &mut WindowIdAllocator {
+        &mut self.window_ids
+    }
+
+    /// Send a GUI message that is followed by variable-length data, such as
+    /// [`qubes_gui::WindowDumpHeader`].
+    /// This never blocks; outgoing messages are queued until there is space
+    /// in the vchan.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the total length is not valid for messages of type `T`.
+    pub fn send_with_data<T: qubes_gui::Message>(
+        &mut self,
+        message: &T,
+        data: &[u8],
+        window: qubes_gui::WindowID,
+    ) -> io::Result<()> {
+        let header = qubes_gui::Header::for_message::<T>(window, size_of::<T>() + data.len())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}", e)))?;
+        self.send_parts(header, &[message.as_bytes(), data])
+    }
+
+    /// Send clipboard data with the given MIME type.  This requires protocol
+    /// version 1.9 or later.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `mime_type` is not a valid MIME type, or if the peer does not
+    /// support clipboard data with MIME types.
+    #[cfg(feature = "extensions")]
+    pub fn send_clipboard_mime_data(&mut self, mime_type: &str, data: &[u8]) -> io::Result<()> {
+        let header = qubes_gui::ClipboardMimeHeader::new(mime_type).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid MIME type {:?}", mime_type),
+            )
+        })?;
+        self.send_with_data(&header, data, 0.into())
+    }
+
+    /// Set the icon of `window` to the given ARGB pixels, in the format
+    /// described by [`qubes_gui::WindowIconHeader`].  This requires protocol
+    /// version 1.10 or later.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the size is invalid or does not match the number of pixels,
+    /// or if the peer does not support window icons.
+    #[cfg(feature = "extensions")]
+    pub fn send_window_icon(
+        &mut self,
+        window: qubes_gui::WindowID,
+        size: qubes_gui::WindowSize,
+        pixels: &[u32],
+    ) -> io::Result<()> {
+        let header = qubes_gui::WindowIconHeader { size };
+        let pixels = qubes_castable::as_bytes(pixels);
+        if header.data_len() != Some(pixels.len()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Bad icon: {} bytes of pixels for {}x{} icon",
+                    pixels.len(),
+                    size.width,
+                    size.height,
+                ),
+            ));
+        }
+        self.send_with_data(&header, pixels, window)
+    }
+
+    /// Redraw the given rectangles of `window` from shared memory.  If the
+    /// `extensions` feature is enabled and the peer supports `MSG_DAMAGE`, the
+    /// rectangles are sent in as few messages as possible; otherwise, one
+    /// [`qubes_gui::ShmImage`] message is sent per rectangle.
+    pub fn send_damage(
+        &mut self,
+        window: qubes_gui::WindowID,
+        rectangles: &[qubes_gui::Rectangle],
+    ) -> io::Result<()> {
+        #[cfg(feature = "extensions")]
+        if self.raw.peer_supports(qubes_gui::MSG_DAMAGE) {
+            for chunk in rectangles.chunks(qubes_gui::MAX_DAMAGE_RECTS as usize) {
+                let header = qubes_gui::DamageHeader {
+                    count: chunk.len() as u32,
+                };
+                self.send_with_data(&header, qubes_castable::as_bytes(chunk), window)?
+            }
+            return Ok(());
+        }
+        for &rectangle in rectangles {
+            self.send(&qubes_gui::ShmImage { rectangle }, window)?
+        }
+        Ok(())
     }
 
     /// Raw version of [`Connection::send`].  Using [`Connection::send`] is preferred
@@ -444,23 +1416,48 @@ impl Connection {
         window: qubes_gui::WindowID,
         ty: u32,
     ) -> io::Result<()> {
-        let untrusted_len = message
-            .len()
-            .try_into()
-            .expect("Message length must fit in a u32");
         let header = qubes_gui::UntrustedHeader {
             ty,
             window,
-            untrusted_len,
+            untrusted_len: message
+                .len()
+                .try_into()
+                .expect("Message length must fit in a u32"),
         };
-        header
+        let header = header
             .validate_length()
             .unwrap()
             .expect("Sending unknown message!");
-        // FIXME this is slow
-        self.raw.write(header.as_bytes())?;
-        self.raw.write(message)?;
-        Ok(())
+        self.send_parts(header, &[message])
+    }
+
+    fn send_parts(&mut self, header: Header, parts: &[&[u8]]) -> io::Result<()> {
+        self.check_handshake_done()?;
+        if !self.raw.peer_supports(header.ty()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Peer does not support messages of type {}",
+                    qubes_gui::MsgType(header.ty())
+                ),
+            ));
+        }
+        // The coalescer relies on the count in the header
+        #[cfg(feature = "extensions")]
+        if header.ty() == qubes_gui::MSG_DAMAGE
+            && qubes_gui::DamageList::parse(&parts.concat()).is_none()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Bad rectangle count in MSG_DAMAGE",
+            ));
+        }
+        // The queue may have drained while reading
+        self.check_high_water_mark();
+        self.make_room(size_of::<UntrustedHeader>() + header.len())?;
+        let res = self.raw.write_message(header, parts);
+        self.check_high_water_mark();
+        res.map_err(From::from)
     }
 
     /// Even rawer version of [`Connection::send`].  Using [`Connection::send`] is
@@ -468,7 +1465,209 @@ impl Connection {
     /// message type.  Otherwise, prefer [`Connection::send_raw`], which at least
     /// ensures correct framing.
     pub fn send_raw_bytes(&mut self, msg: &[u8]) -> io::Result<()> {
-        self.raw.write(msg).map_err(From::from)
+        self.check_handshake_done()?;
+        self.check_high_water_mark();
+        self.make_room(msg.len())?;
+        let res = self.raw.write(msg);
+        self.check_high_water_mark();
+        res.map_err(From::from)
+    }
+
+    /// Fails unless messages can be sent
+    fn check_handshake_done(&self) -> io::Result<()> {
+        match self.raw.handshake_state() {
+            HandshakeState::Done => Ok(()),
+            HandshakeState::Failed => Err(Error::new(
+                ErrorKind::NotConnected,
+                "connection failed, and must be reconnected",
+            )),
+            HandshakeState::Connecting
+            | HandshakeState::Negotiating
+            | HandshakeState::NegotiatingCapabilities => Err(Error::new(
+                ErrorKind::NotConnected,
+                "handshake has not completed",
+            )),
+        }
+    }
+
+    /// Get the progress of the handshake.  Messages can only be sent once it
+    /// is [`HandshakeState::Done`].
+    pub fn handshake_state(&self) -> HandshakeState {
+        self.raw.handshake_state()
+    }
+
+    /// Make as much progress on the handshake as possible without blocking,
+    /// and without reading any messages.  Returns `Poll::Ready(Ok(()))` once
+    /// the handshake has completed, and `Poll::Pending` if the peer has not
+    /// yet sent what is needed.  This lets one event loop drive many
+    /// connections, handling messages only on those that are ready.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the handshake fails, such as with a [`VersionMismatch`], or
+    /// if the connection had already failed.
+    pub fn poll_handshake(&mut self) -> Poll<io::Result<()>> {
+        match self.raw.poll_handshake() {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// The number of bytes queued because the vchan was full.  An agent can
+    /// use this to throttle rendering when the daemon stops reading.
+    pub fn queued_bytes(&self) -> usize {
+        self.raw.queue.len()
+    }
+
+    /// Write as much of the queued data as possible without blocking.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let res = self.raw.flush_pending_writes();
+        self.check_high_water_mark();
+        res.map(drop).map_err(From::from)
+    }
+
+    /// Enable or disable coalescing of queued messages.  While it is enabled,
+    /// a message that is still wholly queued is dropped when a newer one for
+    /// the same window makes it stale, which bounds the growth of the queue
+    /// while the peer is slow:
+    ///
+    /// - A [`qubes_gui::Configure`] replaces a queued one.
+    /// - A [`qubes_gui::ShmImage`] is merged with a queued one whose
+    ///   rectangle overlaps its own, and covers both rectangles.
+    ///
+    /// Only the last queued message for the window is replaced, so the order
+    /// of the messages for each window is kept.  Messages queued before
+    /// coalescing was enabled, and messages queued before raw bytes sent with
+    /// [`Connection::send_raw_bytes`], are never replaced.  Disabled by
+    /// default.
+    pub fn set_coalescing(&mut self, enabled: bool) {
+        self.update_coalescer(enabled)
+    }
+
+    /// Track queued messages if coalescing is enabled, or if the overflow
+    /// policy may drop them
+    fn update_coalescer(&mut self, merge: bool) {
+        let drops = matches!(
+            self.queue_limit,
+            Some(QueueLimit {
+                policy: OverflowPolicy::DropFrameUpdates,
+                ..
+            })
+        );
+        match &mut self.raw.coalescer {
+            _ if !merge && !drops => self.raw.coalescer = None,
+            Some(coalescer) => coalescer.merge = merge,
+            None => self.raw.coalescer = Some(coalesce::Coalescer::new(merge)),
+        }
+    }
+
+    fn is_coalescing(&self) -> bool {
+        self.raw.coalescer.as_ref().is_some_and(|c| c.merge)
+    }
+
+    /// Limit the write queue to `bytes` bytes, applying `policy` to messages
+    /// that would exceed it.  A daemon should set a limit, as otherwise an
+    /// agent that stops reading can make it allocate without bound.  A
+    /// message that fits in the vchan is never refused, even if it is larger
+    /// than the limit.  There is no limit by default.
+    pub fn set_queue_limit(&mut self, bytes: usize, policy: OverflowPolicy) {
+        self.queue_limit = Some(QueueLimit { bytes, policy });
+        self.update_coalescer(self.is_coalescing())
+    }
+
+    /// Remove the limit set by [`Connection::set_queue_limit`].
+    pub fn clear_queue_limit(&mut self) {
+        self.queue_limit = None;
+        self.update_coalescer(self.is_coalescing())
+    }
+
+    /// Make room in the write queue for `len` more bytes, as the policy of
+    /// the queue limit says
+    fn make_room(&mut self, len: usize) -> io::Result<()> {
+        let limit = match self.queue_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        loop {
+            self.raw.flush_pending_writes()?;
+            let queued = self.raw.queue.len();
+            let after = if queued == 0 {
+                len.saturating_sub(self.raw.vchan.buffer_space())
+            } else {
+                queued + len
+            };
+            let excess = match after.checked_sub(limit.bytes) {
+                None | Some(0) => return Ok(()),
+                Some(excess) => excess,
+            };
+            let full = QueueFull {
+                queued,
+                len,
+                limit: limit.bytes,
+            };
+            match limit.policy {
+                OverflowPolicy::Error => return Err(full.into()),
+                OverflowPolicy::DropFrameUpdates => {
+                    let coalescer = self
+                        .raw
+                        .coalescer
+                        .as_mut()
+                        .expect("set_queue_limit() creates the coalescer");
+                    // Dropping frames that would not make enough room
+                    // would lose them for nothing.
+                    if coalescer.frame_update_bytes(&self.raw.queue) < excess {
+                        return Err(full.into());
+                    }
+                    let removed = coalescer.drop_frame_updates(&mut self.raw.queue, excess);
+                    self.raw.metrics.dropped += removed.messages;
+                    self.raw.metrics.bytes_sent -= removed.bytes as u64;
+                    return Ok(());
+                }
+                // Waiting cannot help if nothing is queued, or if the peer
+                // has gone away.  A split connection cannot wait, as the
+                // Reader could not drain incoming data in the meantime.
+                OverflowPolicy::Block if queued == 0 || self.split => return Err(full.into()),
+                OverflowPolicy::Block if self.raw.needs_reconnect() => {
+                    return Err(Error::new(
+                        ErrorKind::NotConnected,
+                        "peer disconnected while the write queue was full",
+                    ))
+                }
+                OverflowPolicy::Block => self.raw.vchan.wait(),
+            }
+        }
+    }
+
+    /// Call `callback` with the number of queued bytes whenever that number
+    /// rises above `bytes`.  It is called again only after the queue has
+    /// drained to `bytes` or less.
+    pub fn set_high_water_mark<F>(&mut self, bytes: usize, callback: F)
+    where
+        F: FnMut(usize) + Send + 'static,
+    {
+        self.high_water_mark = Some(HighWaterMark {
+            bytes,
+            callback: Box::new(callback),
+            above: false,
+        });
+        self.check_high_water_mark()
+    }
+
+    /// Remove the callback set by [`Connection::set_high_water_mark`].
+    pub fn clear_high_water_mark(&mut self) {
+        self.high_water_mark = None
+    }
+
+    fn check_high_water_mark(&mut self) {
+        let queued = self.raw.queue.len();
+        if let Some(mark) = &mut self.high_water_mark {
+            let above = queued > mark.bytes;
+            if above && !mark.above {
+                (mark.callback)(queued)
+            }
+            mark.above = above;
+        }
     }
 
     /// Acknowledge an event (as reported by poll(2), epoll(2), or similar).
@@ -489,24 +1688,110 @@ impl Connection {
         }
     }
 
-    /// Creates a daemon instance
-    pub fn daemon(domain: u16, xconf: qubes_gui::XConf) -> io::Result<Self> {
-        Ok(Self {
-            raw: RawMessageStream::daemon(domain, xconf)?,
-        })
+    /// Like [`Connection::read_message`], but returns the bodies of
+    /// variable-length messages, such as clipboard data, as they arrive
+    /// instead of buffering them.  The caller can then enforce its own size
+    /// limits, and large messages do not need large allocations.  Each call
+    /// returns at most as much of a body as the vchan holds.
+    ///
+    /// If [`Connection::read_message`] is called before the whole body has
+    /// been returned, the rest of the body is discarded.
+    pub fn read_body_chunked(&mut self) -> Poll<io::Result<Chunk<'_>>> {
+        match self.raw.read_body_chunked() {
+            Ok(None) => Poll::Pending,
+            Ok(Some(v)) => Poll::Ready(Ok(v)),
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 
-    /// Creates an agent instance
-    pub fn agent(domain: u16) -> io::Result<Self> {
-        Ok(Self {
-            raw: RawMessageStream::agent(domain)?,
-        })
+    /// Give back a buffer returned by [`Buffer::take`], so that its memory
+    /// can be reused.  Buffers that are too large to keep are freed.
+    pub fn recycle(&mut self, buffer: Vec<u8>) {
+        self.raw.recycle(buffer)
     }
 
-    /// Try to reconnect.  If this fails, the agent is no longer usable; future
-    /// operations may panic.
-    pub fn reconnect(&mut self) -> io::Result<()> {
-        self.raw.reconnect().map_err(From::from)
+    /// Block until a complete message has arrived, and return its header and
+    /// body.  Pass the body to [`Connection::recycle`] once done with it.
+    /// This is meant for simple tools, such as proxies and recorders, that
+    /// serve a single connection; anything else should use
+    /// [`Connection::read_message`] from an event loop.
+    ///
+    /// # Errors
+    ///
+    /// Fails as [`Connection::read_message`] does, and with an error of kind
+    /// [`ErrorKind::UnexpectedEof`] if the peer disconnects first.
+    pub fn recv_message(&mut self) -> io::Result<(Header, Vec<u8>)> {
+        loop {
+            if let Poll::Ready(res) = self.read_message() {
+                break res.map(|buffer| (buffer.hdr(), buffer.take()));
+            }
+            if let Status::Disconnected | Status::HalfClosed = self.status() {
+                break Err(Error::new(ErrorKind::UnexpectedEof, "peer disconnected"));
+            }
+            self.wait()
+        }
+    }
+
+    /// An iterator that calls [`Connection::recv_message`] for each message.
+    /// It ends when the peer disconnects, and after yielding an error.
+    pub fn incoming(&mut self) -> Incoming<'_, V> {
+        Incoming {
+            connection: Some(self),
+        }
+    }
+
+    /// Returns the next event sent by the peer, once a complete message has
+    /// been buffered.  Agents receive [`PeerEvent::FromDaemon`] and daemons
+    /// receive [`PeerEvent::FromAgent`].  Agents skip messages that only an
+    /// agent should send, but daemons reject messages that only a daemon
+    /// should send.  Proxies that pass messages through unchanged can use
+    /// [`Connection::read_message`] instead.
+    ///
+    /// # Errors
+    ///
+    /// Fails on I/O errors, as [`Connection::read_message`] does, or if the
+    /// peer sent an invalid message.  An invalid message is a protocol
+    /// violation, so the caller should disconnect.
+    pub fn read_event(&mut self) -> Poll<io::Result<(qubes_gui::WindowID, PeerEvent<'_>)>> {
+        let res =
+            match self.raw.kind {
+                Kind::Agent => self.raw.next_daemon_event().map(|event| {
+                    event.map(|(window, event)| (window, PeerEvent::FromDaemon(event)))
+                }),
+                Kind::Daemon => self.raw.next_agent_event().map(|event| {
+                    event.map(|(window, event)| (window, PeerEvent::FromAgent(event)))
+                }),
+            };
+        match res {
+            Ok(None) => Poll::Pending,
+            Ok(Some(v)) => Poll::Ready(Ok(v)),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Daemon only: returns the next event sent by the agent, once a complete
+    /// message has been buffered.  Every field is validated, and windows may
+    /// not be larger than [`Connection::window_limits`].
+    ///
+    /// # Errors
+    ///
+    /// Fails on I/O errors, as [`Connection::read_message`] does, if the
+    /// agent sent an invalid message or one that only a daemon may send, or
+    /// if this is an agent instance.  An invalid message is a protocol
+    /// violation, so the caller should disconnect.
+    pub fn next_agent_event(
+        &mut self,
+    ) -> Poll<
+        io::Result<(
+            qubes_gui::WindowID,
+            qubes_gui_agent_proto::daemon::AgentEvent<'_>,
+        )>,
+    > {
+        match self.raw.next_agent_event() {
+            Ok(None) => Poll::Pending,
+            Ok(Some(v)) => Poll::Ready(Ok(v)),
+            Err(e) => Poll::Ready(Err(e)),
+        }
     }
 
     /// Gets and clears the “did_reconnect” flag
@@ -514,18 +1799,217 @@ impl Connection {
         self.raw.reconnected()
     }
 
-    /// Returns true if a reconnection is needed.
+    /// Returns true if a reconnection is needed, because the peer
+    /// disconnected or the transport failed.
     pub fn needs_reconnect(&self) -> bool {
         self.raw.needs_reconnect()
     }
 
-    /// Get version information
+    /// Returns why reading failed, or [`None`] if it has not.  Once reading
+    /// has failed, every read fails until an agent reconnects, which is
+    /// only possible after a [`ReadErrorKind::Transport`] failure.
+    pub fn error_kind(&self) -> Option<ReadErrorKind> {
+        self.raw.error_kind()
+    }
+
+    /// Get the status of the underlying transport.
+    pub fn status(&self) -> Status {
+        self.raw.vchan.status()
+    }
+
+    /// Returns whether this is an agent or a daemon.
+    pub fn kind(&self) -> Kind {
+        self.raw.kind
+    }
+
+    /// Get the domain ID of the peer.  This is `None` for connections
+    /// created with [`Connection::agent_with_transport`] or
+    /// [`Connection::daemon_with_transport`], as a transport does not know
+    /// which domain it leads to.
+    pub fn peer_domid(&self) -> Option<u16> {
+        self.peer_domid
+    }
+
+    /// Get a snapshot of the counters of this connection
+    pub fn metrics(&self) -> Metrics {
+        self.raw.metrics.clone()
+    }
+
+    /// Get version information.  Once version negotiation has completed,
+    /// the version is the one both sides agreed on.
     pub fn xconf(&self) -> qubes_gui::XConfVersion {
         self.raw.xconf
     }
+
+    /// Get the protocol version advertised to the peer.
+    pub fn version(&self) -> u32 {
+        self.raw.version
+    }
+
+    /// Get the protocol version both sides agreed on, or `None` if version
+    /// negotiation has not completed.
+    pub fn negotiated_version(&self) -> Option<u32> {
+        self.raw.negotiated_version()
+    }
+
+    /// Get the oldest protocol version accepted from the peer.
+    pub fn min_version(&self) -> u32 {
+        self.raw.min_version
+    }
+
+    /// Set the oldest protocol version accepted from the peer.  If the peer
+    /// sends an older version, version negotiation fails with a
+    /// [`VersionMismatch`].  By default, daemons serve agents of any version
+    /// with the same major version, including those older than 1.4, and
+    /// agents accept daemons of version 1.4 or newer.  This takes effect at
+    /// the next handshake.
+    ///
+    /// # Errors
+    ///
+    /// Fails with an error of kind [`ErrorKind::InvalidInput`] if `version`
+    /// has a different major version, is newer than the version advertised to
+    /// the peer, or (for agents) is older than 1.4.
+    pub fn set_min_version(&mut self, version: u32) -> io::Result<()> {
+        let oldest = match self.raw.kind {
+            Kind::Agent => MIN_AGENT_VERSION,
+            Kind::Daemon => qubes_gui::PROTOCOL_VERSION_MAJOR << 16,
+        };
+        if version >> 16 == qubes_gui::PROTOCOL_VERSION_MAJOR
+            && (oldest..=self.raw.version).contains(&version)
+        {
+            self.raw.min_version = version;
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Cannot accept protocol versions from {}.{}",
+                    version >> 16,
+                    version & 0xFFFF
+                ),
+            ))
+        }
+    }
+
+    /// Set the protocol version advertised to the peer, for instance to test
+    /// how an agent behaves against a daemon that speaks an older version.
+    /// Like [`Connection::set_capabilities`], this takes effect at the next
+    /// handshake.
+    ///
+    /// # Errors
+    ///
+    /// Fails with an error of kind [`ErrorKind::InvalidInput`] if `version`
+    /// is newer than [`qubes_gui::PROTOCOL_VERSION`], has a different major
+    /// version, or is older than [`Connection::min_version`].  For agents,
+    /// that is never older than 1.4, which is the oldest version this library
+    /// can negotiate with a daemon.
+    pub fn set_version(&mut self, version: u32) -> io::Result<()> {
+        if version >> 16 == qubes_gui::PROTOCOL_VERSION_MAJOR
+            && (self.raw.min_version..=qubes_gui::PROTOCOL_VERSION).contains(&version)
+        {
+            self.raw.version = version;
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Cannot advertise protocol version {}.{}",
+                    version >> 16,
+                    version & 0xFFFF
+                ),
+            ))
+        }
+    }
+
+    /// Get the limits on window sizes, derived from the root window
+    /// configuration.  Only meaningful once version negotiation has
+    /// completed; before that, the absolute limits are returned.
+    pub fn window_limits(&self) -> qubes_gui::WindowLimits {
+        self.raw.xconf.xconf.window_limits()
+    }
+
+    /// Get the capabilities advertised by the peer.  For protocol versions
+    /// before 1.12, these are the capabilities implied by the peer’s version.
+    /// Only meaningful once version negotiation has completed.
+    pub fn peer_capabilities(&self) -> qubes_gui::Capabilities {
+        self.raw.peer_capabilities
+    }
+
+    /// Get the capabilities advertised to the peer.
+    pub fn capabilities(&self) -> qubes_gui::Capabilities {
+        self.raw.capabilities
+    }
+
+    /// Set the capabilities advertised to the peer.  Capabilities not known to
+    /// this library are ignored.  This takes effect at the next handshake, so
+    /// it should be called before the first call to
+    /// [`Connection::read_message`] or after [`Connection::reconnect`].
+    pub fn set_capabilities(&mut self, capabilities: qubes_gui::Capabilities) {
+        self.raw.capabilities = capabilities.intersection(qubes_gui::Capabilities::ALL)
+    }
+}
+
+impl Connection {
+    /// Creates a daemon instance
+    pub fn daemon(domain: u16, xconf: qubes_gui::XConf) -> io::Result<Self> {
+        let mut daemon = Self::from_raw(RawMessageStream::daemon(domain, xconf)?);
+        daemon.peer_domid = Some(domain);
+        Ok(daemon)
+    }
+
+    /// Creates an agent instance
+    pub fn agent(domain: u16) -> io::Result<Self> {
+        let mut agent = Self::from_raw(RawMessageStream::agent(domain)?);
+        agent.peer_domid = Some(domain);
+        Ok(agent)
+    }
+
+    /// Creates an agent instance that advertises protocol version `version`
+    /// instead of [`qubes_gui::PROTOCOL_VERSION`].  See
+    /// [`Connection::set_version`].
+    pub fn agent_with_version(domain: u16, version: u32) -> io::Result<Self> {
+        let mut agent = Self::agent(domain)?;
+        agent.set_version(version)?;
+        Ok(agent)
+    }
+
+    /// Try to reconnect.  If this fails, the agent is no longer usable; future
+    /// operations may panic.  Fails without reconnecting after a
+    /// [`ReadErrorKind::Protocol`] failure.
+    pub fn reconnect(&mut self) -> io::Result<()> {
+        if self.error_kind() == Some(ReadErrorKind::Protocol) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cannot reconnect after a protocol violation",
+            ));
+        }
+        self.raw.reconnect()?;
+        self.raw.metrics.reconnects += 1;
+        // No windows survive a reconnection.
+        self.window_ids = Default::default();
+        Ok(())
+    }
+
+    /// Write all of the queued data, blocking for at most `timeout`.  Fails
+    /// with an error of kind [`ErrorKind::TimedOut`] if some data is still
+    /// queued when `timeout` expires.
+    pub fn flush_blocking(&mut self, timeout: std::time::Duration) -> io::Result<()> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            self.flush()?;
+            if self.raw.queue.is_empty() {
+                break Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining == std::time::Duration::from_secs(0) {
+                break Err(vchan::Error::TimedOut.into());
+            }
+            self.raw.vchan.get_ref().wait_timeout(remaining)?
+        }
+    }
 }
 
-impl std::os::unix::io::AsRawFd for Connection {
+impl<V: Transport + 'static> std::os::unix::io::AsRawFd for Connection<V> {
     fn as_raw_fd(&self) -> std::os::raw::c_int {
         self.raw.as_raw_fd()
     }
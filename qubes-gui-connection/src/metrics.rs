@@ -0,0 +1,77 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Counters for monitoring a connection
+
+use std::collections::BTreeMap;
+
+/// A snapshot of the counters of a connection.  See [`crate::Connection::metrics`].
+///
+/// Counts start at zero when the connection is created, and are kept across
+/// reconnections.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Metrics {
+    /// Messages sent, by type
+    pub sent: BTreeMap<u32, u64>,
+    /// Messages received, by type.  Messages of unknown type, which are
+    /// discarded, are not included.
+    pub received: BTreeMap<u32, u64>,
+    /// Bytes sent, including headers and raw bytes.  Messages dropped by
+    /// coalescing or because the queue was full are not included.
+    pub bytes_sent: u64,
+    /// Bytes of the messages in [`Metrics::received`], including headers
+    pub bytes_received: u64,
+    /// The largest number of bytes ever queued because the vchan was full
+    pub max_queued_bytes: usize,
+    /// Queued messages that were dropped, because a newer message replaced
+    /// them.  See [`crate::Connection::set_coalescing`].
+    pub coalesced: u64,
+    /// Queued frame updates that were dropped, because the write queue was
+    /// full.  See [`crate::OverflowPolicy::DropFrameUpdates`].
+    pub dropped: u64,
+    /// Successful reconnections
+    pub reconnects: u64,
+    /// Messages that were rejected as invalid
+    pub protocol_errors: u64,
+}
+
+impl Metrics {
+    /// The total number of messages sent
+    pub fn messages_sent(&self) -> u64 {
+        self.sent.values().sum()
+    }
+
+    /// The total number of messages received
+    pub fn messages_received(&self) -> u64 {
+        self.received.values().sum()
+    }
+
+    pub(crate) fn count_sent(&mut self, header: qubes_gui::Header) {
+        *self.sent.entry(header.ty()).or_default() += 1;
+    }
+
+    pub(crate) fn count_received(&mut self, header: qubes_gui::Header) {
+        *self.received.entry(header.ty()).or_default() += 1;
+        self.bytes_received +=
+            (std::mem::size_of::<qubes_gui::UntrustedHeader>() + header.len()) as u64;
+    }
+}
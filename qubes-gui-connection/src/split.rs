@@ -0,0 +1,216 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Independent reading and writing halves of a [`Connection`]
+
+use super::{Connection, Endpoint, Kind, PeerEvent};
+use qubes_gui::Header;
+use qubes_gui_agent_proto::OwnedEvent;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::Poll;
+use vchan::Transport;
+
+/// The reading half of a [`Connection`].  See [`Connection::split`].
+#[derive(Debug)]
+pub struct Reader<V: Transport = Endpoint> {
+    inner: Arc<Mutex<Connection<V>>>,
+}
+
+/// The writing half of a [`Connection`].  See [`Connection::split`].
+#[derive(Debug)]
+pub struct Writer<V: Transport = Endpoint> {
+    inner: Arc<Mutex<Connection<V>>>,
+}
+
+fn lock<V: Transport>(inner: &Mutex<Connection<V>>) -> MutexGuard<'_, Connection<V>> {
+    inner
+        .lock()
+        .expect("a thread panicked while using the connection")
+}
+
+impl<V: Transport + 'static> Connection<V> {
+    /// Split the connection into a [`Reader`] and a [`Writer`], which can be
+    /// used from different threads.  Use [`Reader::unsplit`] to get the
+    /// connection back, for instance to reconnect.
+    ///
+    /// The halves share the connection through a lock, which each operation
+    /// holds only briefly.  Nothing here blocks except
+    /// [`Reader::wait`] and [`Writer::flush_blocking`]: sending with a
+    /// [`OverflowPolicy::Block`](crate::OverflowPolicy::Block) queue limit
+    /// fails with [`QueueFull`](crate::QueueFull) instead of blocking.
+    pub fn split(mut self) -> (Reader<V>, Writer<V>) {
+        self.split = true;
+        let inner = Arc::new(Mutex::new(self));
+        (
+            Reader {
+                inner: inner.clone(),
+            },
+            Writer { inner },
+        )
+    }
+}
+
+impl<V: Transport + 'static> Reader<V> {
+    /// See [`Connection::read_message`].  The body is returned by value, as
+    /// the connection cannot stay locked while it is borrowed.  Pass it to
+    /// [`Reader::recycle`] once done with it.
+    pub fn read_message(&mut self) -> Poll<io::Result<(Header, Vec<u8>)>> {
+        lock(&self.inner)
+            .read_message()
+            .map(|res| res.map(|buffer| (buffer.hdr(), buffer.take())))
+    }
+
+    /// Agent only: see [`Connection::read_event`].  The event is returned as
+    /// an [`OwnedEvent`], as the connection cannot stay locked while it is
+    /// borrowed.
+    ///
+    /// # Errors
+    ///
+    /// Fails as [`Connection::read_event`] does.  Also fails with an error
+    /// of kind [`io::ErrorKind::Unsupported`] for a daemon instance, as the
+    /// events a daemon receives have no owned form; use
+    /// [`Reader::read_message`] instead.
+    pub fn read_event(&mut self) -> Poll<io::Result<(qubes_gui::WindowID, OwnedEvent)>> {
+        let mut connection = lock(&self.inner);
+        if connection.kind() == Kind::Daemon {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "owned events are only available to agents",
+            )));
+        }
+        connection.read_event().map(|res| {
+            res.map(|(window, event)| match event {
+                PeerEvent::FromDaemon(event) => (window, event.into_owned()),
+                PeerEvent::FromAgent(_) => unreachable!("agents receive events from the daemon"),
+            })
+        })
+    }
+
+    /// See [`Connection::recycle`].
+    pub fn recycle(&mut self, buffer: Vec<u8>) {
+        lock(&self.inner).recycle(buffer)
+    }
+
+    /// See [`Connection::wait`].  This holds the lock, blocking the
+    /// [`Writer`], until an event arrives, so only call it once poll(2) or
+    /// similar has reported the file descriptor readable.
+    pub fn wait(&mut self) {
+        lock(&self.inner).wait()
+    }
+
+    /// See [`Connection::needs_reconnect`].
+    pub fn needs_reconnect(&self) -> bool {
+        lock(&self.inner).needs_reconnect()
+    }
+
+    /// Put the connection back together.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `writer` did not come from the same call to
+    /// [`Connection::split`].
+    pub fn unsplit(self, writer: Writer<V>) -> Connection<V> {
+        assert!(
+            Arc::ptr_eq(&self.inner, &writer.inner),
+            "unsplitting halves of different connections"
+        );
+        drop(writer);
+        let mut connection = match Arc::try_unwrap(self.inner) {
+            Ok(inner) => inner
+                .into_inner()
+                .expect("a thread panicked while using the connection"),
+            Err(_) => unreachable!("both halves have been consumed"),
+        };
+        connection.split = false;
+        connection
+    }
+}
+
+impl<V: Transport + 'static> Writer<V> {
+    /// See [`Connection::send`].
+    pub fn send<T: qubes_gui::Message>(
+        &mut self,
+        message: &T,
+        window: qubes_gui::WindowID,
+    ) -> io::Result<()> {
+        lock(&self.inner).send(message, window)
+    }
+
+    /// See [`Connection::send_with_data`].
+    pub fn send_with_data<T: qubes_gui::Message>(
+        &mut self,
+        message: &T,
+        data: &[u8],
+        window: qubes_gui::WindowID,
+    ) -> io::Result<()> {
+        lock(&self.inner).send_with_data(message, data, window)
+    }
+
+    /// See [`Connection::send_raw`].
+    pub fn send_raw(
+        &mut self,
+        message: &[u8],
+        window: qubes_gui::WindowID,
+        ty: u32,
+    ) -> io::Result<()> {
+        lock(&self.inner).send_raw(message, window, ty)
+    }
+
+    /// See [`Connection::send_damage`].
+    pub fn send_damage(
+        &mut self,
+        window: qubes_gui::WindowID,
+        rectangles: &[qubes_gui::Rectangle],
+    ) -> io::Result<()> {
+        lock(&self.inner).send_damage(window, rectangles)
+    }
+
+    /// See [`Connection::queued_bytes`].
+    pub fn queued_bytes(&self) -> usize {
+        lock(&self.inner).queued_bytes()
+    }
+
+    /// See [`Connection::flush`].
+    pub fn flush(&mut self) -> io::Result<()> {
+        lock(&self.inner).flush()
+    }
+}
+
+impl Writer {
+    /// See [`Connection::flush_blocking`].  This holds the lock, blocking the
+    /// [`Reader`], until it returns.
+    pub fn flush_blocking(&mut self, timeout: std::time::Duration) -> io::Result<()> {
+        lock(&self.inner).flush_blocking(timeout)
+    }
+}
+
+impl<V: Transport + 'static> std::os::unix::io::AsRawFd for Reader<V> {
+    fn as_raw_fd(&self) -> std::os::raw::c_int {
+        lock(&self.inner).as_raw_fd()
+    }
+}
+
+impl<V: Transport + 'static> std::os::unix::io::AsRawFd for Writer<V> {
+    fn as_raw_fd(&self) -> std::os::raw::c_int {
+        lock(&self.inner).as_raw_fd()
+    }
+}
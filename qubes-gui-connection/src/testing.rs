@@ -0,0 +1,260 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A fake daemon for testing agents without Xen or a GUI qube.
+//!
+//! [`FakeDaemon::new`] returns a fake daemon and a [`MockTransport`] for the
+//! agent under test, which is passed to [`crate::Connection::agent_with_transport`].
+//! The fake daemon negotiates the protocol version as a real one would, after
+//! which a test can inject messages and check the messages the agent sent.
+
+use qubes_castable::Castable as _;
+use qubes_gui::{Header, UntrustedHeader};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::mem::size_of;
+use std::os::unix::prelude::RawFd;
+use std::sync::{Arc, Mutex, MutexGuard};
+use vchan::{Status, Transport};
+
+/// The default amount of data the agent can send before the fake daemon
+/// reads it, which is the size of a real vchan’s ring
+const DEFAULT_CAPACITY: usize = 1 << 16;
+
+#[derive(Debug)]
+struct State {
+    /// Data the agent has not yet received
+    to_agent: VecDeque<u8>,
+    /// Data the agent sent, that the test has not yet taken
+    from_agent: VecDeque<u8>,
+    /// The amount of data the agent can send before the test takes it
+    capacity: usize,
+    /// Whether the fake daemon has disconnected
+    disconnected: bool,
+    /// Configuration sent to the agent
+    xconf: qubes_gui::XConfVersion,
+    /// Whether the agent’s version has been received
+    negotiated: bool,
+    /// Bytes of the agent’s capabilities still to be skipped
+    capabilities_to_skip: usize,
+}
+
+impl State {
+    /// Answer the agent’s side of the handshake, and drop it so that only
+    /// messages remain
+    fn negotiate(&mut self) {
+        if !self.negotiated && self.from_agent.len() >= size_of::<u32>() {
+            let mut version = [0; size_of::<u32>()];
+            for (dst, src) in version
+                .iter_mut()
+                .zip(self.from_agent.drain(..size_of::<u32>()))
+            {
+                *dst = src
+            }
+            let agent_minor = u32::from_ne_bytes(version) & 0xFFFF;
+            let minor = agent_minor.min(self.xconf.version & 0xFFFF);
+            self.xconf.version = self.xconf.version & !0xFFFF | minor;
+            self.to_agent.extend(self.xconf.as_bytes());
+            if self.xconf.version >= qubes_gui::Capabilities::MIN_VERSION {
+                self.to_agent
+                    .extend(qubes_gui::Capabilities::ALL.as_bytes());
+                self.capabilities_to_skip = size_of::<qubes_gui::Capabilities>();
+            }
+            self.negotiated = true;
+        }
+        let skip = self.capabilities_to_skip.min(self.from_agent.len());
+        self.from_agent.drain(..skip);
+        self.capabilities_to_skip -= skip;
+    }
+}
+
+/// The agent’s end of a [`FakeDaemon`]
+#[derive(Debug, Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockTransport {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, buffer: &[u8]) -> Result<(), vchan::Error> {
+        let mut state = self.state();
+        if state.disconnected {
+            return Err(vchan::Error::Write(ErrorKind::BrokenPipe.into()));
+        }
+        if buffer.len() > state.capacity.saturating_sub(state.from_agent.len()) {
+            return Err(vchan::Error::Write(ErrorKind::WouldBlock.into()));
+        }
+        state.from_agent.extend(buffer);
+        state.negotiate();
+        Ok(())
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), vchan::Error> {
+        let mut state = self.state();
+        if buffer.len() > state.to_agent.len() {
+            return Err(vchan::Error::Read(ErrorKind::UnexpectedEof.into()));
+        }
+        let len = buffer.len();
+        for (dst, src) in buffer.iter_mut().zip(state.to_agent.drain(..len)) {
+            *dst = src
+        }
+        Ok(())
+    }
+
+    fn data_ready(&self) -> usize {
+        self.state().to_agent.len()
+    }
+
+    fn buffer_space(&self) -> usize {
+        let state = self.state();
+        if state.disconnected {
+            0
+        } else {
+            state.capacity.saturating_sub(state.from_agent.len())
+        }
+    }
+
+    fn status(&self) -> Status {
+        let state = self.state();
+        if !state.disconnected {
+            Status::Connected
+        } else if state.to_agent.is_empty() {
+            Status::Disconnected
+        } else {
+            Status::HalfClosed
+        }
+    }
+
+    fn wait(&self) {}
+
+    /// Returns -1, as there is nothing to wait for.  `poll` ignores negative
+    /// file descriptors.
+    fn fd(&self) -> RawFd {
+        -1
+    }
+}
+
+/// A scriptable stand-in for the GUI daemon.  See the [module
+/// documentation](self).
+#[derive(Debug)]
+pub struct FakeDaemon {
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeDaemon {
+    /// Creates a fake daemon that sends `xconf` to the agent, and supports
+    /// [`qubes_gui::PROTOCOL_VERSION`]
+    pub fn new(xconf: qubes_gui::XConf) -> (Self, MockTransport) {
+        Self::with_version(xconf, qubes_gui::PROTOCOL_VERSION)
+    }
+
+    /// Creates a fake daemon that sends `xconf` to the agent, and supports
+    /// at most protocol version `version`
+    pub fn with_version(xconf: qubes_gui::XConf, version: u32) -> (Self, MockTransport) {
+        let state = Arc::new(Mutex::new(State {
+            to_agent: VecDeque::new(),
+            from_agent: VecDeque::new(),
+            capacity: DEFAULT_CAPACITY,
+            disconnected: false,
+            xconf: qubes_gui::XConfVersion { version, xconf },
+            negotiated: false,
+            capabilities_to_skip: 0,
+        }));
+        (
+            Self {
+                state: state.clone(),
+            },
+            MockTransport { state },
+        )
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// The negotiated protocol version, once the agent has sent its version
+    pub fn version(&self) -> Option<u32> {
+        let state = self.state();
+        state.negotiated.then(|| state.xconf.version)
+    }
+
+    /// Send raw bytes to the agent, which can be an invalid message
+    pub fn inject(&self, bytes: &[u8]) {
+        self.state().to_agent.extend(bytes)
+    }
+
+    /// Send `message` to the agent, for `window`
+    pub fn send<T: qubes_gui::Message>(&self, message: &T, window: qubes_gui::WindowID) {
+        for part in &qubes_gui::FramedMessage::new(*message, window).parts() {
+            self.inject(part)
+        }
+    }
+
+    /// Set the amount of data the agent can send before the test takes it.
+    /// Zero simulates a daemon that has stopped reading.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.state().capacity = capacity
+    }
+
+    /// Disconnect from the agent.  The agent can still receive data that was
+    /// already sent.
+    pub fn disconnect(&self) {
+        self.state().disconnected = true
+    }
+
+    /// Take every byte the agent sent after the handshake
+    pub fn take_sent(&self) -> Vec<u8> {
+        self.state().from_agent.drain(..).collect()
+    }
+
+    /// Take the next complete message the agent sent, if any
+    ///
+    /// # Panics
+    ///
+    /// Panics if the agent sent an invalid header.
+    pub fn next_message(&self) -> Option<(Header, Vec<u8>)> {
+        let mut state = self.state();
+        let mut header = UntrustedHeader::default();
+        if state.from_agent.len() < size_of::<UntrustedHeader>() {
+            return None;
+        }
+        for (dst, src) in header.as_mut_bytes().iter_mut().zip(&state.from_agent) {
+            *dst = *src
+        }
+        let header = match header.validate_length() {
+            Ok(Some(header)) => header,
+            Ok(None) => panic!("agent sent a message of unknown type {}", header.ty),
+            Err(e) => panic!("agent sent an invalid header: {}", e),
+        };
+        let len = size_of::<UntrustedHeader>() + header.len();
+        if state.from_agent.len() < len {
+            return None;
+        }
+        let mut body: Vec<u8> = state.from_agent.drain(..len).collect();
+        body.drain(..size_of::<UntrustedHeader>());
+        Some((header, body))
+    }
+}
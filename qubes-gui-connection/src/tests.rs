@@ -27,9 +27,25 @@ struct MockVchan {
     buffer_space: usize,
     data_ready: usize,
     cursor: usize,
+    /// Number of calls to send()
+    sends: usize,
 }
 
-impl VchanMock for Rc<RefCell<MockVchan>> {
+/// A [`MockVchan`] shared between the test and the code under test
+#[derive(Clone)]
+struct SharedMock(Rc<RefCell<MockVchan>>);
+
+impl std::ops::Deref for SharedMock {
+    type Target = RefCell<MockVchan>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl vchan::Transport for SharedMock {
+    fn fd(&self) -> std::os::unix::prelude::RawFd {
+        -1
+    }
     fn wait(&self) {}
     fn status(&self) -> vchan::Status {
         vchan::Status::Connected
@@ -47,6 +63,7 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
             "Agents never write more space than is available"
         );
         s.write_buf.extend_from_slice(buffer);
+        s.sends += 1;
         s.buffer_space -= buffer.len();
         Ok(())
     }
@@ -65,9 +82,8 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
         s.data_ready -= bytes;
         Ok(())
     }
-    fn recv_struct<T: Castable + Default>(&self) -> Result<T, vchan::Error> {
+    fn recv(&self, b: &mut [u8]) -> Result<(), vchan::Error> {
         let mut s = self.borrow_mut();
-        let mut v: T = Default::default();
         assert!(
             s.read_buf.len() >= s.data_ready && s.read_buf.len() - s.data_ready >= s.cursor,
             "mock vchan internal bounds error: len is {} and ready is {} but cursor is {}",
@@ -75,7 +91,6 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
             s.data_ready,
             s.cursor,
         );
-        let b = v.as_mut_bytes();
         eprintln!("Reading {} bytes with {} ready", b.len(), s.data_ready);
         assert!(
             b.len() <= s.data_ready,
@@ -84,7 +99,7 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
         b.copy_from_slice(&s.read_buf[s.cursor..s.cursor + b.len()]);
         s.cursor += b.len();
         s.data_ready -= b.len();
-        Ok(v)
+        Ok(())
     }
     fn discard(&self, bytes: usize) -> Result<(), vchan::Error> {
         let mut s = self.borrow_mut();
@@ -101,6 +116,54 @@ impl VchanMock for Rc<RefCell<MockVchan>> {
         Ok(())
     }
 }
+
+/// The configuration of a 1920×1080 screen, used by most tests
+fn xconf() -> qubes_gui::XConf {
+    qubes_gui::XConf {
+        size: qubes_gui::WindowSize {
+            width: 1920,
+            height: 1080,
+        },
+        depth: 24,
+        mem: 1920 * 1080 * 4 / 1024 + 1,
+    }
+}
+
+/// An agent and a daemon connected by a socket, before the handshake
+fn pair() -> (
+    Connection<vchan::SocketTransport>,
+    Connection<vchan::SocketTransport>,
+) {
+    let (agent_socket, daemon_socket) = vchan::SocketTransport::pair().unwrap();
+    (
+        Connection::agent_with_transport(agent_socket),
+        Connection::daemon_with_transport(daemon_socket, xconf()),
+    )
+}
+
+/// An agent and a daemon connected by a socket, which have completed the
+/// handshake
+fn connected_pair() -> (
+    Connection<vchan::SocketTransport>,
+    Connection<vchan::SocketTransport>,
+) {
+    let (mut agent, mut daemon) = pair();
+    for _ in 0..4 {
+        assert!(agent.read_message().is_pending());
+        assert!(daemon.read_message().is_pending());
+    }
+    (agent, daemon)
+}
+
+/// An agent connected to a [`testing::FakeDaemon`], which has completed the
+/// handshake
+fn fake_daemon_agent() -> (testing::FakeDaemon, Connection<testing::MockTransport>) {
+    let (daemon, transport) = testing::FakeDaemon::new(xconf());
+    let mut agent = Connection::agent_with_transport(transport);
+    while agent.poll_handshake().is_pending() {}
+    (daemon, agent)
+}
+
 #[test]
 fn vchan_writes() {
     let mock_vchan = MockVchan {
@@ -109,77 +172,93 @@ fn vchan_writes() {
         buffer_space: 0,
         data_ready: 0,
         cursor: 0,
+        sends: 0,
     };
-    let mut under_test = RawMessageStream::<Rc<RefCell<MockVchan>>> {
-        vchan: Rc::new(RefCell::new(mock_vchan)),
+    let mut under_test = RawMessageStream::<SharedMock> {
+        vchan: BufVchan::new(SharedMock(Rc::new(RefCell::new(mock_vchan)))),
         queue: Default::default(),
         state: ReadState::Connecting,
         buffer: vec![],
+        spare: vec![],
         did_reconnect: false,
         xconf: Default::default(),
         kind: Kind::Agent,
-        domid: 0,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: MIN_AGENT_VERSION,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::EMPTY,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: None,
     };
-    under_test.vchan.borrow_mut().buffer_space = 4;
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 4;
     assert!(
         under_test.read_message().unwrap().is_none(),
         "no bytes to read"
     );
-    under_test.vchan.borrow_mut().write_buf.clear();
+    under_test.vchan.get_ref().borrow_mut().write_buf.clear();
     under_test.write(b"test1").unwrap();
     assert_eq!(under_test.queue.len(), 5, "message queued");
     assert_eq!(under_test.queue, *b"test1");
-    assert_eq!(under_test.vchan.borrow().write_buf, b"", "no bytes written");
-    under_test.vchan.borrow_mut().buffer_space = 3;
+    assert_eq!(
+        under_test.vchan.get_ref().borrow().write_buf,
+        b"",
+        "no bytes written"
+    );
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 3;
     under_test
         .flush_pending_writes()
         .expect("drained successfully");
     assert_eq!(under_test.queue.len(), 2);
     assert_eq!(under_test.queue, *b"t1");
-    assert_eq!(under_test.vchan.borrow().write_buf, b"tes");
-    assert_eq!(under_test.vchan.borrow().buffer_space, 0);
-    under_test.vchan.borrow_mut().buffer_space = 4;
+    assert_eq!(under_test.vchan.get_ref().borrow().write_buf, b"tes");
+    assert_eq!(under_test.vchan.get_ref().borrow().buffer_space, 0);
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 4;
     under_test.write(b"\0another alpha").unwrap();
     assert_eq!(under_test.queue.len(), 12);
-    assert_eq!(under_test.vchan.borrow().write_buf, b"test1\0a");
+    assert_eq!(under_test.vchan.get_ref().borrow().write_buf, b"test1\0a");
     assert_eq!(
         under_test.queue, *b"nother alpha",
         "only the minimum number of bytes stored"
     );
-    under_test.vchan.borrow_mut().buffer_space = 2;
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 2;
     under_test
         .flush_pending_writes()
         .expect("drained successfully");
-    assert_eq!(under_test.vchan.borrow().write_buf, b"test1\0ano");
-    assert_eq!(under_test.vchan.borrow().buffer_space, 0);
-    under_test.vchan.borrow_mut().buffer_space = 7;
+    assert_eq!(under_test.vchan.get_ref().borrow().write_buf, b"test1\0ano");
+    assert_eq!(under_test.vchan.get_ref().borrow().buffer_space, 0);
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 7;
     assert!(
         under_test.read_message().unwrap().is_none(),
         "no bytes to read"
     );
-    assert_eq!(under_test.vchan.borrow().buffer_space, 0);
-    assert_eq!(under_test.vchan.borrow().write_buf, b"test1\0another al");
+    assert_eq!(under_test.vchan.get_ref().borrow().buffer_space, 0);
+    assert_eq!(
+        under_test.vchan.get_ref().borrow().write_buf,
+        b"test1\0another al"
+    );
     assert_eq!(under_test.queue.len(), 3);
     assert_eq!(under_test.queue, *b"pha");
-    under_test.vchan.borrow_mut().buffer_space = 8;
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 8;
     under_test.write(b" gamma delta").expect("write works");
     assert_eq!(
-        under_test.vchan.borrow().write_buf,
+        under_test.vchan.get_ref().borrow().write_buf,
         b"test1\0another alpha gamm"
     );
     under_test.write(b" gamma delta").expect("write works");
     under_test.write(b" gamma delta").expect("write works");
-    under_test.vchan.borrow_mut().buffer_space = 8;
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 8;
     let version = qubes_gui::XConfVersion {
         version: 0x10004,
         xconf: Default::default(),
     };
     under_test
         .vchan
+        .get_ref()
         .borrow_mut()
         .read_buf
         .extend_from_slice(&version.as_bytes());
-    under_test.vchan.borrow_mut().data_ready = 12;
+    under_test.vchan.get_ref().borrow_mut().data_ready = 12;
 
     assert!(under_test.vchan.data_ready() < size_of::<qubes_gui::XConfVersion>());
     assert!(matches!(under_test.state, ReadState::Negotiating));
@@ -187,28 +266,28 @@ fn vchan_writes() {
         under_test.read_message().unwrap().is_none(),
         "not enough bytes to read"
     );
-    assert_eq!(under_test.vchan.borrow().data_ready, 12);
+    assert_eq!(under_test.vchan.get_ref().borrow().data_ready, 12);
     assert!(matches!(under_test.state, ReadState::Negotiating));
-    under_test.vchan.borrow_mut().data_ready += 8;
-    under_test.vchan.borrow_mut().buffer_space = 8;
+    under_test.vchan.get_ref().borrow_mut().data_ready += 8;
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 8;
     assert!(
         under_test.read_message().unwrap().is_none(),
         "no bytes to read"
     );
-    assert_eq!(under_test.vchan.borrow().data_ready, 0);
+    assert_eq!(under_test.vchan.get_ref().borrow().data_ready, 0);
     assert!(matches!(under_test.state, ReadState::ReadingHeader));
-    under_test.vchan.borrow_mut().buffer_space = 8;
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 8;
     assert!(
         under_test.read_message().unwrap().is_none(),
         "no bytes to read"
     );
-    under_test.vchan.borrow_mut().buffer_space = 8;
+    under_test.vchan.get_ref().borrow_mut().buffer_space = 8;
     assert!(
         under_test.read_message().unwrap().is_none(),
         "no bytes to read"
     );
     assert_eq!(
-        under_test.vchan.borrow().write_buf,
+        under_test.vchan.get_ref().borrow().write_buf,
         b"test1\0another alpha gamma delta gamma delta gamma delta",
         "correct data written"
     );
@@ -228,41 +307,50 @@ fn vchan_reads() {
         buffer_space: 0,
         data_ready: 0,
         cursor: 0,
+        sends: 0,
     };
-    let vchan = Rc::new(RefCell::new(mock_vchan));
-    let mut under_test = RawMessageStream::<Rc<RefCell<MockVchan>>> {
-        vchan: vchan.clone(),
+    let vchan = SharedMock(Rc::new(RefCell::new(mock_vchan)));
+    let mut under_test = RawMessageStream::<SharedMock> {
+        vchan: BufVchan::new(vchan.clone()),
         queue: Default::default(),
         state: ReadState::ReadingHeader,
         buffer: vec![],
+        spare: vec![],
         did_reconnect: false,
         xconf: Default::default(),
-        domid: 0,
         kind: Kind::Agent,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: MIN_AGENT_VERSION,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::EMPTY,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: None,
     };
     let mut hdr = UntrustedHeader {
         untrusted_len: 1,
-        ty: qubes_gui::MSG_MFNDUMP,
+        ty: qubes_gui::MSG_CONFIGURE,
         window: 0.into(),
     };
     under_test
         .vchan
+        .get_ref()
         .borrow_mut()
         .read_buf
         .extend_from_slice(hdr.as_bytes());
-    under_test.vchan.borrow_mut().data_ready = 2;
+    under_test.vchan.get_ref().borrow_mut().data_ready = 2;
     assert!(
         under_test.read_message().unwrap().is_none(),
         "not enough data"
     );
     assert!(matches!(under_test.state, ReadState::ReadingHeader));
-    under_test.vchan.borrow_mut().data_ready = 12;
+    under_test.vchan.get_ref().borrow_mut().data_ready = 12;
     assert!(under_test.read_message().is_err(), "bad header!");
-    assert!(matches!(under_test.state, ReadState::Error));
+    assert_eq!(under_test.error_kind(), Some(ReadErrorKind::Protocol));
 
     // Test that a header and partial body can be read in one go
     under_test.state = ReadState::ReadingHeader;
-    under_test.vchan.borrow_mut().data_ready = 13;
+    under_test.vchan.get_ref().borrow_mut().data_ready = 13;
     hdr.ty = qubes_gui::MSG_CONFIGURE;
     hdr.untrusted_len = s!(qubes_gui::Configure);
     vchan
@@ -346,3 +434,1204 @@ fn vchan_reads() {
         "State after complete message not reset to ReadingHeader"
     );
 }
+
+#[cfg(feature = "extensions")]
+#[test]
+fn capability_negotiation() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 64,
+        data_ready: 0,
+        cursor: 0,
+        sends: 0,
+    };
+    let vchan = SharedMock(Rc::new(RefCell::new(mock_vchan)));
+    let mut under_test = RawMessageStream::<SharedMock> {
+        vchan: BufVchan::new(vchan.clone()),
+        queue: Default::default(),
+        state: ReadState::Negotiating,
+        buffer: vec![],
+        spare: vec![],
+        did_reconnect: false,
+        xconf: Default::default(),
+        kind: Kind::Agent,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: MIN_AGENT_VERSION,
+        capabilities: qubes_gui::Capabilities::CURSOR_IMAGE | qubes_gui::Capabilities::OUTPUTS,
+        peer_capabilities: qubes_gui::Capabilities::EMPTY,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: None,
+    };
+    let version = qubes_gui::XConfVersion {
+        version: qubes_gui::Capabilities::MIN_VERSION,
+        xconf: Default::default(),
+    };
+    let daemon_capabilities =
+        qubes_gui::Capabilities::CURSOR_IMAGE | qubes_gui::Capabilities::WINDOW_ICON;
+    vchan
+        .borrow_mut()
+        .read_buf
+        .extend_from_slice(version.as_bytes());
+    vchan.borrow_mut().data_ready = size_of::<qubes_gui::XConfVersion>();
+    assert!(under_test.read_message().unwrap().is_none());
+    assert_eq!(under_test.state, ReadState::NegotiatingCapabilities);
+    assert!(!under_test.reconnected(), "handshake not yet complete");
+    vchan
+        .borrow_mut()
+        .read_buf
+        .extend_from_slice(daemon_capabilities.as_bytes());
+    vchan.borrow_mut().data_ready = size_of::<qubes_gui::Capabilities>();
+    assert!(under_test.read_message().unwrap().is_none());
+    assert_eq!(under_test.state, ReadState::ReadingHeader);
+    assert!(under_test.reconnected(), "handshake complete");
+    assert_eq!(under_test.peer_capabilities, daemon_capabilities);
+    assert_eq!(
+        vchan.borrow().write_buf,
+        under_test.capabilities.as_bytes(),
+        "agent capabilities sent"
+    );
+    assert!(under_test.peer_supports(qubes_gui::MSG_CURSOR_IMAGE));
+    assert!(
+        !under_test.peer_supports(qubes_gui::MSG_WINDOW_ICON),
+        "agent does not support icons"
+    );
+    assert!(
+        !under_test.peer_supports(qubes_gui::MSG_OUTPUTS),
+        "daemon does not support outputs"
+    );
+    assert!(under_test.peer_supports(qubes_gui::MSG_CONFIGURE));
+}
+
+#[test]
+fn daemon_window_limits() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 0,
+        data_ready: 0,
+        cursor: 0,
+        sends: 0,
+    };
+    let vchan = SharedMock(Rc::new(RefCell::new(mock_vchan)));
+    let xconf = xconf();
+    let mut under_test = RawMessageStream::<SharedMock> {
+        vchan: BufVchan::new(vchan.clone()),
+        queue: Default::default(),
+        state: ReadState::ReadingHeader,
+        buffer: vec![],
+        spare: vec![],
+        did_reconnect: false,
+        xconf: qubes_gui::XConfVersion {
+            version: qubes_gui::PROTOCOL_VERSION,
+            xconf,
+        },
+        kind: Kind::Daemon,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: qubes_gui::PROTOCOL_VERSION_MAJOR << 16,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::ALL,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: None,
+    };
+    let hdr = UntrustedHeader {
+        untrusted_len: s!(qubes_gui::Configure),
+        ty: qubes_gui::MSG_CONFIGURE,
+        window: 1.into(),
+    };
+    let mut c = qubes_gui::Configure {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+            size: xconf.size,
+        },
+        override_redirect: 0,
+    };
+    let len = s!(qubes_gui::Configure) as usize + size_of::<UntrustedHeader>();
+    vchan
+        .borrow_mut()
+        .read_buf
+        .extend_from_slice(hdr.as_bytes());
+    vchan.borrow_mut().read_buf.extend_from_slice(c.as_bytes());
+    vchan.borrow_mut().data_ready = len;
+    assert!(
+        under_test.read_message().unwrap().is_some(),
+        "window as large as the root window"
+    );
+    c.rectangle.size.width += 1;
+    vchan
+        .borrow_mut()
+        .read_buf
+        .extend_from_slice(hdr.as_bytes());
+    vchan.borrow_mut().read_buf.extend_from_slice(c.as_bytes());
+    vchan.borrow_mut().data_ready = len;
+    assert!(
+        under_test.read_message().is_err(),
+        "window larger than the root window"
+    );
+    assert_eq!(under_test.error_kind(), Some(ReadErrorKind::Protocol));
+}
+
+#[test]
+fn socket_transport() {
+    let (agent_socket, daemon_socket) = vchan::SocketTransport::pair().unwrap();
+    let xconf = xconf();
+    let mut agent = RawMessageStream {
+        vchan: BufVchan::new(agent_socket),
+        queue: Default::default(),
+        state: ReadState::Connecting,
+        buffer: vec![],
+        spare: vec![],
+        did_reconnect: false,
+        xconf: Default::default(),
+        kind: Kind::Agent,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: MIN_AGENT_VERSION,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::EMPTY,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: None,
+    };
+    let mut daemon = RawMessageStream {
+        vchan: BufVchan::new(daemon_socket),
+        queue: Default::default(),
+        state: ReadState::Negotiating,
+        buffer: vec![],
+        spare: vec![],
+        did_reconnect: false,
+        xconf: qubes_gui::XConfVersion {
+            version: qubes_gui::PROTOCOL_VERSION,
+            xconf,
+        },
+        kind: Kind::Daemon,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: qubes_gui::PROTOCOL_VERSION_MAJOR << 16,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::EMPTY,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: None,
+    };
+    for _ in 0..4 {
+        assert!(agent.read_message().unwrap().is_none());
+        assert!(daemon.read_message().unwrap().is_none());
+    }
+    assert_eq!(agent.state, ReadState::ReadingHeader, "agent connected");
+    assert_eq!(daemon.state, ReadState::ReadingHeader, "daemon connected");
+    assert_eq!(agent.xconf, daemon.xconf, "agent received configuration");
+    let hdr = UntrustedHeader {
+        untrusted_len: 0,
+        ty: qubes_gui::MSG_DESTROY,
+        window: 1.into(),
+    };
+    agent.write(hdr.as_bytes()).unwrap();
+    let msg = daemon.read_message().unwrap().expect("message received");
+    assert_eq!(msg.hdr().ty(), qubes_gui::MSG_DESTROY);
+    assert!(msg.body().is_empty());
+}
+
+#[test]
+fn write_message_single_send() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 4096,
+        data_ready: 0,
+        cursor: 0,
+        sends: 0,
+    };
+    let vchan = SharedMock(Rc::new(RefCell::new(mock_vchan)));
+    let mut under_test = RawMessageStream::<SharedMock> {
+        vchan: BufVchan::new(vchan.clone()),
+        queue: Default::default(),
+        state: ReadState::ReadingHeader,
+        buffer: vec![],
+        spare: vec![],
+        did_reconnect: false,
+        xconf: Default::default(),
+        kind: Kind::Agent,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: MIN_AGENT_VERSION,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::ALL,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: None,
+    };
+    let header = qubes_gui::Header::for_message::<qubes_gui::WindowDumpHeader>(
+        0.into(),
+        size_of::<qubes_gui::WindowDumpHeader>() + 4,
+    )
+    .unwrap();
+    let dump = qubes_gui::WindowDumpHeader::default();
+    under_test
+        .write_message(header, &[dump.as_bytes(), b"refs"])
+        .unwrap();
+    assert_eq!(vchan.borrow().sends, 1, "message sent at once");
+    let expected = [header.inner().as_bytes(), dump.as_bytes(), b"refs"].concat();
+    assert_eq!(vchan.borrow().write_buf, expected);
+    assert!(under_test.queue.is_empty());
+}
+
+#[test]
+fn daemon_agent_events() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 0,
+        data_ready: 0,
+        cursor: 0,
+        sends: 0,
+    };
+    let vchan = SharedMock(Rc::new(RefCell::new(mock_vchan)));
+    let xconf = xconf();
+    let mut under_test = RawMessageStream::<SharedMock> {
+        vchan: BufVchan::new(vchan.clone()),
+        queue: Default::default(),
+        state: ReadState::ReadingHeader,
+        buffer: vec![],
+        spare: vec![],
+        did_reconnect: false,
+        xconf: qubes_gui::XConfVersion {
+            version: qubes_gui::PROTOCOL_VERSION,
+            xconf,
+        },
+        kind: Kind::Daemon,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: qubes_gui::PROTOCOL_VERSION_MAJOR << 16,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::ALL,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: None,
+    };
+    let push = |ty, body: &[u8]| {
+        let hdr = UntrustedHeader {
+            untrusted_len: body.len() as u32,
+            ty,
+            window: 1.into(),
+        };
+        let mut vchan = vchan.borrow_mut();
+        vchan.read_buf.extend_from_slice(hdr.as_bytes());
+        vchan.read_buf.extend_from_slice(body);
+        vchan.data_ready += size_of::<UntrustedHeader>() + body.len();
+    };
+    push(qubes_gui::MSG_SET_TITLE, &[b'a'; 128]);
+    match under_test.next_agent_event().unwrap() {
+        Some((window, qubes_gui_agent_proto::daemon::AgentEvent::SetTitle(_))) => {
+            assert_eq!(window, 1.into())
+        }
+        _ => panic!("expected a title"),
+    }
+    assert!(under_test.next_agent_event().unwrap().is_none());
+    let mut create = qubes_gui::Create::default();
+    create.rectangle.size = xconf.size;
+    create.rectangle.size.width += 1;
+    push(qubes_gui::MSG_CREATE, create.as_bytes());
+    match under_test.next_agent_event() {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+        Ok(_) => panic!("window larger than the root window"),
+    }
+}
+
+#[test]
+fn daemon_rejects_daemon_messages() {
+    let (mut agent, mut daemon) = connected_pair();
+    agent.send_raw(&[], 1.into(), qubes_gui::MSG_CLOSE).unwrap();
+    match daemon.next_agent_event() {
+        Poll::Ready(Err(e)) => {
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+            assert!(e.to_string().contains("wrong direction"), "{}", e);
+        }
+        _ => panic!("MSG_CLOSE from an agent was not rejected"),
+    }
+    assert_eq!(daemon.metrics().protocol_errors, 1);
+}
+
+#[test]
+fn window_id_allocator() {
+    let mut ids = WindowIdAllocator::with_reuse_delay(2);
+    let first = ids.allocate().unwrap();
+    assert_eq!(first, 1.into());
+    assert!(ids.is_allocated(first));
+    assert!(ids.release(first));
+    assert!(!ids.release(first), "double release");
+    assert!(!ids.release(0.into()), "the root window is never allocated");
+    // Not reused until more than 2 other IDs have been released
+    let mut seen = vec![];
+    for _ in 0..2 {
+        let id = ids.allocate().unwrap();
+        seen.push(id);
+        assert!(ids.release(id));
+    }
+    assert_eq!(seen, [2.into(), 3.into()]);
+    assert_eq!(ids.allocate().unwrap(), first);
+}
+
+#[test]
+fn message_hook() {
+    use std::sync::{Arc, Mutex};
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 4096,
+        data_ready: 0,
+        cursor: 0,
+        sends: 0,
+    };
+    let vchan = SharedMock(Rc::new(RefCell::new(mock_vchan)));
+    let seen = Arc::new(Mutex::new(vec![]));
+    let seen_by_hook = seen.clone();
+    let mut under_test = RawMessageStream::<SharedMock> {
+        vchan: BufVchan::new(vchan.clone()),
+        queue: Default::default(),
+        state: ReadState::ReadingHeader,
+        buffer: vec![],
+        spare: vec![],
+        did_reconnect: false,
+        xconf: Default::default(),
+        kind: Kind::Agent,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: MIN_AGENT_VERSION,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::ALL,
+        metrics: Default::default(),
+        coalescer: None,
+        hook: Some(MessageHook(Box::new(move |direction, header: Header| {
+            seen_by_hook
+                .lock()
+                .unwrap()
+                .push((direction, header.ty(), header.len()))
+        }))),
+    };
+    let header = qubes_gui::Header::for_message::<qubes_gui::Destroy>(1.into(), 0).unwrap();
+    under_test.write_message(header, &[]).unwrap();
+    let hdr = UntrustedHeader {
+        untrusted_len: 0,
+        ty: qubes_gui::MSG_CLOSE,
+        window: 1.into(),
+    };
+    vchan
+        .borrow_mut()
+        .read_buf
+        .extend_from_slice(hdr.as_bytes());
+    vchan.borrow_mut().data_ready = size_of::<UntrustedHeader>();
+    assert!(under_test.read_message().unwrap().is_some());
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            (MessageDirection::Sent, qubes_gui::MSG_DESTROY, 0),
+            (MessageDirection::Received, qubes_gui::MSG_CLOSE, 0),
+        ]
+    );
+}
+
+#[test]
+fn split_halves_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Reader>();
+    assert_send::<Writer>();
+}
+
+#[test]
+fn connection_with_transport() {
+    let (mut agent, mut daemon) = connected_pair();
+    assert_eq!(
+        agent.xconf(),
+        daemon.xconf(),
+        "agent received configuration"
+    );
+    let window = agent
+        .create_window(&qubes_gui::Create {
+            rectangle: qubes_gui::Rectangle {
+                top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+                size: qubes_gui::WindowSize {
+                    width: 100,
+                    height: 100,
+                },
+            },
+            parent: None,
+            override_redirect: 0,
+        })
+        .unwrap();
+    match daemon.next_agent_event() {
+        Poll::Ready(Ok((id, qubes_gui_agent_proto::daemon::AgentEvent::Create(_)))) => {
+            assert_eq!(id, window)
+        }
+        _ => panic!("expected a window to be created"),
+    }
+}
+
+#[test]
+fn read_buffer_reuse() {
+    let (agent_socket, daemon_socket) = vchan::SocketTransport::pair().unwrap();
+    let mut agent = RawMessageStream::agent_with_transport(agent_socket);
+    let mut daemon = RawMessageStream::daemon_with_transport(daemon_socket, xconf());
+    for _ in 0..4 {
+        assert!(agent.read_message().unwrap().is_none());
+        assert!(daemon.read_message().unwrap().is_none());
+    }
+    let mut send = |len: usize| {
+        let header =
+            qubes_gui::Header::for_message::<qubes_gui::WindowDumpHeader>(0.into(), len).unwrap();
+        let body = vec![0; len];
+        agent.write_message(header, &[&body]).unwrap();
+    };
+    let small = size_of::<qubes_gui::WindowDumpHeader>();
+    send(small);
+    let body = daemon.read_message().unwrap().unwrap().take();
+    assert_eq!(body.len(), small);
+    let ptr = body.as_ptr();
+    daemon.recycle(body);
+    send(small);
+    let msg = daemon.read_message().unwrap().unwrap();
+    assert_eq!(msg.body().as_ptr(), ptr, "recycled buffer reused");
+    // A large message does not keep its memory once it has been handled
+    send(small + MAX_RETAINED_CAPACITY * 2);
+    while daemon.read_message().unwrap().is_none() {}
+    send(small);
+    while daemon.read_message().unwrap().is_none() {}
+    assert!(daemon.buffer.capacity() <= MAX_RETAINED_CAPACITY);
+}
+
+#[test]
+fn connection_metrics() {
+    let (mut agent, mut daemon) = connected_pair();
+    let before = agent.metrics();
+    let window = agent.window_ids().allocate().unwrap();
+    agent.send(&qubes_gui::Destroy {}, window).unwrap();
+    let after = agent.metrics();
+    assert_eq!(after.messages_sent(), before.messages_sent() + 1);
+    assert_eq!(after.sent.get(&qubes_gui::MSG_DESTROY), Some(&1));
+    assert_eq!(
+        after.bytes_sent,
+        before.bytes_sent + size_of::<UntrustedHeader>() as u64
+    );
+    assert!(daemon.read_message().is_ready());
+    let metrics = daemon.metrics();
+    assert_eq!(metrics.received.get(&qubes_gui::MSG_DESTROY), Some(&1));
+    assert_eq!(metrics.bytes_received, after.bytes_sent);
+    assert_eq!(metrics.protocol_errors, 0);
+    assert_eq!(metrics.reconnects, 0);
+}
+
+#[test]
+fn read_body_chunked() {
+    let (mut agent, mut daemon) = connected_pair();
+    let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+    let send_clipboard = |daemon: &mut Connection<vchan::SocketTransport>| {
+        daemon
+            .send_raw(&data, 0.into(), qubes_gui::MSG_CLIPBOARD_DATA)
+            .unwrap();
+    };
+    send_clipboard(&mut daemon);
+    daemon
+        .send(&qubes_gui::Motion::default(), 1.into())
+        .unwrap();
+    let mut received = vec![];
+    loop {
+        match agent.read_body_chunked() {
+            Poll::Pending => {}
+            Poll::Ready(Ok(Chunk::Header(header))) => {
+                assert_eq!(header.ty(), qubes_gui::MSG_CLIPBOARD_DATA);
+                assert_eq!(header.len(), data.len());
+                assert!(received.is_empty());
+            }
+            Poll::Ready(Ok(Chunk::Body { data, remaining })) => {
+                assert!(!data.is_empty());
+                received.extend_from_slice(data);
+                if remaining == 0 {
+                    break;
+                }
+            }
+            Poll::Ready(r) => panic!("unexpected {:?}", r),
+        }
+    }
+    assert_eq!(received, data);
+    // Fixed-size messages arrive whole
+    match agent.read_body_chunked() {
+        Poll::Ready(Ok(Chunk::Message(buffer))) => {
+            assert_eq!(buffer.hdr().ty(), qubes_gui::MSG_MOTION)
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+    // Switching back to whole messages discards the rest of a body
+    send_clipboard(&mut daemon);
+    daemon
+        .send(&qubes_gui::Motion::default(), 1.into())
+        .unwrap();
+    assert!(matches!(
+        agent.read_body_chunked(),
+        Poll::Ready(Ok(Chunk::Header(_)))
+    ));
+    match agent.read_message() {
+        Poll::Ready(Ok(buffer)) => assert_eq!(buffer.hdr().ty(), qubes_gui::MSG_MOTION),
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn advertised_version() {
+    let (mut agent, mut daemon) = pair();
+    let too_new = qubes_gui::PROTOCOL_VERSION + 1;
+    assert_eq!(
+        agent.set_version(too_new).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        agent.set_version(1 << 16 | 3).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(agent.version(), qubes_gui::PROTOCOL_VERSION);
+    agent.set_version(1 << 16 | 7).unwrap();
+    daemon.set_version(1 << 16 | 5).unwrap();
+    for _ in 0..4 {
+        assert!(agent.read_message().is_pending());
+        assert!(daemon.read_message().is_pending());
+    }
+    assert!(agent.reconnected());
+    assert_eq!(agent.xconf().version, 1 << 16 | 5);
+    assert_eq!(daemon.xconf().version, 1 << 16 | 5);
+    assert_eq!(
+        agent.peer_capabilities(),
+        qubes_gui::Capabilities::implied_by_version(1 << 16 | 5)
+    );
+}
+
+#[test]
+fn coalescing() {
+    let mock_vchan = MockVchan {
+        read_buf: vec![],
+        write_buf: vec![],
+        buffer_space: 0,
+        data_ready: 0,
+        cursor: 0,
+        sends: 0,
+    };
+    let vchan = SharedMock(Rc::new(RefCell::new(mock_vchan)));
+    let mut under_test = RawMessageStream::<SharedMock> {
+        vchan: BufVchan::new(vchan.clone()),
+        queue: Default::default(),
+        state: ReadState::ReadingHeader,
+        buffer: vec![],
+        spare: vec![],
+        did_reconnect: false,
+        xconf: Default::default(),
+        kind: Kind::Agent,
+        version: qubes_gui::PROTOCOL_VERSION,
+        min_version: MIN_AGENT_VERSION,
+        capabilities: qubes_gui::Capabilities::ALL,
+        peer_capabilities: qubes_gui::Capabilities::ALL,
+        hook: None,
+        metrics: Default::default(),
+        coalescer: Some(coalesce::Coalescer::new(true)),
+    };
+    let rectangle = |x, y, width, height| qubes_gui::Rectangle {
+        top_left: qubes_gui::Coordinates { x, y },
+        size: qubes_gui::WindowSize { width, height },
+    };
+    let send = |under_test: &mut RawMessageStream<SharedMock>,
+                window: u32,
+                message: &dyn Fn() -> Vec<u8>,
+                ty: u32| {
+        let body = message();
+        let header = UntrustedHeader {
+            ty,
+            window: window.into(),
+            untrusted_len: body.len() as u32,
+        }
+        .validate_length()
+        .unwrap()
+        .unwrap();
+        under_test.write_message(header, &[&body]).unwrap();
+    };
+    let configure = |width| {
+        move || {
+            qubes_gui::Configure {
+                rectangle: rectangle(0, 0, width, 100),
+                override_redirect: 0,
+            }
+            .as_bytes()
+            .to_vec()
+        }
+    };
+    let image = |x, y| {
+        move || {
+            qubes_gui::ShmImage {
+                rectangle: rectangle(x, y, 10, 10),
+            }
+            .as_bytes()
+            .to_vec()
+        }
+    };
+    send(
+        &mut under_test,
+        1,
+        &configure(100),
+        qubes_gui::MSG_CONFIGURE,
+    );
+    send(
+        &mut under_test,
+        2,
+        &configure(100),
+        qubes_gui::MSG_CONFIGURE,
+    );
+    // Replaces the first message
+    send(
+        &mut under_test,
+        1,
+        &configure(200),
+        qubes_gui::MSG_CONFIGURE,
+    );
+    // Not replaced, as an image for the same window follows
+    send(&mut under_test, 2, &image(0, 0), qubes_gui::MSG_SHMIMAGE);
+    send(
+        &mut under_test,
+        2,
+        &configure(300),
+        qubes_gui::MSG_CONFIGURE,
+    );
+    send(&mut under_test, 1, &image(0, 0), qubes_gui::MSG_SHMIMAGE);
+    // Merged with the previous image
+    send(&mut under_test, 1, &image(5, 5), qubes_gui::MSG_SHMIMAGE);
+    // Does not overlap
+    send(&mut under_test, 1, &image(50, 50), qubes_gui::MSG_SHMIMAGE);
+    // Past the edge of the coordinate space, so not merged
+    send(
+        &mut under_test,
+        3,
+        &image(i32::MAX - 5, 0),
+        qubes_gui::MSG_SHMIMAGE,
+    );
+    send(
+        &mut under_test,
+        3,
+        &image(i32::MAX - 5, 0),
+        qubes_gui::MSG_SHMIMAGE,
+    );
+    assert_eq!(under_test.metrics.coalesced, 2);
+    let mut queue: &[u8] = under_test.queue.make_contiguous();
+    let mut messages = vec![];
+    while !queue.is_empty() {
+        let mut header = UntrustedHeader::default();
+        header
+            .as_mut_bytes()
+            .copy_from_slice(&queue[..size_of::<UntrustedHeader>()]);
+        let len = header.untrusted_len as usize;
+        let body = &queue[size_of::<UntrustedHeader>()..][..len];
+        let size = match header.ty {
+            qubes_gui::MSG_CONFIGURE => qubes_gui::Configure::from_bytes(body).rectangle,
+            qubes_gui::MSG_SHMIMAGE => qubes_gui::ShmImage::from_bytes(body).rectangle,
+            _ => unreachable!(),
+        };
+        messages.push((
+            header.window.window.map_or(0, |w| w.get()),
+            header.ty,
+            size.top_left.x,
+            size.size.width,
+        ));
+        queue = &queue[size_of::<UntrustedHeader>() + len..];
+    }
+    assert_eq!(
+        messages,
+        [
+            (2, qubes_gui::MSG_CONFIGURE, 0, 100),
+            (1, qubes_gui::MSG_CONFIGURE, 0, 200),
+            (2, qubes_gui::MSG_SHMIMAGE, 0, 10),
+            (2, qubes_gui::MSG_CONFIGURE, 0, 300),
+            (1, qubes_gui::MSG_SHMIMAGE, 0, 15),
+            (1, qubes_gui::MSG_SHMIMAGE, 50, 10),
+            (3, qubes_gui::MSG_SHMIMAGE, i32::MAX - 5, 10),
+            (3, qubes_gui::MSG_SHMIMAGE, i32::MAX - 5, 10),
+        ]
+    );
+    // Sent messages can no longer be replaced
+    vchan.borrow_mut().buffer_space = 4096;
+    under_test.flush_pending_writes().unwrap();
+    send(
+        &mut under_test,
+        1,
+        &configure(400),
+        qubes_gui::MSG_CONFIGURE,
+    );
+    send(
+        &mut under_test,
+        1,
+        &configure(500),
+        qubes_gui::MSG_CONFIGURE,
+    );
+    assert_eq!(under_test.metrics.coalesced, 2);
+}
+
+#[test]
+fn close_behavior() {
+    let (mut agent, mut daemon) = connected_pair();
+    let create = qubes_gui::Create {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+            size: qubes_gui::WindowSize {
+                width: 100,
+                height: 100,
+            },
+        },
+        parent: None,
+        override_redirect: 0,
+    };
+    let window = agent.create_window(&create).unwrap();
+    assert_eq!(agent.close_behavior(), CloseBehavior::Notify);
+    assert_eq!(
+        agent.handle_close(window).unwrap(),
+        Some(CloseBehavior::Notify)
+    );
+    agent.set_close_behavior(CloseBehavior::Unmap);
+    assert_eq!(
+        agent.handle_close(window).unwrap(),
+        Some(CloseBehavior::Unmap)
+    );
+    assert!(agent.window_ids().is_allocated(window));
+    agent.set_close_behavior(CloseBehavior::Destroy);
+    assert_eq!(
+        agent.handle_close(window).unwrap(),
+        Some(CloseBehavior::Destroy)
+    );
+    assert!(!agent.window_ids().is_allocated(window));
+    // A late request for a destroyed window is ignored
+    assert_eq!(agent.handle_close(window).unwrap(), None);
+    let mut received = vec![];
+    while let Poll::Ready(event) = daemon.next_agent_event() {
+        let (id, event) = event.unwrap();
+        assert_eq!(id, window);
+        received.push(event.kind());
+    }
+    assert_eq!(
+        received,
+        [
+            qubes_gui::Msg::Create,
+            qubes_gui::Msg::Unmap,
+            qubes_gui::Msg::Destroy
+        ]
+    );
+}
+
+#[test]
+fn fake_daemon() {
+    use crate::testing::FakeDaemon;
+    let xconf = xconf();
+    let (daemon, transport) = FakeDaemon::with_version(xconf, qubes_gui::PROTOCOL_VERSION);
+    let mut agent = Connection::agent_with_transport(transport);
+    for _ in 0..4 {
+        assert!(agent.read_message().is_pending());
+    }
+    assert!(agent.reconnected());
+    assert_eq!(daemon.version(), Some(qubes_gui::PROTOCOL_VERSION));
+    assert_eq!(agent.xconf().xconf, xconf);
+    assert!(daemon.next_message().is_none());
+    let window = agent.window_ids().allocate().unwrap();
+    agent.send(&qubes_gui::Unmap {}, window).unwrap();
+    let (header, body) = daemon.next_message().unwrap();
+    assert_eq!(header.ty(), qubes_gui::MSG_UNMAP);
+    assert_eq!(header.untrusted_window(), window);
+    assert!(body.is_empty());
+    daemon.send(&qubes_gui::Motion::default(), window);
+    match agent.read_message() {
+        Poll::Ready(Ok(buffer)) => assert_eq!(buffer.hdr().ty(), qubes_gui::MSG_MOTION),
+        _ => panic!("expected a message"),
+    }
+    // A daemon that stops reading makes the agent queue its messages
+    daemon.set_capacity(0);
+    agent.send(&qubes_gui::Unmap {}, window).unwrap();
+    assert!(daemon.take_sent().is_empty());
+    assert_eq!(agent.queued_bytes(), size_of::<UntrustedHeader>());
+    daemon.disconnect();
+    assert!(agent.needs_reconnect());
+}
+
+#[test]
+fn reader_recycles_bodies() {
+    let (daemon, agent) = fake_daemon_agent();
+    let (mut reader, _writer) = agent.split();
+    let window = 1.into();
+    daemon.send(&qubes_gui::Motion::default(), window);
+    let body = match reader.read_message() {
+        Poll::Ready(Ok((_, body))) => body,
+        _ => panic!("expected a message"),
+    };
+    let ptr = body.as_ptr();
+    reader.recycle(body);
+    daemon.send(&qubes_gui::Motion::default(), window);
+    match reader.read_message() {
+        Poll::Ready(Ok((_, body))) => assert_eq!(body.as_ptr(), ptr, "recycled buffer reused"),
+        _ => panic!("expected a message"),
+    }
+}
+
+#[test]
+fn reader_read_event() {
+    let (daemon, agent) = fake_daemon_agent();
+    let (mut reader, _writer) = agent.split();
+    assert!(reader.read_event().is_pending());
+    let header = UntrustedHeader {
+        ty: qubes_gui::MSG_CLIPBOARD_DATA,
+        window: 3.into(),
+        untrusted_len: 4,
+    };
+    daemon.inject(&[header.as_bytes(), b"text"].concat());
+    match reader.read_event() {
+        Poll::Ready(Ok((
+            window,
+            qubes_gui_agent_proto::OwnedEvent::ClipboardData { untrusted_data },
+        ))) => {
+            assert_eq!(window, 3.into());
+            assert_eq!(untrusted_data, "text");
+        }
+        _ => panic!("expected clipboard data"),
+    }
+    let (_agent, daemon) = pair();
+    let (mut reader, _writer) = daemon.split();
+    match reader.read_event() {
+        Poll::Ready(Err(e)) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        _ => panic!("daemons cannot read owned events"),
+    }
+}
+
+#[test]
+fn minimum_version() {
+    let negotiate = |agent_version: u32, daemon_min: u32| {
+        let (mut agent, mut daemon) = pair();
+        agent.set_version(agent_version).unwrap();
+        daemon.set_min_version(daemon_min).unwrap();
+        assert_eq!(daemon.negotiated_version(), None);
+        for _ in 0..4 {
+            if let Poll::Ready(Err(e)) = daemon.read_message() {
+                return Err(*e
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<VersionMismatch>())
+                    .expect("version mismatch"));
+            }
+            assert!(agent.read_message().is_pending());
+        }
+        assert_eq!(agent.negotiated_version(), daemon.negotiated_version());
+        Ok(daemon.negotiated_version().unwrap())
+    };
+    assert_eq!(negotiate(1 << 16 | 7, 1 << 16 | 4), Ok(1 << 16 | 7));
+    assert_eq!(
+        negotiate(1 << 16 | 5, 1 << 16 | 6),
+        Err(VersionMismatch {
+            peer: 1 << 16 | 5,
+            min: 1 << 16 | 6,
+            ours: qubes_gui::PROTOCOL_VERSION,
+        })
+    );
+    // Agents cannot negotiate with daemons older than 1.4
+    let (agent_socket, _) = vchan::SocketTransport::pair().unwrap();
+    let mut agent = Connection::agent_with_transport(agent_socket);
+    assert_eq!(agent.min_version(), 1 << 16 | 4);
+    assert_eq!(
+        agent.set_min_version(1 << 16 | 3).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    agent.set_min_version(1 << 16 | 6).unwrap();
+    assert_eq!(
+        agent.set_version(1 << 16 | 5).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn daemon_serves_old_agents() {
+    let (agent_socket, daemon_socket) = vchan::SocketTransport::pair().unwrap();
+    let xconf = xconf();
+    let mut daemon = Connection::daemon_with_transport(daemon_socket, xconf);
+    // A version 1.3 agent expects a bare XConf in reply
+    agent_socket.send((1u32 << 16 | 3).as_bytes()).unwrap();
+    assert!(daemon.read_message().is_pending());
+    assert_eq!(daemon.negotiated_version(), Some(1 << 16 | 3));
+    let mut reply = qubes_gui::XConf::default();
+    agent_socket.recv(reply.as_mut_bytes()).unwrap();
+    assert_eq!(reply, xconf);
+    assert_eq!(agent_socket.data_ready(), 0);
+}
+
+#[test]
+fn poll_handshake() {
+    let (mut agent, mut daemon) = pair();
+    assert_eq!(agent.handshake_state(), HandshakeState::Connecting);
+    assert_eq!(
+        agent
+            .send(&qubes_gui::Unmap {}, 1.into())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::NotConnected
+    );
+    assert!(agent.poll_handshake().is_pending());
+    assert_eq!(agent.handshake_state(), HandshakeState::Negotiating);
+    if qubes_gui::PROTOCOL_VERSION >= qubes_gui::Capabilities::MIN_VERSION {
+        assert!(daemon.poll_handshake().is_pending());
+        assert_eq!(
+            daemon.handshake_state(),
+            HandshakeState::NegotiatingCapabilities
+        );
+    } else {
+        assert!(matches!(daemon.poll_handshake(), Poll::Ready(Ok(()))));
+    }
+    assert!(matches!(agent.poll_handshake(), Poll::Ready(Ok(()))));
+    assert!(matches!(daemon.poll_handshake(), Poll::Ready(Ok(()))));
+    assert_eq!(daemon.handshake_state(), HandshakeState::Done);
+    // Messages are left for read_message
+    daemon
+        .send(&qubes_gui::Motion::default(), 1.into())
+        .unwrap();
+    assert!(matches!(agent.poll_handshake(), Poll::Ready(Ok(()))));
+    match agent.read_message() {
+        Poll::Ready(Ok(buffer)) => assert_eq!(buffer.hdr().ty(), qubes_gui::MSG_MOTION),
+        _ => panic!("expected a message"),
+    }
+}
+
+#[test]
+fn connection_info() {
+    let (agent, daemon) = pair();
+    assert_eq!(agent.kind(), Kind::Agent);
+    assert_eq!(daemon.kind(), Kind::Daemon);
+    assert_eq!(agent.peer_domid(), None);
+    assert_eq!(agent.status(), Status::Connected);
+    assert_eq!(daemon.xconf().xconf, xconf());
+    drop(daemon);
+    assert_ne!(agent.status(), Status::Connected);
+}
+
+#[test]
+fn read_event() {
+    let (mut agent, mut daemon) = connected_pair();
+    let window = agent
+        .create_window(&qubes_gui::Create {
+            rectangle: qubes_gui::Rectangle {
+                top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+                size: qubes_gui::WindowSize {
+                    width: 100,
+                    height: 100,
+                },
+            },
+            parent: None,
+            override_redirect: 0,
+        })
+        .unwrap();
+    match daemon.read_event() {
+        Poll::Ready(Ok((id, PeerEvent::FromAgent(event)))) => {
+            assert_eq!(id, window);
+            assert_eq!(event.kind(), qubes_gui::Msg::Create);
+        }
+        _ => panic!("expected a window to be created"),
+    }
+    daemon.send_raw(&[], window, qubes_gui::MSG_CLOSE).unwrap();
+    match agent.read_event() {
+        Poll::Ready(Ok((id, PeerEvent::FromDaemon(qubes_gui_agent_proto::Event::Close)))) => {
+            assert_eq!(id, window)
+        }
+        _ => panic!("expected a close request"),
+    }
+    assert!(agent.read_event().is_pending());
+}
+
+#[test]
+fn queue_limit() {
+    let connection = || {
+        let mock_vchan = MockVchan {
+            read_buf: vec![],
+            write_buf: vec![],
+            buffer_space: 0,
+            data_ready: 0,
+            cursor: 0,
+            sends: 0,
+        };
+        Connection::from_raw(RawMessageStream::<SharedMock> {
+            vchan: BufVchan::new(SharedMock(Rc::new(RefCell::new(mock_vchan)))),
+            queue: Default::default(),
+            state: ReadState::ReadingHeader,
+            buffer: vec![],
+            spare: vec![],
+            did_reconnect: false,
+            xconf: Default::default(),
+            kind: Kind::Agent,
+            version: qubes_gui::PROTOCOL_VERSION,
+            min_version: MIN_AGENT_VERSION,
+            capabilities: qubes_gui::Capabilities::ALL,
+            peer_capabilities: qubes_gui::Capabilities::ALL,
+            hook: None,
+            metrics: Default::default(),
+            coalescer: None,
+        })
+    };
+    let image = qubes_gui::ShmImage::default();
+    let configure = qubes_gui::Configure::default();
+    let image_len = size_of::<UntrustedHeader>() + size_of::<qubes_gui::ShmImage>();
+    let configure_len = size_of::<UntrustedHeader>() + size_of::<qubes_gui::Configure>();
+    let limit = 2 * image_len + 4;
+
+    let mut under_test = connection();
+    under_test.set_queue_limit(limit, OverflowPolicy::Error);
+    under_test.send(&image, 1.into()).unwrap();
+    under_test.send(&image, 1.into()).unwrap();
+    let err = under_test.send(&image, 1.into()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert_eq!(
+        err.get_ref().unwrap().downcast_ref::<QueueFull>(),
+        Some(&QueueFull {
+            queued: 2 * image_len,
+            len: image_len,
+            limit,
+        })
+    );
+    assert_eq!(under_test.queued_bytes(), 2 * image_len);
+    under_test.clear_queue_limit();
+    under_test.send(&image, 1.into()).unwrap();
+    assert_eq!(under_test.queued_bytes(), 3 * image_len);
+
+    let image_at = |x, y| qubes_gui::ShmImage {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x, y },
+            size: qubes_gui::WindowSize {
+                width: 10,
+                height: 10,
+            },
+        },
+    };
+    let mut under_test = connection();
+    let limit = 2 * image_len + configure_len;
+    under_test.set_queue_limit(limit, OverflowPolicy::DropFrameUpdates);
+    under_test.send(&image_at(0, 0), 1.into()).unwrap();
+    under_test.send(&configure, 1.into()).unwrap();
+    under_test.send(&image_at(20, 20), 1.into()).unwrap();
+    // Window 1's only image is never dropped to make room for window 2
+    let mut other = connection();
+    other.set_queue_limit(image_len, OverflowPolicy::DropFrameUpdates);
+    other.send(&image, 1.into()).unwrap();
+    let err = other.send(&image, 2.into()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert_eq!(other.metrics().dropped, 0);
+    // Drops the first image, folding it into the second
+    under_test.send(&image, 2.into()).unwrap();
+    assert_eq!(under_test.queued_bytes(), limit);
+    assert_eq!(under_test.metrics().dropped, 1);
+    let queue: Vec<u8> = under_test.raw.queue.iter().copied().collect();
+    let merged = &queue[configure_len..][..image_len];
+    assert_eq!(
+        &merged[size_of::<UntrustedHeader>()..],
+        qubes_gui::ShmImage {
+            rectangle: qubes_gui::Rectangle {
+                top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+                size: qubes_gui::WindowSize {
+                    width: 30,
+                    height: 30,
+                },
+            },
+        }
+        .as_bytes()
+    );
+    // Neither remaining image has a later one for its window, so both are kept
+    let err = under_test.send(&configure, 2.into()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert_eq!(under_test.queued_bytes(), limit);
+    assert_eq!(under_test.metrics().dropped, 1);
+    assert_eq!(
+        under_test.metrics().bytes_sent,
+        (2 * image_len + configure_len) as u64
+    );
+
+    // Blocking would also block the Reader, so a split connection fails
+    let mut under_test = connection();
+    under_test.set_queue_limit(image_len, OverflowPolicy::Block);
+    under_test.send(&image, 1.into()).unwrap();
+    let (_reader, mut writer) = under_test.split();
+    let err = writer.send(&image, 1.into()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    assert!(err.get_ref().unwrap().is::<QueueFull>());
+
+    // The coalescer relies on the rectangle count of queued damage
+    #[cfg(feature = "extensions")]
+    {
+        let mut under_test = connection();
+        for &(count, rectangles) in &[(0, 0), (2, 1), (1, 2)] {
+            let data = vec![0; rectangles * size_of::<qubes_gui::Rectangle>()];
+            let err = under_test
+                .send_with_data(&qubes_gui::DamageHeader { count }, &data, 1.into())
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        assert_eq!(under_test.queued_bytes(), 0);
+    }
+}
+
+#[test]
+fn incoming() {
+    let (mut agent, mut daemon) = connected_pair();
+    let motion = qubes_gui::Motion::default();
+    daemon.send(&motion, 1.into()).unwrap();
+    let (header, body) = agent.recv_message().unwrap();
+    assert_eq!(header.ty(), qubes_gui::MSG_MOTION);
+    assert_eq!(body, motion.as_bytes());
+    agent.recycle(body);
+    daemon.send(&motion, 1.into()).unwrap();
+    daemon.send(&motion, 2.into()).unwrap();
+    drop(daemon);
+    let windows: Vec<_> = agent
+        .incoming()
+        .map(|res| res.unwrap().0.untrusted_window())
+        .collect();
+    assert_eq!(windows, [1.into(), 2.into()]);
+    assert_eq!(
+        agent.recv_message().unwrap_err().kind(),
+        ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn transport_errors_are_recoverable() {
+    let (mut agent, daemon) = connected_pair();
+    assert_eq!(agent.error_kind(), None);
+    drop(daemon);
+    // The message stays queued, and the read that tries to flush it fails
+    assert!(agent.send(&qubes_gui::Unmap {}, 1.into()).is_err());
+    assert!(agent.queued_bytes() > 0);
+    match agent.read_message() {
+        Poll::Ready(Err(e)) => assert_eq!(e.kind(), ErrorKind::BrokenPipe),
+        _ => panic!("expected a transport error"),
+    }
+    assert_eq!(agent.error_kind(), Some(ReadErrorKind::Transport));
+    assert!(agent.needs_reconnect());
+    assert!(
+        matches!(agent.read_message(), Poll::Ready(Err(_))),
+        "expected the connection to stay failed"
+    );
+    assert_eq!(agent.error_kind(), Some(ReadErrorKind::Transport));
+}
+
+#[test]
+fn invalid_messages_are_terminal() {
+    let (mut agent, mut daemon) = connected_pair();
+    let create = qubes_gui::Create {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+            size: qubes_gui::WindowSize {
+                width: 100,
+                height: 100,
+            },
+        },
+        parent: None,
+        override_redirect: 2,
+    };
+    agent.send(&create, 1.into()).unwrap();
+    match daemon.read_event() {
+        Poll::Ready(Err(e)) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+        _ => panic!("invalid override_redirect accepted"),
+    }
+    assert_eq!(daemon.error_kind(), Some(ReadErrorKind::Protocol));
+    assert_eq!(daemon.metrics().protocol_errors, 1);
+    assert!(!daemon.needs_reconnect());
+    agent.send(&qubes_gui::Unmap {}, 1.into()).unwrap();
+    assert!(matches!(daemon.read_event(), Poll::Ready(Err(_))));
+    assert_eq!(daemon.error_kind(), Some(ReadErrorKind::Protocol));
+    assert_eq!(daemon.metrics().protocol_errors, 1);
+}
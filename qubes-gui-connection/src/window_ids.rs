@@ -0,0 +1,106 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Allocation of window IDs
+
+use std::collections::{HashSet, VecDeque};
+use std::num::NonZeroU32;
+
+/// The default number of destroyed IDs that are held back from reuse
+const DEFAULT_REUSE_DELAY: usize = 1024;
+
+/// Hands out unused window IDs.  IDs of destroyed windows are not reused
+/// until at least [`WindowIdAllocator::reuse_delay`] other windows have been
+/// destroyed, or fresh IDs run out, as late messages about a destroyed window
+/// would otherwise be taken to refer to its successor.
+#[derive(Debug, Clone)]
+pub struct WindowIdAllocator {
+    /// IDs that have been allocated and not released
+    in_use: HashSet<NonZeroU32>,
+    /// Released IDs, oldest first
+    released: VecDeque<NonZeroU32>,
+    /// The next ID that has never been allocated, if any
+    next: Option<NonZeroU32>,
+    /// Number of released IDs held back from reuse
+    reuse_delay: usize,
+}
+
+impl Default for WindowIdAllocator {
+    fn default() -> Self {
+        Self::with_reuse_delay(DEFAULT_REUSE_DELAY)
+    }
+}
+
+impl WindowIdAllocator {
+    /// Creates an allocator with the default reuse delay
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an allocator that holds back the `reuse_delay` most recently
+    /// released IDs from reuse
+    pub fn with_reuse_delay(reuse_delay: usize) -> Self {
+        Self {
+            in_use: HashSet::new(),
+            released: VecDeque::new(),
+            next: NonZeroU32::new(1),
+            reuse_delay,
+        }
+    }
+
+    /// The number of released IDs held back from reuse
+    pub fn reuse_delay(&self) -> usize {
+        self.reuse_delay
+    }
+
+    /// Allocates an ID that is not in use.  Returns [`None`] if every ID is in
+    /// use.
+    pub fn allocate(&mut self) -> Option<qubes_gui::WindowID> {
+        let id = if self.released.len() > self.reuse_delay {
+            self.released.pop_front()
+        } else if let Some(next) = self.next {
+            self.next = NonZeroU32::new(next.get().wrapping_add(1));
+            Some(next)
+        } else {
+            // Fresh IDs have run out, so reuse must not be delayed further.
+            self.released.pop_front()
+        }?;
+        assert!(self.in_use.insert(id), "allocated an ID in use");
+        Some(qubes_gui::WindowID { window: Some(id) })
+    }
+
+    /// Releases an ID, so that it can be reused after a delay.  Returns
+    /// `false`, and does nothing, if `window` was not allocated.
+    pub fn release(&mut self, window: qubes_gui::WindowID) -> bool {
+        match window.window {
+            Some(id) if self.in_use.remove(&id) => {
+                self.released.push_back(id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if `window` has been allocated and not released
+    pub fn is_allocated(&self, window: qubes_gui::WindowID) -> bool {
+        matches!(window.window, Some(id) if self.in_use.contains(&id))
+    }
+}
@@ -0,0 +1,366 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Daemon-side mapping of window dumps.
+//!
+//! An agent shares a window’s contents by granting the pages of its buffer to
+//! the GUI daemon’s domain and sending the grant refs in a
+//! [`qubes_gui::MSG_WINDOW_DUMP`] message.  This crate maps those grants
+//! read-only with `/dev/xen/gntdev`, so the daemon can display them.
+
+#![forbid(clippy::all, improper_ctypes, improper_ctypes_definitions)]
+
+mod sys;
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The path to the gntdev device
+pub const GNTDEV_PATH: &str = "/dev/xen/gntdev";
+
+/// A handle to `/dev/xen/gntdev`
+#[derive(Debug, Clone)]
+pub struct Gntdev {
+    file: Arc<File>,
+}
+
+impl Gntdev {
+    /// Opens `/dev/xen/gntdev`
+    pub fn open() -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(GNTDEV_PATH)?;
+        Ok(Self {
+            file: Arc::new(file),
+        })
+    }
+
+    /// Maps the grant refs of a window dump sent by domain `domid`.
+    pub fn map_window_dump(
+        &self,
+        domid: u16,
+        dump: &qubes_gui::WindowDumpBody<'_>,
+    ) -> io::Result<MappedBuffer> {
+        let refs: Vec<u32> = dump.grants().collect();
+        let (index, ptr) = self.map_grants(domid, &refs)?;
+        let header = dump.header();
+        Ok(MappedBuffer {
+            file: self.file.clone(),
+            ptr,
+            index,
+            pages: refs.len() as u32,
+            width: header.width,
+            height: header.height,
+        })
+    }
+
+    /// Maps `refs` read-only, returning the gntdev index and the address of
+    /// the mapping.  `refs` must not be empty.
+    fn map_grants(&self, domid: u16, refs: &[u32]) -> io::Result<(u64, NonNull<u8>)> {
+        let fd = self.file.as_raw_fd();
+        let len = refs.len() * qubes_gui::XC_PAGE_SIZE as usize;
+        // The argument is a MapGrantRef followed by the grant refs.  Use u64
+        // storage so that it is suitably aligned.
+        const WORDS: usize = std::mem::size_of::<sys::MapGrantRef>() / 8;
+        let mut arg = vec![0u64; WORDS + refs.len()];
+        let header = arg.as_mut_ptr() as *mut sys::MapGrantRef;
+        // SAFETY: `arg` is large enough and aligned for a MapGrantRef and
+        // `refs.len()` GrantRefs, which are 8 bytes each.
+        let index = unsafe {
+            (*header).count = refs.len() as u32;
+            let grants = header.add(1) as *mut sys::GrantRef;
+            for (i, &r#ref) in refs.iter().enumerate() {
+                grants.add(i).write(sys::GrantRef {
+                    domid: domid.into(),
+                    r#ref,
+                })
+            }
+            if sys::ioctl(fd, sys::IOCTL_GNTDEV_MAP_GRANT_REF, header) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            (*header).index
+        };
+        let unmap = || {
+            let mut arg = sys::UnmapGrantRef {
+                index,
+                count: refs.len() as u32,
+                pad: 0,
+            };
+            // SAFETY: the argument is valid
+            unsafe { sys::ioctl(fd, sys::IOCTL_GNTDEV_UNMAP_GRANT_REF, &mut arg) };
+        };
+        let offset = match std::convert::TryFrom::try_from(index) {
+            Ok(offset) => offset,
+            Err(_) => {
+                unmap();
+                return Err(io::Error::other("gntdev index does not fit in off_t"));
+            }
+        };
+        // SAFETY: mapping a gntdev index that was just returned by the kernel
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ,
+                sys::MAP_SHARED,
+                fd,
+                offset,
+            )
+        };
+        if ptr == sys::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            unmap();
+            return Err(err);
+        }
+        Ok((
+            index,
+            NonNull::new(ptr as *mut u8).expect("mmap() returned NULL"),
+        ))
+    }
+}
+
+/// Copies `buf.len()` bytes starting at `src` into `buf`, using relaxed
+/// atomic loads of whole words where `src` is aligned and of single bytes
+/// elsewhere.
+///
+/// The other domain can write to the mapping at any time.  A plain read that
+/// races with such a write is a data race, which is undefined behavior, and
+/// lets the compiler assume that the memory does not change while it is
+/// read.  A race between atomic accesses is not undefined behavior: each
+/// load returns some value that was written, and the copy as a whole may
+/// mix old and new contents, which is harmless for untrusted pixel data.
+/// Relaxed loads of lock-free atomics no larger than a pointer are also
+/// allowed on read-only memory, such as this `PROT_READ` mapping.
+///
+/// # Safety
+///
+/// `src..src + buf.len()` must be mapped and readable for the whole call.
+unsafe fn copy_from_shared(src: *const u8, buf: &mut [u8]) {
+    const WORD: usize = std::mem::size_of::<usize>();
+    let byte = |i: usize| (*(src.add(i) as *const AtomicU8)).load(Ordering::Relaxed);
+    let head = src.align_offset(WORD).min(buf.len());
+    for (i, dst) in buf[..head].iter_mut().enumerate() {
+        *dst = byte(i)
+    }
+    let mut i = head;
+    for chunk in buf[head..].chunks_exact_mut(WORD) {
+        let word = (*(src.add(i) as *const AtomicUsize)).load(Ordering::Relaxed);
+        chunk.copy_from_slice(&word.to_ne_bytes());
+        i += WORD;
+    }
+    for (j, dst) in buf[i..].iter_mut().enumerate() {
+        *dst = byte(i + j)
+    }
+}
+
+/// A window dump mapped read-only from another domain.  The mapping and the
+/// grants are released when this is dropped.
+///
+/// The other domain can write to the buffer at any time, so its contents are
+/// only ever copied out with atomic loads (see `copy_from_shared`), never
+/// borrowed.
+#[derive(Debug)]
+pub struct MappedBuffer {
+    file: Arc<File>,
+    ptr: NonNull<u8>,
+    index: u64,
+    pages: u32,
+    width: u32,
+    height: u32,
+}
+
+// SAFETY: the mapping is only read with atomic loads, which may race with
+// any other access (see `copy_from_shared`), and the gntdev ioctls can be
+// used from any thread.
+unsafe impl Send for MappedBuffer {}
+// SAFETY: as for `Send`: concurrent reads through `&self` are atomic loads,
+// and nothing mutates the mapping through `&self`.
+unsafe impl Sync for MappedBuffer {}
+
+impl MappedBuffer {
+    /// The width of the window dump, in pixels
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// The height of the window dump, in pixels
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The number of bytes per row
+    pub fn stride(&self) -> usize {
+        self.width as usize * (qubes_gui::DUMMY_DRV_FB_BPP / 8) as usize
+    }
+
+    /// The number of bytes of pixel data.  This excludes the padding at the
+    /// end of the last page.
+    pub fn len(&self) -> usize {
+        self.stride() * self.height as usize
+    }
+
+    /// Returns `true` if there is no pixel data.  This never happens, as
+    /// window dumps cannot be empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies `buf.len()` bytes starting at `offset` into `buf`.  Returns an
+    /// error of kind [`io::ErrorKind::InvalidInput`] if the range is out of
+    /// bounds.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        match offset.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => {
+                // SAFETY: the range is in bounds of the mapping, which lives
+                // as long as `self`.
+                unsafe { copy_from_shared(self.ptr.as_ptr().add(offset), buf) }
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "read out of bounds of window dump",
+            )),
+        }
+    }
+
+    /// Copies row `y` into `buf`, which must be exactly [`Self::stride`]
+    /// bytes long.
+    pub fn read_row(&self, y: u32, buf: &mut [u8]) -> io::Result<()> {
+        if y >= self.height || buf.len() != self.stride() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bad row or buffer size",
+            ));
+        }
+        self.read(y as usize * self.stride(), buf)
+    }
+}
+
+impl Drop for MappedBuffer {
+    fn drop(&mut self) {
+        let len = self.pages as usize * qubes_gui::XC_PAGE_SIZE as usize;
+        let mut arg = sys::UnmapGrantRef {
+            index: self.index,
+            count: self.pages,
+            pad: 0,
+        };
+        // SAFETY: unmapping our own mapping, then releasing its grants
+        unsafe {
+            sys::munmap(self.ptr.as_ptr() as *mut _, len);
+            sys::ioctl(
+                self.file.as_raw_fd(),
+                sys::IOCTL_GNTDEV_UNMAP_GRANT_REF,
+                &mut arg,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_from_shared_alignments() {
+        let src: Vec<u8> = (0..64).collect();
+        for start in 0..16 {
+            for len in 0..(64 - start) {
+                let mut buf = vec![0xFF; len];
+                // SAFETY: the range is in bounds of `src`
+                unsafe { copy_from_shared(src.as_ptr().add(start), &mut buf) }
+                assert_eq!(buf, src[start..start + len]);
+            }
+        }
+    }
+
+    /// A 3×2 window dump in anonymous memory, which `Drop` can unmap.  The
+    /// unmap ioctl on `/dev/null` fails, which is ignored.
+    fn buffer() -> MappedBuffer {
+        const PROT_WRITE: std::os::raw::c_int = 2;
+        const MAP_PRIVATE: std::os::raw::c_int = 2;
+        const MAP_ANONYMOUS: std::os::raw::c_int = 0x20;
+        let len = qubes_gui::XC_PAGE_SIZE as usize;
+        // SAFETY: creating a new private mapping
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(ptr, sys::MAP_FAILED);
+        let ptr = NonNull::new(ptr as *mut u8).unwrap();
+        for i in 0..len {
+            // SAFETY: in bounds of the mapping, which is writable
+            unsafe { ptr.as_ptr().add(i).write(i as u8) }
+        }
+        MappedBuffer {
+            file: Arc::new(File::open("/dev/null").unwrap()),
+            ptr,
+            index: 0,
+            pages: 1,
+            width: 3,
+            height: 2,
+        }
+    }
+
+    #[test]
+    fn read_bounds() {
+        let buffer = buffer();
+        assert_eq!((buffer.stride(), buffer.len()), (12, 24));
+        let mut buf = [0; 24];
+        buffer.read(0, &mut buf).unwrap();
+        assert!(buf.iter().enumerate().all(|(i, &b)| b == i as u8));
+        buffer.read(20, &mut buf[..4]).unwrap();
+        assert_eq!(buf[..4], [20, 21, 22, 23]);
+        buffer.read(24, &mut []).unwrap();
+        // The rest of the page is padding, which cannot be read
+        let kind = |res: io::Result<()>| res.unwrap_err().kind();
+        assert_eq!(
+            kind(buffer.read(21, &mut buf[..4])),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(kind(buffer.read(25, &mut [])), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            kind(buffer.read(usize::MAX, &mut buf[..1])),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn read_row_bounds() {
+        let buffer = buffer();
+        let mut row = [0; 12];
+        buffer.read_row(1, &mut row).unwrap();
+        assert_eq!(row[0], 12);
+        assert!(buffer.read_row(2, &mut row).is_err());
+        assert!(buffer.read_row(0, &mut row[..11]).is_err());
+        assert!(buffer.read_row(0, &mut [0; 13]).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The gntdev ioctl interface (`<xen/gntdev.h>`) and the few libc functions
+//! this crate needs.
+
+use std::os::raw::{c_int, c_long, c_ulong, c_void};
+
+/// One grant to map
+#[repr(C)]
+pub(crate) struct GrantRef {
+    pub domid: u32,
+    pub r#ref: u32,
+}
+
+/// Argument of [`IOCTL_GNTDEV_MAP_GRANT_REF`].  It is followed by `count`
+/// [`GrantRef`]s.
+#[repr(C)]
+pub(crate) struct MapGrantRef {
+    pub count: u32,
+    pub pad: u32,
+    /// Set by the kernel to the offset to pass to `mmap()`
+    pub index: u64,
+}
+
+/// Argument of [`IOCTL_GNTDEV_UNMAP_GRANT_REF`]
+#[repr(C)]
+pub(crate) struct UnmapGrantRef {
+    pub index: u64,
+    pub count: u32,
+    pub pad: u32,
+}
+
+/// `_IOC(_IOC_NONE, 'G', nr, size)`
+const fn gntdev_ioc(nr: c_ulong, size: usize) -> c_ulong {
+    (size as c_ulong) << 16 | (b'G' as c_ulong) << 8 | nr
+}
+
+// The kernel’s struct has a one-element array of grant refs at the end.
+pub(crate) const IOCTL_GNTDEV_MAP_GRANT_REF: c_ulong = gntdev_ioc(
+    0,
+    std::mem::size_of::<MapGrantRef>() + std::mem::size_of::<GrantRef>(),
+);
+pub(crate) const IOCTL_GNTDEV_UNMAP_GRANT_REF: c_ulong =
+    gntdev_ioc(1, std::mem::size_of::<UnmapGrantRef>());
+
+pub(crate) const PROT_READ: c_int = 1;
+pub(crate) const MAP_SHARED: c_int = 1;
+pub(crate) const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    pub(crate) fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    pub(crate) fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: c_long,
+    ) -> *mut c_void;
+    pub(crate) fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
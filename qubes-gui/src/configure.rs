@@ -0,0 +1,124 @@
+/*
+ * The Qubes OS Project, http://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Agent-side tracking of [`MSG_CONFIGURE`](crate::MSG_CONFIGURE) messages.
+
+use crate::{Configure, Rectangle};
+
+/// Tracks the geometry of one window, and decides which [`Configure`]
+/// messages an agent needs to send.
+///
+/// When the daemon sends a [`Configure`] message, the agent must apply the
+/// new geometry and then echo the message back.  When the agent changes the
+/// geometry itself, it sends a [`Configure`] message, which the daemon may
+/// echo.  Echoing an echo causes an endless loop, and echoing every
+/// intermediate size of an interactive resize is wasteful.  This type
+/// prevents both:
+///
+/// - Call [`ConfigureTracker::daemon_configure`] for every [`Configure`]
+///   message from the daemon.  Messages that only confirm the current
+///   geometry are ignored, and a message that arrives before the previous one
+///   was applied replaces it.
+/// - Once the pending geometry has been applied, call
+///   [`ConfigureTracker::take_echo`] and send the result.
+/// - Call [`ConfigureTracker::agent_configure`] when the agent changes the
+///   geometry itself, and send the result, if any.
+///
+/// ```
+/// use qubes_gui::{Configure, ConfigureTracker, Rectangle};
+/// let mut tracker = ConfigureTracker::new();
+/// let mut conf = Configure::default();
+/// conf.rectangle.size.width = 100;
+/// conf.rectangle.size.height = 100;
+/// assert_eq!(tracker.agent_configure(conf), Some(conf));
+/// // The daemon echoes the agent’s message, which must not be echoed again
+/// tracker.daemon_configure(conf);
+/// assert_eq!(tracker.take_echo(), None);
+/// // Two resizes from the daemon are coalesced into one echo
+/// conf.rectangle.size.width = 150;
+/// tracker.daemon_configure(conf);
+/// conf.rectangle.size.width = 200;
+/// tracker.daemon_configure(conf);
+/// assert_eq!(tracker.pending().map(|c| c.rectangle.size.width), Some(200));
+/// assert_eq!(tracker.take_echo(), Some(conf));
+/// assert_eq!(tracker.geometry().map(|r| r.size.width), Some(200));
+/// ```
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ConfigureTracker {
+    /// The last [`Configure`] message sent by the agent
+    sent: Option<Configure>,
+    /// A [`Configure`] message from the daemon that has not been echoed
+    pending: Option<Configure>,
+}
+
+impl ConfigureTracker {
+    /// Creates a tracker for a window that has not been configured yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a [`Configure`] message from the daemon.  It replaces any
+    /// message that has not been echoed yet.  A message that matches the last
+    /// one sent by the agent is a confirmation, and does not need to be
+    /// echoed.
+    pub fn daemon_configure(&mut self, configure: Configure) {
+        self.pending = if self.sent == Some(configure) {
+            None
+        } else {
+            Some(configure)
+        }
+    }
+
+    /// Returns the [`Configure`] message from the daemon that has yet to be
+    /// applied, if any.
+    pub fn pending(&self) -> Option<Configure> {
+        self.pending
+    }
+
+    /// Returns the [`Configure`] message that must be echoed to the daemon,
+    /// if any.  Call this once the geometry from [`ConfigureTracker::pending`]
+    /// has been applied; the message is then considered sent.
+    pub fn take_echo(&mut self) -> Option<Configure> {
+        let configure = self.pending.take()?;
+        self.sent = Some(configure);
+        Some(configure)
+    }
+
+    /// Records that the agent wants to change the geometry of the window, and
+    /// returns the [`Configure`] message to send, or [`None`] if the daemon
+    /// already has this geometry.  Any unapplied message from the daemon is
+    /// discarded, as the agent’s message supersedes it.
+    pub fn agent_configure(&mut self, configure: Configure) -> Option<Configure> {
+        self.pending = None;
+        if self.sent == Some(configure) {
+            None
+        } else {
+            self.sent = Some(configure);
+            Some(configure)
+        }
+    }
+
+    /// The last geometry sent to the daemon, or [`None`] if none has been
+    /// sent yet.
+    pub fn geometry(&self) -> Option<Rectangle> {
+        self.sent.map(|configure| configure.rectangle)
+    }
+}
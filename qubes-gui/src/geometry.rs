@@ -0,0 +1,192 @@
+/*
+ * The Qubes OS Project, http://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Geometry helpers for [`Coordinates`] and [`Rectangle`].
+//!
+//! All arithmetic is done in [`i64`], so none of these functions can
+//! overflow.  A rectangle is valid if its right and bottom edges (which are
+//! exclusive) fit in an [`i32`]; functions that could produce an invalid
+//! rectangle return [`None`] instead.
+
+use crate::{Coordinates, Rectangle, WindowSize};
+use core::convert::TryFrom;
+
+/// Error indicating that a rectangle extends past the coordinate space
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BadRectangleError;
+
+impl core::fmt::Display for BadRectangleError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Rectangle extends past the coordinate space")
+    }
+}
+
+impl Coordinates {
+    /// Adds `other` to `self`, returning [`None`] on overflow.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        Some(Self {
+            x: self.x.checked_add(other.x)?,
+            y: self.y.checked_add(other.y)?,
+        })
+    }
+
+    /// Subtracts `other` from `self`, returning [`None`] on overflow.
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        Some(Self {
+            x: self.x.checked_sub(other.x)?,
+            y: self.y.checked_sub(other.y)?,
+        })
+    }
+}
+
+impl Rectangle {
+    /// Creates a rectangle from its edges, which must be in order.  Returns
+    /// [`None`] if the result would not be valid.
+    fn from_edges(left: i64, top: i64, right: i64, bottom: i64) -> Option<Self> {
+        debug_assert!(left <= right && top <= bottom);
+        let rectangle = Self {
+            top_left: Coordinates {
+                x: i32::try_from(left).ok()?,
+                y: i32::try_from(top).ok()?,
+            },
+            size: WindowSize {
+                width: u32::try_from(right - left).ok()?,
+                height: u32::try_from(bottom - top).ok()?,
+            },
+        };
+        if rectangle.is_valid() {
+            Some(rectangle)
+        } else {
+            None
+        }
+    }
+
+    /// The x coordinate of the left edge
+    pub fn left(&self) -> i64 {
+        self.top_left.x.into()
+    }
+
+    /// The y coordinate of the top edge
+    pub fn top(&self) -> i64 {
+        self.top_left.y.into()
+    }
+
+    /// The x coordinate just past the right edge
+    pub fn right(&self) -> i64 {
+        self.left() + i64::from(self.size.width)
+    }
+
+    /// The y coordinate just past the bottom edge
+    pub fn bottom(&self) -> i64 {
+        self.top() + i64::from(self.size.height)
+    }
+
+    /// Returns true if the right and bottom edges fit in an [`i32`].
+    pub fn is_valid(&self) -> bool {
+        self.right() <= i32::MAX.into() && self.bottom() <= i32::MAX.into()
+    }
+
+    /// Returns true if the rectangle has zero area.
+    pub fn is_empty(&self) -> bool {
+        self.size.width == 0 || self.size.height == 0
+    }
+
+    /// Returns true if the point is inside the rectangle.
+    pub fn contains(&self, point: Coordinates) -> bool {
+        let (x, y) = (i64::from(point.x), i64::from(point.y));
+        x >= self.left() && y >= self.top() && x < self.right() && y < self.bottom()
+    }
+
+    /// Returns the intersection of two rectangles, or [`None`] if they do not
+    /// overlap.
+    pub fn intersect(&self, other: &Self) -> Option<Self> {
+        let left = self.left().max(other.left());
+        let top = self.top().max(other.top());
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if left >= right || top >= bottom {
+            None
+        } else {
+            Self::from_edges(left, top, right, bottom)
+        }
+    }
+
+    /// Returns the smallest rectangle containing both rectangles.  Empty
+    /// rectangles are ignored.  Returns [`None`] if the result would not be
+    /// valid.
+    pub fn union(&self, other: &Self) -> Option<Self> {
+        if other.is_empty() {
+            Some(*self)
+        } else if self.is_empty() {
+            Some(*other)
+        } else {
+            Self::from_edges(
+                self.left().min(other.left()),
+                self.top().min(other.top()),
+                self.right().max(other.right()),
+                self.bottom().max(other.bottom()),
+            )
+        }
+    }
+
+    /// Clips the rectangle to a window of the given size, with its top left
+    /// corner at the origin.  Returns [`None`] if nothing is left.
+    ///
+    /// ```
+    /// use std::convert::TryFrom;
+    /// use qubes_gui::{Rectangle, WindowSize};
+    /// let r = Rectangle::try_from((-10, 5, 100, 100)).unwrap();
+    /// let clamped = r.clamp_to(WindowSize { width: 50, height: 50 }).unwrap();
+    /// assert_eq!(<(i32, i32, u32, u32)>::from(clamped), (0, 5, 50, 45));
+    /// assert!(Rectangle::try_from((i32::MAX, 0, 1, 1)).is_err());
+    /// ```
+    pub fn clamp_to(&self, size: WindowSize) -> Option<Self> {
+        self.intersect(&Self {
+            top_left: Coordinates { x: 0, y: 0 },
+            size,
+        })
+    }
+}
+
+impl TryFrom<(i32, i32, u32, u32)> for Rectangle {
+    type Error = BadRectangleError;
+
+    /// Converts an `(x, y, width, height)` tuple to a rectangle, failing if
+    /// the rectangle would not be valid.
+    fn try_from((x, y, width, height): (i32, i32, u32, u32)) -> Result<Self, Self::Error> {
+        let rectangle = Self {
+            top_left: Coordinates { x, y },
+            size: WindowSize { width, height },
+        };
+        if rectangle.is_valid() {
+            Ok(rectangle)
+        } else {
+            Err(BadRectangleError)
+        }
+    }
+}
+
+impl From<Rectangle> for (i32, i32, u32, u32) {
+    fn from(rectangle: Rectangle) -> Self {
+        let Rectangle { top_left, size } = rectangle;
+        (top_left.x, top_left.y, size.width, size.height)
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ * The Qubes OS Project, http://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Translation of X11 keycodes to keysyms and characters.
+//!
+//! The GUI daemon sends raw X11 keycodes in [`crate::Keypress`] messages.  On
+//! all supported GUI daemons, these are evdev keycodes offset by 8, as
+//! produced by the XKB `evdev` keycodes file.  This module maps them to X11
+//! keysyms, and keysyms to Unicode characters, without depending on any X11
+//! libraries.
+//!
+//! Only the keymap layouts listed in [`Layout`] are supported.  Agents that need
+//! arbitrary layouts must use a real XKB implementation.
+
+use crate::ModifierState;
+
+/// Offset between evdev keycodes and X11 keycodes
+pub const EVDEV_OFFSET: u32 = 8;
+
+/// Keysym for the Backspace key
+pub const XK_BACKSPACE: u32 = 0xff08;
+/// Keysym for the Tab key
+pub const XK_TAB: u32 = 0xff09;
+/// Keysym for the Return key
+pub const XK_RETURN: u32 = 0xff0d;
+/// Keysym for the Pause key
+pub const XK_PAUSE: u32 = 0xff13;
+/// Keysym for the Scroll Lock key
+pub const XK_SCROLL_LOCK: u32 = 0xff14;
+/// Keysym for the Escape key
+pub const XK_ESCAPE: u32 = 0xff1b;
+/// Keysym for the Home key
+pub const XK_HOME: u32 = 0xff50;
+/// Keysym for the Left arrow key
+pub const XK_LEFT: u32 = 0xff51;
+/// Keysym for the Up arrow key
+pub const XK_UP: u32 = 0xff52;
+/// Keysym for the Right arrow key
+pub const XK_RIGHT: u32 = 0xff53;
+/// Keysym for the Down arrow key
+pub const XK_DOWN: u32 = 0xff54;
+/// Keysym for the Page Up key
+pub const XK_PRIOR: u32 = 0xff55;
+/// Keysym for the Page Down key
+pub const XK_NEXT: u32 = 0xff56;
+/// Keysym for the End key
+pub const XK_END: u32 = 0xff57;
+/// Keysym for the Print Screen key
+pub const XK_PRINT: u32 = 0xff61;
+/// Keysym for the Insert key
+pub const XK_INSERT: u32 = 0xff63;
+/// Keysym for the Menu key
+pub const XK_MENU: u32 = 0xff67;
+/// Keysym for the Num Lock key
+pub const XK_NUM_LOCK: u32 = 0xff7f;
+/// Keysym for the keypad Enter key
+pub const XK_KP_ENTER: u32 = 0xff8d;
+/// Keysym for keypad Home (keypad 7 without Num Lock)
+pub const XK_KP_HOME: u32 = 0xff95;
+/// Keysym for keypad Left (keypad 4 without Num Lock)
+pub const XK_KP_LEFT: u32 = 0xff96;
+/// Keysym for keypad Up (keypad 8 without Num Lock)
+pub const XK_KP_UP: u32 = 0xff97;
+/// Keysym for keypad Right (keypad 6 without Num Lock)
+pub const XK_KP_RIGHT: u32 = 0xff98;
+/// Keysym for keypad Down (keypad 2 without Num Lock)
+pub const XK_KP_DOWN: u32 = 0xff99;
+/// Keysym for keypad Page Up (keypad 9 without Num Lock)
+pub const XK_KP_PRIOR: u32 = 0xff9a;
+/// Keysym for keypad Page Down (keypad 3 without Num Lock)
+pub const XK_KP_NEXT: u32 = 0xff9b;
+/// Keysym for keypad End (keypad 1 without Num Lock)
+pub const XK_KP_END: u32 = 0xff9c;
+/// Keysym for keypad Begin (keypad 5 without Num Lock)
+pub const XK_KP_BEGIN: u32 = 0xff9d;
+/// Keysym for keypad Insert (keypad 0 without Num Lock)
+pub const XK_KP_INSERT: u32 = 0xff9e;
+/// Keysym for keypad Delete (keypad decimal point without Num Lock)
+pub const XK_KP_DELETE: u32 = 0xff9f;
+/// Keysym for keypad `*`
+pub const XK_KP_MULTIPLY: u32 = 0xffaa;
+/// Keysym for keypad `+`
+pub const XK_KP_ADD: u32 = 0xffab;
+/// Keysym for keypad `-`
+pub const XK_KP_SUBTRACT: u32 = 0xffad;
+/// Keysym for keypad `.`
+pub const XK_KP_DECIMAL: u32 = 0xffae;
+/// Keysym for keypad `/`
+pub const XK_KP_DIVIDE: u32 = 0xffaf;
+/// Keysym for keypad `0`.  Keypad `1` through `9` follow consecutively.
+pub const XK_KP_0: u32 = 0xffb0;
+/// Keysym for F1.  F2 through F12 follow consecutively.
+pub const XK_F1: u32 = 0xffbe;
+/// Keysym for the left Shift key
+pub const XK_SHIFT_L: u32 = 0xffe1;
+/// Keysym for the right Shift key
+pub const XK_SHIFT_R: u32 = 0xffe2;
+/// Keysym for the left Control key
+pub const XK_CONTROL_L: u32 = 0xffe3;
+/// Keysym for the right Control key
+pub const XK_CONTROL_R: u32 = 0xffe4;
+/// Keysym for the Caps Lock key
+pub const XK_CAPS_LOCK: u32 = 0xffe5;
+/// Keysym for the left Alt key
+pub const XK_ALT_L: u32 = 0xffe9;
+/// Keysym for the right Alt key
+pub const XK_ALT_R: u32 = 0xffea;
+/// Keysym for the left Super key
+pub const XK_SUPER_L: u32 = 0xffeb;
+/// Keysym for the right Super key
+pub const XK_SUPER_R: u32 = 0xffec;
+/// Keysym for the Delete key
+pub const XK_DELETE: u32 = 0xffff;
+
+/// A keymap layout
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Layout {
+    /// The standard US QWERTY layout (XKB `us`)
+    Us,
+}
+
+/// Keysyms for a single key: unshifted and shifted
+#[derive(Copy, Clone)]
+enum Key {
+    /// A key whose keysym does not depend on the modifier state
+    Plain(u32),
+    /// A symbol key: unshifted and shifted keysyms
+    Symbol(u32, u32),
+    /// A letter key: the lowercase Latin-1 keysym.  Affected by both Shift and
+    /// Caps Lock.
+    Letter(u8),
+    /// A keypad key: keysyms with and without Num Lock
+    Keypad(u32, u32),
+}
+
+fn us_key(keycode: u32) -> Option<Key> {
+    use Key::{Keypad, Letter, Plain, Symbol};
+    const NUMBER_ROW: &[u8; 10] = b"1234567890";
+    const NUMBER_ROW_SHIFTED: &[u8; 10] = b"!@#$%^&*()";
+    const TOP_ROW: &[u8; 10] = b"qwertyuiop";
+    const HOME_ROW: &[u8; 9] = b"asdfghjkl";
+    const BOTTOM_ROW: &[u8; 7] = b"zxcvbnm";
+    const KEYPAD: [(u32, u32); 13] = [
+        (XK_KP_0 + 7, XK_KP_HOME),
+        (XK_KP_0 + 8, XK_KP_UP),
+        (XK_KP_0 + 9, XK_KP_PRIOR),
+        (XK_KP_SUBTRACT, XK_KP_SUBTRACT),
+        (XK_KP_0 + 4, XK_KP_LEFT),
+        (XK_KP_0 + 5, XK_KP_BEGIN),
+        (XK_KP_0 + 6, XK_KP_RIGHT),
+        (XK_KP_ADD, XK_KP_ADD),
+        (XK_KP_0 + 1, XK_KP_END),
+        (XK_KP_0 + 2, XK_KP_DOWN),
+        (XK_KP_0 + 3, XK_KP_NEXT),
+        (XK_KP_0, XK_KP_INSERT),
+        (XK_KP_DECIMAL, XK_KP_DELETE),
+    ];
+    let sym = |c: u8| u32::from(c);
+    Some(match keycode {
+        9 => Plain(XK_ESCAPE),
+        10..=19 => {
+            let i = (keycode - 10) as usize;
+            Symbol(sym(NUMBER_ROW[i]), sym(NUMBER_ROW_SHIFTED[i]))
+        }
+        20 => Symbol(sym(b'-'), sym(b'_')),
+        21 => Symbol(sym(b'='), sym(b'+')),
+        22 => Plain(XK_BACKSPACE),
+        23 => Plain(XK_TAB),
+        24..=33 => Letter(TOP_ROW[(keycode - 24) as usize]),
+        34 => Symbol(sym(b'['), sym(b'{')),
+        35 => Symbol(sym(b']'), sym(b'}')),
+        36 => Plain(XK_RETURN),
+        37 => Plain(XK_CONTROL_L),
+        38..=46 => Letter(HOME_ROW[(keycode - 38) as usize]),
+        47 => Symbol(sym(b';'), sym(b':')),
+        48 => Symbol(sym(b'\''), sym(b'"')),
+        49 => Symbol(sym(b'`'), sym(b'~')),
+        50 => Plain(XK_SHIFT_L),
+        51 => Symbol(sym(b'\\'), sym(b'|')),
+        52..=58 => Letter(BOTTOM_ROW[(keycode - 52) as usize]),
+        59 => Symbol(sym(b','), sym(b'<')),
+        60 => Symbol(sym(b'.'), sym(b'>')),
+        61 => Symbol(sym(b'/'), sym(b'?')),
+        62 => Plain(XK_SHIFT_R),
+        63 => Plain(XK_KP_MULTIPLY),
+        64 => Plain(XK_ALT_L),
+        65 => Plain(sym(b' ')),
+        66 => Plain(XK_CAPS_LOCK),
+        67..=76 => Plain(XK_F1 + (keycode - 67)),
+        77 => Plain(XK_NUM_LOCK),
+        78 => Plain(XK_SCROLL_LOCK),
+        79..=91 => {
+            let (with_num_lock, without_num_lock) = KEYPAD[(keycode - 79) as usize];
+            Keypad(with_num_lock, without_num_lock)
+        }
+        95 => Plain(XK_F1 + 10),
+        96 => Plain(XK_F1 + 11),
+        104 => Plain(XK_KP_ENTER),
+        105 => Plain(XK_CONTROL_R),
+        106 => Plain(XK_KP_DIVIDE),
+        107 => Plain(XK_PRINT),
+        108 => Plain(XK_ALT_R),
+        110 => Plain(XK_HOME),
+        111 => Plain(XK_UP),
+        112 => Plain(XK_PRIOR),
+        113 => Plain(XK_LEFT),
+        114 => Plain(XK_RIGHT),
+        115 => Plain(XK_END),
+        116 => Plain(XK_DOWN),
+        117 => Plain(XK_NEXT),
+        118 => Plain(XK_INSERT),
+        119 => Plain(XK_DELETE),
+        127 => Plain(XK_PAUSE),
+        133 => Plain(XK_SUPER_L),
+        134 => Plain(XK_SUPER_R),
+        135 => Plain(XK_MENU),
+        _ => return None,
+    })
+}
+
+impl Layout {
+    /// Translates an X11 keycode to a keysym, taking the Shift, Caps Lock, and
+    /// Num Lock (Mod2) modifiers into account.  Other modifiers do not affect
+    /// the result.
+    ///
+    /// Returns [`None`] if the keycode is not mapped in this layout.
+    pub fn keysym(self, keycode: u32, state: ModifierState) -> Option<u32> {
+        let key = match self {
+            Layout::Us => us_key(keycode)?,
+        };
+        let shift = state.shift();
+        Some(match key {
+            Key::Plain(sym) => sym,
+            Key::Symbol(unshifted, shifted) => {
+                if shift {
+                    shifted
+                } else {
+                    unshifted
+                }
+            }
+            Key::Letter(c) => {
+                if shift != state.contains(ModifierState::LOCK) {
+                    c.to_ascii_uppercase().into()
+                } else {
+                    c.into()
+                }
+            }
+            Key::Keypad(with_num_lock, without_num_lock) => {
+                // As in XKB, Shift inverts the effect of Num Lock
+                if shift != state.contains(ModifierState::MOD2) {
+                    with_num_lock
+                } else {
+                    without_num_lock
+                }
+            }
+        })
+    }
+
+    /// Translates an X11 keycode to the character it would type, if any.
+    /// This is [`Layout::keysym`] followed by [`keysym_to_char`].
+    ///
+    /// ```rust
+    /// # use qubes_gui::{keysym::Layout, ModifierState};
+    /// assert_eq!(Layout::Us.char(38, ModifierState::EMPTY), Some('a'));
+    /// assert_eq!(Layout::Us.char(38, ModifierState::SHIFT), Some('A'));
+    /// assert_eq!(Layout::Us.char(10, ModifierState::SHIFT), Some('!'));
+    /// assert_eq!(Layout::Us.char(87, ModifierState::MOD2), Some('1'));
+    /// assert_eq!(Layout::Us.char(87, ModifierState::EMPTY), None);
+    /// ```
+    pub fn char(self, keycode: u32, state: ModifierState) -> Option<char> {
+        self.keysym(keycode, state).and_then(keysym_to_char)
+    }
+}
+
+/// Translates a keysym to the character it represents, if any.
+///
+/// Latin-1 keysyms and Unicode keysyms (`0x01000000` plus the code point) are
+/// supported, as are keysyms for control characters (such as Return and
+/// Backspace) and for the numeric keypad.
+pub fn keysym_to_char(keysym: u32) -> Option<char> {
+    match keysym {
+        0x20..=0x7e | 0xa0..=0xff => char::from_u32(keysym),
+        0x0100_0100..=0x0110_ffff => char::from_u32(keysym - 0x0100_0000),
+        XK_BACKSPACE => Some('\x08'),
+        XK_TAB => Some('\t'),
+        XK_RETURN | XK_KP_ENTER => Some('\r'),
+        XK_ESCAPE => Some('\x1b'),
+        XK_DELETE => Some('\x7f'),
+        XK_KP_MULTIPLY => Some('*'),
+        XK_KP_ADD => Some('+'),
+        XK_KP_SUBTRACT => Some('-'),
+        XK_KP_DECIMAL => Some('.'),
+        XK_KP_DIVIDE => Some('/'),
+        0xffb0..=0xffb9 => char::from_u32(keysym - XK_KP_0 + u32::from(b'0')),
+        _ => None,
+    }
+}
+
+/// Translates an X11 keycode to an evdev keycode.  Returns [`None`] if the
+/// keycode is too small to have come from an evdev device.
+pub fn x11_to_evdev(keycode: u32) -> Option<u32> {
+    keycode.checked_sub(EVDEV_OFFSET)
+}
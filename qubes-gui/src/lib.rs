@@ -119,11 +119,30 @@
 
 use core::convert::TryFrom;
 use core::num::NonZeroU32;
+use core::ops::RangeInclusive;
 use core::result::Result;
 
+mod configure;
+mod geometry;
+#[cfg(feature = "keysym")]
+pub mod keysym;
+mod lifecycle;
+#[cfg(test)]
+mod tests;
+mod validated;
+pub use configure::ConfigureTracker;
+pub use geometry::BadRectangleError;
+pub use lifecycle::{BadTransitionError, Disposition, Peer, WindowLifecycle, WindowState};
+pub use validated::{
+    BadFieldError, ValidatedButton, ValidatedCrossing, ValidatedFocus, ValidatedKeypress,
+};
+
 /// Arbitrary maximum size of a clipboard message
 pub const MAX_CLIPBOARD_SIZE: u32 = 65000;
 
+/// Arbitrary maximum size of the data in a clipboard message with a MIME type
+pub const MAX_CLIPBOARD_MIME_DATA_SIZE: u32 = 1 << 20;
+
 /// Arbitrary max window height
 pub const MAX_WINDOW_HEIGHT: u32 = 6144;
 
@@ -139,6 +158,31 @@ pub const CURSOR_X11: u32 = 0x100;
 /// Max X11 cursor that can be requested
 pub const CURSOR_X11_MAX: u32 = 0x19a;
 
+/// Maximum width of a cursor image
+pub const MAX_CURSOR_WIDTH: u32 = 256;
+
+/// Maximum height of a cursor image
+pub const MAX_CURSOR_HEIGHT: u32 = 256;
+
+/// Maximum number of outputs (monitors) in a [`MSG_OUTPUTS`] message
+pub const MAX_OUTPUTS: u32 = 32;
+
+/// Maximum number of rectangles in a [`MSG_DAMAGE`] message
+pub const MAX_DAMAGE_RECTS: u32 = 256;
+
+/// Denominator of fixed-point scale factors.  A scale factor of
+/// `SCALE_DENOMINATOR` means no scaling.
+pub const SCALE_DENOMINATOR: u32 = 120;
+
+/// Flag set in [`Output::flags`] for the primary output
+pub const OUTPUT_PRIMARY: u32 = 1 << 0;
+
+/// Maximum width of a window icon
+pub const MAX_ICON_WIDTH: u32 = 512;
+
+/// Maximum height of a window icon
+pub const MAX_ICON_HEIGHT: u32 = 512;
+
 /// Bits-per-pixel of the dummy X11 framebuffer driver
 pub const DUMMY_DRV_FB_BPP: u32 = 32;
 
@@ -168,8 +212,23 @@ pub const PROTOCOL_VERSION_MAJOR: u32 = 1;
 /// The minor version of the protocol.
 pub const PROTOCOL_VERSION_MINOR: u32 = 7;
 
-/// The overall protocol version, as used on the wire.
-pub const PROTOCOL_VERSION: u32 = PROTOCOL_VERSION_MAJOR << 16 | PROTOCOL_VERSION_MINOR;
+/// The minor version of the protocol with the extensions of this library
+/// (versions 1.8 and later).  These versions, and the messages they add
+/// after [`MSG_WINDOW_DUMP_ACK`], are not part of the upstream protocol,
+/// which may assign the same numbers differently.  They are only enabled by
+/// the `extensions` feature, and must only be used if both peers use this
+/// library.
+#[cfg(feature = "extensions")]
+pub const EXTENSIONS_VERSION_MINOR: u32 = 15;
+
+#[cfg(not(feature = "extensions"))]
+const NEGOTIATED_MINOR: u32 = PROTOCOL_VERSION_MINOR;
+#[cfg(feature = "extensions")]
+const NEGOTIATED_MINOR: u32 = EXTENSIONS_VERSION_MINOR;
+
+/// The overall protocol version, as used on the wire.  This is
+/// [`PROTOCOL_VERSION_MINOR`], unless the `extensions` feature is enabled.
+pub const PROTOCOL_VERSION: u32 = PROTOCOL_VERSION_MAJOR << 16 | NEGOTIATED_MINOR;
 
 // This allows pattern-matching against constant values without a huge amount of
 // boilerplate code.
@@ -179,25 +238,69 @@ macro_rules! enum_const {
         $(#[$i: meta])*
         $p: vis enum $n: ident {
             $(
-                $(#[$j: meta])*
+                $(#[cfg($c: meta)])?
+                $(#[doc = $j: expr])*
                 ($const_name: ident, $variant_name: ident) $(= $e: expr)?
             ),*$(,)?
         }
     ) => {
         $(#[$i])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
         #[repr($t)]
         $p enum $n {
             $(
-                $(#[$j])*
+                $(#[cfg($c)])?
+                $(#[doc = $j])*
                 $variant_name $(= $e)?,
             )*
         }
 
         $(
-            $(#[$j])*
+            $(#[cfg($c)])?
+            $(#[doc = $j])*
             $p const $const_name: $t = $n::$variant_name as $t;
         )*
 
+        impl $n {
+            /// All values, in the order they are defined
+            pub const ALL: &'static [Self] = &[$($(#[cfg($c)])? $n::$variant_name,)*];
+
+            /// Returns an iterator over all values, in the order they are
+            /// defined.
+            pub fn iter() -> impl Iterator<Item = Self> {
+                Self::ALL.iter().copied()
+            }
+
+            /// Returns the symbolic name of this value, as used in the C
+            /// implementation.
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(
+                        $(#[cfg($c)])?
+                        $n::$variant_name => stringify!($const_name),
+                    )*
+                }
+            }
+
+            /// Parses a symbolic name, as returned by `name()`.  Returns
+            /// [`None`] if the name is not recognized.
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $(
+                        $(#[cfg($c)])?
+                        stringify!($const_name) => Some($n::$variant_name),
+                    )*
+                    _ => None,
+                }
+            }
+        }
+
+        impl core::fmt::Display for $n {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+
         impl $crate::TryFrom::<$t> for $n {
             type Error = $t;
             #[allow(non_upper_case_globals)]
@@ -205,6 +308,7 @@ macro_rules! enum_const {
             fn try_from(value: $t) -> $crate::Result<Self, $t> {
                 match value {
                     $(
+                        $(#[cfg($c)])?
                         $const_name => return $crate::Result::Ok($n::$variant_name),
                     )*
                     other => $crate::Result::Err(other),
@@ -229,10 +333,11 @@ enum_const! {
         (MSG_CROSSING, Crossing),
         /// Daemon ⇒ agent: A window has just acquired focus.
         (MSG_FOCUS, Focus),
+        #[cfg(feature = "legacy-messages")]
         /// Daemon ⇒ agent, obsolete.
-        (MSG_RESIZE, Resize),
+        (MSG_RESIZE, Resize) = 129,
         /// Agent ⇒ daemon: Creates a window.
-        (MSG_CREATE, Create),
+        (MSG_CREATE, Create) = 130,
         /// Agent ⇒ daemon: Destroys a window.
         (MSG_DESTROY, Destroy),
         /// Bidirectional: A part of the window must be redrawn.
@@ -241,17 +346,19 @@ enum_const! {
         (MSG_UNMAP, Unmap) = 133,
         /// Bidirectional: A window has been moved and/or resized.
         (MSG_CONFIGURE, Configure),
+        #[cfg(feature = "legacy-messages")]
         /// Ask dom0 (only!) to map the given amount of memory into composition
         /// buffer.  Deprecated.
-        (MSG_MFNDUMP, MfnDump),
+        (MSG_MFNDUMP, MfnDump) = 135,
         /// Agent ⇒ daemon: Redraw given area of screen.
-        (MSG_SHMIMAGE, ShmImage),
+        (MSG_SHMIMAGE, ShmImage) = 136,
         /// Daemon ⇒ agent: Request that a window be destroyed.
         (MSG_CLOSE, Close),
+        #[cfg(feature = "legacy-messages")]
         /// Daemon ⇒ agent, deprecated, DO NOT USE
-        (MSG_EXECUTE, Execute),
+        (MSG_EXECUTE, Execute) = 138,
         /// Daemon ⇒ agent: Request clipboard data.
-        (MSG_CLIPBOARD_REQ, ClipboardReq),
+        (MSG_CLIPBOARD_REQ, ClipboardReq) = 139,
         /// Bidirectional: Clipboard data
         (MSG_CLIPBOARD_DATA, ClipboardData),
         /// Agent ⇒ daemon: Set the title of a window.  Called MSG_WMNAME in C.
@@ -272,9 +379,213 @@ enum_const! {
         (MSG_CURSOR, Cursor),
         /// Daemon ⇒ agent: Acknowledge mapping (version 1.7+ only)
         (MSG_WINDOW_DUMP_ACK, DumpAck),
+        #[cfg(feature = "extensions")]
+        /// Agent ⇒ daemon: Set an ARGB cursor image (version 1.8+ only)
+        (MSG_CURSOR_IMAGE, CursorImage),
+        #[cfg(feature = "extensions")]
+        /// Bidirectional: Clipboard data with a MIME type (version 1.9+ only)
+        (MSG_CLIPBOARD_MIME_DATA, ClipboardMimeData),
+        #[cfg(feature = "extensions")]
+        /// Agent ⇒ daemon: Set the icon of a window (version 1.10+ only).
+        /// Called MSG_WMICON in C.
+        (MSG_WINDOW_ICON, WindowIcon),
+        #[cfg(feature = "extensions")]
+        /// Daemon ⇒ agent: Geometry of each output (monitor) (version 1.11+
+        /// only)
+        (MSG_OUTPUTS, Outputs),
+        #[cfg(feature = "extensions")]
+        /// Daemon ⇒ agent: Scale factor of a window, or the default scale
+        /// factor if the window is 0 (version 1.13+ only)
+        (MSG_WINDOW_SCALE, WindowScale),
+        #[cfg(feature = "extensions")]
+        /// Agent ⇒ daemon: Redraw several areas of a window from shared
+        /// memory (version 1.14+ only)
+        (MSG_DAMAGE, Damage),
+        #[cfg(feature = "extensions")]
+        /// Agent ⇒ daemon: Resize an existing shared memory dump by adding
+        /// or removing pages (version 1.15+ only)
+        (MSG_WINDOW_DUMP_DELTA, WindowDumpDelta),
     }
 }
 
+impl Msg {
+    /// The first protocol version, as used on the wire, in which this message
+    /// may be sent.  Returns 0 for messages that are valid in all protocol
+    /// versions.  Sending a message that the peer does not support is a
+    /// protocol error.
+    pub const fn min_version(self) -> u32 {
+        match self {
+            Msg::DumpAck => PROTOCOL_VERSION_MAJOR << 16 | 7,
+            #[cfg(feature = "extensions")]
+            Msg::CursorImage => PROTOCOL_VERSION_MAJOR << 16 | 8,
+            #[cfg(feature = "extensions")]
+            Msg::ClipboardMimeData => PROTOCOL_VERSION_MAJOR << 16 | 9,
+            #[cfg(feature = "extensions")]
+            Msg::WindowIcon => PROTOCOL_VERSION_MAJOR << 16 | 10,
+            #[cfg(feature = "extensions")]
+            Msg::Outputs => PROTOCOL_VERSION_MAJOR << 16 | 11,
+            #[cfg(feature = "extensions")]
+            Msg::WindowScale => PROTOCOL_VERSION_MAJOR << 16 | 13,
+            #[cfg(feature = "extensions")]
+            Msg::Damage => PROTOCOL_VERSION_MAJOR << 16 | 14,
+            #[cfg(feature = "extensions")]
+            Msg::WindowDumpDelta => PROTOCOL_VERSION_MAJOR << 16 | 15,
+            _ => 0,
+        }
+    }
+
+    /// The capability that both peers must support for this message to be
+    /// sent, or [`None`] if the message is not optional.  See
+    /// [`Capabilities`].
+    pub const fn capability(self) -> Option<Capabilities> {
+        match self {
+            #[cfg(feature = "extensions")]
+            Msg::CursorImage => Some(Capabilities::CURSOR_IMAGE),
+            #[cfg(feature = "extensions")]
+            Msg::ClipboardMimeData => Some(Capabilities::CLIPBOARD_MIME),
+            #[cfg(feature = "extensions")]
+            Msg::WindowIcon => Some(Capabilities::WINDOW_ICON),
+            #[cfg(feature = "extensions")]
+            Msg::Outputs => Some(Capabilities::OUTPUTS),
+            #[cfg(feature = "extensions")]
+            Msg::WindowScale => Some(Capabilities::WINDOW_SCALE),
+            #[cfg(feature = "extensions")]
+            Msg::Damage => Some(Capabilities::DAMAGE),
+            #[cfg(feature = "extensions")]
+            Msg::WindowDumpDelta => Some(Capabilities::WINDOW_DUMP_DELTA),
+            _ => None,
+        }
+    }
+
+    /// Which peer may send this message
+    pub const fn direction(self) -> Direction {
+        match self {
+            Msg::Keypress
+            | Msg::Button
+            | Msg::Motion
+            | Msg::Crossing
+            | Msg::Focus
+            | Msg::Close
+            | Msg::ClipboardReq
+            | Msg::KeymapNotify
+            | Msg::DumpAck => Direction::DaemonToAgent,
+            #[cfg(feature = "extensions")]
+            Msg::Outputs | Msg::WindowScale => Direction::DaemonToAgent,
+            #[cfg(feature = "legacy-messages")]
+            Msg::Resize | Msg::Execute => Direction::DaemonToAgent,
+            Msg::Create
+            | Msg::Unmap
+            | Msg::ShmImage
+            | Msg::SetTitle
+            | Msg::Dock
+            | Msg::WindowHints
+            | Msg::WindowClass
+            | Msg::WindowDump
+            | Msg::Cursor => Direction::AgentToDaemon,
+            #[cfg(feature = "extensions")]
+            Msg::CursorImage | Msg::WindowIcon | Msg::Damage | Msg::WindowDumpDelta => {
+                Direction::AgentToDaemon
+            }
+            #[cfg(feature = "legacy-messages")]
+            Msg::MfnDump => Direction::AgentToDaemon,
+            Msg::Destroy | Msg::Map | Msg::Configure | Msg::ClipboardData | Msg::WindowFlags => {
+                Direction::Bidirectional
+            }
+            #[cfg(feature = "extensions")]
+            Msg::ClipboardMimeData => Direction::Bidirectional,
+        }
+    }
+
+    /// Returns the entry for this message in [`REGISTRY`].
+    pub fn info(self) -> &'static MessageInfo {
+        REGISTRY
+            .iter()
+            .find(|info| info.msg == self)
+            .expect("every message is in the registry")
+    }
+}
+
+/// Which peer may send a message
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Only the daemon may send this message.
+    DaemonToAgent,
+    /// Only the agent may send this message.
+    AgentToDaemon,
+    /// Both the daemon and the agent may send this message.
+    Bidirectional,
+}
+
+/// Static information about a message type.  See [`REGISTRY`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MessageInfo {
+    /// The message type
+    pub msg: Msg,
+    /// The symbolic name of the message, as used in the C implementation
+    pub name: &'static str,
+    /// Which peer may send the message
+    pub direction: Direction,
+    /// Minimum length of the message body.  If this is greater than
+    /// `max_len`, the message is never valid.
+    pub min_len: usize,
+    /// Maximum length of the message body
+    pub max_len: usize,
+    /// See [`Msg::min_version`]
+    pub min_version: u32,
+    /// See [`Msg::capability`]
+    pub capability: Option<Capabilities>,
+}
+
+impl MessageInfo {
+    const fn new(msg: Msg) -> Self {
+        let (min_len, max_len) = match msg_length_limits(msg as u32) {
+            Some(limits) => (*limits.start(), *limits.end()),
+            None => panic!("no length limits for known message"),
+        };
+        Self {
+            msg,
+            name: msg.name(),
+            direction: msg.direction(),
+            min_len,
+            max_len,
+            min_version: msg.min_version(),
+            capability: msg.capability(),
+        }
+    }
+
+    /// The message number, as used on the wire
+    pub const fn number(&self) -> u32 {
+        self.msg as u32
+    }
+
+    /// Permissible lengths of the message body.  Some messages impose
+    /// additional restrictions; see [`UntrustedHeader::validate_length`].
+    pub const fn length(&self) -> RangeInclusive<usize> {
+        RangeInclusive::new(self.min_len, self.max_len)
+    }
+}
+
+/// Information about every message type known to this library, in numeric
+/// order.  This is generated from the definition of [`Msg`], so it never
+/// drifts from it.
+///
+/// ```
+/// let info = qubes_gui::Msg::Configure.info();
+/// assert_eq!(info.name, "MSG_CONFIGURE");
+/// assert_eq!(info.direction, qubes_gui::Direction::Bidirectional);
+/// assert!(qubes_gui::REGISTRY.windows(2).all(|w| w[0].number() < w[1].number()));
+/// assert!(qubes_gui::Msg::iter().eq(qubes_gui::REGISTRY.iter().map(|info| info.msg)));
+/// ```
+pub const REGISTRY: &[MessageInfo] = &{
+    let mut registry = [MessageInfo::new(Msg::Keypress); Msg::ALL.len()];
+    let mut i = 0;
+    while i < registry.len() {
+        registry[i] = MessageInfo::new(Msg::ALL[i]);
+        i += 1;
+    }
+    registry
+};
+
 enum_const! {
     #[repr(u32)]
     /// State of a button
@@ -308,6 +619,57 @@ enum_const! {
     }
 }
 
+enum_const! {
+    #[repr(u32)]
+    /// Crossing event
+    pub enum CrossingEvent {
+        /// The pointer has entered the window
+        (EV_ENTER, Enter) = 7,
+        /// The pointer has left the window
+        (EV_LEAVE, Leave) = 8,
+    }
+}
+
+enum_const! {
+    #[repr(u32)]
+    /// X11 detail of a focus or crossing event.  The semantics are defined by
+    /// the X11 core protocol.
+    pub enum NotifyDetail {
+        /// X11 NotifyAncestor
+        (NOTIFY_ANCESTOR, Ancestor) = 0,
+        /// X11 NotifyVirtual
+        (NOTIFY_VIRTUAL, Virtual) = 1,
+        /// X11 NotifyInferior
+        (NOTIFY_INFERIOR, Inferior) = 2,
+        /// X11 NotifyNonlinear
+        (NOTIFY_NONLINEAR, Nonlinear) = 3,
+        /// X11 NotifyNonlinearVirtual
+        (NOTIFY_NONLINEAR_VIRTUAL, NonlinearVirtual) = 4,
+        /// X11 NotifyPointer.  Only valid in focus events.
+        (NOTIFY_POINTER, Pointer) = 5,
+        /// X11 NotifyPointerRoot.  Only valid in focus events.
+        (NOTIFY_POINTER_ROOT, PointerRoot) = 6,
+        /// X11 NotifyDetailNone.  Only valid in focus events.
+        (NOTIFY_DETAIL_NONE, DetailNone) = 7,
+    }
+}
+
+enum_const! {
+    #[repr(u32)]
+    /// X11 mode of a crossing event.  The semantics are defined by the X11
+    /// core protocol.
+    pub enum NotifyMode {
+        /// X11 NotifyNormal
+        (NOTIFY_NORMAL, Normal) = 0,
+        /// X11 NotifyGrab
+        (NOTIFY_GRAB, Grab) = 1,
+        /// X11 NotifyUngrab
+        (NOTIFY_UNGRAB, Ungrab) = 2,
+        /// X11 NotifyWhileGrabbed.  Only valid in focus events.
+        (NOTIFY_WHILE_GRABBED, WhileGrabbed) = 3,
+    }
+}
+
 /// Flags for [`WindowHints`].  These are a bitmask.
 pub enum WindowHintsFlags {
     /// User-specified position
@@ -324,6 +686,16 @@ pub enum WindowHintsFlags {
     PBaseSize = 1 << 8,
 }
 
+impl WindowHintsFlags {
+    /// All flags known to this library
+    pub const ALL: u32 = Self::USPosition as u32
+        | Self::PPosition as u32
+        | Self::PMinSize as u32
+        | Self::PMaxSize as u32
+        | Self::PResizeInc as u32
+        | Self::PBaseSize as u32;
+}
+
 /// Flags for [`WindowFlags`].  These are a bitmask.
 pub enum WindowFlag {
     /// Fullscreen request.  This may or may not be honored.
@@ -334,10 +706,26 @@ pub enum WindowFlag {
     Minimize = 1 << 2,
 }
 
+impl WindowFlag {
+    /// All flags known to this library
+    pub const ALL: u32 =
+        Self::Fullscreen as u32 | Self::DemandsAttention as u32 | Self::Minimize as u32;
+}
+
 /// Trait for Qubes GUI structs, specifying the message number.
 pub trait Message: qubes_castable::Castable + core::default::Default {
     /// The kind of the message
     const KIND: Msg;
+    /// The minimum length of a message of this kind.  Defaults to the size of
+    /// the struct.
+    const MIN_LEN: usize = core::mem::size_of::<Self>();
+    /// The maximum length of a message of this kind.  Defaults to the size of
+    /// the struct.  Messages with trailing data, such as [`WindowDumpHeader`],
+    /// override this.
+    const MAX_LEN: usize = core::mem::size_of::<Self>();
+    /// The permissible lengths of a message of this kind.  The same as
+    /// `Self::MIN_LEN..=Self::MAX_LEN`.
+    const LENGTH: RangeInclusive<usize> = Self::MIN_LEN..=Self::MAX_LEN;
 }
 
 impl From<NonZeroU32> for WindowID {
@@ -354,6 +742,335 @@ impl From<u32> for WindowID {
     }
 }
 
+impl From<u32> for ModifierState {
+    fn from(other: u32) -> Self {
+        qubes_castable::cast!(other)
+    }
+}
+
+impl From<ModifierState> for u32 {
+    fn from(other: ModifierState) -> Self {
+        qubes_castable::cast!(other)
+    }
+}
+
+impl ModifierState {
+    /// No modifiers or buttons
+    pub const EMPTY: Self = Self { bits: 0 };
+    /// Shift key
+    pub const SHIFT: Self = Self { bits: 1 << 0 };
+    /// Caps Lock
+    pub const LOCK: Self = Self { bits: 1 << 1 };
+    /// Control key
+    pub const CONTROL: Self = Self { bits: 1 << 2 };
+    /// X11 Mod1, usually Alt
+    pub const MOD1: Self = Self { bits: 1 << 3 };
+    /// X11 Mod2, usually Num Lock
+    pub const MOD2: Self = Self { bits: 1 << 4 };
+    /// X11 Mod3
+    pub const MOD3: Self = Self { bits: 1 << 5 };
+    /// X11 Mod4, usually Super
+    pub const MOD4: Self = Self { bits: 1 << 6 };
+    /// X11 Mod5
+    pub const MOD5: Self = Self { bits: 1 << 7 };
+    /// Pointer button 1, usually the left button
+    pub const BUTTON1: Self = Self { bits: 1 << 8 };
+    /// Pointer button 2, usually the middle button
+    pub const BUTTON2: Self = Self { bits: 1 << 9 };
+    /// Pointer button 3, usually the right button
+    pub const BUTTON3: Self = Self { bits: 1 << 10 };
+    /// Pointer button 4, usually scroll up
+    pub const BUTTON4: Self = Self { bits: 1 << 11 };
+    /// Pointer button 5, usually scroll down
+    pub const BUTTON5: Self = Self { bits: 1 << 12 };
+    /// All bits that have a defined meaning
+    pub const ALL: Self = Self {
+        bits: (1 << 13) - 1,
+    };
+
+    /// Returns the raw X11 modifier mask.
+    pub const fn bits(self) -> u32 {
+        self.bits
+    }
+
+    /// Creates a [`ModifierState`] from a raw X11 modifier mask.  Returns
+    /// [`None`] if any bit not in [`ModifierState::ALL`] is set.
+    pub const fn from_bits(bits: u32) -> Option<Self> {
+        if bits & !Self::ALL.bits == 0 {
+            Some(Self { bits })
+        } else {
+            None
+        }
+    }
+
+    /// Creates a [`ModifierState`] from a raw X11 modifier mask, discarding
+    /// unknown bits.
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Self {
+            bits: bits & Self::ALL.bits,
+        }
+    }
+
+    /// Returns true if no bits are set.
+    pub const fn is_empty(self) -> bool {
+        self.bits == 0
+    }
+
+    /// Returns true if all bits set in `other` are also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Returns true if any bit set in `other` is also set in `self`.
+    pub const fn intersects(self, other: Self) -> bool {
+        self.bits & other.bits != 0
+    }
+
+    /// Returns true if a Shift key is held.
+    pub const fn shift(self) -> bool {
+        self.contains(Self::SHIFT)
+    }
+
+    /// Returns true if a Control key is held.
+    pub const fn control(self) -> bool {
+        self.contains(Self::CONTROL)
+    }
+
+    /// Returns true if the given pointer button (1 through 5 inclusive) is
+    /// held.  Returns false for all other buttons, as X11 does not report
+    /// them in the modifier mask.
+    pub const fn button(self, button: u32) -> bool {
+        match button {
+            1..=5 => self.bits & (1 << (button + 7)) != 0,
+            _ => false,
+        }
+    }
+}
+
+impl Capabilities {
+    /// The first protocol version, as used on the wire, in which capabilities
+    /// are exchanged during the handshake.
+    pub const MIN_VERSION: u32 = PROTOCOL_VERSION_MAJOR << 16 | 12;
+    /// No capabilities
+    pub const EMPTY: Self = Self { bits: 0 };
+    /// [`MSG_CURSOR_IMAGE`] is supported
+    pub const CURSOR_IMAGE: Self = Self { bits: 1 << 0 };
+    /// [`MSG_CLIPBOARD_MIME_DATA`] is supported
+    pub const CLIPBOARD_MIME: Self = Self { bits: 1 << 1 };
+    /// [`MSG_WINDOW_ICON`] is supported
+    pub const WINDOW_ICON: Self = Self { bits: 1 << 2 };
+    /// [`MSG_OUTPUTS`] is supported
+    pub const OUTPUTS: Self = Self { bits: 1 << 3 };
+    /// [`MSG_WINDOW_SCALE`] is supported
+    pub const WINDOW_SCALE: Self = Self { bits: 1 << 4 };
+    /// [`MSG_DAMAGE`] is supported
+    pub const DAMAGE: Self = Self { bits: 1 << 5 };
+    /// [`MSG_WINDOW_DUMP_DELTA`] is supported
+    pub const WINDOW_DUMP_DELTA: Self = Self { bits: 1 << 6 };
+    /// All capabilities known to this library
+    pub const ALL: Self = Self { bits: (1 << 7) - 1 };
+
+    /// Returns true if all capabilities in `other` are also in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Returns the capabilities in both `self` and `other`.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self {
+            bits: self.bits & other.bits,
+        }
+    }
+
+    /// Returns the capabilities implied by a protocol version, as used on the
+    /// wire.  This is used for versions before [`Capabilities::MIN_VERSION`],
+    /// in which each optional feature is implied by the protocol version.  For
+    /// later versions, it returns [`Capabilities::EMPTY`], as capabilities
+    /// are exchanged explicitly.
+    pub const fn implied_by_version(version: u32) -> Self {
+        let minor = version & 0xFFFF;
+        if version >> 16 != PROTOCOL_VERSION_MAJOR || version >= Self::MIN_VERSION {
+            return Self::EMPTY;
+        }
+        let mut bits = 0;
+        if minor >= 8 {
+            bits |= Self::CURSOR_IMAGE.bits
+        }
+        if minor >= 9 {
+            bits |= Self::CLIPBOARD_MIME.bits
+        }
+        if minor >= 10 {
+            bits |= Self::WINDOW_ICON.bits
+        }
+        if minor >= 11 {
+            bits |= Self::OUTPUTS.bits
+        }
+        Self { bits }
+    }
+}
+
+impl core::ops::BitOr for Capabilities {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self {
+        Self {
+            bits: self.bits | other.bits,
+        }
+    }
+}
+
+impl core::ops::BitOr for ModifierState {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self {
+        Self {
+            bits: self.bits | other.bits,
+        }
+    }
+}
+
+impl core::ops::BitOrAssign for ModifierState {
+    fn bitor_assign(&mut self, other: Self) {
+        self.bits |= other.bits
+    }
+}
+
+impl core::ops::BitAnd for ModifierState {
+    type Output = Self;
+    fn bitand(self, other: Self) -> Self {
+        Self {
+            bits: self.bits & other.bits,
+        }
+    }
+}
+
+impl core::ops::BitAndAssign for ModifierState {
+    fn bitand_assign(&mut self, other: Self) {
+        self.bits &= other.bits
+    }
+}
+
+impl core::ops::Not for ModifierState {
+    type Output = Self;
+    fn not(self) -> Self {
+        Self {
+            bits: !self.bits & Self::ALL.bits,
+        }
+    }
+}
+
+macro_rules! impl_modifiers {
+    ($($t: ty,)+) => {
+        $(impl $t {
+            /// Returns the modifier and button state as a [`ModifierState`].
+            /// Unknown bits are preserved.
+            pub fn modifiers(&self) -> ModifierState {
+                self.state.into()
+            }
+        })+
+    }
+}
+
+impl_modifiers! {
+    Keypress,
+    Button,
+    Motion,
+    Crossing,
+}
+
+impl KeymapNotify {
+    /// Returns true if the key with the given X11 keycode is pressed.
+    pub fn is_pressed(&self, keycode: u8) -> bool {
+        self.keys[usize::from(keycode >> 3)] & (1 << (keycode & 7)) != 0
+    }
+
+    /// Returns an iterator over the keycodes of all pressed keys, in
+    /// increasing order.
+    pub fn pressed(&self) -> PressedKeys<'_> {
+        PressedKeys {
+            keymap: self,
+            next: Some(0),
+        }
+    }
+
+    /// Returns an iterator over the keys whose state differs between
+    /// `previous` and `self`, in increasing order of keycode.  This can be used
+    /// to synthesize press and release events after a focus change.
+    ///
+    /// ```rust
+    /// # use qubes_gui::{KeyChange, KeymapNotify};
+    /// let mut previous = KeymapNotify::default();
+    /// previous.keys[1] = 1 << 1;
+    /// let mut current = KeymapNotify::default();
+    /// current.keys[4] = 1 << 2;
+    /// assert!(current.is_pressed(34));
+    /// assert_eq!(
+    ///     current.diff(&previous).collect::<Vec<_>>(),
+    ///     [KeyChange::Released(9), KeyChange::Pressed(34)],
+    /// );
+    /// ```
+    pub fn diff<'a>(&'a self, previous: &'a Self) -> KeymapDiff<'a> {
+        KeymapDiff {
+            current: self,
+            previous,
+            next: Some(0),
+        }
+    }
+}
+
+/// Iterator over pressed keys, returned by [`KeymapNotify::pressed`].
+#[derive(Debug, Clone)]
+pub struct PressedKeys<'a> {
+    keymap: &'a KeymapNotify,
+    next: Option<u8>,
+}
+
+impl Iterator for PressedKeys<'_> {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        while let Some(keycode) = self.next {
+            self.next = keycode.checked_add(1);
+            if self.keymap.is_pressed(keycode) {
+                return Some(keycode);
+            }
+        }
+        None
+    }
+}
+
+/// A change in the state of a key
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum KeyChange {
+    /// The key with this keycode has been pressed
+    Pressed(u8),
+    /// The key with this keycode has been released
+    Released(u8),
+}
+
+/// Iterator over changed keys, returned by [`KeymapNotify::diff`].
+#[derive(Debug, Clone)]
+pub struct KeymapDiff<'a> {
+    current: &'a KeymapNotify,
+    previous: &'a KeymapNotify,
+    next: Option<u8>,
+}
+
+impl Iterator for KeymapDiff<'_> {
+    type Item = KeyChange;
+    fn next(&mut self) -> Option<KeyChange> {
+        while let Some(keycode) = self.next {
+            self.next = keycode.checked_add(1);
+            match (
+                self.previous.is_pressed(keycode),
+                self.current.is_pressed(keycode),
+            ) {
+                (false, true) => return Some(KeyChange::Pressed(keycode)),
+                (true, false) => return Some(KeyChange::Released(keycode)),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
 qubes_castable::castable! {
     /// A window ID.
     pub struct WindowID {
@@ -363,6 +1080,14 @@ qubes_castable::castable! {
         pub window: Option<NonZeroU32>,
     }
 
+    /// An X11 modifier and pointer button mask, as found in the `state` field
+    /// of [`Keypress`], [`Button`], [`Motion`], and [`Crossing`].  The bit
+    /// values are defined by the X11 core protocol.
+    pub struct ModifierState {
+        /// The raw X11 modifier mask
+        pub bits: u32,
+    }
+
     /// A GUI message as it appears on the wire.  All fields are in native byte
     /// order.
     pub struct UntrustedHeader {
@@ -414,6 +1139,21 @@ qubes_castable::castable! {
         pub mem: u32,
     }
 
+    /// Bidirectional: Optional protocol features supported by the sender;
+    /// sent only at startup, without a header.  Only used in protocol 1.12
+    /// and better.
+    ///
+    /// If the negotiated version is 1.12 or better, the daemon sends this
+    /// immediately after [`XConfVersion`], and the agent replies with its own
+    /// capabilities once it has received the daemon’s.  A message that
+    /// requires a capability (see [`Msg::capability`]) MUST NOT be sent unless
+    /// both peers advertised that capability.  Unknown capabilities MUST be
+    /// ignored.
+    pub struct Capabilities {
+        /// Bitmask of capabilities
+        pub bits: u64,
+    }
+
     /// Daemon ⇒ agent: Version and root window configuration; sent only at
     /// startup, without a header.  Only used in protocol 1.4 and better.
     pub struct XConfVersion {
@@ -441,8 +1181,8 @@ qubes_castable::castable! {
     /// [`Configure`] message.  The window is not immediately mapped.
     pub struct Create {
         /// Rectangle the window is to occupy.  It is a protocol error for the
-        /// width or height to be zero, for the width to exceed
-        /// [`MAX_WINDOW_WIDTH`], or for the height to exceed [`MAX_WINDOW_HEIGHT`].
+        /// width or height to be zero, or for the size to exceed the
+        /// [`WindowLimits`] of the connection.
         pub rectangle: Rectangle,
         /// Parent window, or [`None`] if there is no parent window.  It is a
         /// protocol error to specify a parent window that does not exist.  The
@@ -455,27 +1195,27 @@ qubes_castable::castable! {
         pub override_redirect: u32,
     }
 
-    /// Daemon ⇒ agent: Keypress
+    /// Daemon ⇒ agent: Keypress.  See [`ValidatedKeypress`].
     pub struct Keypress {
         /// The X11 type of key pressed.  MUST be 2 ([`EV_KEY_PRESS`]) or 3
         /// ([`EV_KEY_RELEASE`]).  Anything else is a protocol violation.
         pub ty: u32,
         /// Coordinates of the key press
         pub coordinates: Coordinates,
-        /// X11 key press state
+        /// X11 key press state.  See [`Keypress::modifiers`].
         pub state: u32,
         /// X11 key code
         pub keycode: u32,
     }
 
-    /// Daemon ⇒ agent: Button press
+    /// Daemon ⇒ agent: Button press.  See [`ValidatedButton`].
     pub struct Button {
         /// The type of event.  MUST be 4 ([`EV_BUTTON_PRESS`]) or 5
         /// ([`EV_BUTTON_RELEASE`]).  Anything else is a protocol violation.
         pub ty: u32,
         /// Coordinates of the button press
         pub coordinates: Coordinates,
-        /// Bitmask of modifier keys
+        /// Bitmask of modifier keys.  See [`Button::modifiers`].
         pub state: u32,
         /// X11 button number
         pub button: u32,
@@ -485,23 +1225,24 @@ qubes_castable::castable! {
     pub struct Motion {
         /// Coordinates of the motion event
         pub coordinates: Coordinates,
-        /// Bitmask of buttons that are pressed
+        /// Bitmask of buttons that are pressed.  See [`Motion::modifiers`].
         pub state: u32,
         /// X11 is_hint flag
         pub is_hint: u32,
     }
 
-    /// Daemon ⇒ agent: Crossing event
+    /// Daemon ⇒ agent: Crossing event.  See [`ValidatedCrossing`].
     pub struct Crossing {
-        /// Type of the crossing
+        /// Type of the crossing.  MUST be 7 ([`EV_ENTER`]) or 8
+        /// ([`EV_LEAVE`]).  Anything else is a protocol violation.
         pub ty: u32,
         /// Coordinates of the crossing
         pub coordinates: Coordinates,
         /// X11 state of the crossing
         pub state: u32,
-        /// X11 mode of the crossing
+        /// X11 mode of the crossing.  MUST be between 0 and 2 inclusive.
         pub mode: u32,
-        /// X11 detail of the crossing
+        /// X11 detail of the crossing.  MUST be between 0 and 4 inclusive.
         pub detail: u32,
         /// X11 focus of the crossing
         pub focus: u32,
@@ -523,7 +1264,7 @@ qubes_castable::castable! {
         pub rectangle: Rectangle,
     }
 
-    /// Daemon ⇒ agent: Focus event from GUI qube
+    /// Daemon ⇒ agent: Focus event from GUI qube.  See [`ValidatedFocus`].
     pub struct Focus {
         /// The type of event.  MUST be 9 ([`EV_FOCUS_IN`]) or 10
         /// ([`EV_FOCUS_OUT`]).  Anything else is a protocol error.
@@ -536,7 +1277,8 @@ qubes_castable::castable! {
         pub detail: u32,
     }
 
-    /// Agent ⇒ daemon: Set the window name
+    /// Agent ⇒ daemon: Set the window name.  See [`WMName::new`] and
+    /// [`WMName::as_str`].
     pub struct WMName {
         /// NUL-terminated name
         pub data: [u8; 128],
@@ -556,7 +1298,9 @@ qubes_castable::castable! {
 
     /// Daemon ⇒ agent: Keymap change notification
     pub struct KeymapNotify {
-        /// X11 keymap returned by XQueryKeymap()
+        /// X11 keymap returned by XQueryKeymap().  Bit `n % 8` of byte
+        /// `n / 8` is set if the key with keycode `n` is pressed.  See
+        /// [`KeymapNotify::is_pressed`].
         pub keys: [u8; 32],
     }
 
@@ -600,7 +1344,7 @@ qubes_castable::castable! {
         pub domid: u32,
     }
 
-    /// Agent ⇒ daemon: set window class
+    /// Agent ⇒ daemon: set window class.  See [`WMClass::new`].
     pub struct WMClass {
         /// Window class
         pub res_class: [u8; 64],
@@ -620,6 +1364,31 @@ qubes_castable::castable! {
         pub bpp: u32,
     }
 
+    /// Agent ⇒ daemon: Header of a window dump delta message (version 1.15+
+    /// only).  This changes the size of the window’s existing grant-ref dump
+    /// without re-sending the pages that stay the same.  The daemon keeps the
+    /// first `keep` pages of the existing dump, releases the rest, and then
+    /// appends the `add` grant refs that follow this header.  The result is
+    /// the same as a [`MSG_WINDOW_DUMP`] message with the new size and the
+    /// combined list of grant refs.
+    ///
+    /// It is a protocol error to send this for a window without a grant-ref
+    /// dump, or for `keep` to exceed the number of pages in the existing
+    /// dump.  See [`WindowDumpDeltaHeader::split_body`] for the checks that
+    /// do not depend on the existing dump.
+    pub struct WindowDumpDeltaHeader {
+        /// New width in pixels
+        pub width: u32,
+        /// New height in pixels
+        pub height: u32,
+        /// Bits per pixel.  MUST be 24.
+        pub bpp: u32,
+        /// Number of pages of the existing dump to keep
+        pub keep: u32,
+        /// Number of grant refs that follow this header
+        pub add: u32,
+    }
+
     /// Agent ⇒ daemon: Header of a window dump message
     pub struct Cursor {
         /// Type of cursor
@@ -628,12 +1397,777 @@ qubes_castable::castable! {
 
     /// Daemon ⇒ agent: Acknowledge a window dump message
     pub struct DumpAck {}
+
+    /// Bidirectional: Header of a clipboard message with a MIME type (version
+    /// 1.9+ only).  The header is followed by the clipboard data, which MUST
+    /// NOT exceed [`MAX_CLIPBOARD_MIME_DATA_SIZE`] bytes.  The contents of the
+    /// clipboard are not trusted, and are not validated in any way.
+    ///
+    /// Peers that negotiated version 1.9 or later MAY use this in place of
+    /// [`MSG_CLIPBOARD_DATA`], and MUST accept it wherever
+    /// [`MSG_CLIPBOARD_DATA`] is accepted.  `text/plain;charset=utf-8` data
+    /// SHOULD still be sent with [`MSG_CLIPBOARD_DATA`] for compatibility.
+    pub struct ClipboardMimeHeader {
+        /// NUL-terminated MIME type, such as `image/png` or `text/uri-list`.
+        /// It is a protocol error for this not to be a valid MIME type (see
+        /// [`ClipboardMimeHeader::mime_type`]).  Parameters are not
+        /// supported.
+        pub mime_type: [u8; 64],
+    }
+
+    /// Daemon ⇒ agent: Geometry of a single output (monitor), in the
+    /// coordinate space of the root window (version 1.11+ only).
+    ///
+    /// The body of a [`MSG_OUTPUTS`] message is an array of between 1 and
+    /// [`MAX_OUTPUTS`] of these, and is always sent with a window of 0.  The
+    /// daemon sends this message once after the handshake, and again whenever
+    /// the output configuration changes.  Each message replaces the output
+    /// configuration sent in all previous messages.  See [`OutputList`].
+    pub struct Output {
+        /// Position and size of the output.  It is a protocol error for the
+        /// width or height to be zero.
+        pub rectangle: Rectangle,
+        /// Scale factor of the output, in units of 1/[`SCALE_DENOMINATOR`].
+        /// It is a protocol error for this to be zero.
+        pub scale: u32,
+        /// Output flags, such as [`OUTPUT_PRIMARY`].  Unknown flags MUST be
+        /// ignored.
+        pub flags: u32,
+    }
+
+    /// Daemon ⇒ agent: Scale factor of a window (version 1.13+ only).
+    ///
+    /// If the window is 0, this sets the default scale factor, which applies
+    /// to all windows for which no scale factor has been sent.  The daemon
+    /// sends the default scale factor once after the handshake, and sends
+    /// this message again whenever a scale factor changes, such as when a
+    /// window moves to a different output.  Until the first message arrives,
+    /// the default scale factor is [`WindowScale::DEFAULT`].
+    pub struct WindowScale {
+        /// Scale factor, in units of 1/[`SCALE_DENOMINATOR`].  It is a
+        /// protocol error for this to be zero.
+        pub scale: u32,
+    }
+
+    /// Agent ⇒ daemon: Header of a damage message (version 1.14+ only).  The
+    /// header is followed by `count` [`Rectangle`]s, each of which is
+    /// redrawn as if by a [`ShmImage`] message.  See [`DamageList`].
+    pub struct DamageHeader {
+        /// Number of rectangles.  It is a protocol error for this to be zero,
+        /// to exceed [`MAX_DAMAGE_RECTS`], or to not match the length of the
+        /// message.
+        pub count: u32,
+    }
+
+    /// Agent ⇒ daemon: Header of a window icon message (version 1.10+ only).
+    /// The header is followed by `width * height` pixels, in the same format
+    /// as for [`CursorImageHeader`].  The daemon MAY scale the icon as it
+    /// sees fit.  Agents MAY send several icons of different sizes for the
+    /// same window; daemons SHOULD use the most recent one.
+    pub struct WindowIconHeader {
+        /// Size of the icon.  It is a protocol error for the width or height
+        /// to be zero, for the width to exceed [`MAX_ICON_WIDTH`], or for the
+        /// height to exceed [`MAX_ICON_HEIGHT`].
+        pub size: WindowSize,
+    }
+
+    /// Agent ⇒ daemon: Header of a cursor image message (version 1.8+ only).
+    /// The header is followed by `width * height` pixels, row by row from the
+    /// top left.  Each pixel is a native-endian 32-bit ARGB value with
+    /// premultiplied alpha.  The cursor image replaces any cursor set with
+    /// [`Cursor`], and is itself replaced by the next [`Cursor`] message.
+    pub struct CursorImageHeader {
+        /// Size of the cursor image.  It is a protocol error for the width or
+        /// height to be zero, for the width to exceed [`MAX_CURSOR_WIDTH`], or
+        /// for the height to exceed [`MAX_CURSOR_HEIGHT`].
+        pub size: WindowSize,
+        /// X coordinate of the hotspot.  MUST be less than the width.
+        pub hotspot_x: u32,
+        /// Y coordinate of the hotspot.  MUST be less than the height.
+        pub hotspot_y: u32,
+    }
+}
+
+impl ClipboardMimeHeader {
+    /// Creates a header for clipboard data of the given MIME type.  Returns
+    /// [`None`] if `mime_type` is not a valid MIME type or is too long.
+    pub fn new(mime_type: &str) -> Option<Self> {
+        let mut header = Self::default();
+        if mime_type.len() >= header.mime_type.len() || !is_valid_mime_type(mime_type.as_bytes()) {
+            return None;
+        }
+        header.mime_type[..mime_type.len()].copy_from_slice(mime_type.as_bytes());
+        Some(header)
+    }
+
+    /// Returns the MIME type, or [`None`] if it is not NUL-terminated or is
+    /// not a valid MIME type.
+    pub fn mime_type(&self) -> Option<&str> {
+        parse_mime_type(&self.mime_type)
+    }
+
+    /// Splits the body of a [`MSG_CLIPBOARD_MIME_DATA`] message into the MIME
+    /// type and the clipboard data.  Returns [`None`] if the body is too short
+    /// or the MIME type is not valid.
+    pub fn split_body(body: &[u8]) -> Option<(&str, &[u8])> {
+        const HEADER_LEN: usize = core::mem::size_of::<ClipboardMimeHeader>();
+        if body.len() < HEADER_LEN {
+            return None;
+        }
+        let (header, data) = body.split_at(HEADER_LEN);
+        Some((parse_mime_type(header)?, data))
+    }
+}
+
+fn parse_mime_type(buf: &[u8]) -> Option<&str> {
+    let len = buf.iter().position(|&c| c == 0)?;
+    let mime_type = &buf[..len];
+    if is_valid_mime_type(mime_type) {
+        core::str::from_utf8(mime_type).ok()
+    } else {
+        None
+    }
+}
+
+/// Checks that `mime_type` is of the form `type/subtype`, where `type` and
+/// `subtype` are non-empty and consist of the characters permitted by RFC 6838.
+/// Parameters are not permitted.
+fn is_valid_mime_type(mime_type: &[u8]) -> bool {
+    fn is_valid_name(name: &[u8]) -> bool {
+        match name.split_first() {
+            Some((first, rest)) => {
+                first.is_ascii_alphanumeric()
+                    && rest.iter().all(|&c| {
+                        c.is_ascii_alphanumeric()
+                            || matches!(
+                                c,
+                                b'!' | b'#' | b'$' | b'&' | b'-' | b'^' | b'_' | b'.' | b'+'
+                            )
+                    })
+            }
+            None => false,
+        }
+    }
+    match mime_type.iter().position(|&c| c == b'/') {
+        Some(slash) => is_valid_name(&mime_type[..slash]) && is_valid_name(&mime_type[slash + 1..]),
+        None => false,
+    }
+}
+
+impl WMName {
+    /// Creates a window name message.  Names that do not fit are truncated at
+    /// a character boundary.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `name` contains a control character (including NUL).
+    pub fn new(name: &str) -> Result<Self, BadStringError> {
+        let mut msg = Self::default();
+        write_c_string(&mut msg.data, name)?;
+        Ok(msg)
+    }
+
+    /// Returns the window name.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the name is not NUL-terminated, is not valid UTF-8, or
+    /// contains a control character.
+    pub fn as_str(&self) -> Result<&str, BadStringError> {
+        parse_c_string(&self.data)
+    }
+
+    /// Returns the window name with invalid UTF-8 and control characters
+    /// replaced by `_`, as the GUI daemon displays it.  This never fails.
+    ///
+    /// ```
+    /// let mut name = qubes_gui::WMName::new("Terminal").unwrap();
+    /// assert_eq!(name.as_str(), Ok("Terminal"));
+    /// name.data[1] = b'\n';
+    /// name.data[2] = 0xFF;
+    /// assert!(name.as_str().is_err());
+    /// assert_eq!(name.sanitized().to_string(), "T__minal");
+    /// ```
+    pub fn sanitized(&self) -> SanitizedStr<'_> {
+        SanitizedStr::new(&self.data)
+    }
+}
+
+impl WMClass {
+    /// Creates a window class message.  Strings that do not fit are truncated
+    /// at a character boundary.
+    ///
+    /// # Errors
+    ///
+    /// Fails if either string contains a control character (including NUL).
+    pub fn new(res_class: &str, res_name: &str) -> Result<Self, BadStringError> {
+        let mut msg = Self::default();
+        write_c_string(&mut msg.res_class, res_class)?;
+        write_c_string(&mut msg.res_name, res_name)?;
+        Ok(msg)
+    }
+
+    /// Returns the window class.  Fails under the same conditions as
+    /// [`WMName::as_str`].
+    pub fn res_class(&self) -> Result<&str, BadStringError> {
+        parse_c_string(&self.res_class)
+    }
+
+    /// Returns the window name.  Fails under the same conditions as
+    /// [`WMName::as_str`].
+    pub fn res_name(&self) -> Result<&str, BadStringError> {
+        parse_c_string(&self.res_name)
+    }
+
+    /// Returns the window class, sanitized as by [`WMName::sanitized`].
+    pub fn sanitized_res_class(&self) -> SanitizedStr<'_> {
+        SanitizedStr::new(&self.res_class)
+    }
+
+    /// Returns the window name, sanitized as by [`WMName::sanitized`].
+    pub fn sanitized_res_name(&self) -> SanitizedStr<'_> {
+        SanitizedStr::new(&self.res_name)
+    }
+}
+
+/// Error when converting between a string and a NUL-terminated buffer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BadStringError {
+    /// The buffer is not NUL-terminated
+    NotTerminated,
+    /// The string is not valid UTF-8
+    BadUTF8(core::str::Utf8Error),
+    /// The string contains a control character at the given byte offset
+    Control {
+        /// Byte offset of the control character
+        offset: usize,
+    },
+}
+
+impl core::fmt::Display for BadStringError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BadStringError::NotTerminated => write!(f, "String not NUL-terminated"),
+            BadStringError::BadUTF8(e) => write!(f, "Invalid UTF-8 in string: {}", e),
+            BadStringError::Control { offset } => {
+                write!(f, "Control character at offset {} in string", offset)
+            }
+        }
+    }
+}
+
+/// Returns the string before the first NUL in `buf`, if it is valid UTF-8 and
+/// has no control characters.
+fn parse_c_string(buf: &[u8]) -> Result<&str, BadStringError> {
+    let len = buf
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or(BadStringError::NotTerminated)?;
+    let s = core::str::from_utf8(&buf[..len]).map_err(BadStringError::BadUTF8)?;
+    match s.char_indices().find(|(_, c)| c.is_control()) {
+        Some((offset, _)) => Err(BadStringError::Control { offset }),
+        None => Ok(s),
+    }
+}
+
+/// Copies `s` into `buf` followed by a NUL, truncating `s` at a character
+/// boundary if needed.  `buf` must be zeroed.
+fn write_c_string(buf: &mut [u8], s: &str) -> Result<(), BadStringError> {
+    if let Some((offset, _)) = s.char_indices().find(|(_, c)| c.is_control()) {
+        return Err(BadStringError::Control { offset });
+    }
+    let mut len = s.len().min(buf.len() - 1);
+    while !s.is_char_boundary(len) {
+        len -= 1
+    }
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    Ok(())
+}
+
+/// A string from a NUL-terminated buffer, which displays with invalid UTF-8
+/// and control characters replaced by `_`.  Everything after the first NUL
+/// (or the whole buffer, if there is no NUL) is ignored.
+#[derive(Debug, Copy, Clone)]
+pub struct SanitizedStr<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SanitizedStr<'a> {
+    /// Wraps a NUL-terminated buffer, such as the body of a [`MSG_SET_TITLE`]
+    /// message.
+    pub fn new(buf: &'a [u8]) -> Self {
+        let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        Self { bytes: &buf[..len] }
+    }
+}
+
+impl core::fmt::Display for SanitizedStr<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        use core::fmt::Write as _;
+        let mut bytes = self.bytes;
+        while !bytes.is_empty() {
+            let (valid, rest) = match core::str::from_utf8(bytes) {
+                Ok(valid) => (valid, &[][..]),
+                Err(e) => {
+                    let (valid, invalid) = bytes.split_at(e.valid_up_to());
+                    let bad_len = e.error_len().unwrap_or(invalid.len());
+                    let valid = core::str::from_utf8(valid).expect("checked above");
+                    (valid, &invalid[bad_len..])
+                }
+            };
+            for c in valid.chars() {
+                f.write_char(if c.is_control() { '_' } else { c })?
+            }
+            if rest.len() < bytes.len() - valid.len() {
+                f.write_char('_')?
+            }
+            bytes = rest;
+        }
+        Ok(())
+    }
+}
+
+impl CursorImageHeader {
+    /// Returns the number of bytes of pixel data that must follow this
+    /// header, or [`None`] if the size or hotspot is invalid.
+    pub fn data_len(&self) -> Option<usize> {
+        if self.hotspot_x >= self.size.width || self.hotspot_y >= self.size.height {
+            None
+        } else {
+            argb_data_len(self.size, MAX_CURSOR_WIDTH, MAX_CURSOR_HEIGHT)
+        }
+    }
+}
+
+impl Output {
+    /// Returns true if this output is the primary output.
+    pub fn is_primary(&self) -> bool {
+        self.flags & OUTPUT_PRIMARY != 0
+    }
+
+    /// Returns true if the point is on this output.
+    pub fn contains(&self, point: Coordinates) -> bool {
+        self.rectangle.contains(point)
+    }
+}
+
+/// A validated list of outputs, from the body of a [`MSG_OUTPUTS`] message
+#[derive(Debug, Copy, Clone)]
+pub struct OutputList<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> OutputList<'a> {
+    /// Parses the body of a [`MSG_OUTPUTS`] message.  Returns [`None`] if the
+    /// body is not a valid list of outputs.
+    pub fn parse(body: &'a [u8]) -> Option<Self> {
+        use qubes_castable::Castable as _;
+        let size = core::mem::size_of::<Output>();
+        if body.is_empty()
+            || !body.len().is_multiple_of(size)
+            || body.len() / size > MAX_OUTPUTS as usize
+            || body
+                .chunks_exact(size)
+                .map(Output::from_bytes)
+                .any(|output| {
+                    output.rectangle.size.width == 0
+                        || output.rectangle.size.height == 0
+                        || output.scale == 0
+                })
+        {
+            None
+        } else {
+            Some(Self { body })
+        }
+    }
+
+    /// Returns the number of outputs.  This is always at least 1.
+    pub fn len(&self) -> usize {
+        self.body.len() / core::mem::size_of::<Output>()
+    }
+
+    /// Returns the body of the message this was parsed from.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.body
+    }
+
+    /// Returns false.  An output list is never empty.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns an iterator over the outputs.
+    pub fn iter(&self) -> impl Iterator<Item = Output> + 'a {
+        use qubes_castable::Castable as _;
+        self.body
+            .chunks_exact(core::mem::size_of::<Output>())
+            .map(Output::from_bytes)
+    }
+
+    /// Returns the output that a window with the given rectangle should be
+    /// placed on: the output containing the center of the rectangle, or else
+    /// the primary output, or else the first output.
+    pub fn output_for(&self, rectangle: Rectangle) -> Output {
+        let center = Coordinates {
+            x: (i64::from(rectangle.top_left.x) + i64::from(rectangle.size.width / 2)) as i32,
+            y: (i64::from(rectangle.top_left.y) + i64::from(rectangle.size.height / 2)) as i32,
+        };
+        self.iter()
+            .find(|output| output.contains(center))
+            .or_else(|| self.iter().find(Output::is_primary))
+            .or_else(|| self.iter().next())
+            .expect("output list is never empty")
+    }
+}
+
+/// A validated list of damaged rectangles, from the body of a [`MSG_DAMAGE`]
+/// message
+#[derive(Debug, Copy, Clone)]
+pub struct DamageList<'a> {
+    rectangles: &'a [u8],
+}
+
+impl<'a> DamageList<'a> {
+    /// Parses the body of a [`MSG_DAMAGE`] message.  Returns [`None`] if the
+    /// count in the header is zero, too large, or does not match the number
+    /// of rectangles in the body.
+    pub fn parse(body: &'a [u8]) -> Option<Self> {
+        use qubes_castable::Castable as _;
+        let header_len = core::mem::size_of::<DamageHeader>();
+        if body.len() < header_len {
+            return None;
+        }
+        let (header, rectangles) = body.split_at(header_len);
+        let count = DamageHeader::from_bytes(header).count;
+        if count == 0
+            || count > MAX_DAMAGE_RECTS
+            || rectangles.len() != count as usize * core::mem::size_of::<Rectangle>()
+        {
+            None
+        } else {
+            Some(Self { rectangles })
+        }
+    }
+
+    /// Returns the number of rectangles.  This is always at least 1.
+    pub fn len(&self) -> usize {
+        self.rectangles.len() / core::mem::size_of::<Rectangle>()
+    }
+
+    /// Returns false.  A damage list is never empty.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns an iterator over the rectangles.
+    pub fn iter(&self) -> impl Iterator<Item = Rectangle> + 'a {
+        use qubes_castable::Castable as _;
+        self.rectangles
+            .chunks_exact(core::mem::size_of::<Rectangle>())
+            .map(Rectangle::from_bytes)
+    }
+}
+
+/// The body of a [`MSG_WINDOW_DUMP`] message: a [`WindowDumpHeader`]
+/// followed by the grant refs of the pages of the dump.  This is the only
+/// place where a dump is checked against its declared size.
+///
+/// ```
+/// use qubes_castable::Castable as _;
+/// let header = qubes_gui::WindowDumpHeader {
+///     ty: qubes_gui::WINDOW_DUMP_TYPE_GRANT_REFS,
+///     width: 1024,
+///     height: 2,
+///     bpp: 24,
+/// };
+/// let mut body = header.as_bytes().to_vec();
+/// body.extend_from_slice(&1u32.to_ne_bytes());
+/// let limits = qubes_gui::WindowLimits::MAX;
+/// // 1024 × 2 pixels need two pages
+/// assert!(qubes_gui::WindowDumpBody::parse(&body, &limits).is_none());
+/// body.extend_from_slice(&2u32.to_ne_bytes());
+/// let dump = qubes_gui::WindowDumpBody::parse(&body, &limits).unwrap();
+/// assert_eq!(dump.grants().collect::<Vec<_>>(), [1, 2]);
+/// assert_eq!(dump.len(), 2);
+/// assert!(!dump.is_empty());
+/// assert!(dump.validate(1024, 2));
+/// assert!(!dump.validate(1024, 3));
+/// // Too wide for a 800 × 600 root window
+/// let small = qubes_gui::WindowLimits::for_root_size(qubes_gui::WindowSize {
+///     width: 800,
+///     height: 600,
+/// });
+/// assert!(qubes_gui::WindowDumpBody::parse(&body, &small).is_none());
+/// ```
+#[derive(Debug, Copy, Clone)]
+pub struct WindowDumpBody<'a> {
+    header: WindowDumpHeader,
+    grant_refs: &'a [u8],
+}
+
+impl<'a> WindowDumpBody<'a> {
+    /// Parses the body of a [`MSG_WINDOW_DUMP`] message.  Returns [`None`]
+    /// if the size is not permitted by `limits`, if the type is not
+    /// [`WINDOW_DUMP_TYPE_GRANT_REFS`], if `bpp` is not 24, or if the number
+    /// of grant refs is not the number of pages needed for the size.
+    pub fn parse(body: &'a [u8], limits: &WindowLimits) -> Option<Self> {
+        use qubes_castable::Castable as _;
+        const HEADER_LEN: usize = core::mem::size_of::<WindowDumpHeader>();
+        if body.len() < HEADER_LEN {
+            return None;
+        }
+        let (header, grant_refs) = body.split_at(HEADER_LEN);
+        let header = WindowDumpHeader::from_bytes(header);
+        let pages = limits.dump_pages(header.width, header.height)?;
+        if header.ty != WINDOW_DUMP_TYPE_GRANT_REFS
+            || header.bpp != 24
+            || grant_refs.len() != pages as usize * core::mem::size_of::<u32>()
+        {
+            None
+        } else {
+            Some(Self { header, grant_refs })
+        }
+    }
+
+    /// The header of the dump
+    pub fn header(&self) -> WindowDumpHeader {
+        self.header
+    }
+
+    /// Returns an iterator over the grant refs, one per page.
+    pub fn grants(&self) -> impl Iterator<Item = u32> + 'a {
+        self.grant_refs
+            .chunks_exact(core::mem::size_of::<u32>())
+            .map(|r| u32::from_ne_bytes([r[0], r[1], r[2], r[3]]))
+    }
+
+    /// Returns the number of grant refs.  This is always at least 1.
+    pub fn len(&self) -> usize {
+        self.grant_refs.len() / core::mem::size_of::<u32>()
+    }
+
+    /// Returns `false`, as there is always at least one grant ref.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns true if the dump has exactly the given dimensions, such as
+    /// those of the window it is for.
+    pub fn validate(&self, width: u32, height: u32) -> bool {
+        self.header.width == width && self.header.height == height
+    }
+}
+
+impl WindowDumpDeltaHeader {
+    /// Splits the body of a [`MSG_WINDOW_DUMP_DELTA`] message into the
+    /// header and the grant refs to add.  Returns [`None`] if the size is
+    /// not permitted by `limits`, if `bpp` is not 24, if the number of grant
+    /// refs does not match `add`, or if `keep + add` is not the number of
+    /// pages needed for the new size.
+    pub fn split_body<'a>(body: &'a [u8], limits: &WindowLimits) -> Option<(Self, &'a [u8])> {
+        use qubes_castable::Castable as _;
+        const HEADER_LEN: usize = core::mem::size_of::<WindowDumpDeltaHeader>();
+        if body.len() < HEADER_LEN {
+            return None;
+        }
+        let (header, refs) = body.split_at(HEADER_LEN);
+        let header = Self::from_bytes(header);
+        let pages = limits.dump_pages(header.width, header.height)?;
+        if header.bpp != 24
+            || refs.len() != header.add as usize * core::mem::size_of::<u32>()
+            || header.keep.checked_add(header.add) != Some(pages)
+        {
+            None
+        } else {
+            Some((header, refs))
+        }
+    }
+}
+
+/// Returns the number of pages needed for a window dump of the given size,
+/// or [`None`] if the size is invalid.
+pub fn dump_pages(width: u32, height: u32) -> Option<u32> {
+    if width == 0 || height == 0 || width > MAX_WINDOW_WIDTH || height > MAX_WINDOW_HEIGHT {
+        None
+    } else {
+        Some((width * height * (DUMMY_DRV_FB_BPP / 8) + XC_PAGE_SIZE - 1) >> 12)
+    }
+}
+
+impl WindowScale {
+    /// No scaling
+    pub const DEFAULT: Self = Self {
+        scale: SCALE_DENOMINATOR,
+    };
+
+    /// Returns true if the scale factor is valid (not zero).
+    pub fn is_valid(&self) -> bool {
+        self.scale != 0
+    }
+
+    /// Scales a length in logical pixels to physical pixels, rounding to the
+    /// nearest pixel.
+    pub fn apply(&self, length: u32) -> u32 {
+        let scaled = (u64::from(length) * u64::from(self.scale) + u64::from(SCALE_DENOMINATOR / 2))
+            / u64::from(SCALE_DENOMINATOR);
+        u32::try_from(scaled).unwrap_or(u32::MAX)
+    }
+}
+
+impl Cursor {
+    /// Returns true if the cursor is [`CURSOR_DEFAULT`], or an X11 cursor
+    /// font glyph no greater than [`CURSOR_X11_MAX`].
+    pub fn is_valid(&self) -> bool {
+        self.cursor == CURSOR_DEFAULT || (CURSOR_X11..=CURSOR_X11_MAX).contains(&self.cursor)
+    }
+}
+
+impl WindowHints {
+    /// Returns true if only the flags in [`WindowHintsFlags::ALL`] are set,
+    /// and the minimum size is no larger than the maximum size if both are
+    /// given.
+    pub fn is_valid(&self) -> bool {
+        let both = WindowHintsFlags::PMinSize as u32 | WindowHintsFlags::PMaxSize as u32;
+        self.flags & !WindowHintsFlags::ALL == 0
+            && (self.flags & both != both
+                || (self.min_size.width <= self.max_size.width
+                    && self.min_size.height <= self.max_size.height))
+    }
+}
+
+impl WindowFlags {
+    /// Returns true if only the flags in [`WindowFlag::ALL`] are set or
+    /// unset.
+    pub fn is_valid(&self) -> bool {
+        (self.set | self.unset) & !WindowFlag::ALL == 0
+    }
+}
+
+impl WindowIconHeader {
+    /// Returns the number of bytes of pixel data that must follow this
+    /// header, or [`None`] if the size is invalid.
+    pub fn data_len(&self) -> Option<usize> {
+        argb_data_len(self.size, MAX_ICON_WIDTH, MAX_ICON_HEIGHT)
+    }
+}
+
+/// Returns the length in bytes of an ARGB image of the given size, or [`None`]
+/// if either dimension is zero or exceeds the given maximum.
+fn argb_data_len(size: WindowSize, max_width: u32, max_height: u32) -> Option<usize> {
+    let WindowSize { width, height } = size;
+    if width == 0 || height == 0 || width > max_width || height > max_height {
+        None
+    } else {
+        Some(width as usize * height as usize * core::mem::size_of::<u32>())
+    }
+}
+
+/// Effective limits on the size of a window.
+///
+/// [`MAX_WINDOW_WIDTH`] and [`MAX_WINDOW_HEIGHT`] are absolute caps, but the
+/// daemon also reports the size of its root window in [`XConf`], and no
+/// window needs to be larger than that.  Use [`XConf::window_limits`] to get
+/// the limits that apply to a connection.
+///
+/// The limits never exceed the absolute caps, so the sizes derived from them
+/// cannot overflow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WindowLimits {
+    max_width: u32,
+    max_height: u32,
+}
+
+impl Default for WindowLimits {
+    fn default() -> Self {
+        Self::MAX
+    }
+}
+
+impl WindowLimits {
+    /// The absolute limits, used when nothing better is known.
+    pub const MAX: Self = Self {
+        max_width: MAX_WINDOW_WIDTH,
+        max_height: MAX_WINDOW_HEIGHT,
+    };
+
+    /// Returns the limits for the given root window size.  Each dimension is
+    /// capped at the absolute limit, and a zero dimension (which no real
+    /// daemon sends) means that dimension is not known.
+    pub fn for_root_size(size: WindowSize) -> Self {
+        let limit = |root: u32, max: u32| if root == 0 { max } else { root.min(max) };
+        Self {
+            max_width: limit(size.width, MAX_WINDOW_WIDTH),
+            max_height: limit(size.height, MAX_WINDOW_HEIGHT),
+        }
+    }
+
+    /// Maximum window width, in pixels
+    pub fn max_width(&self) -> u32 {
+        self.max_width
+    }
+
+    /// Maximum window height, in pixels
+    pub fn max_height(&self) -> u32 {
+        self.max_height
+    }
+
+    /// Returns true if a window of the given size is permitted: neither
+    /// dimension may be zero or exceed the limit.
+    pub fn allows(&self, size: WindowSize) -> bool {
+        size.width != 0
+            && size.height != 0
+            && size.width <= self.max_width
+            && size.height <= self.max_height
+    }
+
+    /// Maximum size of a shared memory segment for a window, in bytes
+    pub fn max_window_mem(&self) -> u32 {
+        self.max_width * self.max_height * (DUMMY_DRV_FB_BPP / 8)
+    }
+
+    /// Maximum number of shared pages in a single segment
+    pub fn max_grant_refs_count(&self) -> u32 {
+        (self.max_window_mem() + XC_PAGE_SIZE - 1) >> 12
+    }
+
+    /// Returns the number of pages needed for a window dump of the given
+    /// size, or [`None`] if the size is not permitted.
+    pub fn dump_pages(&self, width: u32, height: u32) -> Option<u32> {
+        if self.allows(WindowSize { width, height }) {
+            dump_pages(width, height)
+        } else {
+            None
+        }
+    }
+}
+
+impl XConf {
+    /// Returns the window size limits implied by this root window
+    /// configuration.
+    pub fn window_limits(&self) -> WindowLimits {
+        WindowLimits::for_root_size(self.size)
+    }
+}
+
+impl Create {
+    /// Returns true if the rectangle of this window is permitted by `limits`.
+    pub fn fits(&self, limits: &WindowLimits) -> bool {
+        limits.allows(self.rectangle.size)
+    }
+}
+
+impl Configure {
+    /// Returns true if the rectangle of this window is permitted by `limits`.
+    pub fn fits(&self, limits: &WindowLimits) -> bool {
+        limits.allows(self.rectangle.size)
+    }
 }
 
 macro_rules! impl_message {
-    ($(($t: ty, $kind: expr),)+) => {
-        $(impl Message for $t {
+    ($($(#[cfg($c: meta)])? ($t: ty, $kind: expr $(, $max_len: expr)?),)+) => {
+        $($(#[cfg($c)])? impl Message for $t {
             const KIND: Msg = $kind;
+            $(const MAX_LEN: usize = $max_len;)?
         })+
     }
 }
@@ -652,13 +2186,62 @@ impl_message! {
     (KeymapNotify, Msg::KeymapNotify),
     (WindowHints, Msg::WindowHints),
     (WindowFlags, Msg::WindowFlags),
-    (ShmCmd, Msg::ShmImage),
+    #[cfg(feature = "legacy-messages")]
+    (ShmCmd, Msg::MfnDump),
     (WMClass, Msg::WindowClass),
-    (WindowDumpHeader, Msg::WindowDump),
+    (
+        WindowDumpHeader,
+        Msg::WindowDump,
+        core::mem::size_of::<WindowDumpHeader>()
+            + MAX_GRANT_REFS_COUNT as usize * core::mem::size_of::<u32>()
+    ),
     (Cursor, Msg::Cursor),
+    #[cfg(feature = "extensions")]
+    (
+        CursorImageHeader,
+        Msg::CursorImage,
+        core::mem::size_of::<CursorImageHeader>()
+            + (MAX_CURSOR_WIDTH * MAX_CURSOR_HEIGHT) as usize * core::mem::size_of::<u32>()
+    ),
+    #[cfg(feature = "extensions")]
+    (
+        ClipboardMimeHeader,
+        Msg::ClipboardMimeData,
+        core::mem::size_of::<ClipboardMimeHeader>() + MAX_CLIPBOARD_MIME_DATA_SIZE as usize
+    ),
+    #[cfg(feature = "extensions")]
+    (
+        WindowIconHeader,
+        Msg::WindowIcon,
+        core::mem::size_of::<WindowIconHeader>()
+            + (MAX_ICON_WIDTH * MAX_ICON_HEIGHT) as usize * core::mem::size_of::<u32>()
+    ),
+    #[cfg(feature = "extensions")]
+    (
+        Output,
+        Msg::Outputs,
+        MAX_OUTPUTS as usize * core::mem::size_of::<Output>()
+    ),
+    #[cfg(feature = "extensions")]
+    (WindowScale, Msg::WindowScale),
+    #[cfg(feature = "extensions")]
+    (
+        WindowDumpDeltaHeader,
+        Msg::WindowDumpDelta,
+        core::mem::size_of::<WindowDumpDeltaHeader>()
+            + MAX_GRANT_REFS_COUNT as usize * core::mem::size_of::<u32>()
+    ),
+    #[cfg(feature = "extensions")]
+    (
+        DamageHeader,
+        Msg::Damage,
+        core::mem::size_of::<DamageHeader>()
+            + MAX_DAMAGE_RECTS as usize * core::mem::size_of::<Rectangle>()
+    ),
     (Destroy, Msg::Destroy),
     (Dock, Msg::Dock),
     (Unmap, Msg::Unmap),
+    (DumpAck, Msg::DumpAck),
 }
 
 /// Error indicating that the length of a message is bad
@@ -675,11 +2258,26 @@ impl core::fmt::Display for BadLengthError {
         write!(
             f,
             "Bad length {} for message of type {}",
-            self.untrusted_len, self.ty
+            self.untrusted_len,
+            MsgType(self.ty)
         )
     }
 }
 
+/// A raw message type that displays as its symbolic name (such as
+/// `MSG_CONFIGURE`) if it is known, and as a number otherwise.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MsgType(pub u32);
+
+impl core::fmt::Display for MsgType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match Msg::try_from(self.0) {
+            Ok(msg) => f.write_str(msg.name()),
+            Err(ty) => write!(f, "unknown message type {}", ty),
+        }
+    }
+}
+
 /// A header that has been validated to be a valid message.
 ///
 /// Transmuting a [`Header`] to an [`UntrustedHeader`] is safe.
@@ -717,6 +2315,137 @@ impl Header {
     }
 }
 
+impl Header {
+    /// Creates a header for a message of type `T` with a body of `body_len`
+    /// bytes, including the struct itself and any data that follows it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `body_len` is not a valid length for messages of type `T`.
+    pub fn for_message<T: Message>(
+        window: WindowID,
+        body_len: usize,
+    ) -> Result<Self, BadLengthError> {
+        let ty = T::KIND as u32;
+        let untrusted_len = u32::try_from(body_len).map_err(|_| BadLengthError {
+            ty,
+            untrusted_len: u32::MAX,
+        })?;
+        let header = UntrustedHeader {
+            ty,
+            window,
+            untrusted_len,
+        };
+        Ok(header
+            .validate_length()?
+            .expect("Message::KIND is always a known message type"))
+    }
+}
+
+/// A fixed-size message together with a header that is guaranteed to match
+/// it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FramedMessage<T: Message> {
+    header: Header,
+    message: T,
+}
+
+impl<T: Message> FramedMessage<T> {
+    /// Frames `message`, which is directed to `window`.
+    pub fn new(message: T, window: WindowID) -> Self {
+        let header = Header::for_message::<T>(window, core::mem::size_of::<T>())
+            .expect("the size of a message struct is always a valid length");
+        Self { header, message }
+    }
+
+    /// The header of the message
+    pub fn header(&self) -> Header {
+        self.header
+    }
+
+    /// The body of the message
+    pub fn message(&self) -> &T {
+        &self.message
+    }
+
+    /// The window the message is directed to
+    pub fn window(&self) -> WindowID {
+        self.header.untrusted_window()
+    }
+
+    /// The header and body, as they appear on the wire
+    pub fn parts(&self) -> [&[u8]; 2] {
+        use qubes_castable::Castable as _;
+        [self.header.0.as_bytes(), self.message.as_bytes()]
+    }
+}
+
+/// Numbers of the deprecated messages, which stay reserved even when the
+/// `legacy-messages` feature is disabled.
+#[cfg(not(feature = "legacy-messages"))]
+const LEGACY_RESIZE: u32 = 129;
+#[cfg(not(feature = "legacy-messages"))]
+const LEGACY_MFNDUMP: u32 = 135;
+#[cfg(not(feature = "legacy-messages"))]
+const LEGACY_EXECUTE: u32 = 138;
+
+/// Returns the permissible lengths of a message of type `ty`, or [`None`] if
+/// the message type is not known.
+///
+/// This is the dynamic counterpart of [`Message::LENGTH`], for use when the
+/// type of the message is only known at runtime.  Some messages impose
+/// additional restrictions that are not expressible as a range; see
+/// [`UntrustedHeader::validate_length`].  Messages that are never valid, such
+/// as `MSG_RESIZE` and `MSG_EXECUTE`, have an empty range.  So do deprecated
+/// messages when the `legacy-messages` feature is disabled.
+pub const fn msg_length_limits(ty: u32) -> Option<RangeInclusive<usize>> {
+    Some(match ty {
+        MSG_CLIPBOARD_DATA => 0..=MAX_CLIPBOARD_SIZE as usize,
+        MSG_BUTTON => Button::LENGTH,
+        MSG_KEYPRESS => Keypress::LENGTH,
+        MSG_MOTION => Motion::LENGTH,
+        MSG_CROSSING => Crossing::LENGTH,
+        MSG_FOCUS => Focus::LENGTH,
+        MSG_CREATE => Create::LENGTH,
+        MSG_DESTROY => Destroy::LENGTH,
+        MSG_MAP => MapInfo::LENGTH,
+        MSG_UNMAP => Unmap::LENGTH,
+        MSG_CONFIGURE => Configure::LENGTH,
+        #[cfg(feature = "legacy-messages")]
+        MSG_MFNDUMP => 0..=MAX_MFN_COUNT as usize * core::mem::size_of::<u32>(),
+        MSG_SHMIMAGE => ShmImage::LENGTH,
+        MSG_CLOSE | MSG_CLIPBOARD_REQ => 0..=0,
+        MSG_SET_TITLE => WMName::LENGTH,
+        MSG_KEYMAP_NOTIFY => KeymapNotify::LENGTH,
+        MSG_DOCK => Dock::LENGTH,
+        MSG_WINDOW_HINTS => WindowHints::LENGTH,
+        MSG_WINDOW_FLAGS => WindowFlags::LENGTH,
+        MSG_WINDOW_CLASS => WMClass::LENGTH,
+        MSG_WINDOW_DUMP => WindowDumpHeader::LENGTH,
+        MSG_CURSOR => Cursor::LENGTH,
+        MSG_WINDOW_DUMP_ACK => DumpAck::LENGTH,
+        #[cfg(feature = "extensions")]
+        MSG_CURSOR_IMAGE => CursorImageHeader::LENGTH,
+        #[cfg(feature = "extensions")]
+        MSG_CLIPBOARD_MIME_DATA => ClipboardMimeHeader::LENGTH,
+        #[cfg(feature = "extensions")]
+        MSG_WINDOW_ICON => WindowIconHeader::LENGTH,
+        #[cfg(feature = "extensions")]
+        MSG_OUTPUTS => Output::LENGTH,
+        #[cfg(feature = "extensions")]
+        MSG_WINDOW_SCALE => WindowScale::LENGTH,
+        #[cfg(feature = "extensions")]
+        MSG_DAMAGE => DamageHeader::LENGTH,
+        #[cfg(feature = "extensions")]
+        MSG_WINDOW_DUMP_DELTA => WindowDumpDeltaHeader::LENGTH,
+        #[cfg(feature = "legacy-messages")]
+        MSG_RESIZE | MSG_EXECUTE => RangeInclusive::new(1, 0),
+        #[cfg(not(feature = "legacy-messages"))]
+        LEGACY_RESIZE | LEGACY_MFNDUMP | LEGACY_EXECUTE => RangeInclusive::new(1, 0),
+        _ => return None,
+    })
+}
+
 impl UntrustedHeader {
     /// Validate that the length of this header is correct
     ///
@@ -730,41 +2459,34 @@ impl UntrustedHeader {
     /// Returns an error if the length is bad, or if the type of the message is
     /// not valid in any supported protocol version.
     pub fn validate_length(&self) -> Result<Option<Header>, BadLengthError> {
-        const U32_SIZE: u32 = size_of::<u32>() as u32;
         use core::mem::size_of;
-        let untrusted_len = self.untrusted_len;
-        if match self.ty {
-            MSG_CLIPBOARD_DATA => untrusted_len <= MAX_CLIPBOARD_SIZE,
-            MSG_BUTTON => untrusted_len == size_of::<Button>() as u32,
-            MSG_KEYPRESS => untrusted_len == size_of::<Keypress>() as u32,
-            MSG_MOTION => untrusted_len == size_of::<Motion>() as u32,
-            MSG_CROSSING => untrusted_len == size_of::<Crossing>() as u32,
-            MSG_FOCUS => untrusted_len == size_of::<Focus>() as u32,
-            MSG_CREATE => untrusted_len == size_of::<Create>() as u32,
-            MSG_DESTROY => untrusted_len == 0,
-            MSG_MAP => untrusted_len == size_of::<MapInfo>() as u32,
-            MSG_UNMAP => untrusted_len == 0,
-            MSG_CONFIGURE => untrusted_len == size_of::<Configure>() as u32,
-            MSG_MFNDUMP if untrusted_len % U32_SIZE != 0 => false,
-            MSG_MFNDUMP => untrusted_len / U32_SIZE <= MAX_MFN_COUNT,
-            MSG_SHMIMAGE => untrusted_len == size_of::<ShmImage>() as u32,
-            MSG_CLOSE | MSG_CLIPBOARD_REQ => untrusted_len == 0,
-            MSG_SET_TITLE => untrusted_len == size_of::<WMName>() as u32,
-            MSG_KEYMAP_NOTIFY => untrusted_len == size_of::<KeymapNotify>() as u32,
-            MSG_DOCK => untrusted_len == 0,
-            MSG_WINDOW_HINTS => untrusted_len == size_of::<WindowHints>() as u32,
-            MSG_WINDOW_FLAGS => untrusted_len == size_of::<WindowFlags>() as u32,
-            MSG_WINDOW_CLASS => untrusted_len == size_of::<WMClass>() as u32,
-            MSG_WINDOW_DUMP if untrusted_len < size_of::<WindowDumpHeader>() as u32 => false,
-            MSG_WINDOW_DUMP => {
-                let refs_len = untrusted_len - size_of::<WindowDumpHeader>() as u32;
-                (refs_len % U32_SIZE) == 0 && (refs_len / U32_SIZE) <= MAX_GRANT_REFS_COUNT
+        const U32_SIZE: usize = size_of::<u32>();
+        let untrusted_len = self.untrusted_len as usize;
+        let limits = match msg_length_limits(self.ty) {
+            Some(limits) => limits,
+            None => return Ok(None),
+        };
+        if limits.contains(&untrusted_len)
+            && match self.ty {
+                #[cfg(feature = "legacy-messages")]
+                MSG_MFNDUMP => untrusted_len.is_multiple_of(U32_SIZE),
+                #[cfg(feature = "extensions")]
+                MSG_CURSOR_IMAGE | MSG_WINDOW_ICON => untrusted_len.is_multiple_of(U32_SIZE),
+                #[cfg(feature = "extensions")]
+                MSG_OUTPUTS => untrusted_len.is_multiple_of(size_of::<Output>()),
+                #[cfg(feature = "extensions")]
+                MSG_DAMAGE => (untrusted_len - size_of::<DamageHeader>())
+                    .is_multiple_of(size_of::<Rectangle>()),
+                MSG_WINDOW_DUMP => {
+                    (untrusted_len - size_of::<WindowDumpHeader>()).is_multiple_of(U32_SIZE)
+                }
+                #[cfg(feature = "extensions")]
+                MSG_WINDOW_DUMP_DELTA => {
+                    (untrusted_len - size_of::<WindowDumpDeltaHeader>()).is_multiple_of(U32_SIZE)
+                }
+                _ => true,
             }
-            MSG_CURSOR => untrusted_len == size_of::<Cursor>() as u32,
-            MSG_WINDOW_DUMP_ACK => untrusted_len == 0,
-            MSG_EXECUTE => false,
-            _ => return Ok(None),
-        } {
+        {
             Ok(Some(Header(*self)))
         } else {
             Err(BadLengthError {
@@ -0,0 +1,167 @@
+/*
+ * The Qubes OS Project, http://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Tracking of the lifecycle of a window, shared by agents and daemons.
+
+use crate::{Msg, MsgType};
+
+/// The state of a window
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum WindowState {
+    /// The window has been created with [`MSG_CREATE`](crate::MSG_CREATE),
+    /// but never mapped.
+    Created,
+    /// The window is mapped.
+    Mapped,
+    /// The window was mapped, but has since been unmapped.
+    Unmapped,
+    /// The window has been destroyed.
+    Destroyed,
+}
+
+/// The peer that sent a message
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Peer {
+    /// The GUI agent
+    Agent,
+    /// The GUI daemon
+    Daemon,
+}
+
+/// What to do with a message, as decided by [`WindowLifecycle::apply`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Disposition {
+    /// The message is valid and should be processed.
+    Process,
+    /// The message was sent by the daemon before it learned that the window
+    /// was destroyed.  It must be ignored.
+    Ignore,
+}
+
+/// Error indicating that a message is not valid in the current state of a
+/// window
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BadTransitionError {
+    /// The state of the window
+    pub state: WindowState,
+    /// The type of the message
+    pub msg: Msg,
+    /// The peer that sent the message
+    pub sender: Peer,
+}
+
+impl core::fmt::Display for BadTransitionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:?} sent {} for a window in state {:?}",
+            self.sender,
+            MsgType(self.msg as u32),
+            self.state
+        )
+    }
+}
+
+/// The lifecycle of one window: Created → Mapped ⇄ Unmapped → Destroyed.
+///
+/// Only the agent maps and unmaps windows; a [`MSG_MAP`](crate::MSG_MAP)
+/// from the daemon is a redraw request and does not change the state.
+/// Unmapping a window that is not mapped has no effect.  Either peer may
+/// destroy a window.
+///
+/// After the agent destroys a window, the daemon may still send messages
+/// about it that it sent before it received the
+/// [`MSG_DESTROY`](crate::MSG_DESTROY), including its confirmation of the
+/// destruction.  [`WindowLifecycle::apply`] returns [`Disposition::Ignore`]
+/// for these.  Messages from the agent about a destroyed window are always
+/// errors.
+///
+/// ```
+/// use qubes_gui::{Disposition, Msg, Peer, WindowLifecycle, WindowState};
+/// let mut window = WindowLifecycle::new();
+/// assert_eq!(window.apply(Msg::Map, Peer::Agent), Ok(Disposition::Process));
+/// assert_eq!(window.state(), WindowState::Mapped);
+/// assert_eq!(window.apply(Msg::Destroy, Peer::Agent), Ok(Disposition::Process));
+/// // The daemon sent a keypress before it saw the MSG_DESTROY
+/// assert_eq!(window.apply(Msg::Keypress, Peer::Daemon), Ok(Disposition::Ignore));
+/// assert!(window.apply(Msg::Configure, Peer::Agent).is_err());
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WindowLifecycle {
+    state: WindowState,
+}
+
+impl Default for WindowLifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WindowLifecycle {
+    /// Creates the lifecycle of a window that has just been created with
+    /// [`MSG_CREATE`](crate::MSG_CREATE).
+    pub fn new() -> Self {
+        Self {
+            state: WindowState::Created,
+        }
+    }
+
+    /// The current state of the window
+    pub fn state(&self) -> WindowState {
+        self.state
+    }
+
+    /// Returns true if the window has not been destroyed.
+    pub fn is_alive(&self) -> bool {
+        self.state != WindowState::Destroyed
+    }
+
+    /// Updates the state for a message about this window, and decides what
+    /// to do with the message.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the message is not valid in the current state.  This includes
+    /// [`MSG_CREATE`](crate::MSG_CREATE) for a window that already exists,
+    /// and any message from the agent about a destroyed window.
+    pub fn apply(&mut self, msg: Msg, sender: Peer) -> Result<Disposition, BadTransitionError> {
+        use WindowState::*;
+        let error = BadTransitionError {
+            state: self.state,
+            msg,
+            sender,
+        };
+        if self.state == Destroyed {
+            return match sender {
+                Peer::Daemon => Ok(Disposition::Ignore),
+                Peer::Agent => Err(error),
+            };
+        }
+        self.state = match (msg, sender) {
+            (Msg::Create, _) => return Err(error),
+            (Msg::Destroy, _) => Destroyed,
+            (Msg::Map, Peer::Agent) => Mapped,
+            (Msg::Unmap, Peer::Agent) if self.state == Mapped => Unmapped,
+            _ => self.state,
+        };
+        Ok(Disposition::Process)
+    }
+}
@@ -0,0 +1,496 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ */
+
+extern crate std;
+
+use super::*;
+use qubes_castable::Castable as _;
+use std::vec::Vec;
+
+fn rect(x: i32, y: i32, width: u32, height: u32) -> Rectangle {
+    Rectangle::try_from((x, y, width, height)).unwrap()
+}
+
+#[test]
+fn rectangle_overflow() {
+    assert_eq!(
+        Rectangle::try_from((i32::MAX, 0, 1, 1)),
+        Err(BadRectangleError)
+    );
+    assert_eq!(
+        Rectangle::try_from((0, i32::MAX - 1, 1, 2)),
+        Err(BadRectangleError)
+    );
+    assert_eq!(
+        Rectangle::try_from((-1, 0, u32::MAX, 1)),
+        Err(BadRectangleError)
+    );
+    assert!(Rectangle::try_from((i32::MAX - 1, 0, 1, 1)).is_ok());
+    assert!(Rectangle::try_from((i32::MIN, 0, u32::MAX, 1)).is_ok());
+    let max = Coordinates { x: i32::MAX, y: 0 };
+    let one = Coordinates { x: 1, y: 1 };
+    assert_eq!(max.checked_add(one), None);
+    assert_eq!(Coordinates { x: 0, y: i32::MIN }.checked_sub(one), None);
+}
+
+#[test]
+fn rectangle_set_operations() {
+    let a = rect(0, 0, 10, 10);
+    assert_eq!(a.intersect(&rect(10, 0, 10, 10)), None);
+    assert_eq!(a.intersect(&rect(0, 0, 0, 10)), None);
+    assert_eq!(a.intersect(&rect(5, 5, 10, 10)), Some(rect(5, 5, 5, 5)));
+    // The union spans the whole coordinate space without overflowing
+    let low = rect(i32::MIN, 0, 1, 1);
+    let high = rect(i32::MAX - 1, 0, 1, 1);
+    assert_eq!(low.union(&high), Some(rect(i32::MIN, 0, u32::MAX, 1)));
+    assert_eq!(a.union(&rect(100, 100, 0, 0)), Some(a));
+    assert_eq!(
+        a.clamp_to(WindowSize {
+            width: 0,
+            height: 10
+        }),
+        None
+    );
+    assert!(!a.contains(Coordinates { x: 10, y: 0 }));
+}
+
+#[test]
+fn validated_keypress_button_focus() {
+    let keypress = Keypress {
+        ty: 4,
+        ..Default::default()
+    };
+    assert_eq!(
+        ValidatedKeypress::try_from(keypress),
+        Err(BadFieldError {
+            msg: "MSG_KEYPRESS",
+            field: "ty",
+            value: 4,
+        })
+    );
+    let button = Button {
+        ty: 2,
+        ..Default::default()
+    };
+    assert_eq!(
+        ValidatedButton::try_from(button).map_err(|e| (e.field, e.value)),
+        Err(("ty", 2))
+    );
+    let focus = Focus {
+        ty: EV_FOCUS_IN,
+        mode: 0,
+        detail: 8,
+    };
+    assert_eq!(
+        ValidatedFocus::try_from(focus).map_err(|e| (e.field, e.value)),
+        Err(("detail", 8))
+    );
+    // NotifyPointer is valid in focus events, but not in crossing events
+    let focus = Focus {
+        detail: NOTIFY_POINTER,
+        ..focus
+    };
+    assert!(ValidatedFocus::try_from(focus).is_ok());
+}
+
+#[test]
+fn validated_crossing() {
+    let good = Crossing {
+        ty: EV_ENTER,
+        mode: NOTIFY_NORMAL,
+        detail: NOTIFY_ANCESTOR,
+        ..Default::default()
+    };
+    assert!(ValidatedCrossing::try_from(good).is_ok());
+    let check = |crossing: Crossing, field: &'static str, value: u32| {
+        assert_eq!(
+            ValidatedCrossing::try_from(crossing),
+            Err(BadFieldError {
+                msg: "MSG_CROSSING",
+                field,
+                value,
+            })
+        )
+    };
+    check(Crossing { ty: 6, ..good }, "ty", 6);
+    check(
+        Crossing {
+            ty: EV_FOCUS_IN,
+            ..good
+        },
+        "ty",
+        EV_FOCUS_IN,
+    );
+    check(Crossing { mode: 4, ..good }, "mode", 4);
+    check(
+        Crossing {
+            mode: NOTIFY_WHILE_GRABBED,
+            ..good
+        },
+        "mode",
+        NOTIFY_WHILE_GRABBED,
+    );
+    for &detail in &[NOTIFY_POINTER, NOTIFY_POINTER_ROOT, NOTIFY_DETAIL_NONE, 8] {
+        check(Crossing { detail, ..good }, "detail", detail);
+    }
+}
+
+#[test]
+fn configure_tracker() {
+    let mut tracker = ConfigureTracker::new();
+    let conf = Configure {
+        rectangle: rect(0, 0, 100, 100),
+        ..Default::default()
+    };
+    assert_eq!(tracker.take_echo(), None);
+    assert_eq!(tracker.geometry(), None);
+    // Sending the same geometry twice is a no-op
+    assert_eq!(tracker.agent_configure(conf), Some(conf));
+    assert_eq!(tracker.agent_configure(conf), None);
+    // An echo of an echo is not echoed again
+    let mut resized = conf;
+    resized.rectangle.size.width = 200;
+    tracker.daemon_configure(resized);
+    assert_eq!(tracker.take_echo(), Some(resized));
+    assert_eq!(tracker.take_echo(), None);
+    tracker.daemon_configure(resized);
+    assert_eq!(tracker.pending(), None);
+    // A change from the agent discards an unapplied one from the daemon
+    tracker.daemon_configure(conf);
+    assert_eq!(tracker.agent_configure(conf), Some(conf));
+    assert_eq!(tracker.take_echo(), None);
+    assert_eq!(tracker.geometry(), Some(conf.rectangle));
+}
+
+#[test]
+fn lifecycle_bad_transitions() {
+    let bad = |state, msg, sender| Err(BadTransitionError { state, msg, sender });
+    let mut window = WindowLifecycle::new();
+    assert_eq!(
+        window.apply(Msg::Create, Peer::Agent),
+        bad(WindowState::Created, Msg::Create, Peer::Agent)
+    );
+    // Only the agent maps and unmaps windows
+    assert_eq!(
+        window.apply(Msg::Map, Peer::Daemon),
+        Ok(Disposition::Process)
+    );
+    assert_eq!(window.state(), WindowState::Created);
+    assert_eq!(
+        window.apply(Msg::Unmap, Peer::Agent),
+        Ok(Disposition::Process)
+    );
+    assert_eq!(window.state(), WindowState::Created);
+    assert_eq!(
+        window.apply(Msg::Map, Peer::Agent),
+        Ok(Disposition::Process)
+    );
+    assert_eq!(
+        window.apply(Msg::Unmap, Peer::Agent),
+        Ok(Disposition::Process)
+    );
+    assert_eq!(window.state(), WindowState::Unmapped);
+    assert_eq!(
+        window.apply(Msg::Create, Peer::Daemon),
+        bad(WindowState::Unmapped, Msg::Create, Peer::Daemon)
+    );
+    assert_eq!(window.state(), WindowState::Unmapped);
+    assert_eq!(
+        window.apply(Msg::Destroy, Peer::Daemon),
+        Ok(Disposition::Process)
+    );
+    assert!(!window.is_alive());
+    for &msg in &[Msg::Destroy, Msg::Map, Msg::Create] {
+        assert_eq!(window.apply(msg, Peer::Daemon), Ok(Disposition::Ignore));
+        assert_eq!(
+            window.apply(msg, Peer::Agent),
+            bad(WindowState::Destroyed, msg, Peer::Agent)
+        );
+    }
+    assert_eq!(window.state(), WindowState::Destroyed);
+}
+
+#[test]
+fn modifier_state() {
+    assert_eq!(ModifierState::from_bits(1 << 13), None);
+    assert_eq!(
+        ModifierState::from_bits_truncate(u32::MAX),
+        ModifierState::ALL
+    );
+    let state = ModifierState::ALL;
+    assert!(!state.button(0));
+    assert!(!state.button(6));
+    assert!(!state.button(u32::MAX));
+    assert!(state.button(5));
+    assert!(!ModifierState::BUTTON1.button(2));
+    assert!(!ModifierState::SHIFT.intersects(ModifierState::CONTROL));
+}
+
+#[test]
+fn capabilities() {
+    let version = |minor| PROTOCOL_VERSION_MAJOR << 16 | minor;
+    assert_eq!(
+        Capabilities::implied_by_version(version(7)),
+        Capabilities::EMPTY
+    );
+    assert_eq!(
+        Capabilities::implied_by_version(version(9)),
+        Capabilities::CURSOR_IMAGE | Capabilities::CLIPBOARD_MIME
+    );
+    // Capabilities are negotiated explicitly from MIN_VERSION on
+    assert_eq!(
+        Capabilities::implied_by_version(Capabilities::MIN_VERSION),
+        Capabilities::EMPTY
+    );
+    // A different major version implies nothing
+    assert_eq!(
+        Capabilities::implied_by_version((PROTOCOL_VERSION_MAJOR + 1) << 16 | 11),
+        Capabilities::EMPTY
+    );
+    let agent = Capabilities::CURSOR_IMAGE | Capabilities::DAMAGE;
+    let both = agent.intersection(Capabilities::DAMAGE | Capabilities::OUTPUTS);
+    assert_eq!(both, Capabilities::DAMAGE);
+    assert!(!both.contains(Capabilities::CURSOR_IMAGE));
+    assert!(Capabilities::ALL.contains(agent));
+}
+
+#[test]
+fn output_list() {
+    let rect = |x, y, width, height| Rectangle {
+        top_left: Coordinates { x, y },
+        size: WindowSize { width, height },
+    };
+    let output = Output {
+        rectangle: rect(0, 0, 1920, 1080),
+        scale: SCALE_DENOMINATOR,
+        flags: 0,
+    };
+    let list = |outputs: &[Output]| -> Vec<u8> {
+        outputs.iter().flat_map(|o| o.as_bytes().to_vec()).collect()
+    };
+    assert!(OutputList::parse(&[]).is_none());
+    let body = list(&[output]);
+    assert!(OutputList::parse(&body[1..]).is_none());
+    assert_eq!(OutputList::parse(&body).map(|l| l.len()), Some(1));
+    for bad in [
+        Output { scale: 0, ..output },
+        Output {
+            rectangle: rect(0, 0, 0, 1080),
+            ..output
+        },
+        Output {
+            rectangle: rect(0, 0, 1920, 0),
+            ..output
+        },
+    ]
+    .iter()
+    {
+        assert!(OutputList::parse(&list(&[output, *bad])).is_none());
+    }
+    let too_many = list(&[output; MAX_OUTPUTS as usize + 1]);
+    assert!(OutputList::parse(&too_many).is_none());
+    let max = &too_many[core::mem::size_of::<Output>()..];
+    assert!(OutputList::parse(max).is_some());
+}
+
+#[test]
+fn damage_list() {
+    let one = Rectangle {
+        top_left: Coordinates { x: 0, y: 0 },
+        size: WindowSize {
+            width: 1,
+            height: 1,
+        },
+    };
+    let body = |count: u32, rects: usize| -> Vec<u8> {
+        let mut body = DamageHeader { count }.as_bytes().to_vec();
+        for _ in 0..rects {
+            body.extend_from_slice(one.as_bytes())
+        }
+        body
+    };
+    assert!(DamageList::parse(&[0; 3]).is_none());
+    assert!(DamageList::parse(&body(0, 0)).is_none());
+    assert!(DamageList::parse(&body(2, 1)).is_none());
+    assert!(DamageList::parse(&body(1, 2)).is_none());
+    assert!(DamageList::parse(&body(1, 1)[..11]).is_none());
+    let max = MAX_DAMAGE_RECTS as usize;
+    assert!(DamageList::parse(&body(MAX_DAMAGE_RECTS + 1, max + 1)).is_none());
+    let full = body(MAX_DAMAGE_RECTS, max);
+    let list = DamageList::parse(&full).unwrap();
+    assert_eq!(list.len(), max);
+    assert!(list.iter().all(|r| r == one));
+}
+
+#[test]
+fn wm_name() {
+    assert_eq!(
+        WMName::new("a\0b").map(|_| ()),
+        Err(BadStringError::Control { offset: 1 })
+    );
+    assert_eq!(
+        WMName::new("ab\u{85}").map(|_| ()),
+        Err(BadStringError::Control { offset: 2 })
+    );
+    // Truncation does not split a character
+    let long = "é".repeat(100);
+    let name = WMName::new(&long).unwrap();
+    assert_eq!(name.as_str(), Ok(&long[..126]));
+    let mut name = WMName { data: [b'a'; 128] };
+    assert_eq!(name.as_str(), Err(BadStringError::NotTerminated));
+    name.data[127] = 0;
+    name.data[0] = 0xC3;
+    assert!(matches!(name.as_str(), Err(BadStringError::BadUTF8(_))));
+    name.data[0] = b'\t';
+    assert_eq!(name.as_str(), Err(BadStringError::Control { offset: 0 }));
+}
+
+#[test]
+fn wm_class() {
+    assert_eq!(
+        WMClass::new("xterm", "\x1b").map(|_| ()),
+        Err(BadStringError::Control { offset: 0 })
+    );
+    assert_eq!(
+        WMClass::new("x\nterm", "xterm").map(|_| ()),
+        Err(BadStringError::Control { offset: 1 })
+    );
+    let mut class = WMClass::new("XTerm", "xterm").unwrap();
+    assert_eq!(class.res_class(), Ok("XTerm"));
+    assert_eq!(class.res_name(), Ok("xterm"));
+    class.res_name = [b'x'; 64];
+    assert_eq!(class.res_name(), Err(BadStringError::NotTerminated));
+    assert_eq!(class.res_class(), Ok("XTerm"));
+}
+
+#[test]
+fn window_limits() {
+    let max = WindowLimits::MAX;
+    assert_eq!(
+        max.max_window_mem(),
+        MAX_WINDOW_WIDTH * MAX_WINDOW_HEIGHT * 4
+    );
+    assert_eq!(
+        max.max_grant_refs_count(),
+        max.max_window_mem() / XC_PAGE_SIZE
+    );
+    // Larger roots are capped, and unknown dimensions use the caps
+    let huge = WindowLimits::for_root_size(WindowSize {
+        width: u32::MAX,
+        height: 0,
+    });
+    assert_eq!(huge, max);
+    let small = WindowLimits::for_root_size(WindowSize {
+        width: 800,
+        height: 600,
+    });
+    assert_eq!((small.max_width(), small.max_height()), (800, 600));
+    assert_eq!(small.max_window_mem(), 800 * 600 * 4);
+    assert!(small.allows(WindowSize {
+        width: 800,
+        height: 600
+    }));
+    assert!(!small.allows(WindowSize {
+        width: 801,
+        height: 1
+    }));
+    assert!(!small.allows(WindowSize {
+        width: 0,
+        height: 1
+    }));
+}
+
+#[test]
+fn window_dump_delta() {
+    let body = |header: WindowDumpDeltaHeader, refs: u32| -> Vec<u8> {
+        let mut body = header.as_bytes().to_vec();
+        for i in 0..refs {
+            body.extend_from_slice(&i.to_ne_bytes())
+        }
+        body
+    };
+    // 1024 × 2 pixels need two pages
+    let good = WindowDumpDeltaHeader {
+        width: 1024,
+        height: 2,
+        bpp: 24,
+        keep: 1,
+        add: 1,
+    };
+    let limits = WindowLimits::MAX;
+    let delta = body(good, 1);
+    let (header, refs) = WindowDumpDeltaHeader::split_body(&delta, &limits).unwrap();
+    assert_eq!((header.keep, header.add, refs.len()), (1, 1, 4));
+    let small = WindowLimits::for_root_size(WindowSize {
+        width: 800,
+        height: 600,
+    });
+    assert!(WindowDumpDeltaHeader::split_body(&delta, &small).is_none());
+    let bad = |header: WindowDumpDeltaHeader, refs: u32| {
+        assert!(WindowDumpDeltaHeader::split_body(&body(header, refs), &limits).is_none())
+    };
+    bad(good, 0);
+    bad(good, 2);
+    bad(WindowDumpDeltaHeader { bpp: 32, ..good }, 1);
+    bad(WindowDumpDeltaHeader { keep: 2, ..good }, 1);
+    bad(WindowDumpDeltaHeader { keep: 0, ..good }, 1);
+    bad(
+        WindowDumpDeltaHeader {
+            keep: u32::MAX,
+            ..good
+        },
+        1,
+    );
+    bad(WindowDumpDeltaHeader { width: 0, ..good }, 1);
+    bad(
+        WindowDumpDeltaHeader {
+            height: MAX_WINDOW_HEIGHT + 1,
+            ..good
+        },
+        1,
+    );
+    assert!(WindowDumpDeltaHeader::split_body(&delta[..19], &limits).is_none());
+}
+
+#[cfg(feature = "keysym")]
+#[test]
+fn keysym_unmapped() {
+    use keysym::*;
+    assert_eq!(Layout::Us.keysym(0, ModifierState::EMPTY), None);
+    assert_eq!(Layout::Us.keysym(255, ModifierState::ALL), None);
+    assert_eq!(Layout::Us.keysym(u32::MAX, ModifierState::EMPTY), None);
+    // Caps Lock and Shift cancel out for letters, but not for symbols
+    let both = ModifierState::SHIFT | ModifierState::LOCK;
+    assert_eq!(Layout::Us.char(38, both), Some('a'));
+    assert_eq!(Layout::Us.char(10, both), Some('!'));
+    assert_eq!(
+        Layout::Us.char(87, ModifierState::SHIFT | ModifierState::MOD2),
+        None
+    );
+    assert_eq!(keysym_to_char(0), None);
+    assert_eq!(keysym_to_char(0x7f), None);
+    assert_eq!(keysym_to_char(0xffff_ffff), None);
+    // Surrogates and code points below U+0100 are not Unicode keysyms
+    assert_eq!(keysym_to_char(0x0100_d800), None);
+    assert_eq!(keysym_to_char(0x0100_0041), None);
+    assert_eq!(keysym_to_char(0x0111_0000), None);
+    assert_eq!(x11_to_evdev(EVDEV_OFFSET - 1), None);
+    assert_eq!(x11_to_evdev(EVDEV_OFFSET), Some(0));
+}
@@ -0,0 +1,231 @@
+/*
+ * The Qubes OS Project, http://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Validated versions of messages whose fields have restricted values.
+//!
+//! The wire structs ([`Keypress`], [`Button`], [`Crossing`], and [`Focus`])
+//! use raw integers, as they must be able to hold any bit pattern.  The types
+//! in this module can only hold valid values, and converting to them performs
+//! all of the checks required by the protocol specification.
+
+use crate::{
+    Button, ButtonEvent, Coordinates, Crossing, CrossingEvent, Focus, FocusEvent, KeyEvent,
+    Keypress, ModifierState, NotifyDetail, NotifyMode,
+};
+use core::convert::TryFrom;
+
+/// Error indicating that a field of a message has an invalid value
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BadFieldError {
+    /// The name of the message, such as `MSG_KEYPRESS`
+    pub msg: &'static str,
+    /// The name of the bad field
+    pub field: &'static str,
+    /// The bad value
+    pub value: u32,
+}
+
+impl core::fmt::Display for BadFieldError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Bad value {} for field {} of {}",
+            self.value, self.field, self.msg
+        )
+    }
+}
+
+fn field<T: TryFrom<u32, Error = u32>>(
+    msg: &'static str,
+    field: &'static str,
+    value: u32,
+) -> Result<T, BadFieldError> {
+    T::try_from(value).map_err(|value| BadFieldError { msg, field, value })
+}
+
+/// A validated [`Keypress`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidatedKeypress {
+    /// Whether the key was pressed or released
+    pub ty: KeyEvent,
+    /// Coordinates of the key press
+    pub coordinates: Coordinates,
+    /// Modifier state
+    pub state: ModifierState,
+    /// X11 key code
+    pub keycode: u32,
+}
+
+impl TryFrom<Keypress> for ValidatedKeypress {
+    type Error = BadFieldError;
+    fn try_from(keypress: Keypress) -> Result<Self, BadFieldError> {
+        Ok(Self {
+            ty: field("MSG_KEYPRESS", "ty", keypress.ty)?,
+            coordinates: keypress.coordinates,
+            state: keypress.state.into(),
+            keycode: keypress.keycode,
+        })
+    }
+}
+
+impl From<ValidatedKeypress> for Keypress {
+    fn from(keypress: ValidatedKeypress) -> Self {
+        Self {
+            ty: keypress.ty as u32,
+            coordinates: keypress.coordinates,
+            state: keypress.state.into(),
+            keycode: keypress.keycode,
+        }
+    }
+}
+
+/// A validated [`Button`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidatedButton {
+    /// Whether the button was pressed or released
+    pub ty: ButtonEvent,
+    /// Coordinates of the button press
+    pub coordinates: Coordinates,
+    /// Modifier state
+    pub state: ModifierState,
+    /// X11 button number
+    pub button: u32,
+}
+
+impl TryFrom<Button> for ValidatedButton {
+    type Error = BadFieldError;
+    fn try_from(button: Button) -> Result<Self, BadFieldError> {
+        Ok(Self {
+            ty: field("MSG_BUTTON", "ty", button.ty)?,
+            coordinates: button.coordinates,
+            state: button.state.into(),
+            button: button.button,
+        })
+    }
+}
+
+impl From<ValidatedButton> for Button {
+    fn from(button: ValidatedButton) -> Self {
+        Self {
+            ty: button.ty as u32,
+            coordinates: button.coordinates,
+            state: button.state.into(),
+            button: button.button,
+        }
+    }
+}
+
+/// A validated [`Crossing`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidatedCrossing {
+    /// Whether the pointer entered or left the window
+    pub ty: CrossingEvent,
+    /// Coordinates of the crossing
+    pub coordinates: Coordinates,
+    /// Modifier state
+    pub state: ModifierState,
+    /// X11 mode of the crossing.  Never [`NotifyMode::WhileGrabbed`].
+    pub mode: NotifyMode,
+    /// X11 detail of the crossing.  Never [`NotifyDetail::Pointer`],
+    /// [`NotifyDetail::PointerRoot`], or [`NotifyDetail::DetailNone`].
+    pub detail: NotifyDetail,
+    /// X11 focus of the crossing
+    pub focus: u32,
+}
+
+impl TryFrom<Crossing> for ValidatedCrossing {
+    type Error = BadFieldError;
+    fn try_from(crossing: Crossing) -> Result<Self, BadFieldError> {
+        const MSG: &str = "MSG_CROSSING";
+        let mode = match field(MSG, "mode", crossing.mode)? {
+            NotifyMode::WhileGrabbed => Err(BadFieldError {
+                msg: MSG,
+                field: "mode",
+                value: crossing.mode,
+            }),
+            mode => Ok(mode),
+        }?;
+        let detail = match field(MSG, "detail", crossing.detail)? {
+            NotifyDetail::Pointer | NotifyDetail::PointerRoot | NotifyDetail::DetailNone => {
+                Err(BadFieldError {
+                    msg: MSG,
+                    field: "detail",
+                    value: crossing.detail,
+                })
+            }
+            detail => Ok(detail),
+        }?;
+        Ok(Self {
+            ty: field(MSG, "ty", crossing.ty)?,
+            coordinates: crossing.coordinates,
+            state: crossing.state.into(),
+            mode,
+            detail,
+            focus: crossing.focus,
+        })
+    }
+}
+
+impl From<ValidatedCrossing> for Crossing {
+    fn from(crossing: ValidatedCrossing) -> Self {
+        Self {
+            ty: crossing.ty as u32,
+            coordinates: crossing.coordinates,
+            state: crossing.state.into(),
+            mode: crossing.mode as u32,
+            detail: crossing.detail as u32,
+            focus: crossing.focus,
+        }
+    }
+}
+
+/// A validated [`Focus`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ValidatedFocus {
+    /// Whether the window gained or lost focus
+    pub ty: FocusEvent,
+    /// The X11 event mode.  This is not validated, as agents MAY accept
+    /// nonzero values.
+    pub mode: u32,
+    /// The X11 event detail
+    pub detail: NotifyDetail,
+}
+
+impl TryFrom<Focus> for ValidatedFocus {
+    type Error = BadFieldError;
+    fn try_from(focus: Focus) -> Result<Self, BadFieldError> {
+        Ok(Self {
+            ty: field("MSG_FOCUS", "ty", focus.ty)?,
+            mode: focus.mode,
+            detail: field("MSG_FOCUS", "detail", focus.detail)?,
+        })
+    }
+}
+
+impl From<ValidatedFocus> for Focus {
+    fn from(focus: ValidatedFocus) -> Self {
+        Self {
+            ty: focus.ty as u32,
+            mode: focus.mode,
+            detail: focus.detail as u32,
+        }
+    }
+}
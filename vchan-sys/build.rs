@@ -0,0 +1,99 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! With the `bindgen` feature, generates bindings from the installed
+//! libvchan headers by running the `bindgen` command-line tool.
+//!
+//! The header is found with `pkg-config vchan-xen`, or can be given with the
+//! `LIBVCHAN_HEADER` environment variable.  The `BINDGEN` environment
+//! variable overrides the path to `bindgen`.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+const DEFAULT_HEADER: &str = "/usr/include/vchan-xen/libvchan.h";
+
+/// Returns the include flags for libvchan from pkg-config, if any.
+fn pkg_config_includes() -> Vec<String> {
+    Command::new(env::var_os("PKG_CONFIG").unwrap_or_else(|| "pkg-config".into()))
+        .args(["--cflags-only-I", "vchan-xen"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // dlopen needs a fixed list of functions, so it uses the hand-written
+    // declarations.
+    if env::var_os("CARGO_FEATURE_BINDGEN").is_none()
+        || env::var_os("CARGO_FEATURE_DLOPEN").is_some()
+    {
+        return;
+    }
+    println!("cargo:rerun-if-env-changed=LIBVCHAN_HEADER");
+    println!("cargo:rerun-if-env-changed=BINDGEN");
+    let includes = pkg_config_includes();
+    let header = match env::var_os("LIBVCHAN_HEADER") {
+        Some(header) => PathBuf::from(header),
+        None => includes
+            .iter()
+            .map(|flag| PathBuf::from(&flag[2..]).join("libvchan.h"))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| DEFAULT_HEADER.into()),
+    };
+    println!("cargo:rerun-if-changed={}", header.display());
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set")).join("bindings.rs");
+    let bindgen = env::var_os("BINDGEN").unwrap_or_else(|| "bindgen".into());
+    let status = Command::new(&bindgen)
+        .arg(&header)
+        .arg("-o")
+        .arg(&out)
+        .args([
+            "--allowlist-function",
+            "libvchan_.*",
+            // Keep the hand-written opaque type, so that the API does not
+            // depend on how the header spells it
+            "--blocklist-type",
+            "libvchan_t",
+            "--raw-line",
+            "use super::libvchan_t;",
+            "--no-layout-tests",
+            "--",
+        ])
+        .args(&includes)
+        .status()
+        .unwrap_or_else(|e| panic!("cannot run {:?}: {}", bindgen, e));
+    assert!(
+        status.success(),
+        "{:?} failed to generate bindings for {}",
+        bindgen,
+        header.display()
+    );
+}
@@ -0,0 +1,134 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Runtime loading of libvchan with `dlopen(3)`.
+
+use super::{libvchan_t, LoadError};
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::OnceLock;
+
+/// The name passed to `dlopen(3)`
+const LIBRARY_NAME: &[u8] = b"libvchan-xen.so\0";
+const RTLD_NOW: c_int = 2;
+
+#[link(name = "dl")]
+extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *mut c_char;
+}
+
+/// Returns the message from `dlerror(3)`.
+fn last_dl_error() -> String {
+    // SAFETY: dlerror() returns NULL or a NUL-terminated string
+    unsafe {
+        let msg = dlerror();
+        if msg.is_null() {
+            "unknown dynamic linker error".to_owned()
+        } else {
+            std::ffi::CStr::from_ptr(msg).to_string_lossy().into_owned()
+        }
+    }
+}
+
+macro_rules! dynamic_functions {
+    ($(pub fn $name: ident($($arg: ident: $ty: ty),*) $(-> $ret: ty)?;)+) => {
+        struct Functions {
+            $($name: unsafe extern "C" fn($($ty),*) $(-> $ret)?,)+
+        }
+
+        impl Functions {
+            /// # Safety
+            ///
+            /// `handle` must be a handle to libvchan returned by `dlopen(3)`.
+            unsafe fn resolve(handle: *mut c_void) -> Result<Self, LoadError> {
+                Ok(Self {
+                    $($name: {
+                        let symbol = concat!(stringify!($name), "\0");
+                        let ptr = dlsym(handle, symbol.as_ptr() as *const c_char);
+                        if ptr.is_null() {
+                            return Err(LoadError::MissingSymbol(stringify!($name)));
+                        }
+                        // SAFETY: the symbol has this signature in libvchan
+                        std::mem::transmute::<*mut c_void, unsafe extern "C" fn($($ty),*) $(-> $ret)?>(ptr)
+                    },)+
+                })
+            }
+        }
+
+        $(
+            /// Calls the function of the same name in libvchan.
+            ///
+            /// # Safety
+            ///
+            /// Same as the C function.
+            ///
+            /// # Panics
+            ///
+            /// Panics if libvchan could not be loaded; see [`load`](crate::load).
+            pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                (functions().$name)($($arg),*)
+            }
+        )+
+    }
+}
+
+dynamic_functions! {
+    pub fn libvchan_server_init(domain: c_int, port: c_int, read_min: usize, write_min: usize) -> *mut libvchan_t;
+    pub fn libvchan_client_init(domain: c_int, port: c_int) -> *mut libvchan_t;
+    pub fn libvchan_write(ctrl: *mut libvchan_t, data: *const c_void, size: usize) -> c_int;
+    pub fn libvchan_send(ctrl: *mut libvchan_t, data: *const c_void, size: usize) -> c_int;
+    pub fn libvchan_read(ctrl: *mut libvchan_t, data: *mut c_void, size: usize) -> c_int;
+    pub fn libvchan_recv(ctrl: *mut libvchan_t, data: *mut c_void, size: usize) -> c_int;
+    pub fn libvchan_wait(ctrl: *mut libvchan_t) -> c_int;
+    pub fn libvchan_close(ctrl: *mut libvchan_t);
+    pub fn libvchan_fd_for_select(ctrl: *const libvchan_t) -> c_int;
+    pub fn libvchan_is_open(ctrl: *const libvchan_t) -> c_int;
+    pub fn libvchan_data_ready(ctrl: *const libvchan_t) -> c_int;
+    pub fn libvchan_buffer_space(ctrl: *const libvchan_t) -> c_int;
+}
+
+static FUNCTIONS: OnceLock<Result<Functions, LoadError>> = OnceLock::new();
+
+fn try_functions() -> &'static Result<Functions, LoadError> {
+    FUNCTIONS.get_or_init(|| {
+        // SAFETY: LIBRARY_NAME is NUL-terminated, and libvchan has no
+        // initializers with preconditions.
+        let handle = unsafe { dlopen(LIBRARY_NAME.as_ptr() as *const c_char, RTLD_NOW) };
+        if handle.is_null() {
+            return Err(LoadError::Library(last_dl_error()));
+        }
+        // SAFETY: handle was just returned by dlopen().  It is never closed,
+        // so the function pointers stay valid.
+        unsafe { Functions::resolve(handle) }
+    })
+}
+
+fn functions() -> &'static Functions {
+    match try_functions() {
+        Ok(functions) => functions,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+pub(crate) fn load() -> Result<(), LoadError> {
+    try_functions().as_ref().map(|_| ()).map_err(Clone::clone)
+}
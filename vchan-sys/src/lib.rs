@@ -26,7 +26,9 @@
 pub struct libvchan_t {
     _unused: [u8; 0],
 }
-use std::os::raw::{c_int, c_void};
+use std::os::raw::c_int;
+#[cfg(not(any(feature = "dlopen", feature = "bindgen")))]
+use std::os::raw::c_void;
 
 /* return values from libvchan_is_open */
 /* remote disconnected or remote domain dead */
@@ -36,6 +38,59 @@ pub const VCHAN_CONNECTED: c_int = 1;
 /* vchan server initialized, waiting for client to connect */
 pub const VCHAN_WAITING: c_int = 2;
 
+/// Error loading libvchan at runtime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The library could not be loaded.  Contains the message from the
+    /// dynamic linker.
+    Library(String),
+    /// The library does not export a required symbol
+    MissingSymbol(&'static str),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Library(msg) => write!(f, "Cannot load libvchan: {}", msg),
+            LoadError::MissingSymbol(name) => write!(f, "libvchan does not export {}", name),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Ensures that libvchan is available.  With the `dlopen` feature, this loads
+/// the library if it has not been loaded yet; the functions in this crate
+/// panic if it cannot be loaded.  Otherwise, the library is linked at build
+/// time and this always succeeds.
+///
+/// # Errors
+///
+/// Fails if the library or one of its symbols cannot be found.
+pub fn load() -> Result<(), LoadError> {
+    #[cfg(feature = "dlopen")]
+    return dynamic::load();
+    #[cfg(not(feature = "dlopen"))]
+    Ok(())
+}
+
+#[cfg(feature = "dlopen")]
+mod dynamic;
+#[cfg(feature = "dlopen")]
+pub use dynamic::*;
+
+/// Bindings generated from the installed libvchan headers at build time
+#[cfg(all(feature = "bindgen", not(feature = "dlopen")))]
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
+#[cfg(all(feature = "bindgen", not(feature = "dlopen")))]
+pub use generated::*;
+#[cfg(all(feature = "bindgen", not(feature = "dlopen")))]
+#[link(name = "vchan-xen")]
+extern "C" {}
+
+#[cfg(not(any(feature = "dlopen", feature = "bindgen")))]
 #[link(name = "vchan-xen")]
 extern "C" {
     pub fn libvchan_server_init(
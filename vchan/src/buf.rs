@@ -0,0 +1,305 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Buffered reading from a [`Transport`].
+
+use super::{Error, Status, Transport, Vchan};
+use std::cell::RefCell;
+use std::io::{BufRead, ErrorKind, Read};
+use std::os::unix::prelude::RawFd;
+
+/// The most [`BufRead::fill_buf`] reads at once
+const CAPACITY: usize = 4096;
+
+#[derive(Debug, Default)]
+struct ReadBuffer {
+    data: Vec<u8>,
+    /// Offset of the first unconsumed byte in `data`
+    pos: usize,
+}
+
+impl ReadBuffer {
+    fn available(&self) -> &[u8] {
+        &self.data[self.pos..]
+    }
+
+    /// Moves up to `buf.len()` bytes to `buf`, and returns how many were
+    /// moved.
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.data.len() - self.pos);
+        buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+        self.pos += len;
+        len
+    }
+}
+
+/// A [`Transport`] with a read buffer, so that data can be examined before
+/// it is consumed.
+///
+/// This implements [`BufRead`], which does not block, and provides
+/// [`BufVchan::peek`].  It also implements [`Transport`]: data that has
+/// been buffered, but not consumed, is received first.
+///
+/// ```
+/// use std::io::BufRead;
+/// use vchan::{BufVchan, SocketTransport, Transport};
+/// let (a, b) = SocketTransport::pair().unwrap();
+/// let mut b = BufVchan::new(b);
+/// a.send(b"\x05\x00hello").unwrap();
+/// // Look at the length before deciding to read the body
+/// let len = b.peek(2).unwrap().unwrap();
+/// let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+/// assert!(b.peek(2 + len + 1).unwrap().is_none(), "only 7 bytes sent");
+/// b.consume(2);
+/// let mut body = vec![0; len];
+/// b.recv(&mut body).unwrap();
+/// assert_eq!(body, b"hello");
+/// ```
+#[derive(Debug)]
+pub struct BufVchan<T = Vchan> {
+    inner: T,
+    buf: RefCell<ReadBuffer>,
+}
+
+impl<T: Transport> BufVchan<T> {
+    /// Wraps a transport.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            buf: Default::default(),
+        }
+    }
+
+    /// Returns a reference to the wrapped transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport.  Reading from
+    /// it directly skips any buffered data.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the wrapped transport.  Any buffered data is lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Discards all buffered data.
+    pub fn clear(&mut self) {
+        let buf = self.buf.get_mut();
+        buf.data.clear();
+        buf.pos = 0;
+    }
+
+    /// Returns the data that has been buffered, but not consumed.
+    pub fn buffer(&mut self) -> &[u8] {
+        self.buf.get_mut().available()
+    }
+
+    /// Returns the next `n` bytes without consuming them, or `None` if
+    /// they have not all arrived yet.  This never blocks.  Use
+    /// [`BufRead::consume`] to consume the data afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Fails if allocating memory or reading from the transport fails.
+    pub fn peek(&mut self, n: usize) -> Result<Option<&[u8]>, Error> {
+        let buf = self.buf.get_mut();
+        let buffered = buf.data.len() - buf.pos;
+        if buffered < n {
+            let needed = n - buffered;
+            if self.inner.data_ready() < needed {
+                return Ok(None);
+            }
+            buf.data.drain(..buf.pos);
+            buf.pos = 0;
+            self.inner.recv_into(&mut buf.data, needed)?;
+        }
+        Ok(Some(&buf.data[buf.pos..buf.pos + n]))
+    }
+}
+
+impl<T: Transport> BufRead for BufVchan<T> {
+    /// Returns the buffered data.  If there is none, reads whatever is
+    /// available without blocking.
+    ///
+    /// Returns an empty slice once the peer has disconnected and all data
+    /// has been read, and an error of kind [`ErrorKind::WouldBlock`] if no
+    /// data is available yet.
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let buf = self.buf.get_mut();
+        if buf.pos == buf.data.len() {
+            let ready = self.inner.data_ready().min(CAPACITY);
+            if ready == 0 {
+                return match self.inner.status() {
+                    Status::Disconnected | Status::HalfClosed => Ok(&[]),
+                    Status::Connected | Status::Waiting => Err(ErrorKind::WouldBlock.into()),
+                };
+            }
+            buf.data.clear();
+            buf.pos = 0;
+            self.inner.recv_into(&mut buf.data, ready)?;
+        }
+        Ok(buf.available())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let buf = self.buf.get_mut();
+        assert!(
+            amt <= buf.data.len() - buf.pos,
+            "consumed more than was buffered"
+        );
+        buf.pos += amt;
+    }
+}
+
+impl<T: Transport> Read for BufVchan<T> {
+    /// Reads data without blocking, as for [`BufRead::fill_buf`].
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let available = self.fill_buf()?;
+        let len = available.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<T: Transport> Transport for BufVchan<T> {
+    fn send(&self, buffer: &[u8]) -> Result<(), Error> {
+        self.inner.send(buffer)
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        let len = self.buf.borrow_mut().take(buffer);
+        self.inner.recv(&mut buffer[len..])
+    }
+
+    fn data_ready(&self) -> usize {
+        self.buf.borrow().available().len() + self.inner.data_ready()
+    }
+
+    fn buffer_space(&self) -> usize {
+        self.inner.buffer_space()
+    }
+
+    fn status(&self) -> Status {
+        self.inner.status()
+    }
+
+    fn shutdown_write(&self) -> Result<(), Error> {
+        self.inner.shutdown_write()
+    }
+
+    fn wait(&self) {
+        self.inner.wait()
+    }
+
+    fn fd(&self) -> RawFd {
+        self.inner.fd()
+    }
+
+    fn recv_into(&self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), Error> {
+        let mut buf = self.buf.borrow_mut();
+        let len = bytes.min(buf.available().len());
+        buffer.try_reserve(bytes).map_err(Error::OutOfMemory)?;
+        let old_len = buffer.len();
+        buffer.extend_from_slice(&buf.available()[..len]);
+        let res = self.inner.recv_into(buffer, bytes - len);
+        match res {
+            Ok(()) => buf.pos += len,
+            Err(_) => buffer.truncate(old_len),
+        }
+        res
+    }
+
+    fn discard(&self, bytes: usize) -> Result<(), Error> {
+        let mut buf = self.buf.borrow_mut();
+        let len = bytes.min(buf.available().len());
+        buf.pos += len;
+        self.inner.discard(bytes - len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SocketTransport;
+
+    /// A [`BufVchan`] with `b"hel"` buffered and `b"lo world"` still in the
+    /// transport, and the peer
+    fn split() -> (BufVchan<SocketTransport>, SocketTransport) {
+        let (ours, peer) = SocketTransport::pair().unwrap();
+        let mut ours = BufVchan::new(ours);
+        peer.send(b"hel").unwrap();
+        assert_eq!(ours.peek(3).unwrap().unwrap(), b"hel");
+        peer.send(b"lo world").unwrap();
+        assert_eq!(ours.buffer(), b"hel");
+        assert_eq!(ours.data_ready(), 11);
+        (ours, peer)
+    }
+
+    #[test]
+    fn recv() {
+        let (ours, _peer) = split();
+        let mut buf = [0; 8];
+        ours.recv(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello wo");
+        assert_eq!(ours.data_ready(), 3);
+        ours.recv(&mut buf[..3]).unwrap();
+        assert_eq!(&buf[..3], b"rld");
+    }
+
+    #[test]
+    fn recv_into() {
+        let (ours, _peer) = split();
+        let mut buf = b"> ".to_vec();
+        ours.recv_into(&mut buf, 5).unwrap();
+        assert_eq!(buf, b"> hello");
+        ours.recv_into(&mut buf, 6).unwrap();
+        assert_eq!(buf, b"> hello world");
+        assert_eq!(ours.data_ready(), 0);
+    }
+
+    #[test]
+    fn recv_into_failure() {
+        let (ours, peer) = split();
+        drop(peer);
+        let mut buf = b"> ".to_vec();
+        assert!(ours.recv_into(&mut buf, 12).is_err());
+        assert_eq!(buf, b"> ");
+    }
+
+    #[test]
+    fn discard() {
+        let (ours, _peer) = split();
+        ours.discard(2).unwrap();
+        assert_eq!(ours.data_ready(), 9);
+        ours.discard(4).unwrap();
+        let mut buf = [0; 5];
+        ours.recv(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+    }
+}
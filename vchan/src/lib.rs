@@ -23,6 +23,25 @@
 
 use std::io::{ErrorKind, Read, Write};
 use std::os::{raw::c_int, raw::c_void, unix::prelude::RawFd};
+use std::time::{Duration, Instant};
+
+mod buf;
+mod mux;
+mod server;
+mod socket;
+mod sync;
+mod sys;
+mod transcript;
+mod transport;
+pub use buf::BufVchan;
+pub use mux::{Mux, MuxStream, DEFAULT_WINDOW};
+pub use server::ServerVchan;
+pub use socket::SocketTransport;
+pub use sync::SyncVchan;
+pub use transcript::{
+    read_transcript, Direction, Record, RecordingVchan, ReplayVchan, TRANSCRIPT_MAGIC,
+};
+pub use transport::Transport;
 
 macro_rules! static_assert {
     ($s: expr) => {
@@ -42,37 +61,108 @@ pub enum Status {
     Connected,
     /// Server initialized, waiting for client to connect
     Waiting,
+    /// The peer will send no more data, but still receives data.  Data it
+    /// sent before can still be received.  libvchan cannot close one
+    /// direction of a vchan, so a [`Vchan`] never has this status.
+    HalfClosed,
 }
 
 /// Error on a vchan
-#[derive(Debug, Clone)]
+///
+/// Errors from libvchan carry the [`std::io::Error`] that caused them, so
+/// that callers can tell (for instance) an interrupted call from a peer that
+/// has gone away.
+#[derive(Debug)]
 pub enum Error {
     /// Failure allocating memory
     OutOfMemory(std::collections::TryReserveError),
-    /// Vchan read error
-    Read,
-    /// Vchan write error
-    Write,
+    /// Vchan read error.  If the peer disconnected, the error is of kind
+    /// [`ErrorKind::UnexpectedEof`].
+    Read(std::io::Error),
+    /// Vchan write error.  If the peer disconnected, the error is of kind
+    /// [`ErrorKind::BrokenPipe`].
+    Write(std::io::Error),
     /// Cannot listen
-    CannotListen,
+    CannotListen(std::io::Error),
     /// Cannot connect
-    CannotConnect,
+    CannotConnect(std::io::Error),
+    /// libvchan could not be loaded
+    Load(vchan_sys::LoadError),
+    /// Error waiting for an event
+    Wait(std::io::Error),
+    /// Operation timed out
+    TimedOut,
+}
+
+impl Error {
+    /// The underlying I/O error, if any
+    pub fn io_error(&self) -> Option<&std::io::Error> {
+        match self {
+            Error::Read(e)
+            | Error::Write(e)
+            | Error::CannotListen(e)
+            | Error::CannotConnect(e)
+            | Error::Wait(e) => Some(e),
+            Error::OutOfMemory(_) | Error::Load(_) | Error::TimedOut => None,
+        }
+    }
+
+    /// Returns true if the operation failed because the peer disconnected.
+    pub fn is_disconnected(&self) -> bool {
+        match self {
+            Error::Read(e) | Error::Write(e) => matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset
+            ),
+            _ => false,
+        }
+    }
+
+    /// Returns true if the operation was interrupted by a signal, and can be
+    /// retried.
+    pub fn is_interrupted(&self) -> bool {
+        matches!(self.io_error(), Some(e) if e.kind() == ErrorKind::Interrupted)
+    }
 }
 
 impl From<Error> for std::io::Error {
+    /// Converts to an [`std::io::Error`].  Errors from libvchan are returned
+    /// as-is, so their kind and OS error code are preserved.
     fn from(t: Error) -> Self {
-        Self::new(ErrorKind::Other, format!("{}", t))
+        match t {
+            Error::Read(e)
+            | Error::Write(e)
+            | Error::CannotListen(e)
+            | Error::CannotConnect(e)
+            | Error::Wait(e) => e,
+            Error::OutOfMemory(_) => Self::new(ErrorKind::OutOfMemory, t),
+            Error::TimedOut => Self::new(ErrorKind::TimedOut, t),
+            Error::Load(_) => Self::other(t),
+        }
     }
 }
 
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
-            Error::Read => write!(f, "Error during vchan read"),
-            Error::Write => write!(f, "Error during vchan write"),
-            Error::CannotListen => write!(f, "Cannot listen on vchan"),
-            Error::CannotConnect => write!(f, "Cannot connect to vchan"),
+            Error::Read(e) => write!(f, "Error during vchan read: {}", e),
+            Error::Write(e) => write!(f, "Error during vchan write: {}", e),
+            Error::CannotListen(e) => write!(f, "Cannot listen on vchan: {}", e),
+            Error::CannotConnect(e) => write!(f, "Cannot connect to vchan: {}", e),
             Error::OutOfMemory(e) => write!(f, "{}", e),
+            Error::Load(e) => write!(f, "{}", e),
+            Error::Wait(e) => write!(f, "Error waiting for vchan event: {}", e),
+            Error::TimedOut => write!(f, "Vchan operation timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::OutOfMemory(e) => Some(e),
+            Error::Load(e) => Some(e),
+            _ => self.io_error().map(|e| e as _),
         }
     }
 }
@@ -85,8 +175,83 @@ impl core::fmt::Display for Error {
 #[derive(Debug)]
 pub struct Vchan {
     inner: *mut vchan_sys::libvchan_t,
+    read_size: Option<usize>,
+    write_size: usize,
+}
+
+/// The size libvchan allocates for a ring that must hold at least `min`
+/// bytes: 1024 or 2048 bytes in the shared page, or a power of two number of
+/// pages.
+fn ring_size(min: usize) -> Option<usize> {
+    min.max(1024).checked_next_power_of_two()
+}
+
+/// A builder for [`Vchan`]s
+///
+/// ```no_run
+/// let vchan = vchan::Vchan::builder()
+///     .domain(5u16)
+///     .port(6000)
+///     .read_min(1 << 16)
+///     .server()
+///     .unwrap();
+/// assert_eq!(vchan.read_buffer_size(), Some(1 << 16));
+/// assert_eq!(vchan.write_buffer_size(), 1024);
+/// ```
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct VchanBuilder {
+    domain: u16,
+    port: c_int,
+    read_min: usize,
+    write_min: usize,
 }
 
+impl VchanBuilder {
+    /// The domain of the peer.  Defaults to 0 (dom0).
+    pub fn domain(&mut self, domain: impl Into<u16>) -> &mut Self {
+        self.domain = domain.into();
+        self
+    }
+
+    /// The port.  Defaults to 0.
+    pub fn port(&mut self, port: c_int) -> &mut Self {
+        self.port = port;
+        self
+    }
+
+    /// The minimum size of the ring for data from the peer.  Defaults to 0,
+    /// which gives the smallest ring (1024 bytes).  Only used by
+    /// [`VchanBuilder::server`], as the server allocates both rings.
+    pub fn read_min(&mut self, read_min: usize) -> &mut Self {
+        self.read_min = read_min;
+        self
+    }
+
+    /// The minimum size of the ring for data to the peer.  Defaults to 0,
+    /// which gives the smallest ring (1024 bytes).  Only used by
+    /// [`VchanBuilder::server`], as the server allocates both rings.
+    pub fn write_min(&mut self, write_min: usize) -> &mut Self {
+        self.write_min = write_min;
+        self
+    }
+
+    /// Creates a listening vchan, as with [`Vchan::server`].
+    pub fn server(&self) -> Result<Vchan, Error> {
+        Vchan::server(self.domain, self.port, self.read_min, self.write_min)
+    }
+
+    /// Connects to a vchan, as with [`Vchan::client`].
+    pub fn client(&self) -> Result<Vchan, Error> {
+        Vchan::client(self.domain, self.port)
+    }
+}
+
+// SAFETY: a libvchan_t has no thread affinity, so it can be used (and
+// closed) from any thread, as long as it is only used from one thread at a
+// time.  It is not Sync: concurrent calls would race on the ring indices.
+// Use SyncVchan to share a vchan between threads.
+unsafe impl Send for Vchan {}
+
 fn c_int_to_usize(i: c_int) -> usize {
     assert!(i >= 0, "c_int_to_usize passed negative number");
     // If u32 doesn’t actually fit in a usize, fail the build
@@ -95,8 +260,30 @@ fn c_int_to_usize(i: c_int) -> usize {
 }
 
 impl Vchan {
+    /// Returns a builder for a vchan.
+    pub fn builder() -> VchanBuilder {
+        VchanBuilder::default()
+    }
+
+    /// The size of the ring for data from the peer, if known.  It is at
+    /// least the `read_min` passed to [`Vchan::server`].  A client cannot
+    /// find out the size of this ring, so this is [`None`] for a client.
+    pub fn read_buffer_size(&self) -> Option<usize> {
+        self.read_size
+    }
+
+    /// The size of the ring for data to the peer.  For a server, this is at
+    /// least the `write_min` passed to [`Vchan::server`].  For a client, it
+    /// is whatever the server allocated.
+    pub fn write_buffer_size(&self) -> usize {
+        self.write_size
+    }
+
     /// Creates a listening vchan that listens from requests from the given domain
     /// on the given port.
+    ///
+    /// libvchan rounds `read_min` and `write_min` up to 1024, 2048, or a power
+    /// of two number of pages.
     #[inline]
     pub fn server(
         domain: impl Into<u16>,
@@ -110,27 +297,43 @@ impl Vchan {
             read_min: usize,
             write_min: usize,
         ) -> Result<Vchan, Error> {
+            vchan_sys::load().map_err(Error::Load)?;
             let ptr = unsafe {
                 vchan_sys::libvchan_server_init(domain.into(), port, read_min, write_min)
             };
             if ptr.is_null() {
-                Err(Error::CannotListen)
+                Err(Error::CannotListen(std::io::Error::last_os_error()))
             } else {
-                Ok(Vchan { inner: ptr })
+                let mut vchan = Vchan {
+                    inner: ptr,
+                    read_size: ring_size(read_min),
+                    write_size: 0,
+                };
+                // Nothing has been written yet, so the write ring is empty
+                vchan.write_size = vchan.buffer_space();
+                Ok(vchan)
             }
         }
         server_inner(domain.into(), port, read_min, write_min)
     }
 
     /// Creates a vchan that will connect to the given domain via the given port.
+    /// The server chooses the sizes of the rings.
     #[inline]
     pub fn client(domain: impl Into<u16>, port: c_int) -> Result<Self, Error> {
         fn client_inner(domain: u16, port: c_int) -> Result<Vchan, Error> {
+            vchan_sys::load().map_err(Error::Load)?;
             let ptr = unsafe { vchan_sys::libvchan_client_init(domain.into(), port) };
             if ptr.is_null() {
-                Err(Error::CannotConnect)
+                Err(Error::CannotConnect(std::io::Error::last_os_error()))
             } else {
-                Ok(Vchan { inner: ptr })
+                let mut vchan = Vchan {
+                    inner: ptr,
+                    read_size: None,
+                    write_size: 0,
+                };
+                vchan.write_size = vchan.buffer_space();
+                Ok(vchan)
             }
         }
         client_inner(domain.into(), port)
@@ -177,6 +380,100 @@ impl Vchan {
         unsafe { vchan_sys::libvchan_wait(self.inner) };
     }
 
+    /// Like [`Vchan::wait`], but gives up after `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TimedOut`] if no event happened in time, and
+    /// [`Error::Wait`] if polling the file descriptor failed.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), Error> {
+        self.wait_until(Instant::now() + timeout)
+    }
+
+    fn wait_until(&self, deadline: Instant) -> Result<(), Error> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // Round up, so that this does not spin when less than 1ms remains
+            let millis = remaining.as_millis()
+                + u128::from(!remaining.subsec_nanos().is_multiple_of(1_000_000));
+            let millis = millis.min(c_int::MAX as u128) as c_int;
+            match sys::poll_one(self.fd(), sys::POLLIN, millis) {
+                Ok(0) if Instant::now() < deadline => {}
+                Ok(0) => break Err(Error::TimedOut),
+                Err(e) => break Err(Error::Wait(e)),
+                Ok(_) => {
+                    // An event is pending, so this does not block
+                    self.wait();
+                    break Ok(());
+                }
+            }
+        }
+    }
+
+    /// Like [`Vchan::send`], but gives up after `timeout`.  If this times out,
+    /// part of the buffer may have been sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TimedOut`] if the buffer could not be sent in time,
+    /// and [`Error::Write`] if the peer disconnected or writing failed.
+    pub fn send_timeout(&self, mut buffer: &[u8], timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let to_send = buffer.len().min(self.buffer_space());
+            if to_send > 0 {
+                // There is enough space, so this does not block
+                self.send(&buffer[..to_send])?;
+                buffer = &buffer[to_send..];
+            }
+            if buffer.is_empty() {
+                break Ok(());
+            }
+            if self.status() == Status::Disconnected {
+                break Err(Error::Write(ErrorKind::BrokenPipe.into()));
+            }
+            self.wait_until(deadline)?
+        }
+    }
+
+    /// Like [`Vchan::recv`], but gives up after `timeout`.  If this times out,
+    /// part of the buffer may have been filled.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TimedOut`] if the buffer could not be filled in time,
+    /// and [`Error::Read`] if the peer disconnected or reading failed.
+    pub fn recv_timeout(&self, mut buffer: &mut [u8], timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let to_recv = buffer.len().min(self.data_ready());
+            if to_recv > 0 {
+                // There is enough data, so this does not block
+                self.recv(&mut buffer[..to_recv])?;
+                buffer = &mut buffer[to_recv..];
+            }
+            if buffer.is_empty() {
+                break Ok(());
+            }
+            if self.status() == Status::Disconnected {
+                break Err(Error::Read(ErrorKind::UnexpectedEof.into()));
+            }
+            self.wait_until(deadline)?
+        }
+    }
+
+    /// The error for a failed libvchan call: `disconnected` if the peer has
+    /// gone away, and `errno` otherwise.  Must be called right after the
+    /// failing call, before `errno` can be overwritten.
+    fn last_error(&self, disconnected: ErrorKind) -> std::io::Error {
+        let e = std::io::Error::last_os_error();
+        if self.status() == Status::Disconnected {
+            disconnected.into()
+        } else {
+            e
+        }
+    }
+
     /// Write the entire buffer
     pub fn send(&self, buffer: &[u8]) -> Result<(), Error> {
         assert!(
@@ -188,7 +485,7 @@ impl Vchan {
         let res =
             unsafe { vchan_sys::libvchan_send(self.inner, buffer.as_ptr() as _, buffer.len()) };
         if res == -1 {
-            Err(Error::Write)
+            Err(Error::Write(self.last_error(ErrorKind::BrokenPipe)))
         } else {
             assert!(res >= 0, "sent negative number of bytes?");
             assert_eq!(res as usize, buffer.len(), "libvchan_send short write?");
@@ -216,7 +513,7 @@ impl Vchan {
         // vchan.
         let res = vchan_sys::libvchan_recv(self.inner, ptr, size);
         if res == -1 {
-            Err(Error::Read)
+            Err(Error::Read(self.last_error(ErrorKind::UnexpectedEof)))
         } else {
             assert!(res >= 0, "received negative number of bytes?");
             assert_eq!(res as usize, size, "libvchan_recv short read?");
@@ -277,35 +574,90 @@ impl Vchan {
         // Castable struct can have any byte pattern.
         unsafe { Ok(datum.assume_init()) }
     }
-}
 
-impl Write for Vchan {
-    fn write(&mut self, buffer: &[u8]) -> Result<usize, std::io::Error> {
-        let res =
-            unsafe { vchan_sys::libvchan_write(self.inner, buffer.as_ptr() as _, buffer.len()) };
+    /// The implementation of [`Write::write`], which does not need exclusive
+    /// access.
+    fn write_nonblocking(&self, buffer: &[u8]) -> Result<usize, std::io::Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let space = self.buffer_space();
+        if space == 0 {
+            return Err(match self.status() {
+                Status::Disconnected => ErrorKind::BrokenPipe.into(),
+                Status::Connected | Status::Waiting | Status::HalfClosed => {
+                    ErrorKind::WouldBlock.into()
+                }
+            });
+        }
+        let to_write = buffer.len().min(space);
+        let res = unsafe { vchan_sys::libvchan_write(self.inner, buffer.as_ptr() as _, to_write) };
         if res == -1 {
-            Err(std::io::Error::new(ErrorKind::Other, "vchan write error"))
+            Err(std::io::Error::last_os_error())
         } else {
             assert!(res >= 0, "wrote negative number of bytes?");
             Ok(res as _)
         }
     }
 
+    /// The implementation of [`Read::read`], which does not need exclusive
+    /// access.
+    fn read_nonblocking(&self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let ready = self.data_ready();
+        if ready == 0 {
+            return match self.status() {
+                Status::Disconnected | Status::HalfClosed => Ok(0),
+                Status::Connected | Status::Waiting => Err(ErrorKind::WouldBlock.into()),
+            };
+        }
+        let to_read = buffer.len().min(ready);
+        let res =
+            unsafe { vchan_sys::libvchan_read(self.inner, buffer.as_mut_ptr() as _, to_read) };
+        if res == -1 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            assert!(res >= 0, "read negative number of bytes?");
+            Ok(res as _)
+        }
+    }
+}
+
+impl Write for Vchan {
+    /// Writes as much of `buffer` as fits in the vchan without blocking.
+    ///
+    /// Returns an error of kind [`ErrorKind::WouldBlock`] if the vchan is
+    /// full and the peer is still connected, and [`ErrorKind::BrokenPipe`] if
+    /// the peer has disconnected.
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, std::io::Error> {
+        self.write_nonblocking(buffer)
+    }
+
     fn flush(&mut self) -> Result<(), std::io::Error> {
         Ok(())
     }
 }
 
 impl Read for Vchan {
+    /// Reads the data that is available without blocking.
+    ///
+    /// Returns `Ok(0)` once the peer has disconnected and all data has been
+    /// read, and an error of kind [`ErrorKind::WouldBlock`] if no data is
+    /// available yet.
     fn read(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
-        let res =
-            unsafe { vchan_sys::libvchan_read(self.inner, buffer.as_mut_ptr() as _, buffer.len()) };
-        if res == -1 {
-            Err(std::io::Error::new(ErrorKind::Other, "vchan read error"))
-        } else {
-            assert!(res >= 0, "read negative number of bytes?");
-            Ok(res as _)
-        }
+        self.read_nonblocking(buffer)
+    }
+}
+
+impl std::os::unix::io::AsRawFd for Vchan {
+    /// See [`Vchan::fd`].  The descriptor becomes readable when an event is
+    /// pending, so it can be registered with any reactor, such as
+    /// `async-io` or `tokio`.  Do not make it non-blocking: the blocking
+    /// methods of [`Vchan`] rely on it blocking.
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd()
     }
 }
 
@@ -0,0 +1,586 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Numbered logical streams over a single [`Transport`].
+//!
+//! Each frame starts with an 8-byte header, with all fields little-endian:
+//!
+//! | Size | Contents                                                 |
+//! |------|----------------------------------------------------------|
+//! | 2    | Stream number                                            |
+//! | 1    | Frame kind: 0 for data, 1 for credit, 2 for close        |
+//! | 1    | Reserved, must be 0                                      |
+//! | 4    | For data, the length of the payload; for credit, the     |
+//! |      | number of bytes granted; for close, 0                    |
+//!
+//! Only data frames have a payload.  Each side may send at most the window
+//! size on each stream before it receives credit for more, so that data on
+//! one stream that is not being read does not block the others.  Both sides
+//! must use the same window size.  A frame for a stream that the receiver has
+//! not opened is a protocol error, so the peer cannot make the receiver
+//! buffer more than one window for each stream it opened.
+
+use super::{Error, Status, Transport};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::os::unix::prelude::RawFd;
+use std::rc::Rc;
+
+const HEADER_SIZE: usize = 8;
+const KIND_DATA: u8 = 0;
+const KIND_CREDIT: u8 = 1;
+const KIND_CLOSE: u8 = 2;
+
+/// The default window size of each stream
+pub const DEFAULT_WINDOW: u32 = 1 << 16;
+
+#[derive(Debug)]
+struct Stream {
+    /// Data received but not yet read
+    received: VecDeque<u8>,
+    /// Bytes that may be sent before more credit is received
+    credit: u32,
+    /// Bytes read since credit was last granted to the peer
+    consumed: u32,
+    /// The peer closed the stream
+    peer_closed: bool,
+    /// This side closed the stream
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    streams: BTreeMap<u16, Stream>,
+    /// Header of a data frame whose payload has not fully arrived
+    header: Option<(u16, u32)>,
+    /// Control frames waiting for space in the transport
+    control: VecDeque<[u8; HEADER_SIZE]>,
+    /// The peer violated the protocol.  Terminal state.
+    failed: bool,
+}
+
+fn header(stream: u16, kind: u8, len: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..2].copy_from_slice(&stream.to_le_bytes());
+    header[2] = kind;
+    header[4..].copy_from_slice(&len.to_le_bytes());
+    header
+}
+
+fn new_stream(window: u32) -> Stream {
+    Stream {
+        received: VecDeque::new(),
+        credit: window,
+        consumed: 0,
+        peer_closed: false,
+        closed: false,
+    }
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::Read(std::io::Error::new(ErrorKind::InvalidData, msg))
+}
+
+/// A multiplexer of numbered logical streams over one [`Transport`].
+///
+/// Stream numbers are not negotiated: both sides must agree on what each one
+/// is used for, and must open a stream (with [`Mux::open`], or with any
+/// method that sends or receives on it) before the peer sends anything on
+/// it.  The methods of this type never block.  Use [`Mux::stream`] to get a
+/// blocking [`Transport`] for one stream, which can be used with anything
+/// that takes a transport.
+///
+/// ```
+/// use std::rc::Rc;
+/// use vchan::{Mux, SocketTransport, Transport};
+/// let (a, b) = SocketTransport::pair().unwrap();
+/// let (a, b) = (Rc::new(Mux::with_window(a, 4)), Rc::new(Mux::with_window(b, 4)));
+/// b.open(0);
+/// b.open(1);
+/// // Only one window worth of data can be sent before the peer reads it
+/// assert_eq!(a.send(0, b"hello").unwrap(), 4);
+/// assert_eq!(a.send(1, b"log").unwrap(), 3);
+/// let mut buf = [0; 4];
+/// assert_eq!(b.recv(1, &mut buf).unwrap(), 3);
+/// assert_eq!(&buf[..3], b"log");
+/// // Streams are independent
+/// let gui = Mux::stream(&b, 0);
+/// gui.recv(&mut buf).unwrap();
+/// assert_eq!(&buf, b"hell");
+/// // Reading gave credit back to the sender
+/// assert_eq!(a.send(0, b"o").unwrap(), 1);
+/// // b did not open stream 2
+/// a.send(2, b"?").unwrap();
+/// assert!(b.poll().is_err());
+/// ```
+#[derive(Debug)]
+pub struct Mux<T> {
+    transport: T,
+    window: u32,
+    state: RefCell<State>,
+}
+
+impl<T: Transport> Mux<T> {
+    /// Creates a multiplexer with the [default window size](DEFAULT_WINDOW).
+    pub fn new(transport: T) -> Self {
+        Self::with_window(transport, DEFAULT_WINDOW)
+    }
+
+    /// Creates a multiplexer with the given window size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window` is zero.
+    pub fn with_window(transport: T, window: u32) -> Self {
+        assert!(window > 0, "window size cannot be zero");
+        Self {
+            transport,
+            window,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns the underlying transport.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Opens stream `id`, so that the peer may send on it.  Opening a stream
+    /// that is already open does nothing.
+    pub fn open(&self, id: u16) {
+        let window = self.window;
+        self.state
+            .borrow_mut()
+            .streams
+            .entry(id)
+            .or_insert_with(|| new_stream(window));
+    }
+
+    /// Opens stream `id` and returns a blocking [`Transport`] for it.
+    pub fn stream(mux: &Rc<Self>, id: u16) -> MuxStream<T> {
+        mux.open(id);
+        MuxStream {
+            mux: mux.clone(),
+            id,
+        }
+    }
+
+    /// Sends as many queued control frames as fit.
+    fn flush_control(&self, state: &mut State) -> Result<(), Error> {
+        while let Some(frame) = state.control.front() {
+            if self.transport.buffer_space() < HEADER_SIZE {
+                break;
+            }
+            self.transport.send(frame)?;
+            state.control.pop_front();
+        }
+        Ok(())
+    }
+
+    fn poll_inner(&self, state: &mut State) -> Result<(), Error> {
+        self.flush_control(state)?;
+        loop {
+            let ready = self.transport.data_ready();
+            let (id, len) = match state.header {
+                Some(header) => header,
+                None if ready < HEADER_SIZE => break Ok(()),
+                None => {
+                    let mut buf = [0u8; HEADER_SIZE];
+                    self.transport.recv(&mut buf)?;
+                    let id = u16::from_le_bytes([buf[0], buf[1]]);
+                    let len = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+                    if buf[3] != 0 {
+                        break Err(protocol_error("reserved byte in mux header is not 0"));
+                    }
+                    let window = self.window;
+                    let stream = match state.streams.get_mut(&id) {
+                        Some(stream) => stream,
+                        None => break Err(protocol_error("frame for unopened mux stream")),
+                    };
+                    match buf[2] {
+                        KIND_DATA => {
+                            let outstanding = stream.received.len() as u64
+                                + u64::from(stream.consumed)
+                                + u64::from(len);
+                            if stream.peer_closed {
+                                break Err(protocol_error("data on closed mux stream"));
+                            } else if outstanding > u64::from(window) {
+                                break Err(protocol_error("mux stream window exceeded"));
+                            }
+                            state.header = Some((id, len));
+                            (id, len)
+                        }
+                        KIND_CREDIT => match stream.credit.checked_add(len) {
+                            Some(credit) => {
+                                stream.credit = credit;
+                                continue;
+                            }
+                            None => break Err(protocol_error("mux stream credit overflow")),
+                        },
+                        KIND_CLOSE if len == 0 => {
+                            stream.peer_closed = true;
+                            continue;
+                        }
+                        _ => break Err(protocol_error("bad mux frame")),
+                    }
+                }
+            };
+            let len = len as usize;
+            if self.transport.data_ready() < len {
+                break Ok(());
+            }
+            let mut data = vec![0; len];
+            self.transport.recv(&mut data)?;
+            state.header = None;
+            let stream = state.streams.get_mut(&id).expect("checked above");
+            stream.received.extend(data);
+        }
+    }
+
+    fn failed(&self, state: &State) -> Result<(), Error> {
+        if state.failed {
+            Err(protocol_error("mux is in an error state"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Processes incoming frames without blocking, and sends any pending
+    /// credit.  The other methods call this as needed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the transport fails or the peer violates the protocol.  A
+    /// protocol violation is permanent: every later call will fail.
+    pub fn poll(&self) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        self.failed(&state)?;
+        let res = self.poll_inner(&mut state);
+        if let Err(Error::Read(ref e)) = res {
+            state.failed = e.kind() == ErrorKind::InvalidData;
+        }
+        res
+    }
+
+    /// Sends as much of `data` on stream `id` as possible without blocking,
+    /// and returns the number of bytes sent.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the stream was closed with [`Mux::close`], or if the
+    /// transport fails.
+    pub fn send(&self, id: u16, data: &[u8]) -> Result<usize, Error> {
+        self.poll()?;
+        let mut state = self.state.borrow_mut();
+        let stream = state
+            .streams
+            .entry(id)
+            .or_insert_with(|| new_stream(self.window));
+        if stream.closed {
+            return Err(Error::Write(ErrorKind::BrokenPipe.into()));
+        }
+        let space = self.transport.buffer_space().saturating_sub(HEADER_SIZE);
+        let len = data.len().min(space).min(stream.credit as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        // len fits in a u32, as it is at most the credit
+        self.transport
+            .send(&header(id, KIND_DATA, u32::try_from(len).unwrap()))?;
+        self.transport.send(&data[..len])?;
+        stream.credit -= len as u32;
+        Ok(len)
+    }
+
+    /// Receives as much data from stream `id` as is available, and returns
+    /// the number of bytes received.  Returns 0 if no data is available; use
+    /// [`Mux::is_peer_closed`] to check if more can arrive.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the transport fails or the peer violates the protocol.
+    pub fn recv(&self, id: u16, buf: &mut [u8]) -> Result<usize, Error> {
+        self.poll()?;
+        let mut state = self.state.borrow_mut();
+        let window = self.window;
+        let stream = state
+            .streams
+            .entry(id)
+            .or_insert_with(|| new_stream(self.window));
+        let len = buf.len().min(stream.received.len());
+        for (dst, src) in buf.iter_mut().zip(stream.received.drain(..len)) {
+            *dst = src
+        }
+        stream.consumed += len as u32;
+        // Grant credit in batches, so that small reads do not each cost a
+        // frame
+        if stream.consumed >= window / 2 || (len > 0 && stream.received.is_empty()) {
+            let credit = std::mem::replace(&mut stream.consumed, 0);
+            if credit > 0 {
+                state.control.push_back(header(id, KIND_CREDIT, credit));
+                self.flush_control(&mut state)?;
+            }
+        }
+        Ok(len)
+    }
+
+    /// Returns the amount of data that can be received from stream `id`
+    /// without blocking.
+    pub fn data_ready(&self, id: u16) -> usize {
+        // Errors will be reported by the next call to recv()
+        let _ = self.poll();
+        let state = self.state.borrow();
+        state.streams.get(&id).map_or(0, |s| s.received.len())
+    }
+
+    /// Returns the amount of data that can be sent on stream `id` without
+    /// blocking.
+    pub fn buffer_space(&self, id: u16) -> usize {
+        let _ = self.poll();
+        let state = self.state.borrow();
+        let credit =
+            state
+                .streams
+                .get(&id)
+                .map_or(self.window, |s| if s.closed { 0 } else { s.credit });
+        (credit as usize).min(self.transport.buffer_space().saturating_sub(HEADER_SIZE))
+    }
+
+    /// Tells the peer that no more data will be sent on stream `id`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the transport fails.
+    pub fn close(&self, id: u16) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+        let stream = state
+            .streams
+            .entry(id)
+            .or_insert_with(|| new_stream(self.window));
+        if !stream.closed {
+            stream.closed = true;
+            state.control.push_back(header(id, KIND_CLOSE, 0));
+        }
+        self.flush_control(&mut state)
+    }
+
+    /// Returns true if the peer closed stream `id` and all of its data has
+    /// been received.
+    pub fn is_peer_closed(&self, id: u16) -> bool {
+        let _ = self.poll();
+        let state = self.state.borrow();
+        matches!(state.streams.get(&id), Some(s) if s.peer_closed && s.received.is_empty())
+    }
+}
+
+/// One stream of a [`Mux`], as a blocking [`Transport`]
+#[derive(Debug)]
+pub struct MuxStream<T> {
+    mux: Rc<Mux<T>>,
+    id: u16,
+}
+
+impl<T: Transport> MuxStream<T> {
+    /// The stream number
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Blocks until the transport has an event, unless it has disconnected.
+    fn wait_or_fail(&self, error: Error) -> Result<(), Error> {
+        match self.mux.transport.status() {
+            Status::Disconnected => Err(error),
+            _ => {
+                self.mux.transport.wait();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: Transport> Transport for MuxStream<T> {
+    fn send(&self, mut buffer: &[u8]) -> Result<(), Error> {
+        while !buffer.is_empty() {
+            match self.mux.send(self.id, buffer)? {
+                0 => self.wait_or_fail(Error::Write(ErrorKind::BrokenPipe.into()))?,
+                n => buffer = &buffer[n..],
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&self, mut buffer: &mut [u8]) -> Result<(), Error> {
+        while !buffer.is_empty() {
+            match self.mux.recv(self.id, buffer)? {
+                0 if self.mux.is_peer_closed(self.id) => {
+                    return Err(Error::Read(ErrorKind::UnexpectedEof.into()))
+                }
+                0 => self.wait_or_fail(Error::Read(ErrorKind::UnexpectedEof.into()))?,
+                n => buffer = &mut buffer[n..],
+            }
+        }
+        Ok(())
+    }
+
+    fn data_ready(&self) -> usize {
+        self.mux.data_ready(self.id)
+    }
+
+    fn buffer_space(&self) -> usize {
+        self.mux.buffer_space(self.id)
+    }
+
+    /// The status of the transport, or [`Status::HalfClosed`] if the
+    /// transport is connected but the peer closed this stream.
+    fn status(&self) -> Status {
+        match self.mux.transport.status() {
+            Status::Connected if self.mux.is_peer_closed(self.id) => Status::HalfClosed,
+            status => status,
+        }
+    }
+
+    fn shutdown_write(&self) -> Result<(), Error> {
+        self.mux.close(self.id)
+    }
+
+    fn wait(&self) {
+        self.mux.transport.wait()
+    }
+
+    fn fd(&self) -> RawFd {
+        self.mux.transport.fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SocketTransport;
+
+    /// A mux with a window of 4 and stream 0 open, and the raw peer
+    fn mux() -> (Mux<SocketTransport>, SocketTransport) {
+        let (ours, peer) = SocketTransport::pair().unwrap();
+        let mux = Mux::with_window(ours, 4);
+        mux.open(0);
+        (mux, peer)
+    }
+
+    fn is_protocol_error<T>(res: Result<T, Error>) -> bool {
+        matches!(res, Err(Error::Read(e)) if e.kind() == ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn window_exceeded() {
+        let (mux, peer) = mux();
+        peer.send(&header(0, KIND_DATA, 3)).unwrap();
+        peer.send(b"abc").unwrap();
+        mux.poll().unwrap();
+        // The 3 bytes have not been read, so only 1 more fits
+        peer.send(&header(0, KIND_DATA, 2)).unwrap();
+        assert!(is_protocol_error(mux.poll()));
+    }
+
+    #[test]
+    fn credit_overflow() {
+        let (mux, peer) = mux();
+        peer.send(&header(0, KIND_CREDIT, u32::MAX - 4)).unwrap();
+        mux.poll().unwrap();
+        // Only limited by the transport now
+        assert!(mux.buffer_space(0) > 4);
+        peer.send(&header(0, KIND_CREDIT, 1)).unwrap();
+        assert!(is_protocol_error(mux.poll()));
+    }
+
+    #[test]
+    fn data_after_close() {
+        let (mux, peer) = mux();
+        peer.send(&header(0, KIND_DATA, 1)).unwrap();
+        peer.send(b"x").unwrap();
+        peer.send(&header(0, KIND_CLOSE, 0)).unwrap();
+        mux.poll().unwrap();
+        // Closed, but there is still data to read
+        assert!(!mux.is_peer_closed(0));
+        peer.send(&header(0, KIND_DATA, 1)).unwrap();
+        peer.send(b"y").unwrap();
+        assert!(is_protocol_error(mux.poll()));
+    }
+
+    #[test]
+    fn reserved_byte() {
+        let (mux, peer) = mux();
+        let mut frame = header(0, KIND_CREDIT, 1);
+        frame[3] = 1;
+        peer.send(&frame).unwrap();
+        assert!(is_protocol_error(mux.poll()));
+    }
+
+    #[test]
+    fn failure_is_terminal() {
+        let (mux, peer) = mux();
+        peer.send(&header(1, KIND_DATA, 1)).unwrap();
+        peer.send(b"x").unwrap();
+        assert!(is_protocol_error(mux.poll()));
+        // A valid frame does not get the mux out of the error state
+        peer.send(&header(0, KIND_DATA, 1)).unwrap();
+        peer.send(b"y").unwrap();
+        assert!(is_protocol_error(mux.poll()));
+        assert!(is_protocol_error(mux.recv(0, &mut [0; 1])));
+        assert!(is_protocol_error(mux.send(0, b"z")));
+        assert_eq!(mux.data_ready(0), 0);
+        assert_eq!(peer.data_ready(), 0);
+    }
+
+    #[test]
+    fn control_frames_wait_for_space() {
+        let (mux, peer) = mux();
+        // Fill the transport, without going through the mux
+        let mut filler = 0;
+        loop {
+            let space = mux.transport().buffer_space();
+            if space < HEADER_SIZE {
+                break;
+            }
+            mux.transport().send(&vec![0; space]).unwrap();
+            filler += space;
+        }
+        assert_eq!(mux.send(0, b"data").unwrap(), 0);
+        mux.close(0).unwrap();
+        peer.send(&header(0, KIND_DATA, 4)).unwrap();
+        peer.send(b"abcd").unwrap();
+        // Reading all of the window grants credit, which is queued too
+        let mut buf = [0; 4];
+        assert_eq!(mux.recv(0, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(peer.data_ready(), filler);
+        // Once there is space, the frames are sent in order
+        peer.discard(filler).unwrap();
+        mux.poll().unwrap();
+        let mut frames = [0; 2 * HEADER_SIZE];
+        peer.recv(&mut frames).unwrap();
+        assert_eq!(frames[..HEADER_SIZE], header(0, KIND_CLOSE, 0));
+        assert_eq!(frames[HEADER_SIZE..], header(0, KIND_CREDIT, 4));
+        assert_eq!(peer.data_ready(), 0);
+        // Closed streams cannot send
+        assert!(mux.send(0, b"more").is_err());
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A vchan server that can listen again once its client is gone.
+
+use super::{Error, Status, Transport, Vchan, VchanBuilder};
+use std::os::unix::prelude::RawFd;
+
+/// A vchan server that can listen again, with the same configuration, once
+/// its client has disconnected.
+///
+/// A vchan cannot be reused after its peer disconnects, so the server must
+/// close it and listen again.  [`ServerVchan::relisten`] does this.  If
+/// listening again fails, the server is left closed: its status is
+/// [`Status::Disconnected`], and the other methods panic until a later
+/// call to [`ServerVchan::relisten`] succeeds.
+///
+/// ```no_run
+/// use vchan::{ServerVchan, Status, Transport, Vchan};
+/// let mut server = ServerVchan::new(*Vchan::builder().domain(5u16).port(6000)).unwrap();
+/// loop {
+///     while server.status() != Status::Disconnected {
+///         // serve the client
+///         # break
+///     }
+///     server.relisten().unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ServerVchan {
+    config: VchanBuilder,
+    vchan: Option<Vchan>,
+}
+
+impl ServerVchan {
+    /// Starts listening with the given configuration.
+    pub fn new(config: VchanBuilder) -> Result<Self, Error> {
+        Ok(Self {
+            vchan: Some(config.server()?),
+            config,
+        })
+    }
+
+    /// The configuration used to listen
+    pub fn config(&self) -> &VchanBuilder {
+        &self.config
+    }
+
+    /// The current vchan, or [`None`] if listening again failed.
+    pub fn vchan(&self) -> Option<&Vchan> {
+        self.vchan.as_ref()
+    }
+
+    /// Closes the current vchan and listens again on the same domain and
+    /// port.  The old vchan must be closed first, as only one server can
+    /// listen on a port.
+    ///
+    /// # Errors
+    ///
+    /// Fails if listening fails.  The server is then closed.
+    pub fn relisten(&mut self) -> Result<(), Error> {
+        self.vchan = None;
+        self.vchan = Some(self.config.server()?);
+        Ok(())
+    }
+
+    fn get(&self) -> &Vchan {
+        self.vchan
+            .as_ref()
+            .expect("vchan closed by failed relisten()")
+    }
+}
+
+impl Transport for ServerVchan {
+    fn send(&self, buffer: &[u8]) -> Result<(), Error> {
+        self.get().send(buffer)
+    }
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.get().recv(buffer)
+    }
+    fn data_ready(&self) -> usize {
+        self.get().data_ready()
+    }
+    fn buffer_space(&self) -> usize {
+        self.get().buffer_space()
+    }
+    /// The status of the current vchan, or [`Status::Disconnected`] if
+    /// listening again failed.
+    fn status(&self) -> Status {
+        self.vchan
+            .as_ref()
+            .map(Vchan::status)
+            .unwrap_or(Status::Disconnected)
+    }
+    fn wait(&self) {
+        self.get().wait()
+    }
+    fn fd(&self) -> RawFd {
+        self.get().fd()
+    }
+    fn recv_into(&self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), Error> {
+        self.get().recv_into(buffer, bytes)
+    }
+    fn discard(&self, bytes: usize) -> Result<(), Error> {
+        self.get().discard(bytes)
+    }
+    #[cfg(feature = "castable")]
+    fn recv_struct<T: qubes_castable::Castable + Default>(&self) -> Result<T, Error> {
+        self.get().recv_struct()
+    }
+}
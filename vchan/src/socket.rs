@@ -0,0 +1,146 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A [`Transport`] over a Unix domain socket, for tests.
+
+use super::{sys, Error, Status, Transport};
+use std::io::{Read, Write};
+use std::os::raw::c_int;
+use std::os::unix::net::UnixStream;
+use std::os::unix::prelude::{AsRawFd, RawFd};
+
+/// A [`Transport`] over a connected Unix domain socket.
+///
+/// This allows testing code that uses vchans without a Xen host:
+///
+/// ```
+/// use vchan::{SocketTransport, Transport};
+/// let (agent, daemon) = SocketTransport::pair().unwrap();
+/// agent.send(b"hello").unwrap();
+/// assert_eq!(daemon.data_ready(), 5);
+/// let mut buf = [0; 5];
+/// daemon.recv(&mut buf).unwrap();
+/// assert_eq!(&buf, b"hello");
+///
+/// // Half-close the agent side.  The daemon can still send.
+/// agent.shutdown_write().unwrap();
+/// assert_eq!(daemon.status(), vchan::Status::HalfClosed);
+/// daemon.send(b"bye").unwrap();
+/// assert_eq!(agent.data_ready(), 3);
+/// ```
+#[derive(Debug)]
+pub struct SocketTransport {
+    socket: UnixStream,
+}
+
+impl From<UnixStream> for SocketTransport {
+    fn from(socket: UnixStream) -> Self {
+        Self { socket }
+    }
+}
+
+impl SocketTransport {
+    /// Creates a pair of connected transports.
+    pub fn pair() -> std::io::Result<(Self, Self)> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((a.into(), b.into()))
+    }
+
+    /// Returns the underlying socket.
+    pub fn into_inner(self) -> UnixStream {
+        self.socket
+    }
+
+    fn ioctl(&self, request: std::os::raw::c_ulong) -> usize {
+        let mut value: c_int = 0;
+        // SAFETY: both requests store a c_int in the pointed-to memory
+        match unsafe { sys::ioctl(self.socket.as_raw_fd(), request, &mut value) } {
+            0 if value > 0 => value as usize,
+            _ => 0,
+        }
+    }
+}
+
+impl Transport for SocketTransport {
+    fn send(&self, buffer: &[u8]) -> Result<(), Error> {
+        (&self.socket).write_all(buffer).map_err(Error::Write)
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        (&self.socket).read_exact(buffer).map_err(Error::Read)
+    }
+
+    fn data_ready(&self) -> usize {
+        self.ioctl(sys::FIONREAD)
+    }
+
+    /// Returns a conservative estimate of the space in the send buffer.
+    /// The kernel also counts its bookkeeping overhead against the buffer,
+    /// so only half of the free space is reported.
+    fn buffer_space(&self) -> usize {
+        let mut size: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as u32;
+        // SAFETY: size and len are valid for SO_SNDBUF
+        let res = unsafe {
+            sys::getsockopt(
+                self.socket.as_raw_fd(),
+                sys::SOL_SOCKET,
+                sys::SO_SNDBUF,
+                &mut size as *mut c_int as *mut _,
+                &mut len,
+            )
+        };
+        if res != 0 || size <= 0 {
+            return 0;
+        }
+        (size as usize).saturating_sub(self.ioctl(sys::SIOCOUTQ)) / 2
+    }
+
+    fn status(&self) -> Status {
+        match sys::poll_one(self.socket.as_raw_fd(), sys::POLLRDHUP, 0) {
+            Ok(events) if events & (sys::POLLHUP | sys::POLLERR) != 0 => Status::Disconnected,
+            Ok(events) if events & sys::POLLRDHUP != 0 => Status::HalfClosed,
+            Ok(_) => Status::Connected,
+            Err(_) => Status::Disconnected,
+        }
+    }
+
+    fn shutdown_write(&self) -> Result<(), Error> {
+        self.socket
+            .shutdown(std::net::Shutdown::Write)
+            .map_err(Error::Write)
+    }
+
+    /// Blocks until data can be received, or until data can be sent if the
+    /// send buffer is full.
+    fn wait(&self) {
+        let events = if self.buffer_space() == 0 {
+            sys::POLLIN | sys::POLLOUT
+        } else {
+            sys::POLLIN
+        };
+        let _ = sys::poll_one(self.socket.as_raw_fd(), events, -1);
+    }
+
+    fn fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
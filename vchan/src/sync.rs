@@ -0,0 +1,284 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! A [`Vchan`] that can be shared between threads.
+
+use super::{Error, Status, Transport, Vchan};
+use std::io::{Read, Write};
+use std::os::unix::prelude::RawFd;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+/// Which thread, if any, is blocked in [`Vchan::wait`]
+#[derive(Debug, Default)]
+struct WaitState {
+    /// A thread is blocked in [`Vchan::wait`]
+    waiting: bool,
+    /// Incremented each time [`Vchan::wait`] returns
+    generation: u64,
+}
+
+/// A [`Vchan`] that can be shared between threads, so that one thread can
+/// read while another writes.
+///
+/// A vchan consists of two independent rings, one for each direction.
+/// Reads are serialized by one lock and writes by another, so a reader and a
+/// writer never block each other.  The event channel is shared, however, and
+/// a thread blocked in [`Vchan::wait`] consumes events meant for the other
+/// direction too.  Only one thread waits at a time, and it wakes the others
+/// whenever an event arrives, so that they can check if they can make
+/// progress.
+///
+/// A [`SocketTransport`](crate::SocketTransport) can be shared the same way,
+/// which is useful for testing.  Other [`Transport`]s can be wrapped, but the
+/// wrapper cannot be shared between threads.
+#[derive(Debug)]
+pub struct SyncVchan<T: Transport = Vchan> {
+    vchan: T,
+    read: Mutex<()>,
+    write: Mutex<()>,
+    wait: Mutex<WaitState>,
+    wakeup: Condvar,
+}
+
+// SAFETY: reads only touch the read ring and are serialized by `read`, and
+// writes only touch the write ring and are serialized by `write`.  Both
+// directions notify the peer via the event channel, and libvchan uses atomic
+// operations for the notification flags in the shared page.  Only one thread
+// calls libvchan_wait() at a time, as it is guarded by `wait`.  Everything
+// else only reads shared state.
+unsafe impl Sync for SyncVchan<Vchan> {}
+
+// SAFETY: a SocketTransport is Sync itself, as are the other fields.
+unsafe impl Sync for SyncVchan<crate::SocketTransport> {}
+
+/// Locks a mutex, ignoring poisoning: the data protected by these mutexes is
+/// always consistent.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<T: Transport> From<T> for SyncVchan<T> {
+    fn from(vchan: T) -> Self {
+        Self::new(vchan)
+    }
+}
+
+impl<T: Transport> SyncVchan<T> {
+    /// Wraps a [`Vchan`] or other [`Transport`].
+    pub fn new(vchan: T) -> Self {
+        Self {
+            vchan,
+            read: Mutex::new(()),
+            write: Mutex::new(()),
+            wait: Mutex::new(WaitState::default()),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Returns the wrapped [`Vchan`] or other [`Transport`].
+    pub fn into_inner(self) -> T {
+        self.vchan
+    }
+
+    /// See [`Vchan::status`].
+    pub fn status(&self) -> Status {
+        self.vchan.status()
+    }
+
+    /// See [`Vchan::data_ready`].
+    pub fn data_ready(&self) -> usize {
+        self.vchan.data_ready()
+    }
+
+    /// See [`Vchan::buffer_space`].
+    pub fn buffer_space(&self) -> usize {
+        self.vchan.buffer_space()
+    }
+
+    /// See [`Vchan::fd`].
+    pub fn fd(&self) -> RawFd {
+        self.vchan.fd()
+    }
+
+    /// Like [`Vchan::wait`], but can be called by several threads at once.
+    /// If another thread is already waiting, this waits for that thread to
+    /// see an event instead.
+    pub fn wait(&self) {
+        drop(self.wait_locked(lock(&self.wait)))
+    }
+
+    /// Waits for an event.  `state` must be the guard of `self.wait`.
+    fn wait_locked<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, WaitState>,
+    ) -> MutexGuard<'a, WaitState> {
+        if state.waiting {
+            // Another thread is in libvchan_wait(), and will wake this one
+            // once an event arrives.
+            let generation = state.generation;
+            while state.waiting && state.generation == generation {
+                state = self.wakeup.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            state
+        } else {
+            state.waiting = true;
+            drop(state);
+            self.vchan.wait();
+            let mut state = lock(&self.wait);
+            state.waiting = false;
+            state.generation = state.generation.wrapping_add(1);
+            self.wakeup.notify_all();
+            state
+        }
+    }
+
+    /// Blocks until `ready` returns true or the peer disconnects.  `ready`
+    /// is checked with the wait lock held, so events that another thread
+    /// consumed are not lost.
+    fn wait_for(&self, ready: impl Fn() -> bool) {
+        let mut state = lock(&self.wait);
+        while !ready() && self.vchan.status() != Status::Disconnected {
+            state = self.wait_locked(state);
+        }
+    }
+
+    /// Like [`Vchan::send`], but can be called while another thread is
+    /// receiving.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Write`] if the peer disconnects or writing fails.
+    pub fn send(&self, mut buffer: &[u8]) -> Result<(), Error> {
+        let _guard = lock(&self.write);
+        loop {
+            let to_send = buffer.len().min(self.vchan.buffer_space());
+            if to_send > 0 {
+                // There is enough space, so this does not block
+                self.vchan.send(&buffer[..to_send])?;
+                buffer = &buffer[to_send..];
+            }
+            if buffer.is_empty() {
+                break Ok(());
+            }
+            if self.vchan.status() == Status::Disconnected {
+                break Err(Error::Write(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            self.wait_for(|| self.vchan.buffer_space() > 0)
+        }
+    }
+
+    /// Like [`Vchan::recv`], but can be called while another thread is
+    /// sending.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::Read`] if the peer disconnects or reading fails.
+    pub fn recv(&self, mut buffer: &mut [u8]) -> Result<(), Error> {
+        let _guard = lock(&self.read);
+        loop {
+            let to_recv = buffer.len().min(self.vchan.data_ready());
+            if to_recv > 0 {
+                // There is enough data, so this does not block
+                self.vchan.recv(&mut buffer[..to_recv])?;
+                buffer = &mut buffer[to_recv..];
+            }
+            if buffer.is_empty() {
+                break Ok(());
+            }
+            if self.vchan.status() == Status::Disconnected {
+                break Err(Error::Read(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.wait_for(|| self.vchan.data_ready() > 0)
+        }
+    }
+}
+
+impl<T: Transport> std::os::unix::io::AsRawFd for SyncVchan<T> {
+    /// See [`Vchan::fd`].
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd()
+    }
+}
+
+impl Read for &SyncVchan {
+    /// See the [`Read`] implementation of [`Vchan`].
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        let _guard = lock(&self.read);
+        self.vchan.read_nonblocking(buffer)
+    }
+}
+
+impl Write for &SyncVchan {
+    /// See the [`Write`] implementation of [`Vchan`].
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, std::io::Error> {
+        let _guard = lock(&self.write);
+        self.vchan.write_nonblocking(buffer)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SocketTransport;
+
+    #[test]
+    fn concurrent_reader_writer_and_waiter() {
+        const SENT: usize = 1 << 20;
+        const RECEIVED: usize = 1 << 16;
+        let (ours, peer) = SocketTransport::pair().unwrap();
+        let shared = SyncVchan::new(ours);
+        let sent: Vec<u8> = (0..SENT).map(|i| i as u8).collect();
+        std::thread::scope(|s| {
+            let peer = &peer;
+            let (shared, sent) = (&shared, &sent);
+            // More than the socket buffer, so the writer has to wait
+            let writer = s.spawn(move || shared.send(sent).unwrap());
+            let reader = s.spawn(move || {
+                let mut buf = vec![0; RECEIVED];
+                shared.recv(&mut buf).unwrap();
+                buf
+            });
+            let waiter = s.spawn(move || shared.wait());
+            s.spawn(move || {
+                let data: Vec<u8> = (0..RECEIVED).map(|i| (i * 7) as u8).collect();
+                for chunk in data.chunks(4096) {
+                    peer.send(chunk).unwrap()
+                }
+                // Left unread, so that waiting never blocks once the reader
+                // is done
+                peer.send(&[0]).unwrap()
+            });
+            let mut buf = vec![0; SENT];
+            peer.recv(&mut buf).unwrap();
+            assert!(buf == *sent);
+            writer.join().unwrap();
+            waiter.join().unwrap();
+            let received = reader.join().unwrap();
+            assert!((0..RECEIVED).all(|i| received[i] == (i * 7) as u8));
+        });
+        assert_eq!(shared.data_ready(), 1);
+        assert_eq!(shared.status(), Status::Connected);
+    }
+}
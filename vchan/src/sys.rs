@@ -0,0 +1,76 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The few libc functions this crate needs.
+
+use std::os::raw::{c_int, c_short, c_ulong, c_void};
+
+#[repr(C)]
+pub(crate) struct PollFd {
+    pub fd: c_int,
+    pub events: c_short,
+    pub revents: c_short,
+}
+
+pub(crate) const POLLIN: c_short = 0x1;
+pub(crate) const POLLOUT: c_short = 0x4;
+pub(crate) const POLLERR: c_short = 0x8;
+pub(crate) const POLLHUP: c_short = 0x10;
+pub(crate) const POLLRDHUP: c_short = 0x2000;
+
+pub(crate) const SOL_SOCKET: c_int = 1;
+pub(crate) const SO_SNDBUF: c_int = 7;
+pub(crate) const FIONREAD: c_ulong = 0x541B;
+pub(crate) const SIOCOUTQ: c_ulong = 0x5411;
+
+extern "C" {
+    pub(crate) fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    pub(crate) fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    pub(crate) fn getsockopt(
+        fd: c_int,
+        level: c_int,
+        name: c_int,
+        value: *mut c_void,
+        len: *mut u32,
+    ) -> c_int;
+}
+
+/// Polls a single file descriptor, retrying if interrupted by a signal.
+/// Returns the events that happened, or 0 on timeout.
+pub(crate) fn poll_one(fd: c_int, events: c_short, timeout: c_int) -> std::io::Result<c_short> {
+    loop {
+        let mut pollfd = PollFd {
+            fd,
+            events,
+            revents: 0,
+        };
+        // SAFETY: pollfd is a valid pollfd, and nfds is 1
+        match unsafe { poll(&mut pollfd, 1, timeout) } {
+            -1 => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    break Err(e);
+                }
+            }
+            _ => break Ok(pollfd.revents),
+        }
+    }
+}
@@ -0,0 +1,466 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! Recording and replay of vchan traffic.
+//!
+//! A transcript starts with the 8-byte magic number `QVCHANT1`, followed by
+//! any number of records.  Each record is:
+//!
+//! | Size | Contents                                                       |
+//! |------|----------------------------------------------------------------|
+//! | 1    | `b'>'` for data sent to the peer, `b'<'` for data received     |
+//! | 8    | Microseconds since the recording started, little-endian `u64`   |
+//! | 4    | Length of the data, little-endian `u32`                        |
+//! | n    | The data                                                       |
+//!
+//! A transcript ends at the end of the file.
+
+use super::{Status, Transport};
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::prelude::RawFd;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The magic number at the start of every transcript
+pub const TRANSCRIPT_MAGIC: [u8; 8] = *b"QVCHANT1";
+
+/// The direction of a [`Record`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Data sent to the peer
+    Sent,
+    /// Data received from the peer
+    Received,
+}
+
+/// One record in a transcript
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Whether the data was sent or received
+    pub direction: Direction,
+    /// The time since the recording started
+    pub time: Duration,
+    /// The data
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Writes the record in transcript format.
+    pub fn write_to(&self, w: &mut impl Write) -> Result<(), Error> {
+        let len = u32::try_from(self.data.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "record too long"))?;
+        let micros = u64::try_from(self.time.as_micros()).unwrap_or(u64::MAX);
+        let direction = match self.direction {
+            Direction::Sent => b'>',
+            Direction::Received => b'<',
+        };
+        w.write_all(&[direction])?;
+        w.write_all(&micros.to_le_bytes())?;
+        w.write_all(&len.to_le_bytes())?;
+        w.write_all(&self.data)
+    }
+
+    /// Reads a record in transcript format.  Returns `Ok(None)` at the end
+    /// of the transcript.
+    pub fn read_from(r: &mut impl Read) -> Result<Option<Self>, Error> {
+        let mut header = [0u8; 13];
+        match r.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => r.read_exact(&mut header[1..])?,
+        }
+        let direction = match header[0] {
+            b'>' => Direction::Sent,
+            b'<' => Direction::Received,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "bad record direction")),
+        };
+        let mut micros = [0u8; 8];
+        let mut len = [0u8; 4];
+        micros.copy_from_slice(&header[1..9]);
+        len.copy_from_slice(&header[9..]);
+        // The length is untrusted, so only allocate for data that is there
+        let len = u32::from_le_bytes(len);
+        let mut data = vec![];
+        r.take(len.into()).read_to_end(&mut data)?;
+        if data.len() != len as usize {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated record"));
+        }
+        Ok(Some(Self {
+            direction,
+            time: Duration::from_micros(u64::from_le_bytes(micros)),
+            data,
+        }))
+    }
+}
+
+/// Reads a whole transcript, including the magic number.
+pub fn read_transcript(mut r: impl Read) -> Result<Vec<Record>, Error> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if magic != TRANSCRIPT_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a vchan transcript"));
+    }
+    let mut records = vec![];
+    while let Some(record) = Record::read_from(&mut r)? {
+        records.push(record)
+    }
+    Ok(records)
+}
+
+/// A wrapper that records all data read from and written to `T` in a
+/// transcript.
+///
+/// If writing to the transcript fails, the error is returned, even though
+/// the data has already been transferred.
+pub struct RecordingVchan<T> {
+    inner: T,
+    log: Mutex<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for RecordingVchan<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RecordingVchan")
+            .field("inner", &self.inner)
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+impl<T> RecordingVchan<T> {
+    /// Starts recording the traffic on `inner` to `log`.
+    pub fn new(inner: T, mut log: impl Write + Send + 'static) -> Result<Self, Error> {
+        log.write_all(&TRANSCRIPT_MAGIC)?;
+        Ok(Self {
+            inner,
+            log: Mutex::new(Box::new(log)),
+            start: Instant::now(),
+        })
+    }
+
+    /// Starts recording the traffic on `inner` to the file at `path`, which
+    /// is created or truncated.
+    pub fn create(inner: T, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = std::fs::File::create(path)?;
+        Self::new(inner, std::io::BufWriter::new(file))
+    }
+
+    /// Returns a reference to the wrapped vchan.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped vchan.  Traffic on this
+    /// reference is not recorded.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Flushes the transcript and returns the wrapped vchan.
+    pub fn into_inner(self) -> Result<T, Error> {
+        self.log_mut().flush()?;
+        Ok(self.inner)
+    }
+
+    fn log_mut(&self) -> std::sync::MutexGuard<'_, Box<dyn Write + Send>> {
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        // Take the lock before reading the clock, so that the timestamps in
+        // the transcript are in order.
+        let mut log = self.log_mut();
+        Record {
+            direction,
+            time: self.start.elapsed(),
+            data: data.to_owned(),
+        }
+        .write_to(&mut *log)
+    }
+}
+
+impl<T: Read> Read for RecordingVchan<T> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buffer)?;
+        self.record(Direction::Received, &buffer[..n])?;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for RecordingVchan<T> {
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        let n = self.inner.write(buffer)?;
+        self.record(Direction::Sent, &buffer[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()?;
+        self.log_mut().flush()
+    }
+}
+
+/// A fake vchan that replays a transcript.
+///
+/// Reads return the received data from the transcript, and writes are
+/// checked against the sent data.  The two directions are independent, and
+/// timestamps are ignored, so the replay does not depend on how reads and
+/// writes happened to interleave when the transcript was recorded.  Once all
+/// received data has been read, the peer has [half-closed] the channel, and
+/// once all sent data has been written, it has disconnected.
+///
+/// [half-closed]: Status::HalfClosed
+///
+/// ```
+/// use std::io::{Read, Write};
+/// use vchan::{read_transcript, Direction, RecordingVchan, ReplayVchan};
+/// # fn main() -> std::io::Result<()> {
+/// let mut transcript = vchan::TRANSCRIPT_MAGIC.to_vec();
+/// vchan::Record {
+///     direction: Direction::Received,
+///     time: Default::default(),
+///     data: b"hello".to_vec(),
+/// }
+/// .write_to(&mut transcript)?;
+/// let replay = ReplayVchan::from_reader(&transcript[..])?;
+///
+/// // Record a replay of the transcript into a new one
+/// let (log, contents) = SharedLog::new();
+/// let mut vchan = RecordingVchan::new(replay, log)?;
+/// let mut buf = [0; 5];
+/// vchan.read_exact(&mut buf)?;
+/// assert_eq!(&buf, b"hello");
+/// // The transcript does not expect anything to be sent
+/// assert!(vchan.write(b"x").is_err());
+/// assert!(vchan.into_inner()?.is_finished());
+///
+/// let records = read_transcript(&contents.lock().unwrap()[..])?;
+/// assert_eq!(records.len(), 1);
+/// assert_eq!(records[0].data, b"hello");
+/// # Ok(())
+/// # }
+/// # use std::sync::{Arc, Mutex};
+/// # struct SharedLog(Arc<Mutex<Vec<u8>>>);
+/// # impl SharedLog {
+/// #     fn new() -> (Self, Arc<Mutex<Vec<u8>>>) {
+/// #         let v = Arc::new(Mutex::new(vec![]));
+/// #         (Self(v.clone()), v)
+/// #     }
+/// # }
+/// # impl Write for SharedLog {
+/// #     fn write(&mut self, b: &[u8]) -> std::io::Result<usize> {
+/// #         self.0.lock().unwrap().write(b)
+/// #     }
+/// #     fn flush(&mut self) -> std::io::Result<()> {
+/// #         Ok(())
+/// #     }
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ReplayVchan {
+    received: Vec<u8>,
+    read_offset: Cell<usize>,
+    sent: Vec<u8>,
+    write_offset: Cell<usize>,
+}
+
+impl ReplayVchan {
+    /// Creates a replay of the given records.
+    pub fn new(records: impl IntoIterator<Item = Record>) -> Self {
+        let mut replay = Self::default();
+        for record in records {
+            match record.direction {
+                Direction::Sent => replay.sent.extend(record.data),
+                Direction::Received => replay.received.extend(record.data),
+            }
+        }
+        replay
+    }
+
+    /// Creates a replay of the transcript read from `r`.
+    pub fn from_reader(r: impl Read) -> Result<Self, Error> {
+        read_transcript(r).map(Self::new)
+    }
+
+    /// Creates a replay of the transcript in the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// The amount of received data that has not been read yet
+    pub fn data_ready(&self) -> usize {
+        self.received.len() - self.read_offset.get()
+    }
+
+    /// The amount of sent data that has not been written yet
+    pub fn buffer_space(&self) -> usize {
+        self.sent.len() - self.write_offset.get()
+    }
+
+    /// [`Status::Connected`] until all received data has been read, then
+    /// [`Status::HalfClosed`] until all sent data has been written, then
+    /// [`Status::Disconnected`].
+    pub fn status(&self) -> Status {
+        if self.data_ready() != 0 {
+            Status::Connected
+        } else if self.buffer_space() != 0 {
+            Status::HalfClosed
+        } else {
+            Status::Disconnected
+        }
+    }
+
+    /// Returns true if all received data has been read and all sent data
+    /// has been written.
+    pub fn is_finished(&self) -> bool {
+        self.data_ready() == 0 && self.buffer_space() == 0
+    }
+}
+
+impl ReplayVchan {
+    fn read_replay(&self, buffer: &mut [u8]) -> usize {
+        let n = buffer.len().min(self.data_ready());
+        let offset = self.read_offset.get();
+        buffer[..n].copy_from_slice(&self.received[offset..offset + n]);
+        self.read_offset.set(offset + n);
+        n
+    }
+
+    fn write_replay(&self, buffer: &[u8]) -> Result<usize, Error> {
+        let offset = self.write_offset.get();
+        let expected = &self.sent[offset..];
+        if buffer.len() > expected.len() || buffer != &expected[..buffer.len()] {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "data written at offset {} does not match the transcript",
+                    offset
+                ),
+            ));
+        }
+        self.write_offset.set(offset + buffer.len());
+        Ok(buffer.len())
+    }
+}
+
+impl Read for ReplayVchan {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.read_replay(buffer))
+    }
+}
+
+impl Write for ReplayVchan {
+    /// Checks `buffer` against the sent data in the transcript.
+    ///
+    /// Fails with [`ErrorKind::InvalidData`] if it does not match, or if more
+    /// data is written than the transcript contains.
+    fn write(&mut self, buffer: &[u8]) -> Result<usize, Error> {
+        self.write_replay(buffer)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Transport for ReplayVchan {
+    /// Checks `buffer` against the sent data in the transcript, as for the
+    /// [`Write`] implementation.
+    fn send(&self, buffer: &[u8]) -> Result<(), super::Error> {
+        self.write_replay(buffer)
+            .map(drop)
+            .map_err(super::Error::Write)
+    }
+
+    /// Fails with [`ErrorKind::UnexpectedEof`] if the transcript does not
+    /// contain enough received data, as the replay would never finish.
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), super::Error> {
+        if buffer.len() > self.data_ready() {
+            return Err(super::Error::Read(ErrorKind::UnexpectedEof.into()));
+        }
+        self.read_replay(buffer);
+        Ok(())
+    }
+
+    fn data_ready(&self) -> usize {
+        ReplayVchan::data_ready(self)
+    }
+
+    fn buffer_space(&self) -> usize {
+        ReplayVchan::buffer_space(self)
+    }
+
+    fn status(&self) -> Status {
+        ReplayVchan::status(self)
+    }
+
+    /// Does nothing, as a replay never needs to wait.
+    fn wait(&self) {}
+
+    /// Returns -1, as a replay has no file descriptor.  `poll` ignores
+    /// negative file descriptors.
+    fn fd(&self) -> RawFd {
+        -1
+    }
+}
+
+impl<T: Transport> Transport for RecordingVchan<T> {
+    fn send(&self, buffer: &[u8]) -> Result<(), super::Error> {
+        self.inner.send(buffer)?;
+        self.record(Direction::Sent, buffer)
+            .map_err(super::Error::Write)
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), super::Error> {
+        self.inner.recv(buffer)?;
+        self.record(Direction::Received, buffer)
+            .map_err(super::Error::Read)
+    }
+
+    fn data_ready(&self) -> usize {
+        self.inner.data_ready()
+    }
+
+    fn buffer_space(&self) -> usize {
+        self.inner.buffer_space()
+    }
+
+    fn status(&self) -> Status {
+        self.inner.status()
+    }
+
+    fn shutdown_write(&self) -> Result<(), super::Error> {
+        self.inner.shutdown_write()
+    }
+
+    fn wait(&self) {
+        self.inner.wait()
+    }
+
+    fn fd(&self) -> RawFd {
+        self.inner.fd()
+    }
+}
@@ -0,0 +1,204 @@
+/*
+ * The Qubes OS Project, https://www.qubes-os.org
+ *
+ * Copyright (C) 2021  Demi Marie Obenour  <demi@invisiblethingslab.com>
+ *
+ * This program is free software; you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License
+ * as published by the Free Software Foundation; either version 2
+ * of the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301, USA.
+ *
+ */
+
+//! The [`Transport`] trait, which abstracts over the backend of a vchan.
+
+use super::{Error, Status, SyncVchan, Vchan};
+use std::io::ErrorKind;
+use std::os::unix::prelude::RawFd;
+
+/// A bidirectional, stream-oriented channel with the semantics of a vchan.
+///
+/// [`Vchan`] is the real implementation.  [`SocketTransport`] and
+/// [`ReplayVchan`] are useful for tests, and [`RecordingVchan`] records
+/// the traffic on any other transport.
+///
+/// [`SocketTransport`]: crate::SocketTransport
+/// [`ReplayVchan`]: crate::ReplayVchan
+/// [`RecordingVchan`]: crate::RecordingVchan
+pub trait Transport {
+    /// Sends the entire buffer, blocking until there is enough space.
+    fn send(&self, buffer: &[u8]) -> Result<(), Error>;
+
+    /// Blocks until the given buffer is full.
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Returns the amount of data that can be received without blocking.
+    fn data_ready(&self) -> usize;
+
+    /// Returns the amount of data that can be sent without blocking.
+    fn buffer_space(&self) -> usize;
+
+    /// Returns the status of the channel.
+    fn status(&self) -> Status;
+
+    /// Signals that no more data will be sent.  Data that has already been
+    /// sent is still delivered, and data can still be received.  The peer
+    /// sees a status of [`Status::HalfClosed`] once it has received all
+    /// data.
+    ///
+    /// # Errors
+    ///
+    /// The default implementation fails with an error of kind
+    /// [`ErrorKind::Unsupported`].  libvchan cannot close one direction of a
+    /// vchan, so this is what [`Vchan`] does.
+    fn shutdown_write(&self) -> Result<(), Error> {
+        Err(Error::Write(ErrorKind::Unsupported.into()))
+    }
+
+    /// Blocks until an event happens on the channel, as for [`Vchan::wait`].
+    fn wait(&self);
+
+    /// Returns a file descriptor that becomes readable when an event is
+    /// pending.  The only valid use of this descriptor is to call `poll` or
+    /// similar.
+    fn fd(&self) -> RawFd;
+
+    /// Extends the vector with `bytes` bytes from the channel.
+    ///
+    /// # Errors
+    ///
+    /// Fails if allocating memory fails or if receiving fails.  On failure,
+    /// the vector is unchanged.
+    fn recv_into(&self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), Error> {
+        buffer.try_reserve(bytes).map_err(Error::OutOfMemory)?;
+        let len = buffer.len();
+        buffer.resize(len + bytes, 0);
+        let res = self.recv(&mut buffer[len..]);
+        if res.is_err() {
+            buffer.truncate(len);
+        }
+        res
+    }
+
+    /// Receives and discards `bytes` bytes from the channel.
+    fn discard(&self, mut bytes: usize) -> Result<(), Error> {
+        let mut buf = [0u8; 256];
+        while bytes > 0 {
+            let to_read = buf.len().min(bytes);
+            self.recv(&mut buf[..to_read])?;
+            bytes -= to_read;
+        }
+        Ok(())
+    }
+
+    /// Receives any [`qubes_castable::Castable`] struct.
+    #[cfg(feature = "castable")]
+    fn recv_struct<T: qubes_castable::Castable + Default>(&self) -> Result<T, Error>
+    where
+        Self: Sized,
+    {
+        let mut datum = T::default();
+        self.recv(datum.as_mut_bytes())?;
+        Ok(datum)
+    }
+}
+
+impl Transport for Vchan {
+    fn send(&self, buffer: &[u8]) -> Result<(), Error> {
+        Vchan::send(self, buffer)
+    }
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        Vchan::recv(self, buffer)
+    }
+    fn data_ready(&self) -> usize {
+        Vchan::data_ready(self)
+    }
+    fn buffer_space(&self) -> usize {
+        Vchan::buffer_space(self)
+    }
+    fn status(&self) -> Status {
+        Vchan::status(self)
+    }
+    fn wait(&self) {
+        Vchan::wait(self)
+    }
+    fn fd(&self) -> RawFd {
+        Vchan::fd(self)
+    }
+    fn recv_into(&self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), Error> {
+        Vchan::recv_into(self, buffer, bytes)
+    }
+    fn discard(&self, bytes: usize) -> Result<(), Error> {
+        Vchan::discard(self, bytes)
+    }
+    #[cfg(feature = "castable")]
+    fn recv_struct<T: qubes_castable::Castable + Default>(&self) -> Result<T, Error> {
+        Vchan::recv_struct(self)
+    }
+}
+
+impl<T: Transport> Transport for SyncVchan<T> {
+    fn send(&self, buffer: &[u8]) -> Result<(), Error> {
+        SyncVchan::send(self, buffer)
+    }
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        SyncVchan::recv(self, buffer)
+    }
+    fn data_ready(&self) -> usize {
+        SyncVchan::data_ready(self)
+    }
+    fn buffer_space(&self) -> usize {
+        SyncVchan::buffer_space(self)
+    }
+    fn status(&self) -> Status {
+        SyncVchan::status(self)
+    }
+    fn wait(&self) {
+        SyncVchan::wait(self)
+    }
+    fn fd(&self) -> RawFd {
+        SyncVchan::fd(self)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send(&self, buffer: &[u8]) -> Result<(), Error> {
+        (**self).send(buffer)
+    }
+    fn recv(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        (**self).recv(buffer)
+    }
+    fn data_ready(&self) -> usize {
+        (**self).data_ready()
+    }
+    fn buffer_space(&self) -> usize {
+        (**self).buffer_space()
+    }
+    fn status(&self) -> Status {
+        (**self).status()
+    }
+    fn shutdown_write(&self) -> Result<(), Error> {
+        (**self).shutdown_write()
+    }
+    fn wait(&self) {
+        (**self).wait()
+    }
+    fn fd(&self) -> RawFd {
+        (**self).fd()
+    }
+    fn recv_into(&self, buffer: &mut Vec<u8>, bytes: usize) -> Result<(), Error> {
+        (**self).recv_into(buffer, bytes)
+    }
+    fn discard(&self, bytes: usize) -> Result<(), Error> {
+        (**self).discard(bytes)
+    }
+}